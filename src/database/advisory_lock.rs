@@ -0,0 +1,131 @@
+use crate::database::{error::DbError, pgbouncer::Unprepared};
+use anyhow::{Context, Result};
+use sqlx::{
+    PgConnection, Postgres,
+    postgres::PgArguments,
+    query::{Query, QueryScalar},
+};
+use std::fmt;
+
+/// トランザクション単位のアドバイザリロックのキーです。
+///
+/// PostgreSQL の `pg_advisory_xact_lock(bigint)` と `pg_advisory_xact_lock(int, int)` に対応します。
+/// 2 つの形式のキー空間は別物のため、`Single(1)` と `Pair(0, 1)` は異なるロックです。
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum AdvisoryLockKey {
+    /// 64 ビット整数 1 つのキーです。
+    Single(i64),
+    /// 32 ビット整数 2 つのキーです。
+    Pair(i32, i32),
+}
+
+impl From<i64> for AdvisoryLockKey {
+    fn from(key: i64) -> Self {
+        Self::Single(key)
+    }
+}
+
+impl From<(i32, i32)> for AdvisoryLockKey {
+    fn from((first, second): (i32, i32)) -> Self {
+        Self::Pair(first, second)
+    }
+}
+
+impl fmt::Display for AdvisoryLockKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Single(key) => write!(f, "{key}"),
+            Self::Pair(first, second) => write!(f, "({first}, {second})"),
+        }
+    }
+}
+
+/// アドバイザリロックを待たずに取得しようとした結果です。
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[must_use]
+pub enum LockAttempt<T> {
+    /// ロックを取得して処理を実行し、コミットしました。
+    Acquired(T),
+    /// 他のトランザクションがロックを保持していたため、何も実行せずにロールバックしました。
+    LockNotAcquired,
+}
+
+impl<T> LockAttempt<T> {
+    /// ロックを取得できた場合は処理の結果を返します。
+    pub fn acquired(self) -> Option<T> {
+        match self {
+            Self::Acquired(value) => Some(value),
+            Self::LockNotAcquired => None,
+        }
+    }
+}
+
+/// トランザクション内でアドバイザリロックを取得します。取得できるまで待機します。
+pub(super) async fn lock(
+    connection: &mut PgConnection,
+    key: AdvisoryLockKey,
+    pgbouncer_mode: bool,
+) -> Result<()> {
+    lock_query(key, pgbouncer_mode)
+        .execute(connection)
+        .await
+        .map_err(DbError::from)
+        .with_context(|| format!("Failed to acquire advisory lock {key}"))?;
+    Ok(())
+}
+
+/// トランザクション内でアドバイザリロックの取得を試み、取得できたかどうかを返します。
+pub(super) async fn try_lock(
+    connection: &mut PgConnection,
+    key: AdvisoryLockKey,
+    pgbouncer_mode: bool,
+) -> Result<bool> {
+    try_lock_query(key, pgbouncer_mode)
+        .fetch_one(connection)
+        .await
+        .map_err(DbError::from)
+        .with_context(|| format!("Failed to try advisory lock {key}"))
+}
+
+fn lock_query(key: AdvisoryLockKey, pgbouncer_mode: bool) -> Query<'static, Postgres, PgArguments> {
+    let query = match key {
+        AdvisoryLockKey::Single(key) => sqlx::query("SELECT pg_advisory_xact_lock($1)").bind(key),
+        AdvisoryLockKey::Pair(first, second) => sqlx::query("SELECT pg_advisory_xact_lock($1, $2)")
+            .bind(first)
+            .bind(second),
+    };
+    query.unprepared_if(pgbouncer_mode)
+}
+
+fn try_lock_query(
+    key: AdvisoryLockKey,
+    pgbouncer_mode: bool,
+) -> QueryScalar<'static, Postgres, bool, PgArguments> {
+    let query = match key {
+        AdvisoryLockKey::Single(key) => {
+            sqlx::query_scalar("SELECT pg_try_advisory_xact_lock($1)").bind(key)
+        }
+        AdvisoryLockKey::Pair(first, second) => {
+            sqlx::query_scalar("SELECT pg_try_advisory_xact_lock($1, $2)")
+                .bind(first)
+                .bind(second)
+        }
+    };
+    query.unprepared_if(pgbouncer_mode)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sqlx::Execute;
+
+    #[test]
+    fn lock_queries_are_unnamed_in_pgbouncer_mode() {
+        for key in [AdvisoryLockKey::Single(1), AdvisoryLockKey::Pair(1, 2)] {
+            assert!(Execute::persistent(&lock_query(key, false)));
+            assert!(!Execute::persistent(&lock_query(key, true)));
+            assert!(Execute::persistent(&try_lock_query(key, false)));
+            assert!(!Execute::persistent(&try_lock_query(key, true)));
+        }
+    }
+}
@@ -0,0 +1,171 @@
+use crate::database::{error::DbError, identifier::quote_qualified_identifier};
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use sqlx::{PgConnection, PgPool, Postgres, postgres::PgArguments, query::Query};
+use uuid::Uuid;
+
+/// `AuditLog::new` が書き込むテーブル名です。
+pub const DEFAULT_AUDIT_TABLE: &str = "tx_audit_log";
+
+/// `TransactionExecutor` で実行したトランザクションを記録する監査ログの設定です。
+///
+/// コミットするトランザクションには、コミットの直前に同じトランザクション内で 1 行を追加するため、
+/// 記録は処理の結果と原子的に確定します。ロールバックしたトランザクションは既定では記録せず、
+/// `with_rollbacks(true)` の場合のみ、失敗したトランザクションの外で別の書き込みとして記録します。
+/// この書き込みは失敗しても警告ログを出力するだけで、呼び出し元のエラーは変わりません。
+///
+/// ```ignore
+/// let executor = TransactionExecutor::for_workload(&pool, Workload::Batch)
+///     .with_audit_log(AuditLog::new().with_application_user("nightly-batch").with_rollbacks(true));
+/// executor.ensure_audit_table().await?;
+/// executor.execute_queries_labeled("close_month", queries).await?;
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuditLog {
+    table: String,
+    insert_sql: String,
+    record_rollbacks: bool,
+    application_user: Option<String>,
+}
+
+impl Default for AuditLog {
+    fn default() -> Self {
+        Self::with_quoted_table(format!("\"{DEFAULT_AUDIT_TABLE}\""))
+    }
+}
+
+impl AuditLog {
+    /// `tx_audit_log` テーブルに書き込む設定を作成します。
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// `table` に書き込む設定を作成します。`schema.table` 形式で指定でき、引用符で囲んで埋め込みます。
+    pub fn for_table(table: &str) -> Result<Self> {
+        let table = quote_qualified_identifier(table)
+            .with_context(|| format!("Invalid audit table name: {table:?}"))?;
+        Ok(Self::with_quoted_table(table))
+    }
+
+    fn with_quoted_table(table: String) -> Self {
+        let insert_sql = format!(
+            "INSERT INTO {table} (transaction_id, label, outcome, statement_count, rows_affected, \
+             started_at, finished_at, application_user, error) \
+             VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)"
+        );
+        Self {
+            table,
+            insert_sql,
+            record_rollbacks: false,
+            application_user: None,
+        }
+    }
+
+    /// ロールバックしたトランザクションも記録するかを指定します。既定値は `false` です。
+    pub fn with_rollbacks(mut self, record_rollbacks: bool) -> Self {
+        self.record_rollbacks = record_rollbacks;
+        self
+    }
+
+    /// 記録に含めるアプリケーションの利用者を指定します。
+    pub fn with_application_user(mut self, application_user: impl Into<String>) -> Self {
+        self.application_user = Some(application_user.into());
+        self
+    }
+
+    /// ロールバックしたトランザクションも記録するかどうかを返します。
+    pub fn records_rollbacks(&self) -> bool {
+        self.record_rollbacks
+    }
+
+    /// 監査ログのテーブルがなければ作成します。
+    pub(super) async fn ensure_table(&self, pool: &PgPool) -> Result<()> {
+        let sql = format!(
+            "CREATE TABLE IF NOT EXISTS {} (\
+                 transaction_id uuid PRIMARY KEY, \
+                 label text, \
+                 outcome text NOT NULL, \
+                 statement_count bigint NOT NULL, \
+                 rows_affected bigint NOT NULL, \
+                 started_at timestamptz NOT NULL, \
+                 finished_at timestamptz NOT NULL, \
+                 application_user text, \
+                 error text\
+             )",
+            self.table
+        );
+        sqlx::query(&sql)
+            .execute(pool)
+            .await
+            .map_err(DbError::from)
+            .with_context(|| format!("Failed to create audit table {}", self.table))?;
+        Ok(())
+    }
+
+    /// コミットするトランザクションの記録を、そのトランザクション上で追加します。
+    pub(super) async fn record_commit(
+        &self,
+        connection: &mut PgConnection,
+        entry: &AuditEntry,
+        persistent: bool,
+    ) -> Result<()> {
+        self.insert(entry, "committed", None, persistent)
+            .execute(connection)
+            .await
+            .map_err(DbError::from)
+            .with_context(|| format!("Failed to write audit log to {}", self.table))?;
+        Ok(())
+    }
+
+    /// ロールバックしたトランザクションの記録を、別の書き込みとして追加します。失敗は警告ログに出力します。
+    pub(super) async fn record_rollback(
+        &self,
+        pool: &PgPool,
+        entry: &AuditEntry,
+        error: &anyhow::Error,
+        persistent: bool,
+    ) {
+        let error = format!("{error:#}");
+        if let Err(audit_error) = self
+            .insert(entry, "rolled_back", Some(&error), persistent)
+            .execute(pool)
+            .await
+        {
+            tracing::warn!(
+                transaction_id = %entry.transaction_id,
+                table = %self.table,
+                "Failed to write audit log for rolled back transaction: {audit_error}"
+            );
+        }
+    }
+
+    fn insert<'q>(
+        &'q self,
+        entry: &'q AuditEntry,
+        outcome: &'q str,
+        error: Option<&'q str>,
+        persistent: bool,
+    ) -> Query<'q, Postgres, PgArguments> {
+        sqlx::query(&self.insert_sql)
+            .persistent(persistent)
+            .bind(entry.transaction_id)
+            .bind(entry.label.as_deref())
+            .bind(outcome)
+            .bind(i64::try_from(entry.statement_count).unwrap_or(i64::MAX))
+            .bind(i64::try_from(entry.rows_affected).unwrap_or(i64::MAX))
+            .bind(entry.started_at)
+            .bind(Utc::now())
+            .bind(self.application_user.as_deref())
+            .bind(error)
+    }
+}
+
+/// 監査ログに記録するトランザクションの内容です。
+#[derive(Debug, Clone)]
+pub(super) struct AuditEntry {
+    pub(super) transaction_id: Uuid,
+    pub(super) label: Option<String>,
+    pub(super) statement_count: usize,
+    pub(super) rows_affected: u64,
+    pub(super) started_at: DateTime<Utc>,
+}
@@ -0,0 +1,71 @@
+use anyhow::{Result, bail};
+use std::fmt;
+
+/// 接続 URL のスキームから判別するデータベースの種類です。
+///
+/// PostgreSQL 以外は対応する cargo フィーチャー（`sqlite`・`mysql`）を有効にした場合のみ使えます。
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Backend {
+    /// `postgres://`・`postgresql://` です。
+    Postgres,
+    /// `sqlite:` です。
+    Sqlite,
+    /// `mysql://`・`mariadb://` です。
+    MySql,
+}
+
+impl Backend {
+    /// 接続 URL のスキームからデータベースの種類を判別します。
+    ///
+    /// 未知のスキームや、フィーチャーを有効にしていないデータベースの場合はエラーを返します。
+    pub fn from_url(url: &str) -> Result<Self> {
+        let Some((scheme, _)) = url.split_once(':') else {
+            bail!("Database URL has no scheme");
+        };
+        let backend = match scheme.to_ascii_lowercase().as_str() {
+            "postgres" | "postgresql" => Self::Postgres,
+            "sqlite" => Self::Sqlite,
+            "mysql" | "mariadb" => Self::MySql,
+            scheme => bail!("Unsupported database URL scheme: {scheme:?}"),
+        };
+        if !backend.is_enabled() {
+            bail!(
+                "Database URL uses {backend}, but this build does not enable the `{}` feature",
+                backend.feature()
+            );
+        }
+        Ok(backend)
+    }
+
+    /// SQLx の `Database::NAME` と同じ表記の名前を返します。
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Postgres => "PostgreSQL",
+            Self::Sqlite => "SQLite",
+            Self::MySql => "MySQL",
+        }
+    }
+
+    /// このデータベースを有効にする cargo フィーチャー名を返します。
+    fn feature(self) -> &'static str {
+        match self {
+            Self::Postgres => "postgres",
+            Self::Sqlite => "sqlite",
+            Self::MySql => "mysql",
+        }
+    }
+
+    fn is_enabled(self) -> bool {
+        match self {
+            Self::Postgres => true,
+            Self::Sqlite => cfg!(feature = "sqlite"),
+            Self::MySql => cfg!(feature = "mysql"),
+        }
+    }
+}
+
+impl fmt::Display for Backend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
@@ -1,20 +1,84 @@
-use anyhow::{Context, Result, anyhow, ensure};
-use dotenv::dotenv;
-use sqlx::{PgPool, postgres::PgPoolOptions};
-use std::{sync::Arc, time::Duration};
-use tokio::sync::OnceCell;
+pub use crate::database::pool_config::PoolEnvConfig;
 
-const ENV_DATABASE_URL: &str = "DATABASE_URL";
-const ENV_CONNECTION_POOL: &str = "CONNECTION_POOL";
-const DEFAULT_MAX_CONNECTIONS: u32 = 10;
+use crate::database::{
+    backend::Backend,
+    error::DbError,
+    health::{self, HealthMonitorConfig, HealthMonitorHandle, HealthReport, HealthStatus},
+    instrumentation::traced_query,
+    listener::{self, NotificationStream},
+    metrics,
+    migration::{MigrationError, MigrationReport, applied_migrations},
+    pgbouncer::{Unprepared, ensure_map_supported},
+    pool_config::{PoolConfig, Workload},
+    pool_stats::{PoolStats, StatsReporterHandle},
+    retry::AcquireRetry,
+};
+use anyhow::{Context, Result, bail, ensure};
+use chrono::{DateTime, Utc};
+use futures_util::{Stream, StreamExt, TryFutureExt, future, stream};
+use sqlx::{
+    Connection, FromRow, PgConnection, PgPool, Postgres, Transaction,
+    migrate::Migrator,
+    pool::PoolConnection,
+    postgres::{PgArguments, PgConnectOptions, PgPoolOptions, PgRow},
+    query::QueryAs,
+    query::{Map, Query},
+};
+use std::{
+    collections::{HashMap, HashSet},
+    future::Future,
+    path::Path,
+    pin::pin,
+    sync::{Arc, LazyLock, Mutex, PoisonError},
+    time::{Duration, Instant},
+};
+use tokio::{sync::OnceCell, time::MissedTickBehavior};
 
 pub type SharedConnectionPool = Arc<ConnectionPool>;
 
-static SHARED_CONNECTION_POOL: OnceCell<SharedConnectionPool> = OnceCell::const_new();
+/// 接続 URL で指定しなかった場合に SQLx が使うステートメントキャッシュの容量です。
+const DEFAULT_STATEMENT_CACHE_CAPACITY: usize = 100;
+/// 接続 URL でステートメントキャッシュの容量を指定するパラメータ名です。
+const URL_STATEMENT_CACHE_CAPACITY: &str = "statement-cache-capacity";
+
+/// マイグレーションがこの時間を超えても終わらない場合に、ロック待ちの可能性を警告します。
+const MIGRATION_LOCK_NOTICE: Duration = Duration::from_secs(5);
+
+/// 共有接続プールを保持するセルです。
+///
+/// テスト用のリセット API がセルごと差し替えられるよう、セルを `Arc` で包んで保持します。
+/// 初期化そのものは `OnceCell` が担うため、同時に初回呼び出しが行われてもプールは 1 つだけ作成されます。
+static SHARED_CONNECTION_POOL: LazyLock<Mutex<Arc<OnceCell<SharedConnectionPool>>>> =
+    LazyLock::new(Default::default);
+
+/// 名前付き共有接続プールのセルです。キーは大文字に正規化したプール名です。
+///
+/// マップのロックはセルの取得・登録の間だけ保持し、プールの作成は各セルの `OnceCell` が担います。
+/// そのため、同じ名前を同時に初期化してもプールは 1 つだけ作成され、別の名前の初期化は互いを待ちません。
+static NAMED_CONNECTION_POOLS: LazyLock<
+    Mutex<HashMap<String, Arc<OnceCell<SharedConnectionPool>>>>,
+> = LazyLock::new(Default::default);
+
+/// プールが作成したサーバー側バックエンドを識別する情報です。
+///
+/// PID は再利用されるため、開始時刻と組み合わせて別プロセスを誤って終了しないようにします。
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct BackendId {
+    pid: i32,
+    started_at: DateTime<Utc>,
+}
 
 #[derive(Clone)]
 pub struct ConnectionPool {
     pool: PgPool,
+    batch: Option<PgPool>,
+    replica: Option<PgPool>,
+    backends: Arc<Mutex<HashSet<BackendId>>>,
+    slow_query_threshold: Option<Duration>,
+    pgbouncer_mode: bool,
+    statement_cache_capacity: usize,
+    hold_warn_threshold: Option<Duration>,
+    acquire_retry: AcquireRetry,
 }
 
 impl ConnectionPool {
@@ -22,7 +86,8 @@ impl ConnectionPool {
     ///
     /// プールはプロセス内で一度だけ作成され、以後はすべての呼び出し元で再利用されます。
     pub async fn shared() -> Result<SharedConnectionPool> {
-        let connection_pool = SHARED_CONNECTION_POOL
+        let cell = shared_cell();
+        let connection_pool = cell
             .get_or_try_init(|| async {
                 let connection_pool = Self::new().await?;
                 Ok::<SharedConnectionPool, anyhow::Error>(Arc::new(connection_pool))
@@ -32,52 +97,1302 @@ impl ConnectionPool {
         Ok(Arc::clone(connection_pool))
     }
 
-    /// 環境変数の設定から PostgreSQL 接続プールを新規作成します。
+    /// 明示的な設定で共有接続プールを初期化し、そのインスタンスを返します。
+    ///
+    /// 環境変数ではなく `config` からプールを作成する点を除き、`shared()` と同じ共有インスタンスを
+    /// 初期化します。共有プールが既に初期化されている場合は、設定の食い違いを見逃さないよう
+    /// 既存のプールを返さずにエラーを返します。起動時に `shared()` より先に呼び出してください。
+    pub async fn shared_with(config: PoolConfig) -> Result<SharedConnectionPool> {
+        let mut initialized_here = false;
+        let cell = shared_cell();
+        let connection_pool = cell
+            .get_or_try_init(|| async {
+                let connection_pool = Self::connect(&config).await?;
+                initialized_here = true;
+                Ok::<SharedConnectionPool, anyhow::Error>(Arc::new(connection_pool))
+            })
+            .await?;
+        ensure!(
+            initialized_here,
+            "Shared connection pool is already initialized"
+        );
+
+        Ok(Arc::clone(connection_pool))
+    }
+
+    /// 名前付きの共有接続プールを返します。初回呼び出し時にプールを作成します。
     ///
-    /// 必須の環境変数:
-    /// - `DATABASE_URL`
+    /// 接続先は `DATABASE_URL_<NAME>`、調整値は `CONNECTION_POOL_<NAME>` など
+    /// 既定の変数名に `_<NAME>` を付けた環境変数から読み込みます（`PoolEnvConfig::for_name`）。
+    /// 名前の大文字・小文字は区別しません。`shared()` が返す既定のプールとは別のインスタンスです。
+    pub async fn shared_named(name: &str) -> Result<SharedConnectionPool> {
+        let env_config = PoolEnvConfig::for_name(name)?;
+        let cell = named_cell(&name.to_ascii_uppercase());
+        let connection_pool = cell
+            .get_or_try_init(|| async {
+                let connection_pool = Self::from_env_config(&env_config)
+                    .await
+                    .with_context(|| format!("Failed to create connection pool {name:?}"))?;
+                Ok::<SharedConnectionPool, anyhow::Error>(Arc::new(connection_pool))
+            })
+            .await?;
+
+        Ok(Arc::clone(connection_pool))
+    }
+
+    /// 共有接続プールを `connection_pool` に差し替えます。
+    ///
+    /// テストごとに別のプールを使うためのものです。差し替え前に `shared()` で取得済みの
+    /// インスタンスは、呼び出し元が保持している間そのまま使えます。
+    #[cfg(any(test, feature = "test-util"))]
+    pub fn set_shared_for_tests(connection_pool: SharedConnectionPool) {
+        *SHARED_CONNECTION_POOL
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) =
+            Arc::new(OnceCell::new_with(Some(connection_pool)));
+    }
+
+    /// 共有接続プールと名前付き共有接続プールをすべて未初期化の状態に戻します。
     ///
-    /// 任意の環境変数:
-    /// - `CONNECTION_POOL`（未設定時は `DEFAULT_MAX_CONNECTIONS`）
+    /// 次の `shared()`・`shared_with()`・`shared_named()` の呼び出しで新しいプールが作成されます。
+    #[cfg(any(test, feature = "test-util"))]
+    pub fn reset_shared() {
+        *SHARED_CONNECTION_POOL
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) = Arc::default();
+        NAMED_CONNECTION_POOLS
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clear();
+    }
+
+    /// 環境変数の設定から PostgreSQL 接続プールを新規作成します。
+    ///
+    /// 参照する環境変数は `PoolConfig::from_env` を参照してください。
     async fn new() -> Result<Self> {
-        dotenv().ok();
-        let database_url = std::env::var(ENV_DATABASE_URL)
-            .with_context(|| format!("{ENV_DATABASE_URL} must be set"))?;
-        let max_connections = read_u32_env(ENV_CONNECTION_POOL, DEFAULT_MAX_CONNECTIONS)?;
+        Self::connect(&PoolConfig::from_env()?).await
+    }
+
+    /// 指定した環境変数名の設定から PostgreSQL 接続プールを新規作成します。
+    ///
+    /// `config.load_dotenv` が `false` の場合は `.env` を読み込まず、プロセスの環境変数のみを参照します。
+    pub async fn from_env_config(config: &PoolEnvConfig) -> Result<Self> {
+        Self::connect(&PoolConfig::from_env_config(config)?).await
+    }
+
+    /// `config` の接続先と調整値で PostgreSQL 接続プールを新規作成します。
+    ///
+    /// `config` にレプリカの接続 URL がある場合は、読み取り用のプールも同じ調整値で作成します。
+    /// 新しい接続ごとに接続先バックエンドを記録します。記録は接続のたびに `track_backend` で整理するため、
+    /// プールが閉じた接続の分だけ増え続けることはありません。
+    /// 接続 URL が PostgreSQL 以外の場合はエラーを返します。その場合は `GenericConnectionPool` を使ってください。
+    pub async fn connect(config: &PoolConfig) -> Result<Self> {
+        let backend = Backend::from_url(config.database_url())?;
         ensure!(
-            max_connections > 0,
-            "{ENV_CONNECTION_POOL} must be greater than 0"
+            backend == Backend::Postgres,
+            "ConnectionPool only supports PostgreSQL; use GenericConnectionPool for {backend}"
         );
-
-        let pool = PgPoolOptions::new()
-            .min_connections(1)
-            .max_connections(max_connections)
-            .acquire_timeout(Duration::from_secs(5))
-            .idle_timeout(Some(Duration::from_secs(300)))
-            .max_lifetime(Some(Duration::from_secs(1800)))
-            .test_before_acquire(true)
-            .connect(&database_url)
+        let backends = Arc::new(Mutex::new(HashSet::new()));
+        // PgBouncer 経由ではバックエンドの PID が接続ごとに対応しないため、強制終了用に記録しません。
+        let tracked_backends = (!config.pgbouncer_mode()).then(|| Arc::clone(&backends));
+        let mut primary_options = pool_options(config, tracked_backends.clone());
+        if let Some(split) = config.workload_split() {
+            primary_options = primary_options.max_connections(split.interactive);
+        }
+        let pool = primary_options
+            .connect_with(connect_options(config, config.database_url())?)
             .await
+            .map_err(DbError::from)
             .context("Failed to create database connection pool")?;
 
-        Ok(Self { pool })
+        let batch = match config.workload_split() {
+            Some(split) => Some(
+                pool_options(config, tracked_backends)
+                    .min_connections(0)
+                    .max_connections(split.batch)
+                    .connect_with(connect_options(config, config.database_url())?)
+                    .await
+                    .map_err(DbError::from)
+                    .context("Failed to create batch connection pool")?,
+            ),
+            None => None,
+        };
+
+        let replica = match config.replica_database_url() {
+            Some(replica_url) => Some(
+                pool_options(config, None)
+                    .connect_with(connect_options(config, replica_url)?)
+                    .await
+                    .map_err(DbError::from)
+                    .context("Failed to create replica connection pool")?,
+            ),
+            None => None,
+        };
+
+        Ok(Self {
+            pool,
+            batch,
+            replica,
+            backends,
+            slow_query_threshold: config.slow_query_threshold(),
+            pgbouncer_mode: config.pgbouncer_mode(),
+            statement_cache_capacity: statement_cache_capacity(config, config.database_url()),
+            hold_warn_threshold: config.hold_warn_threshold(),
+            acquire_retry: config.acquire_retry(),
+        })
     }
 
-    /// database モジュール内で利用する SQLx の PostgreSQL プール参照を返します。
-    pub(super) fn get(&self) -> &PgPool {
-        &self.pool
+    /// 接続プールを閉じ、使用中の接続が返却されるまで最大 `drain_timeout` 待機します。
+    ///
+    /// 呼び出した時点で新しい接続の取得は失敗するようになり、アイドル接続は直ちに閉じられます。
+    /// 期限までに返却されなかった接続は、別接続から `pg_terminate_backend` を発行して
+    /// サーバー側で強制終了し、その数を返します。期限内にすべて返却された場合は `0` を返します。
+    /// 強制終了されたトランザクションはサーバー側でロールバックされます。
+    ///
+    /// バッチ処理用とレプリカ用のプールも同じ期限で閉じますが、強制終了の対象はプライマリ
+    /// （バッチ処理用を含む）の接続のみです。
+    pub async fn close(&self, drain_timeout: Duration) -> Result<usize> {
+        let close_all = futures_util::future::join_all(
+            std::iter::once(&self.pool)
+                .chain(&self.batch)
+                .chain(&self.replica)
+                .map(PgPool::close),
+        );
+        if tokio::time::timeout(drain_timeout, close_all).await.is_ok() {
+            return Ok(0);
+        }
+
+        let backends: Vec<BackendId> = self
+            .backends
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .iter()
+            .copied()
+            .collect();
+        let (pids, started_ats): (Vec<i32>, Vec<DateTime<Utc>>) = backends
+            .iter()
+            .map(|backend| (backend.pid, backend.started_at))
+            .unzip();
+
+        let mut connection = PgConnection::connect_with(&self.pool.connect_options())
+            .await
+            .map_err(DbError::from)
+            .context("Failed to connect for force-closing pooled connections")?;
+        let terminated: i64 = sqlx::query_scalar(
+            "SELECT count(*) FILTER (WHERE pg_terminate_backend(activity.pid)) \
+             FROM pg_stat_activity AS activity \
+             JOIN UNNEST($1::int4[], $2::timestamptz[]) AS tracked(pid, backend_start) \
+             ON activity.pid = tracked.pid AND activity.backend_start = tracked.backend_start",
+        )
+        .bind(pids)
+        .bind(started_ats)
+        .fetch_one(&mut connection)
+        .await
+        .map_err(DbError::from)
+        .context("Failed to force-close pooled connections")?;
+        connection
+            .close()
+            .await
+            .map_err(DbError::from)
+            .context("Failed to close force-close connection")?;
+
+        Ok(usize::try_from(terminated).unwrap_or(0))
+    }
+
+    /// プライマリに `SELECT 1` を発行して疎通を確認し、応答時間とサーバー情報を返します。
+    ///
+    /// 接続の取得から `SHOW server_version` の応答までが `timeout` を超えた場合は、
+    /// 原因に `DbError::Timeout` を含むエラーを返します。接続の失敗は `DbError::PoolTimeout` や
+    /// `DbError::Io` など SQLx のエラーを分類した原因で返るため、タイムアウトと区別できます。
+    /// 応答時間が `degraded_latency` を超えた場合は `HealthStatus::Degraded` と判定します。
+    pub async fn health_check(
+        &self,
+        timeout: Duration,
+        degraded_latency: Duration,
+    ) -> Result<HealthReport> {
+        let probe = async {
+            let started_at = Instant::now();
+            let mut connection = self
+                .pool
+                .acquire()
+                .await
+                .map_err(DbError::from)
+                .context("Failed to acquire connection for health check")?;
+            sqlx::query("SELECT 1")
+                .unprepared_if(self.pgbouncer_mode)
+                .execute(&mut *connection)
+                .await
+                .map_err(DbError::from)
+                .context("Failed to ping database")?;
+            let latency = started_at.elapsed();
+            let server_version: String = sqlx::query_scalar("SHOW server_version")
+                .unprepared_if(self.pgbouncer_mode)
+                .fetch_one(&mut *connection)
+                .await
+                .map_err(DbError::from)
+                .context("Failed to read server version")?;
+            Ok::<_, anyhow::Error>((latency, server_version))
+        };
+        let (latency, server_version) = match tokio::time::timeout(timeout, probe).await {
+            Ok(result) => result?,
+            Err(_) => {
+                return Err(DbError::Timeout { timeout }).context("Health check timed out");
+            }
+        };
+
+        let status = if latency > degraded_latency {
+            HealthStatus::Degraded { latency }
+        } else {
+            HealthStatus::Healthy
+        };
+        Ok(HealthReport {
+            status,
+            latency,
+            server_version,
+            is_closed: self.pool.is_closed(),
+            pool: self.stats(),
+        })
+    }
+
+    /// `config.interval` ごとに `health_check` を実行し、判定の変化を通知するヘルスモニタを起動します。
+    ///
+    /// 一時的な失敗で判定が揺れないよう、`config.failure_threshold` 回連続して失敗した時点で
+    /// `HealthStatus::Unhealthy` とし、`config.recovery_threshold` 回連続して成功した時点で復帰します。
+    /// チェックの失敗はログに出力するだけで、タスクは終了しません。
+    ///
+    /// 返されたハンドルを `stop` するか破棄するとタスクは停止します。タスクはプールへの弱参照のみを
+    /// 保持するため、プールの破棄を妨げず、プールが破棄または `close` された時点でも停止します。
+    ///
+    /// ```ignore
+    /// let monitor = connection_pool.spawn_health_monitor(HealthMonitorConfig::new())?;
+    /// let mut status = monitor.subscribe();
+    /// while status.changed().await.is_ok() {
+    ///     if !status.borrow().is_available() {
+    ///         // 読み取り専用モードに切り替えるなど
+    ///     }
+    /// }
+    /// ```
+    pub fn spawn_health_monitor(
+        self: &Arc<Self>,
+        config: HealthMonitorConfig,
+    ) -> Result<HealthMonitorHandle> {
+        config.validate()?;
+
+        let connection_pool = Arc::downgrade(self);
+        Ok(health::spawn_monitor(config, move || {
+            let connection_pool = connection_pool.upgrade();
+            async move {
+                let connection_pool = connection_pool.filter(|pool| !pool.pool.is_closed())?;
+                let report = connection_pool
+                    .health_check(config.timeout(), config.degraded_latency())
+                    .await;
+                Some(report.map(|report| report.status))
+            }
+        }))
     }
+
+    /// `close` が呼び出され、接続プールが閉じられているかどうかを返します。
+    ///
+    /// 閉じられたプールからはトランザクションを開始できず、`QueryExecutor` や `TransactionExecutor` は
+    /// 原因に `DbError::PoolClosed` を含むエラーを直ちに返します。開始済みのトランザクションは
+    /// 接続を返却するまでそのまま使え、コミットもできます。
+    pub fn is_closed(&self) -> bool {
+        self.pool.is_closed()
+    }
+
+    /// プライマリの接続プールの使用状況を返します。
+    pub fn stats(&self) -> PoolStats {
+        PoolStats::from_pool(&self.pool)
+    }
+
+    /// `interval` ごとに `stats()` を取得して `sink` に渡すタスクを起動します。
+    ///
+    /// 返されたハンドルを `stop` するか破棄するとタスクは停止します。タスクはプールへの弱参照のみを
+    /// 保持するため、プールの破棄を妨げず、プールが破棄または `close` された時点でも停止します。
+    pub fn spawn_stats_reporter<F>(
+        self: &Arc<Self>,
+        interval: Duration,
+        mut sink: F,
+    ) -> Result<StatsReporterHandle>
+    where
+        F: FnMut(PoolStats) + Send + 'static,
+    {
+        ensure!(!interval.is_zero(), "interval must be greater than 0");
+
+        let connection_pool = Arc::downgrade(self);
+        let task = tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            ticker.set_missed_tick_behavior(MissedTickBehavior::Skip);
+            loop {
+                ticker.tick().await;
+                let Some(connection_pool) = connection_pool.upgrade() else {
+                    break;
+                };
+                if connection_pool.pool.is_closed() {
+                    break;
+                }
+                sink(connection_pool.stats());
+            }
+        });
+        Ok(StatsReporterHandle::new(task))
+    }
+
+    /// `migrator` の未適用のマイグレーションをプライマリに適用し、今回適用したものを返します。
+    ///
+    /// 複数のインスタンスが同時に呼び出しても、SQLx がアドバイザリロックで直列化するため安全です。
+    /// ロックの待機を含めて `MIGRATION_LOCK_NOTICE` 以上かかった場合は、他のインスタンスがマイグレーション中である
+    /// 旨の警告ログを出力して待ち続けます。失敗した場合は原因に `MigrationError` を含むエラーを返します。
+    ///
+    /// PgBouncer 互換モードでは、SQLx のセッション単位のアドバイザリロックを保持できないためエラーを返します。
+    pub async fn run_migrations(&self, migrator: &Migrator) -> Result<MigrationReport> {
+        if self.pgbouncer_mode {
+            bail!(
+                "Migrations require a session-level advisory lock and cannot run in pgbouncer_mode; \
+                 run them over a direct database connection"
+            );
+        }
+        let before: HashSet<i64> = applied_migrations(&self.pool)
+            .await
+            .map_err(DbError::from)
+            .context("Failed to read applied migrations")?
+            .into_iter()
+            .map(|migration| migration.version)
+            .collect();
+
+        let mut run = pin!(migrator.run(&self.pool));
+        let result = tokio::select! {
+            result = &mut run => result,
+            () = tokio::time::sleep(MIGRATION_LOCK_NOTICE) => {
+                tracing::warn!(
+                    waited = ?MIGRATION_LOCK_NOTICE,
+                    "Migrations are still running; another instance may be holding the migration lock"
+                );
+                run.await
+            }
+        };
+        result.map_err(MigrationError::from).context(
+            "Failed to run migrations (another instance may have been migrating concurrently)",
+        )?;
+
+        let applied = applied_migrations(&self.pool)
+            .await
+            .map_err(DbError::from)
+            .context("Failed to read applied migrations")?
+            .into_iter()
+            .filter(|migration| !before.contains(&migration.version))
+            .collect();
+        Ok(MigrationReport { applied })
+    }
+
+    /// `channels` を `LISTEN` で購読し、受信した通知のストリームを返します。
+    ///
+    /// 購読にはプライマリの接続を 1 本使い続けます。接続が切れた場合は自動で再接続します。
+    /// `LISTEN` はセッション単位の状態のため、PgBouncer 互換モードではエラーを返します。
+    pub async fn listen(&self, channels: &[&str]) -> Result<NotificationStream> {
+        if self.pgbouncer_mode {
+            bail!(
+                "LISTEN is session-level and cannot be used in pgbouncer_mode; \
+                 listen over a direct database connection"
+            );
+        }
+        listener::listen(&self.pool, channels).await
+    }
+
+    /// `dir` からマイグレーションを読み込み、`run_migrations` と同じく適用します。
+    pub async fn run_migrations_from(&self, dir: impl AsRef<Path>) -> Result<MigrationReport> {
+        let dir = dir.as_ref();
+        let migrator = Migrator::new(dir)
+            .await
+            .map_err(MigrationError::from)
+            .with_context(|| format!("Failed to load migrations from {}", dir.display()))?;
+        self.run_migrations(&migrator).await
+    }
+
+    /// `workload` の処理が使うプライマリのプール参照を返します。
+    ///
+    /// 接続数を分割していない場合は、処理の種類にかかわらず同じプールを返します。
+    pub(super) fn for_workload(&self, workload: Workload) -> &PgPool {
+        match (workload, &self.batch) {
+            (Workload::Batch, Some(batch)) => batch,
+            _ => &self.pool,
+        }
+    }
+
+    /// 読み取り用レプリカのプール参照を返します。レプリカが設定されていない場合は `None` です。
+    pub(super) fn replica(&self) -> Option<&PgPool> {
+        self.replica.as_ref()
+    }
+
+    /// プールの設定で指定された、遅いステートメントとして警告するまでの時間を返します。
+    pub(super) fn slow_query_threshold(&self) -> Option<Duration> {
+        self.slow_query_threshold
+    }
+
+    /// プライマリの接続に設定したステートメントキャッシュの容量を返します。
+    ///
+    /// `PoolConfig` の指定、接続 URL の `statement-cache-capacity`、SQLx の既定値の順に決まり、
+    /// PgBouncer 互換モードでは常に 0 です。
+    pub fn statement_cache_capacity(&self) -> usize {
+        self.statement_cache_capacity
+    }
+
+    /// プールの設定で PgBouncer 互換モードが有効かどうかを返します。
+    pub(super) fn pgbouncer_mode(&self) -> bool {
+        self.pgbouncer_mode
+    }
+
+    /// プールの設定で指定された、開いたままのトランザクションを警告するまでの時間を返します。
+    pub(super) fn hold_warn_threshold(&self) -> Option<Duration> {
+        self.hold_warn_threshold
+    }
+
+    /// プールの設定で指定された、接続の取得がタイムアウトした場合の再試行の方針を返します。
+    pub(super) fn acquire_retry(&self) -> AcquireRetry {
+        self.acquire_retry
+    }
+
+    /// クエリを実行し、最大 1 行を `FromRow` 実装型に変換して返します。
+    ///
+    /// クエリ結果が空の場合は `Ok(None)` を返します。
+    pub async fn fetch_optional_as<'a, T>(
+        &self,
+        query: Query<'a, Postgres, PgArguments>,
+    ) -> Result<Option<T>>
+    where
+        T: for<'r> FromRow<'r, PgRow> + Send + Unpin,
+    {
+        let row = traced_query(
+            query
+                .unprepared_if(self.pgbouncer_mode)
+                .try_map(|row: PgRow| T::from_row(&row))
+                .fetch_optional(&self.pool),
+        )
+        .await
+        .map_err(DbError::from)
+        .context("Failed to fetch optional row")?;
+        Ok(row)
+    }
+
+    /// マッピング済みクエリを実行し、ちょうど 1 行であることを確認して返します。
+    ///
+    /// 結果が空の場合に `Ok(None)` を返し、2 行目以降を読み捨てる `fetch_optional_as` とは異なり、
+    /// 0 行なら `DbError::NotFound`、2 行以上なら `DbError::TooManyRows` をエラーの原因に含めて返します。
+    /// 行数を数えるために全行を受信するため、主キー検索のように結果が高々数行のクエリに使用してください。
+    pub async fn fetch_exactly_one<'a, U, F>(
+        &self,
+        query: Map<'a, Postgres, F, PgArguments>,
+    ) -> Result<U>
+    where
+        U: Send + Unpin,
+        F: FnMut(PgRow) -> std::result::Result<U, sqlx::Error> + Send + 'static,
+    {
+        ensure_map_supported(self.pgbouncer_mode)?;
+        let rows = traced_query(query.fetch_all(&self.pool))
+            .await
+            .map_err(DbError::from)
+            .context("Failed to fetch rows")?;
+        exactly_one(rows)
+    }
+
+    /// クエリを実行し、ちょうど 1 行であることを確認して `FromRow` 実装型に変換して返します。
+    ///
+    /// 行数の扱いは `fetch_exactly_one` と同じです。`Map` を受け付けない PgBouncer 互換モードでも使えます。
+    pub async fn fetch_exactly_one_as<'a, T>(
+        &self,
+        query: Query<'a, Postgres, PgArguments>,
+    ) -> Result<T>
+    where
+        T: for<'r> FromRow<'r, PgRow> + Send + Unpin,
+    {
+        let rows = traced_query(
+            query
+                .unprepared_if(self.pgbouncer_mode)
+                .try_map(|row: PgRow| T::from_row(&row))
+                .fetch_all(&self.pool),
+        )
+        .await
+        .map_err(DbError::from)
+        .context("Failed to fetch rows")?;
+        exactly_one(rows)
+    }
+
+    /// マッピング済みクエリを実行し、行を 1 行ずつ返すストリームを返します。
+    ///
+    /// `fetch_all` と異なり結果をベクタにまとめないため、大量の行を一定のメモリで順に処理できます。
+    /// ストリームは取得元の接続を保持し続けるため、処理が終わるか不要になった時点で破棄してください。
+    /// 途中で発生したエラーはストリームの要素として返り、その時点でストリームは終了します。
+    pub fn fetch_stream<'a, U, F>(
+        &'a self,
+        query: Map<'a, Postgres, F, PgArguments>,
+    ) -> impl Stream<Item = Result<U>> + Send + 'a
+    where
+        U: Send + Unpin + 'a,
+        F: FnMut(PgRow) -> std::result::Result<U, sqlx::Error> + Send + 'static,
+    {
+        if let Err(error) = ensure_map_supported(self.pgbouncer_mode) {
+            return stream::once(future::ready(Err(error))).left_stream();
+        }
+        query
+            .fetch(&self.pool)
+            .map(|row| {
+                row.map_err(DbError::from)
+                    .context("Failed to fetch row from stream")
+            })
+            .right_stream()
+    }
+
+    /// クエリを実行し、行を 1 行ずつ `FromRow` 実装型に変換して返すストリームを返します。
+    ///
+    /// ストリームの扱いは `fetch_stream` と同じです。`Map` を受け付けない PgBouncer 互換モードでも使えます。
+    pub fn fetch_stream_as<'a, T>(
+        &'a self,
+        query: Query<'a, Postgres, PgArguments>,
+    ) -> impl Stream<Item = Result<T>> + Send + 'a
+    where
+        T: for<'r> FromRow<'r, PgRow> + Send + Unpin + 'a,
+    {
+        query
+            .unprepared_if(self.pgbouncer_mode)
+            .try_map(|row: PgRow| T::from_row(&row))
+            .fetch(&self.pool)
+            .map(|row| {
+                row.map_err(DbError::from)
+                    .context("Failed to fetch row from stream")
+            })
+    }
+
+    /// クエリを実行し、全行を `FromRow` 実装型に変換したベクタとして返します。
+    pub async fn fetch_all_as<'a, T>(
+        &self,
+        query: Query<'a, Postgres, PgArguments>,
+    ) -> Result<Vec<T>>
+    where
+        T: for<'r> FromRow<'r, PgRow> + Send + Unpin,
+    {
+        let rows = traced_query(
+            query
+                .unprepared_if(self.pgbouncer_mode)
+                .try_map(|row: PgRow| T::from_row(&row))
+                .fetch_all(&self.pool),
+        )
+        .await
+        .map_err(DbError::from)
+        .context("Failed to fetch rows")?;
+        Ok(rows)
+    }
+
+    /// `sqlx::query_as` で作成したクエリを実行し、最大 1 行を返します。
+    ///
+    /// `fetch_optional_as` と同じく結果が空の場合は `Ok(None)` を返します。
+    /// `bind` を連結した `query_as` をそのまま渡せるため、列数の多い構造体を
+    /// `#[derive(FromRow)]` で受け取る場合に使います。NULL を許容する列は `Option` で受け取ってください。
+    pub async fn fetch_one_query_as<'a, T>(
+        &self,
+        query: QueryAs<'a, Postgres, T, PgArguments>,
+    ) -> Result<Option<T>>
+    where
+        T: for<'r> FromRow<'r, PgRow> + Send + Unpin,
+    {
+        let row = traced_query(
+            query
+                .unprepared_if(self.pgbouncer_mode)
+                .fetch_optional(&self.pool),
+        )
+        .await
+        .map_err(DbError::from)
+        .context("Failed to fetch optional row")?;
+        Ok(row)
+    }
+
+    /// マッピング済みクエリを実行し、最大 `max_rows` 行を全行のベクタとして返します。
+    ///
+    /// `max_rows` を超える行を受信した時点で読み取りをやめ、原因に `DbError::RowLimitExceeded` を含む
+    /// エラーを返します。詳細は `QueryExecutor::fetch_all_limited` を参照してください。
+    pub async fn fetch_all_limited<'a, U, F>(
+        &self,
+        query: Map<'a, Postgres, F, PgArguments>,
+        max_rows: usize,
+    ) -> Result<Vec<U>>
+    where
+        U: Send + Unpin,
+        F: FnMut(PgRow) -> std::result::Result<U, sqlx::Error> + Send + 'static,
+    {
+        ensure_map_supported(self.pgbouncer_mode)?;
+        let rows = traced_query(collect_limited(query.fetch(&self.pool), Some(max_rows)))
+            .await
+            .map_err(DbError::from)
+            .context("Failed to fetch rows")?
+            .ok_or(DbError::RowLimitExceeded { limit: max_rows })
+            .context("Failed to fetch rows")?;
+        Ok(rows)
+    }
+
+    /// マッピング済みクエリを `timeout` 以内に実行し、最大 1 行を返します。クエリ結果が空の場合は `Ok(None)` を返します。
+    ///
+    /// `acquire_timeout` は接続の取得待ちにのみ適用されるため、ネットワークの分断やインデックスのない
+    /// 全件走査で応答が返らない場合に備えて、クエリの実行と受信に期限を設けます。期限を過ぎた場合は
+    /// 原因に `DbError::Timeout` を含むエラーを返し、使っていた接続はプールに戻さずに閉じます。
+    /// 詳細は `QueryExecutor::with_read_timeout` を参照してください。
+    pub async fn fetch_one_timeout<'a, U, F>(
+        &self,
+        query: Map<'a, Postgres, F, PgArguments>,
+        timeout: Duration,
+    ) -> Result<Option<U>>
+    where
+        U: Send + Unpin,
+        F: FnMut(PgRow) -> std::result::Result<U, sqlx::Error> + Send + 'static,
+    {
+        ensure_map_supported(self.pgbouncer_mode)?;
+        let mut connection = acquire_connection(&self.pool, &self.acquire_retry).await?;
+        let row = within_read_timeout(
+            Some(timeout),
+            traced_query(query.fetch_optional(&mut *connection)).map_err(DbError::from),
+        )
+        .await
+        .inspect_err(|error| discard_if_timed_out(&mut connection, error))
+        .context("Failed to fetch optional row")?;
+        Ok(row)
+    }
+
+    /// マッピング済みクエリを `timeout` 以内に実行し、全行をベクタとして返します。
+    ///
+    /// 期限は全行の受信までに適用されます。期限を過ぎた場合の扱いは `fetch_one_timeout` と同じです。
+    pub async fn fetch_all_timeout<'a, U, F>(
+        &self,
+        query: Map<'a, Postgres, F, PgArguments>,
+        timeout: Duration,
+    ) -> Result<Vec<U>>
+    where
+        U: Send + Unpin,
+        F: FnMut(PgRow) -> std::result::Result<U, sqlx::Error> + Send + 'static,
+    {
+        ensure_map_supported(self.pgbouncer_mode)?;
+        let mut connection = acquire_connection(&self.pool, &self.acquire_retry).await?;
+        let rows = within_read_timeout(
+            Some(timeout),
+            traced_query(query.fetch_all(&mut *connection)).map_err(DbError::from),
+        )
+        .await
+        .inspect_err(|error| discard_if_timed_out(&mut connection, error))
+        .context("Failed to fetch rows")?;
+        Ok(rows)
+    }
+
+    /// `sqlx::query_as` で作成したクエリを実行し、全行をベクタとして返します。
+    pub async fn fetch_all_query_as<'a, T>(
+        &self,
+        query: QueryAs<'a, Postgres, T, PgArguments>,
+    ) -> Result<Vec<T>>
+    where
+        T: for<'r> FromRow<'r, PgRow> + Send + Unpin,
+    {
+        let rows = traced_query(
+            query
+                .unprepared_if(self.pgbouncer_mode)
+                .fetch_all(&self.pool),
+        )
+        .await
+        .map_err(DbError::from)
+        .context("Failed to fetch rows")?;
+        Ok(rows)
+    }
+}
+
+/// `rows` を順に受信し、`max_rows` 行を超える行を受信した時点で受信をやめて `Ok(None)` を返します。
+///
+/// `max_rows` が `None` の場合はすべての行を受信します。受信をやめた接続に残る未受信の結果は、
+/// SQLx がプールへの返却時に読み捨てます。
+pub(super) async fn collect_limited<U>(
+    rows: impl Stream<Item = sqlx::Result<U>>,
+    max_rows: Option<usize>,
+) -> sqlx::Result<Option<Vec<U>>> {
+    let mut rows = pin!(rows);
+    let mut collected = Vec::new();
+    while let Some(row) = rows.next().await {
+        let row = row?;
+        if max_rows.is_some_and(|max_rows| collected.len() >= max_rows) {
+            return Ok(None);
+        }
+        collected.push(row);
+    }
+    Ok(Some(collected))
+}
+
+/// `read` を `read_timeout` 以内に完了させます。`read_timeout` が `None` の場合は完了まで待ちます。
+///
+/// 期限を過ぎた場合は `read` を破棄し、`DbError::Timeout` を返します。
+pub(super) async fn within_read_timeout<T>(
+    read_timeout: Option<Duration>,
+    read: impl Future<Output = std::result::Result<T, DbError>>,
+) -> std::result::Result<T, DbError> {
+    match read_timeout {
+        Some(timeout) => tokio::time::timeout(timeout, read)
+            .await
+            .map_err(|_| DbError::Timeout { timeout })?,
+        None => read.await,
+    }
+}
+
+/// 読み取りが期限切れで中断された場合、`connection` をプールに戻さずに閉じるよう設定します。
+///
+/// クエリの途中で中断した接続には受信していない結果が残っている可能性があるため、再利用しません。
+pub(super) fn discard_if_timed_out(connection: &mut PoolConnection<Postgres>, error: &DbError) {
+    if matches!(error, DbError::Timeout { .. }) {
+        connection.close_on_drop();
+    }
+}
+
+/// 行がちょうど 1 行であればそれを返し、そうでなければ件数に応じた `DbError` を返します。
+pub(super) fn exactly_one<U>(rows: Vec<U>) -> Result<U> {
+    let count = rows.len();
+    let mut rows = rows.into_iter();
+    match (rows.next(), count) {
+        (Some(row), 1) => Ok(row),
+        (None, _) => Err(DbError::NotFound).context("Expected exactly one row"),
+        _ => Err(DbError::TooManyRows { count }).context("Expected exactly one row"),
+    }
+}
+
+/// `config` の調整値を反映したプールのオプションを返します。
+///
+/// 新しい接続ごとにセッション設定を適用し、`tracked_backends` がある場合は接続先バックエンドを記録します。
+fn pool_options(
+    config: &PoolConfig,
+    tracked_backends: Option<Arc<Mutex<HashSet<BackendId>>>>,
+) -> PgPoolOptions {
+    let session_setup = Arc::new(config.session_setup().clone());
+    PgPoolOptions::new()
+        .after_connect(move |connection, _metadata| {
+            let session_setup = Arc::clone(&session_setup);
+            let tracked_backends = tracked_backends.clone();
+            Box::pin(async move {
+                session_setup
+                    .apply(connection)
+                    .await
+                    .map_err(|error| sqlx::Error::Configuration(error.into()))?;
+                if let Some(tracked_backends) = tracked_backends {
+                    track_backend(connection, &tracked_backends).await?;
+                }
+                Ok(())
+            })
+        })
+        .min_connections(config.min_connections())
+        .max_connections(config.max_connections())
+        .acquire_timeout(config.acquire_timeout())
+        .idle_timeout(config.idle_timeout())
+        .max_lifetime(config.max_lifetime())
+        .test_before_acquire(config.test_before_acquire())
+}
+
+/// 接続 URL から接続設定を作成し、`statement_cache_capacity` で決まる容量と TLS 設定を上書きします。
+fn connect_options(config: &PoolConfig, url: &str) -> Result<PgConnectOptions> {
+    let options: PgConnectOptions = url
+        .parse()
+        .map_err(DbError::from)
+        .context("Invalid database URL")?;
+    config
+        .tls()
+        .apply(options.statement_cache_capacity(statement_cache_capacity(config, url)))
+}
+
+/// 接続に設定するステートメントキャッシュの容量を返します。
+///
+/// PgBouncer 互換モードでは 0、それ以外は `PoolConfig` の指定、接続 URL のパラメータ、
+/// SQLx の既定値の順に優先します。接続 URL の値が数値でない場合は既定値を返しますが、
+/// その URL は `connect_options` の解析でエラーになります。
+fn statement_cache_capacity(config: &PoolConfig, url: &str) -> usize {
+    if config.pgbouncer_mode() {
+        return 0;
+    }
+    config.statement_cache_capacity().unwrap_or_else(|| {
+        url.split_once('?')
+            .into_iter()
+            .flat_map(|(_, query)| query.split('&'))
+            .filter_map(|pair| pair.split_once('='))
+            .find(|(key, _)| *key == URL_STATEMENT_CACHE_CAPACITY)
+            .and_then(|(_, value)| value.parse().ok())
+            .unwrap_or(DEFAULT_STATEMENT_CACHE_CAPACITY)
+    })
+}
+
+/// 接続先バックエンドを `tracked_backends` に記録し、終了済みのバックエンドを取り除きます。
+///
+/// SQLx は接続を閉じたことを通知しないため、新しい接続を記録するたびに `pg_stat_activity` と突き合わせ、
+/// 記録済みのうち既に存在しないバックエンドを取り除きます。突き合わせの間に他の接続が記録したバックエンドは
+/// 取り除きません。
+async fn track_backend(
+    connection: &mut PgConnection,
+    tracked_backends: &Mutex<HashSet<BackendId>>,
+) -> sqlx::Result<()> {
+    let (tracked_pids, tracked_started_ats): (Vec<i32>, Vec<DateTime<Utc>>) = tracked_backends
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .iter()
+        .map(|backend| (backend.pid, backend.started_at))
+        .unzip();
+    let rows: Vec<(i32, DateTime<Utc>, bool)> = sqlx::query_as(
+        "SELECT activity.pid, activity.backend_start, activity.pid = pg_backend_pid() \
+         FROM pg_stat_activity AS activity \
+         LEFT JOIN UNNEST($1::int4[], $2::timestamptz[]) AS tracked(pid, backend_start) \
+         ON activity.pid = tracked.pid AND activity.backend_start = tracked.backend_start \
+         WHERE activity.pid = pg_backend_pid() OR tracked.pid IS NOT NULL",
+    )
+    .bind(&tracked_pids)
+    .bind(&tracked_started_ats)
+    .fetch_all(connection)
+    .await?;
+
+    let mut current = None;
+    let mut alive = HashSet::new();
+    for (pid, started_at, is_current) in rows {
+        let backend = BackendId { pid, started_at };
+        if is_current {
+            current = Some(backend);
+        }
+        alive.insert(backend);
+    }
+    let current = current.ok_or(sqlx::Error::RowNotFound)?;
+    let checked: HashSet<BackendId> = tracked_pids
+        .into_iter()
+        .zip(tracked_started_ats)
+        .map(|(pid, started_at)| BackendId { pid, started_at })
+        .collect();
+
+    let mut tracked_backends = tracked_backends
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner);
+    tracked_backends.retain(|backend| alive.contains(backend) || !checked.contains(backend));
+    tracked_backends.insert(current);
+    Ok(())
+}
+
+/// 現在の共有接続プールのセルを返します。
+fn shared_cell() -> Arc<OnceCell<SharedConnectionPool>> {
+    Arc::clone(
+        &SHARED_CONNECTION_POOL
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner),
+    )
 }
 
-/// 環境変数を `u32` として読み取ります。
+/// 名前付き共有接続プールのセルを返します。未登録の場合は空のセルを登録します。
+fn named_cell(key: &str) -> Arc<OnceCell<SharedConnectionPool>> {
+    let mut pools = NAMED_CONNECTION_POOLS
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner);
+    Arc::clone(pools.entry(key.to_string()).or_default())
+}
+
+/// 接続プールから接続を取得します。
 ///
-/// 変数が未設定の場合は `default_value` を返します。
-fn read_u32_env(key: &str, default_value: u32) -> Result<u32> {
-    match std::env::var(key) {
-        Ok(value) => value
-            .parse::<u32>()
-            .with_context(|| format!("{key} must be a valid u32")),
-        Err(std::env::VarError::NotPresent) => Ok(default_value),
-        Err(error) => Err(anyhow!("Failed to read {key}: {error}")),
+/// 取得待ちがタイムアウトした場合は `retry` に従って取得をやり直します。再試行を使い切った場合は、
+/// 原因を判別できるよう試行回数・合計の待機時間と、その時点の使用中接続数・最大接続数・
+/// アイドル接続数をエラーに含めます。タイムアウト以外のエラーは再試行しません。
+pub(super) async fn acquire_connection(
+    pool: &PgPool,
+    retry: &AcquireRetry,
+) -> Result<PoolConnection<Postgres>> {
+    let started_at = Instant::now();
+    let expires_at = retry
+        .deadline
+        .map(|deadline| tokio::time::Instant::now() + deadline);
+    let mut attempt = 1;
+    loop {
+        let acquire_started_at = Instant::now();
+        let acquired = match expires_at {
+            Some(expires_at) => tokio::time::timeout_at(expires_at, pool.acquire())
+                .await
+                .unwrap_or(Err(sqlx::Error::PoolTimedOut)),
+            None => pool.acquire().await,
+        };
+        metrics::record_acquire(pool, acquire_started_at.elapsed());
+        match acquired {
+            Ok(connection) => return Ok(connection),
+            Err(sqlx::Error::PoolTimedOut) => {}
+            Err(error) => return Err(DbError::from(error).into()),
+        }
+
+        let waited = started_at.elapsed();
+        match retry.delay(attempt, waited) {
+            Some(delay) => {
+                tracing::debug!(attempt, ?delay, "retrying connection acquisition");
+                tokio::time::sleep(delay).await;
+                attempt += 1;
+            }
+            _ => {
+                let PoolStats {
+                    size,
+                    idle,
+                    in_use,
+                    max_connections,
+                } = PoolStats::from_pool(pool);
+                let acquire_timeout = pool.options().get_acquire_timeout();
+                return Err(DbError::PoolTimeout).with_context(|| {
+                    format!(
+                        "Pool exhausted after {attempt} attempt(s) over {waited:?}: \
+                         {in_use}/{max_connections} in use \
+                         (size {size}, idle {idle}, acquire timeout {acquire_timeout:?})"
+                    )
+                });
+            }
+        }
+    }
+}
+
+/// 接続プールから接続を取得し、トランザクションを開始します。
+///
+/// 接続の取得は `acquire_connection` と同じく `retry` に従って再試行します。
+pub(super) async fn begin_transaction(
+    pool: &PgPool,
+    retry: &AcquireRetry,
+) -> Result<Transaction<'static, Postgres>> {
+    let connection = acquire_connection(pool, retry)
+        .await
+        .context("Failed to start database transaction")?;
+    Transaction::begin(connection, None)
+        .await
+        .map_err(DbError::from)
+        .context("Failed to start database transaction")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::{
+        pool_config::PoolConfigBuilder,
+        query_executor::QueryExecutor,
+        testing,
+        transaction_executor::TransactionExecutor,
+        transaction_options::{IsolationLevel, TransactionOptions},
+    };
+
+    async fn connect_small_pool(max_connections: u32) -> ConnectionPool {
+        let config = PoolConfig::builder()
+            .database_url(testing::database_url())
+            .max_connections(max_connections)
+            .min_connections(0)
+            .build()
+            .unwrap();
+        ConnectionPool::connect(&config).await.unwrap()
+    }
+
+    fn tracked_pids(connection_pool: &ConnectionPool) -> HashSet<i32> {
+        connection_pool
+            .backends
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|backend| backend.pid)
+            .collect()
+    }
+
+    #[tokio::test]
+    #[ignore = "requires a PostgreSQL database (TEST_DATABASE_URL)"]
+    async fn tracked_backends_drop_closed_connections() {
+        let connection_pool = connect_small_pool(1).await;
+
+        let mut connection = connection_pool.pool.acquire().await.unwrap();
+        let first_pid: i32 = sqlx::query_scalar("SELECT pg_backend_pid()")
+            .fetch_one(&mut *connection)
+            .await
+            .unwrap();
+        connection.close().await.unwrap();
+
+        let mut connection = connection_pool.pool.acquire().await.unwrap();
+        let second_pid: i32 = sqlx::query_scalar("SELECT pg_backend_pid()")
+            .fetch_one(&mut *connection)
+            .await
+            .unwrap();
+
+        assert_ne!(first_pid, second_pid);
+        assert_eq!(tracked_pids(&connection_pool), HashSet::from([second_pid]));
+    }
+
+    #[tokio::test]
+    #[ignore = "requires a PostgreSQL database (TEST_DATABASE_URL)"]
+    async fn close_terminates_transactions_held_past_the_drain_timeout() {
+        let connection_pool = connect_small_pool(2).await;
+        let mut tx = connection_pool.pool.begin().await.unwrap();
+        sqlx::query("SELECT 1").execute(&mut *tx).await.unwrap();
+
+        let terminated = connection_pool
+            .close(Duration::from_millis(200))
+            .await
+            .unwrap();
+
+        assert_eq!(terminated, 1);
+        assert!(sqlx::query("SELECT 1").execute(&mut *tx).await.is_err());
+    }
+
+    #[test]
+    fn exactly_one_returns_not_found_for_no_rows() {
+        let error = exactly_one(Vec::<i64>::new()).unwrap_err();
+
+        assert!(matches!(DbError::find(&error), Some(DbError::NotFound)));
+        assert_eq!(error.to_string(), "Expected exactly one row");
+    }
+
+    #[test]
+    fn exactly_one_returns_the_only_row() {
+        assert_eq!(exactly_one(vec![42]).unwrap(), 42);
+    }
+
+    #[test]
+    fn exactly_one_returns_too_many_rows_with_the_count() {
+        let error = exactly_one(vec![1, 2, 3]).unwrap_err();
+
+        assert!(matches!(
+            DbError::find(&error),
+            Some(DbError::TooManyRows { count: 3 })
+        ));
+    }
+
+    const URL: &str = "postgres://user@localhost/app";
+
+    fn config(
+        url: &str,
+        configure: impl FnOnce(PoolConfigBuilder) -> PoolConfigBuilder,
+    ) -> PoolConfig {
+        configure(PoolConfig::builder().database_url(url))
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn statement_cache_capacity_defaults_without_settings() {
+        let config = config(URL, |builder| builder);
+
+        assert_eq!(
+            statement_cache_capacity(&config, URL),
+            DEFAULT_STATEMENT_CACHE_CAPACITY
+        );
+    }
+
+    #[test]
+    fn statement_cache_capacity_reads_the_url_parameter() {
+        for url in [
+            "postgres://user@localhost/app?statement-cache-capacity=25",
+            "postgres://user@localhost/app?sslmode=disable&statement-cache-capacity=25",
+        ] {
+            assert_eq!(
+                statement_cache_capacity(&config(url, |builder| builder), url),
+                25
+            );
+        }
+    }
+
+    #[test]
+    fn statement_cache_capacity_reads_the_builder() {
+        let config = config(URL, |builder| builder.statement_cache_capacity(10));
+
+        assert_eq!(statement_cache_capacity(&config, URL), 10);
+    }
+
+    #[test]
+    fn statement_cache_capacity_prefers_the_builder_over_the_url() {
+        let url = "postgres://user@localhost/app?statement-cache-capacity=25";
+        let config = config(url, |builder| builder.statement_cache_capacity(0));
+
+        assert_eq!(statement_cache_capacity(&config, url), 0);
+    }
+
+    #[test]
+    fn statement_cache_capacity_is_zero_in_pgbouncer_mode() {
+        let url = "postgres://user@localhost/app?statement-cache-capacity=25";
+        let config = config(url, |builder| builder.pgbouncer_mode(true));
+
+        assert_eq!(statement_cache_capacity(&config, url), 0);
+    }
+
+    #[test]
+    fn invalid_statement_cache_capacity_in_the_url_is_rejected() {
+        let url = "postgres://user@localhost/app?statement-cache-capacity=many";
+        let config = config(url, |builder| builder);
+
+        assert_eq!(
+            statement_cache_capacity(&config, url),
+            DEFAULT_STATEMENT_CACHE_CAPACITY
+        );
+        let error = connect_options(&config, url).unwrap_err();
+        assert!(error.to_string().contains("Invalid database URL"));
+    }
+
+    #[tokio::test]
+    async fn collect_limited_accepts_exactly_the_limit() {
+        let rows = stream::iter([Ok(1), Ok(2), Ok(3)]);
+
+        assert_eq!(
+            collect_limited(rows, Some(3)).await.unwrap(),
+            Some(vec![1, 2, 3])
+        );
+    }
+
+    #[tokio::test]
+    async fn collect_limited_stops_one_row_over_the_limit() {
+        let rows = stream::iter([Ok(1), Ok(2), Ok(3)]);
+
+        assert_eq!(collect_limited(rows, Some(2)).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn collect_limited_reports_an_error_in_place_of_the_row_over_the_limit() {
+        let rows = stream::iter([Ok(1), Ok(2), Err(sqlx::Error::RowNotFound)]);
+
+        let error = collect_limited(rows, Some(2)).await.unwrap_err();
+
+        assert!(matches!(error, sqlx::Error::RowNotFound));
+    }
+
+    #[tokio::test]
+    #[ignore = "requires a PostgreSQL database (TEST_DATABASE_URL)"]
+    async fn fetch_all_limited_over_the_limit_keeps_the_connection_reusable() {
+        let connection_pool = connect_small_pool(1).await;
+        let fetch = |rows: i32| {
+            connection_pool.fetch_all_limited(
+                sqlx::query("SELECT n FROM generate_series(1, $1) AS n")
+                    .bind(rows)
+                    .try_map(|row: PgRow| sqlx::Row::try_get::<i32, _>(&row, 0)),
+                3,
+            )
+        };
+
+        let error = fetch(1_000).await.unwrap_err();
+        let rows = fetch(3).await.unwrap();
+
+        assert!(matches!(
+            DbError::find(&error),
+            Some(DbError::RowLimitExceeded { limit: 3 })
+        ));
+        assert_eq!(rows, [1, 2, 3]);
+    }
+
+    #[tokio::test]
+    #[ignore = "requires a PostgreSQL database (TEST_DATABASE_URL)"]
+    async fn fetch_exactly_one_classifies_the_row_count() {
+        let connection_pool = testing::connect().await;
+        let fetch = |rows: i32| {
+            connection_pool.fetch_exactly_one(
+                sqlx::query("SELECT n FROM generate_series(1, $1) AS n")
+                    .bind(rows)
+                    .try_map(|row: PgRow| sqlx::Row::try_get::<i32, _>(&row, 0)),
+            )
+        };
+
+        let none = fetch(0).await.unwrap_err();
+        let one = fetch(1).await.unwrap();
+        let many = fetch(2).await.unwrap_err();
+
+        assert!(matches!(DbError::find(&none), Some(DbError::NotFound)));
+        assert_eq!(one, 1);
+        assert!(matches!(
+            DbError::find(&many),
+            Some(DbError::TooManyRows { count: 2 })
+        ));
+    }
+
+    #[tokio::test]
+    #[ignore = "requires a PostgreSQL database (TEST_DATABASE_URL)"]
+    async fn new_transactions_fail_with_pool_closed_after_close() {
+        let connection_pool = testing::connect().await;
+        let executor = TransactionExecutor::from_shared_pool(&connection_pool);
+        connection_pool.close(Duration::from_secs(5)).await.unwrap();
+
+        let error = executor
+            .execute_query(sqlx::query("SELECT 1"))
+            .await
+            .unwrap_err();
+
+        assert!(connection_pool.is_closed());
+        assert!(matches!(DbError::find(&error), Some(DbError::PoolClosed)));
+    }
+
+    #[tokio::test]
+    #[ignore = "requires a PostgreSQL database (TEST_DATABASE_URL)"]
+    async fn transactions_begun_before_close_can_still_commit() {
+        let connection_pool = testing::connect().await;
+        let executor = TransactionExecutor::from_shared_pool(&connection_pool);
+        let mut tx = executor.begin().await.unwrap();
+
+        let closing = tokio::spawn({
+            let connection_pool = Arc::clone(&connection_pool);
+            async move { connection_pool.close(Duration::from_secs(5)).await }
+        });
+        while !connection_pool.is_closed() {
+            tokio::task::yield_now().await;
+        }
+
+        sqlx::query("SELECT 1").execute(&mut **tx).await.unwrap();
+        tx.commit().await.unwrap();
+        assert_eq!(closing.await.unwrap().unwrap(), 0);
+    }
+
+    #[tokio::test]
+    #[ignore = "requires a PostgreSQL database (TEST_DATABASE_URL)"]
+    async fn pgbouncer_mode_leaves_no_named_statements_on_the_server() {
+        let config = PoolConfig::builder()
+            .database_url(testing::database_url())
+            .max_connections(1)
+            .min_connections(0)
+            .pgbouncer_mode(true)
+            .build()
+            .unwrap();
+        let connection_pool = Arc::new(ConnectionPool::connect(&config).await.unwrap());
+        let transaction_executor = TransactionExecutor::from_shared_pool(&connection_pool);
+        let query_executor = QueryExecutor::from_shared_pool(&connection_pool);
+        let options = TransactionOptions {
+            isolation: Some(IsolationLevel::Serializable),
+            statement_timeout: Some(Duration::from_secs(5)),
+            ..TransactionOptions::default()
+        };
+
+        transaction_executor
+            .execute_queries_with_options(&options, [sqlx::query("SELECT $1::int").bind(1)])
+            .await
+            .unwrap();
+        transaction_executor
+            .execute_queries_with_advisory_lock(42_i64, [sqlx::query("SELECT 1")])
+            .await
+            .unwrap();
+        let _ = transaction_executor
+            .try_execute_queries_with_advisory_lock((4, 2), [sqlx::query("SELECT 1")])
+            .await
+            .unwrap();
+        transaction_executor
+            .execute_queries_with_deadline(Duration::from_secs(5), [sqlx::query("SELECT 1")])
+            .await
+            .unwrap();
+        query_executor
+            .fetch_all_as::<(i32,)>(sqlx::query("SELECT 1"))
+            .await
+            .unwrap();
+        connection_pool
+            .health_check(Duration::from_secs(5), Duration::from_secs(5))
+            .await
+            .unwrap();
+        let (one,): (i32,) = query_executor
+            .fetch_exactly_one_as(sqlx::query("SELECT 1"))
+            .await
+            .unwrap();
+        let streamed: Vec<(i32,)> = query_executor
+            .fetch_stream_as::<(i32,)>(sqlx::query("SELECT 1"))
+            .map(Result::unwrap)
+            .collect()
+            .await;
+        let mapped = query_executor
+            .fetch_one(sqlx::query("SELECT 1").persistent(false).map(|_: PgRow| ()))
+            .await;
+
+        let named_statements: i64 =
+            sqlx::query_scalar("SELECT count(*) FROM pg_prepared_statements")
+                .persistent(false)
+                .fetch_one(&connection_pool.pool)
+                .await
+                .unwrap();
+        assert_eq!(named_statements, 0);
+        assert_eq!((one, streamed), (1, vec![(1,)]));
+        let Err(error) = mapped else {
+            panic!("mapped queries must be rejected in pgbouncer_mode");
+        };
+        assert!(error.to_string().contains("pgbouncer_mode"));
     }
 }
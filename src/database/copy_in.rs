@@ -0,0 +1,145 @@
+use crate::database::query_spec::BindValue;
+use std::fmt::Write;
+
+/// `TransactionExecutor::copy_in` で書き込む 1 列分の値です。`QuerySpec` にバインドする値と同じ型を使います。
+pub type CopyValue = BindValue;
+
+/// 1 行分の値を COPY の text 形式（タブ区切り、改行終端、NULL は `\N`）で `buffer` に追記します。
+pub(super) fn encode_row(values: &[CopyValue], buffer: &mut String) {
+    for (index, value) in values.iter().enumerate() {
+        if index > 0 {
+            buffer.push('\t');
+        }
+        encode_value(value, buffer);
+    }
+    buffer.push('\n');
+}
+
+fn encode_value(value: &CopyValue, buffer: &mut String) {
+    // `String` への書き込みは失敗しないため、`write!` の結果は無視します。
+    match value {
+        BindValue::Int2(Some(value)) => _ = write!(buffer, "{value}"),
+        BindValue::Int4(Some(value)) => _ = write!(buffer, "{value}"),
+        BindValue::Int8(Some(value)) => _ = write!(buffer, "{value}"),
+        BindValue::Float8(Some(value)) => encode_float(*value, buffer),
+        BindValue::Bool(Some(value)) => buffer.push(if *value { 't' } else { 'f' }),
+        BindValue::Text(Some(value)) => escape_text(value, buffer),
+        BindValue::Timestamptz(Some(value)) => _ = write!(buffer, "{}", value.to_rfc3339()),
+        BindValue::Timestamp(Some(value)) => {
+            _ = write!(buffer, "{}", value.format("%Y-%m-%d %H:%M:%S%.f"));
+        }
+        BindValue::Date(Some(value)) => _ = write!(buffer, "{}", value.format("%Y-%m-%d")),
+        BindValue::Uuid(Some(value)) => _ = write!(buffer, "{value}"),
+        BindValue::Jsonb(Some(value)) => escape_text(&value.to_string(), buffer),
+        _ => buffer.push_str("\\N"),
+    }
+}
+
+/// PostgreSQL が受け付ける表記で浮動小数点数を書き込みます。
+fn encode_float(value: f64, buffer: &mut String) {
+    if value.is_nan() {
+        buffer.push_str("NaN");
+    } else if value.is_infinite() {
+        buffer.push_str(if value > 0.0 { "Infinity" } else { "-Infinity" });
+    } else {
+        _ = write!(buffer, "{value}");
+    }
+}
+
+/// text 形式で特別な意味を持つバックスラッシュ・タブ・改行・復帰をエスケープします。
+fn escape_text(value: &str, buffer: &mut String) {
+    for c in value.chars() {
+        match c {
+            '\\' => buffer.push_str("\\\\"),
+            '\t' => buffer.push_str("\\t"),
+            '\n' => buffer.push_str("\\n"),
+            '\r' => buffer.push_str("\\r"),
+            c => buffer.push(c),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn encode(values: &[CopyValue]) -> String {
+        let mut buffer = String::new();
+        encode_row(values, &mut buffer);
+        buffer
+    }
+
+    #[test]
+    fn encode_row_separates_columns_with_tabs_and_ends_with_a_newline() {
+        assert_eq!(
+            encode(&[
+                BindValue::Int4(Some(1)),
+                BindValue::Bool(Some(true)),
+                BindValue::Text(Some("a".to_string())),
+            ]),
+            "1\tt\ta\n"
+        );
+        assert_eq!(encode(&[]), "\n");
+    }
+
+    #[test]
+    fn encode_row_writes_null_as_backslash_n() {
+        assert_eq!(
+            encode(&[
+                BindValue::Int8(None),
+                BindValue::Text(None),
+                BindValue::Jsonb(None),
+            ]),
+            "\\N\t\\N\t\\N\n"
+        );
+    }
+
+    #[test]
+    fn encode_row_distinguishes_null_from_the_text_backslash_n() {
+        assert_eq!(
+            encode(&[BindValue::Text(Some("\\N".to_string()))]),
+            "\\\\N\n"
+        );
+    }
+
+    #[test]
+    fn escape_text_escapes_copy_control_characters() {
+        let mut buffer = String::new();
+        escape_text("a\tb\nc\rd\\e", &mut buffer);
+        assert_eq!(buffer, "a\\tb\\nc\\rd\\\\e");
+    }
+
+    #[test]
+    fn escape_text_keeps_other_characters() {
+        let mut buffer = String::new();
+        escape_text("日本語 'quoted' \"x\" ,;", &mut buffer);
+        assert_eq!(buffer, "日本語 'quoted' \"x\" ,;");
+    }
+
+    #[test]
+    fn encode_float_writes_special_values_as_postgres_accepts_them() {
+        for (value, expected) in [
+            (f64::NAN, "NaN"),
+            (f64::INFINITY, "Infinity"),
+            (f64::NEG_INFINITY, "-Infinity"),
+            (1.5, "1.5"),
+            (-0.25, "-0.25"),
+        ] {
+            let mut buffer = String::new();
+            encode_float(value, &mut buffer);
+            assert_eq!(buffer, expected);
+        }
+    }
+
+    #[test]
+    fn encode_row_escapes_jsonb_text() {
+        // JSON の文字列内の制御文字は `\t` などに、バックスラッシュは `\\` にシリアライズされ、
+        // COPY の text 形式ではさらにそのバックスラッシュをエスケープします。
+        let value = json!({"text": "a\tb\nc\rd\\e"});
+        assert_eq!(
+            encode(&[BindValue::Jsonb(Some(value))]),
+            concat!(r#"{"text":"a\\tb\\nc\\rd\\\\e"}"#, "\n")
+        );
+    }
+}
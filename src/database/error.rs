@@ -0,0 +1,539 @@
+use crate::database::sql_script::{
+    dollar_quote_tag, is_escape_string_prefix, is_identifier_char, skip_dollar_quoted, skip_quoted,
+};
+use std::{borrow::Cow, collections::BTreeSet, fmt, time::Duration};
+use thiserror::Error;
+
+const SQLSTATE_UNIQUE_VIOLATION: &str = "23505";
+const SQLSTATE_FOREIGN_KEY_VIOLATION: &str = "23503";
+const SQLSTATE_CHECK_VIOLATION: &str = "23514";
+const SQLSTATE_SERIALIZATION_FAILURE: &str = "40001";
+const SQLSTATE_DEADLOCK_DETECTED: &str = "40P01";
+const SQLSTATE_QUERY_CANCELED: &str = "57014";
+/// `ErrorDetail::include_sql` でエラーに含める SQL 文の最大文字数です。
+const DEFAULT_SQL_DETAIL_LEN: usize = 200;
+
+/// SQLSTATE とエラー種別で分類したデータベースエラーです。
+///
+/// database モジュールの公開 API は `anyhow::Error` を返しますが、SQLx のエラーは必ずこの型に
+/// 変換してからコンテキストを付与するため、呼び出し側は `DbError::find` または
+/// `downcast_ref::<DbError>()` で種別を判別できます。元の `sqlx::Error` は `source` として保持されます。
+#[derive(Debug, Error)]
+pub enum DbError {
+    /// 一意制約違反（SQLSTATE `23505`）です。
+    #[error("unique constraint violation{}", constraint_suffix(constraint))]
+    UniqueViolation {
+        constraint: Option<String>,
+        #[source]
+        source: sqlx::Error,
+    },
+    /// 外部キー制約違反（SQLSTATE `23503`）です。
+    #[error("foreign key constraint violation{}", constraint_suffix(constraint))]
+    ForeignKeyViolation {
+        constraint: Option<String>,
+        #[source]
+        source: sqlx::Error,
+    },
+    /// 検査制約違反（SQLSTATE `23514`）です。
+    #[error("check constraint violation{}", constraint_suffix(constraint))]
+    CheckViolation {
+        constraint: Option<String>,
+        #[source]
+        source: sqlx::Error,
+    },
+    /// 直列化失敗（SQLSTATE `40001`）です。トランザクションの再実行で成功し得ます。
+    #[error("serialization failure")]
+    SerializationFailure(#[source] sqlx::Error),
+    /// デッドロック検出（SQLSTATE `40P01`）です。トランザクションの再実行で成功し得ます。
+    #[error("deadlock detected")]
+    Deadlock(#[source] sqlx::Error),
+    /// ステートメントがサーバー側で取り消されたこと（SQLSTATE `57014`）を表します。
+    ///
+    /// `statement_timeout` の超過や `pg_cancel_backend` による取り消しで発生します。
+    #[error("query canceled")]
+    QueryCanceled(#[source] sqlx::Error),
+    /// 行が必要な操作で結果が空だったことを表します。
+    #[error("no rows returned")]
+    NotFound,
+    /// 1 行だけが必要な操作で複数行が返ったことを表します。
+    #[error("expected exactly one row, got {count}")]
+    TooManyRows { count: usize },
+    /// 全行を返す読み取りで結果が上限の行数を超えたことを表します。上限を超えた時点で読み取りをやめています。
+    #[error("query returned more than {limit} rows")]
+    RowLimitExceeded { limit: usize },
+    /// 接続プールからの接続取得がタイムアウトしたことを表します。
+    #[error("timed out waiting for a pooled connection")]
+    PoolTimeout,
+    /// 接続プールが閉じられた後に接続を取得しようとしたことを表します。
+    #[error("connection pool is shutting down")]
+    PoolClosed,
+    /// 操作が指定した時間内に完了しなかったことを表します。
+    #[error("timed out after {timeout:?}")]
+    Timeout { timeout: Duration },
+    /// 呼び出し側の取り消し要求によりトランザクションをロールバックしたことを表します。
+    #[error("transaction cancelled")]
+    Cancelled,
+    /// 同時実行数を制限した実行器で、待機の上限時間内に実行枠を確保できなかったことを表します。
+    #[error("executor saturated: no transaction slot became available within {queue_timeout:?}")]
+    ExecutorSaturated { queue_timeout: Duration },
+    /// トランザクション全体の期限を超えたことを表します。
+    #[error("transaction deadline of {deadline:?} exceeded while {phase}")]
+    DeadlineExceeded {
+        deadline: Duration,
+        phase: DeadlinePhase,
+    },
+    /// 接続先との通信に失敗したことを表します。
+    #[error("database I/O error")]
+    Io(#[source] sqlx::Error),
+    /// 上記以外のエラーです。
+    #[error("database operation failed")]
+    Other(#[source] sqlx::Error),
+}
+
+/// トランザクションの期限を超えた時点で実行していた処理です。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeadlinePhase {
+    /// 接続の取得とトランザクションの開始です。
+    Begin,
+    /// `index` 番目のステートメントの実行です。
+    Statement { index: usize },
+    /// クロージャで渡されたトランザクション本体の実行です。
+    Body,
+    /// `before_commit` フックとコミットです。この段階で期限を超えた場合、コミットされたかどうかは不明です。
+    Commit,
+}
+
+impl fmt::Display for DeadlinePhase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Begin => f.write_str("starting transaction"),
+            Self::Statement { index } => write!(f, "executing statement at index {index}"),
+            Self::Body => f.write_str("running transaction body"),
+            Self::Commit => f.write_str("committing"),
+        }
+    }
+}
+
+/// 失敗したステートメントのエラーに含める詳細の範囲です。
+///
+/// どの設定でも、ステートメントの位置と、データベースが返した SQLSTATE・制約名、SQL 文中のプレースホルダの
+/// 位置（`$1, $2` など）を含めます。バインドした値はどの設定でも含めません。SQL 文をログに残せない場合に
+/// 備えて、SQL 文は `IncludeSql` を指定した場合にのみ含めます。
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ErrorDetail {
+    /// SQL 文を含めません。
+    #[default]
+    Minimal,
+    /// SQL 文を先頭から `max_len` 文字までに切り詰めて含めます。
+    ///
+    /// 文字列リテラル（ドル引用を含む）は `'…'` に置き換え、改行などの連続する空白は 1 つの空白に詰めます。
+    /// 数値など文字列以外のリテラルは置き換えないため、値は SQL 文に埋め込まずにバインドしてください。
+    IncludeSql { max_len: usize },
+}
+
+impl ErrorDetail {
+    /// SQL 文を既定の長さ（200 文字）までに切り詰めて含める設定を返します。
+    pub fn include_sql() -> Self {
+        Self::IncludeSql {
+            max_len: DEFAULT_SQL_DETAIL_LEN,
+        }
+    }
+
+    /// `index` 番目のステートメント `sql` が `error` で失敗したことを表すコンテキストを返します。
+    pub(super) fn statement_context(self, index: usize, sql: &str, error: &DbError) -> String {
+        let (sanitized, placeholders) = scan_sql(sql);
+        let mut details = Vec::new();
+        if let Some(code) = error.sqlstate() {
+            details.push(format!("SQLSTATE {code}"));
+        }
+        if let Some(constraint) = error.constraint() {
+            details.push(format!("constraint {constraint}"));
+        }
+        details.push(match placeholders.len() {
+            0 => "no bound parameters".to_string(),
+            count => {
+                let positions: Vec<String> = placeholders
+                    .iter()
+                    .map(|position| format!("${position}"))
+                    .collect();
+                let noun = if count == 1 {
+                    "parameter"
+                } else {
+                    "parameters"
+                };
+                format!("{count} bound {noun} at {}", positions.join(", "))
+            }
+        });
+        if let Self::IncludeSql { max_len } = self {
+            details.push(format!("SQL: {}", truncate_chars(&sanitized, max_len)));
+        }
+        format!(
+            "Failed to execute query in transaction at index {index} ({})",
+            details.join("; ")
+        )
+    }
+}
+
+/// SQL 文の文字列リテラルを `'…'` に置き換えて空白を詰めた文と、プレースホルダの番号を返します。
+///
+/// 引用の判定は `split_statements` と同じ規則に従い、`E'...'` のバックスラッシュエスケープと
+/// ドル引用（`$$ ... $$`・`$tag$ ... $tag$`）も文字列リテラルとして置き換えます。
+fn scan_sql(sql: &str) -> (String, BTreeSet<u32>) {
+    let mut sanitized = String::with_capacity(sql.len());
+    let mut placeholders = BTreeSet::new();
+    // 引用の読み飛ばしで数える行番号です。ここでは使いません。
+    let mut line = 0;
+    let mut chars = sql.char_indices().peekable();
+    while let Some((index, c)) = chars.next() {
+        match c {
+            '\'' => {
+                let backslash_escapes = is_escape_string_prefix(&sql[..index]);
+                skip_quoted(&mut chars, &mut line, '\'', backslash_escapes);
+                sanitized.push_str("'…'");
+            }
+            '"' => {
+                skip_quoted(&mut chars, &mut line, '"', false);
+                let end = chars.peek().map_or(sql.len(), |&(end, _)| end);
+                sanitized.push_str(&sql[index..end]);
+            }
+            '$' if chars.peek().is_some_and(|(_, next)| next.is_ascii_digit()) => {
+                let mut number = String::new();
+                while let Some((_, digit)) = chars.next_if(|(_, next)| next.is_ascii_digit()) {
+                    number.push(digit);
+                }
+                if let Ok(position) = number.parse() {
+                    placeholders.insert(position);
+                }
+                sanitized.push('$');
+                sanitized.push_str(&number);
+            }
+            '$' => match dollar_quote_tag(&sql[index..]) {
+                Some(tag) if !sql[..index].ends_with(is_identifier_char) => {
+                    skip_dollar_quoted(sql, index, tag, &mut chars, &mut line);
+                    sanitized.push_str("'…'");
+                }
+                _ => sanitized.push('$'),
+            },
+            c if c.is_whitespace() => {
+                if !sanitized.is_empty() && !sanitized.ends_with(' ') {
+                    sanitized.push(' ');
+                }
+            }
+            c => sanitized.push(c),
+        }
+    }
+    let len = sanitized.trim_end().len();
+    sanitized.truncate(len);
+    (sanitized, placeholders)
+}
+
+/// `text` が `max_len` 文字を超える場合は切り詰めて `…` を付けます。
+fn truncate_chars(text: &str, max_len: usize) -> Cow<'_, str> {
+    match text.char_indices().nth(max_len) {
+        Some((end, _)) => Cow::Owned(format!("{}…", &text[..end])),
+        None => Cow::Borrowed(text),
+    }
+}
+
+impl DbError {
+    /// エラーのチェーンから最初に見つかった `DbError` を返します。
+    pub fn find(error: &anyhow::Error) -> Option<&DbError> {
+        error
+            .chain()
+            .find_map(|cause| cause.downcast_ref::<DbError>())
+    }
+
+    /// 制約違反の場合は、違反した制約名を返します。
+    pub fn constraint(&self) -> Option<&str> {
+        match self {
+            Self::UniqueViolation { constraint, .. }
+            | Self::ForeignKeyViolation { constraint, .. }
+            | Self::CheckViolation { constraint, .. } => constraint.as_deref(),
+            _ => None,
+        }
+    }
+
+    /// データベースが返したエラーの場合は、SQLSTATE を返します。
+    pub fn sqlstate(&self) -> Option<String> {
+        let source = match self {
+            Self::UniqueViolation { source, .. }
+            | Self::ForeignKeyViolation { source, .. }
+            | Self::CheckViolation { source, .. }
+            | Self::SerializationFailure(source)
+            | Self::Deadlock(source)
+            | Self::QueryCanceled(source)
+            | Self::Other(source) => source,
+            _ => return None,
+        };
+        source
+            .as_database_error()?
+            .code()
+            .map(|code| code.into_owned())
+    }
+
+    /// メトリクスのラベルなどに使う、エラーの種別を表す固定の文字列を返します。
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::UniqueViolation { .. } => "unique_violation",
+            Self::ForeignKeyViolation { .. } => "foreign_key_violation",
+            Self::CheckViolation { .. } => "check_violation",
+            Self::SerializationFailure(_) => "serialization_failure",
+            Self::Deadlock(_) => "deadlock",
+            Self::QueryCanceled(_) => "query_canceled",
+            Self::NotFound => "not_found",
+            Self::TooManyRows { .. } => "too_many_rows",
+            Self::RowLimitExceeded { .. } => "row_limit_exceeded",
+            Self::PoolTimeout => "pool_timeout",
+            Self::PoolClosed => "pool_closed",
+            Self::Timeout { .. } => "timeout",
+            Self::Cancelled => "cancelled",
+            Self::ExecutorSaturated { .. } => "executor_saturated",
+            Self::DeadlineExceeded { .. } => "deadline_exceeded",
+            Self::Io(_) => "io",
+            Self::Other(_) => "other",
+        }
+    }
+
+    /// 新しいトランザクションで再実行すれば成功し得るエラーかどうかを返します。
+    pub fn is_retryable(&self) -> bool {
+        matches!(self, Self::SerializationFailure(_) | Self::Deadlock(_))
+    }
+}
+
+impl From<sqlx::Error> for DbError {
+    fn from(error: sqlx::Error) -> Self {
+        match &error {
+            sqlx::Error::RowNotFound => Self::NotFound,
+            sqlx::Error::PoolTimedOut => Self::PoolTimeout,
+            sqlx::Error::PoolClosed => Self::PoolClosed,
+            sqlx::Error::Io(_) | sqlx::Error::Tls(_) => Self::Io(error),
+            sqlx::Error::Database(database_error) => {
+                let code = database_error.code().map(|code| code.into_owned());
+                let constraint = database_error.constraint().map(str::to_string);
+                classify_sqlstate(code.as_deref(), constraint, error)
+            }
+            _ => Self::Other(error),
+        }
+    }
+}
+
+/// SQLSTATE をもとにデータベースから返されたエラーを分類します。
+fn classify_sqlstate(
+    code: Option<&str>,
+    constraint: Option<String>,
+    source: sqlx::Error,
+) -> DbError {
+    match code {
+        Some(SQLSTATE_UNIQUE_VIOLATION) => DbError::UniqueViolation { constraint, source },
+        Some(SQLSTATE_FOREIGN_KEY_VIOLATION) => DbError::ForeignKeyViolation { constraint, source },
+        Some(SQLSTATE_CHECK_VIOLATION) => DbError::CheckViolation { constraint, source },
+        Some(SQLSTATE_SERIALIZATION_FAILURE) => DbError::SerializationFailure(source),
+        Some(SQLSTATE_DEADLOCK_DETECTED) => DbError::Deadlock(source),
+        Some(SQLSTATE_QUERY_CANCELED) => DbError::QueryCanceled(source),
+        _ => DbError::Other(source),
+    }
+}
+
+fn constraint_suffix(constraint: &Option<String>) -> String {
+    constraint
+        .as_deref()
+        .map(|constraint| format!(" on {constraint}"))
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::retry::is_retryable;
+    use anyhow::Context;
+    use sqlx::error::{DatabaseError, ErrorKind};
+    use std::{borrow::Cow, error::Error as StdError, fmt};
+
+    /// SQLSTATE と制約名だけを持つ、テスト用のデータベースエラーです。
+    #[derive(Debug)]
+    struct FakeDatabaseError {
+        code: &'static str,
+        constraint: Option<&'static str>,
+    }
+
+    impl fmt::Display for FakeDatabaseError {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(f, "fake database error {}", self.code)
+        }
+    }
+
+    impl StdError for FakeDatabaseError {}
+
+    impl DatabaseError for FakeDatabaseError {
+        fn message(&self) -> &str {
+            "fake database error"
+        }
+
+        fn code(&self) -> Option<Cow<'_, str>> {
+            Some(Cow::Borrowed(self.code))
+        }
+
+        fn as_error(&self) -> &(dyn StdError + Send + Sync + 'static) {
+            self
+        }
+
+        fn as_error_mut(&mut self) -> &mut (dyn StdError + Send + Sync + 'static) {
+            self
+        }
+
+        fn into_error(self: Box<Self>) -> Box<dyn StdError + Send + Sync + 'static> {
+            self
+        }
+
+        fn constraint(&self) -> Option<&str> {
+            self.constraint
+        }
+
+        fn kind(&self) -> ErrorKind {
+            ErrorKind::Other
+        }
+    }
+
+    fn database_error(code: &'static str, constraint: Option<&'static str>) -> sqlx::Error {
+        sqlx::Error::Database(Box::new(FakeDatabaseError { code, constraint }))
+    }
+
+    #[test]
+    fn scan_sql_masks_string_literals_and_collects_placeholders() {
+        let (sanitized, placeholders) =
+            scan_sql("SELECT *\n  FROM users\n WHERE name = 'it''s' AND id = $2 OR id = $10");
+        assert_eq!(
+            sanitized,
+            "SELECT * FROM users WHERE name = '…' AND id = $2 OR id = $10"
+        );
+        assert_eq!(placeholders.into_iter().collect::<Vec<_>>(), [2, 10]);
+    }
+
+    #[test]
+    fn scan_sql_masks_dollar_quoted_literals() {
+        let (sanitized, _) = scan_sql("SELECT $$secret$$, $tag$other; $$ secret$tag$, a$b$c");
+        assert_eq!(sanitized, "SELECT '…', '…', a$b$c");
+    }
+
+    #[test]
+    fn scan_sql_follows_backslash_escapes_in_escape_strings() {
+        let (sanitized, placeholders) = scan_sql(r"SELECT E'it\'s secret', 'x' WHERE id = $1");
+        assert_eq!(sanitized, "SELECT E'…', '…' WHERE id = $1");
+        assert_eq!(placeholders.into_iter().collect::<Vec<_>>(), [1]);
+    }
+
+    #[test]
+    fn scan_sql_keeps_quoted_identifiers() {
+        let (sanitized, _) = scan_sql(r#"SELECT "it's" FROM t WHERE v = 'secret'"#);
+        assert_eq!(sanitized, r#"SELECT "it's" FROM t WHERE v = '…'"#);
+    }
+
+    #[test]
+    fn statement_context_includes_sql_only_when_enabled() {
+        let error = DbError::from(database_error("23505", Some("users_email_key")));
+        let sql = "INSERT INTO users (email) VALUES ($1)";
+
+        let minimal = ErrorDetail::Minimal.statement_context(3, sql, &error);
+        assert_eq!(
+            minimal,
+            "Failed to execute query in transaction at index 3 \
+             (SQLSTATE 23505; constraint users_email_key; 1 bound parameter at $1)"
+        );
+        assert!(!minimal.contains("INSERT"));
+
+        let detailed = ErrorDetail::include_sql().statement_context(3, sql, &error);
+        assert_eq!(
+            detailed,
+            "Failed to execute query in transaction at index 3 \
+             (SQLSTATE 23505; constraint users_email_key; 1 bound parameter at $1; \
+             SQL: INSERT INTO users (email) VALUES ($1))"
+        );
+    }
+
+    #[test]
+    fn statement_context_truncates_sql_on_a_character_boundary() {
+        let error = DbError::from(sqlx::Error::PoolClosed);
+        let detail = ErrorDetail::IncludeSql { max_len: 10 };
+
+        let context = detail.statement_context(0, "SELECT 数量 FROM 注文", &error);
+
+        assert_eq!(
+            context,
+            "Failed to execute query in transaction at index 0 \
+             (no bound parameters; SQL: SELECT 数量 …)"
+        );
+    }
+
+    #[test]
+    fn classifies_database_errors_by_sqlstate() {
+        let cases = [
+            ("23505", Some("users_email_key"), "unique_violation", false),
+            (
+                "23503",
+                Some("orders_user_id_fkey"),
+                "foreign_key_violation",
+                false,
+            ),
+            ("23514", Some("positive_amount"), "check_violation", false),
+            ("40001", None, "serialization_failure", true),
+            ("40P01", None, "deadlock", true),
+            ("57014", None, "query_canceled", false),
+            ("42P01", None, "other", false),
+        ];
+        for (code, constraint, kind, retryable) in cases {
+            let error = DbError::from(database_error(code, constraint));
+            assert_eq!(error.kind(), kind, "SQLSTATE {code}");
+            assert_eq!(error.is_retryable(), retryable, "SQLSTATE {code}");
+            assert_eq!(error.sqlstate().as_deref(), Some(code));
+            assert_eq!(error.constraint(), constraint, "SQLSTATE {code}");
+        }
+    }
+
+    #[test]
+    fn classifies_pool_and_row_errors() {
+        let cases = [
+            (sqlx::Error::PoolTimedOut, "pool_timeout"),
+            (sqlx::Error::PoolClosed, "pool_closed"),
+            (sqlx::Error::RowNotFound, "not_found"),
+            (sqlx::Error::Protocol("unexpected".to_string()), "other"),
+        ];
+        for (source, kind) in cases {
+            let error = DbError::from(source);
+            assert_eq!(error.kind(), kind);
+            assert_eq!(error.sqlstate(), None);
+            assert!(!error.is_retryable());
+        }
+    }
+
+    #[test]
+    fn find_survives_context_wrapping() {
+        let error = Err::<(), _>(DbError::from(database_error("40001", None)))
+            .context("Failed to execute query")
+            .with_context(|| format!("Failed to run job {}", "nightly"))
+            .unwrap_err();
+
+        let found = DbError::find(&error).expect("DbError is in the chain");
+        assert!(matches!(found, DbError::SerializationFailure(_)));
+        assert!(found.is_retryable());
+    }
+
+    #[test]
+    fn is_retryable_accepts_converted_and_unconverted_sqlx_errors() {
+        for code in ["40001", "40P01"] {
+            let unconverted = anyhow::Error::from(database_error(code, None)).context("closure");
+            let converted = anyhow::Error::from(DbError::from(database_error(code, None)));
+
+            assert!(is_retryable(&unconverted), "{code}");
+            assert!(is_retryable(&converted), "{code}");
+        }
+        assert!(!is_retryable(&anyhow::Error::from(database_error(
+            "23505", None
+        ))));
+    }
+
+    #[test]
+    fn find_returns_none_without_a_db_error() {
+        let error = anyhow::anyhow!("plain error").context("outer");
+        assert!(DbError::find(&error).is_none());
+    }
+}
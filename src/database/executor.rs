@@ -0,0 +1,329 @@
+use crate::database::{
+    error::DbError,
+    pgbouncer::{Unprepared, ensure_map_supported},
+    query_executor::QueryExecutor,
+    query_spec::QuerySpec,
+    transaction_executor::{self, TransactionExecutor},
+};
+use anyhow::{Context, Result};
+use sqlx::{
+    Postgres, Transaction,
+    postgres::{PgArguments, PgRow},
+    query::{Map, Query},
+};
+use std::future::Future;
+use tokio::sync::Mutex;
+
+/// クエリの実行手段を抽象化したトレイトです。
+///
+/// `QueryExecutor`・`TransactionExecutor`・`TransactionHandle`（`test-util` フィーチャーでは `MockExecutor` も）が実装します。リポジトリ関数を
+/// `impl DatabaseExecutor` を受け取るように書くと、同じ関数を単独のトランザクションでも、
+/// 呼び出し側が開いたトランザクションの中でも実行できます。
+///
+/// ```ignore
+/// async fn deactivate_user(db: &impl DatabaseExecutor, id: i64) -> anyhow::Result<u64> {
+///     db.execute_query(sqlx::query("UPDATE users SET active = false WHERE id = $1").bind(id))
+///         .await
+/// }
+///
+/// deactivate_user(&query_executor, 1).await?;
+/// transaction_executor
+///     .with_transaction(|tx| {
+///         Box::pin(async move {
+///             let handle = TransactionHandle::new(tx);
+///             deactivate_user(&handle, 1).await?;
+///             deactivate_user(&handle, 2).await
+///         })
+///     })
+///     .await?;
+/// ```
+pub trait DatabaseExecutor: Send + Sync {
+    /// 複数クエリを順に実行し、クエリごとに影響を受けた行数を返します。
+    fn execute_queries<'a, I>(&self, queries: I) -> impl Future<Output = Result<Vec<u64>>> + Send
+    where
+        I: IntoIterator<Item = Query<'a, Postgres, PgArguments>> + Send,
+        I::IntoIter: Send;
+
+    /// 単一クエリを実行し、影響を受けた行数を返します。
+    fn execute_query<'a>(
+        &self,
+        query: Query<'a, Postgres, PgArguments>,
+    ) -> impl Future<Output = Result<u64>> + Send {
+        async move {
+            let rows_affected = self.execute_queries(std::iter::once(query)).await?;
+            Ok(rows_affected.into_iter().sum())
+        }
+    }
+
+    /// `QuerySpec` の列を順に実行し、クエリごとに影響を受けた行数を返します。
+    fn execute_specs(
+        &self,
+        specs: Vec<QuerySpec>,
+    ) -> impl Future<Output = Result<Vec<u64>>> + Send {
+        async move {
+            self.execute_queries(specs.iter().map(QuerySpec::query))
+                .await
+        }
+    }
+
+    /// マッピング済みクエリを実行し、最大 1 行を返します。クエリ結果が空の場合は `Ok(None)` を返します。
+    fn fetch_one<'a, U, F>(
+        &self,
+        query: Map<'a, Postgres, F, PgArguments>,
+    ) -> impl Future<Output = Result<Option<U>>> + Send
+    where
+        U: Send + Unpin + 'static,
+        F: FnMut(PgRow) -> std::result::Result<U, sqlx::Error> + Send + 'static;
+
+    /// マッピング済みクエリを実行し、全行をベクタとして返します。
+    fn fetch_all<'a, U, F>(
+        &self,
+        query: Map<'a, Postgres, F, PgArguments>,
+    ) -> impl Future<Output = Result<Vec<U>>> + Send
+    where
+        U: Send + Unpin + 'static,
+        F: FnMut(PgRow) -> std::result::Result<U, sqlx::Error> + Send + 'static;
+}
+
+impl DatabaseExecutor for QueryExecutor {
+    fn execute_queries<'a, I>(&self, queries: I) -> impl Future<Output = Result<Vec<u64>>> + Send
+    where
+        I: IntoIterator<Item = Query<'a, Postgres, PgArguments>> + Send,
+        I::IntoIter: Send,
+    {
+        QueryExecutor::execute_queries(self, queries)
+    }
+
+    fn fetch_one<'a, U, F>(
+        &self,
+        query: Map<'a, Postgres, F, PgArguments>,
+    ) -> impl Future<Output = Result<Option<U>>> + Send
+    where
+        U: Send + Unpin + 'static,
+        F: FnMut(PgRow) -> std::result::Result<U, sqlx::Error> + Send + 'static,
+    {
+        QueryExecutor::fetch_one(self, query)
+    }
+
+    fn fetch_all<'a, U, F>(
+        &self,
+        query: Map<'a, Postgres, F, PgArguments>,
+    ) -> impl Future<Output = Result<Vec<U>>> + Send
+    where
+        U: Send + Unpin + 'static,
+        F: FnMut(PgRow) -> std::result::Result<U, sqlx::Error> + Send + 'static,
+    {
+        QueryExecutor::fetch_all(self, query)
+    }
+}
+
+impl DatabaseExecutor for TransactionExecutor {
+    fn execute_queries<'a, I>(&self, queries: I) -> impl Future<Output = Result<Vec<u64>>> + Send
+    where
+        I: IntoIterator<Item = Query<'a, Postgres, PgArguments>> + Send,
+        I::IntoIter: Send,
+    {
+        TransactionExecutor::execute_queries(self, queries)
+    }
+
+    fn execute_specs(
+        &self,
+        specs: Vec<QuerySpec>,
+    ) -> impl Future<Output = Result<Vec<u64>>> + Send {
+        TransactionExecutor::execute_specs(self, specs)
+    }
+
+    fn fetch_one<'a, U, F>(
+        &self,
+        query: Map<'a, Postgres, F, PgArguments>,
+    ) -> impl Future<Output = Result<Option<U>>> + Send
+    where
+        U: Send + Unpin + 'static,
+        F: FnMut(PgRow) -> std::result::Result<U, sqlx::Error> + Send + 'static,
+    {
+        TransactionExecutor::fetch_one(self, query)
+    }
+
+    fn fetch_all<'a, U, F>(
+        &self,
+        query: Map<'a, Postgres, F, PgArguments>,
+    ) -> impl Future<Output = Result<Vec<U>>> + Send
+    where
+        U: Send + Unpin + 'static,
+        F: FnMut(PgRow) -> std::result::Result<U, sqlx::Error> + Send + 'static,
+    {
+        TransactionExecutor::fetch_all(self, query)
+    }
+}
+
+/// 開始済みのトランザクションを `DatabaseExecutor` として扱うためのラッパーです。
+///
+/// クエリは包んだトランザクション上で実行され、コミットとロールバックは呼び出し側に任せます。
+/// クエリが失敗した場合、PostgreSQL はトランザクション全体を中断状態にするため、エラーはそのまま
+/// 呼び出し側へ返してトランザクションをロールバックしてください。
+pub struct TransactionHandle<'t> {
+    tx: Mutex<&'t mut Transaction<'static, Postgres>>,
+    pgbouncer_mode: bool,
+}
+
+impl<'t> TransactionHandle<'t> {
+    /// 開始済みのトランザクションを包みます。
+    pub fn new(tx: &'t mut Transaction<'static, Postgres>) -> Self {
+        Self {
+            tx: Mutex::new(tx),
+            pgbouncer_mode: false,
+        }
+    }
+
+    /// PgBouncer のトランザクションプーリング向けに、`Query` を名前のないステートメントで実行するかを指定します。
+    ///
+    /// 有効な場合、`Map` を受け取る `fetch_one`・`fetch_all` はエラーを返します。詳細は
+    /// `PoolConfigBuilder::pgbouncer_mode` を参照してください。
+    pub fn with_pgbouncer_mode(mut self, pgbouncer_mode: bool) -> Self {
+        self.pgbouncer_mode = pgbouncer_mode;
+        self
+    }
+}
+
+impl DatabaseExecutor for TransactionHandle<'_> {
+    async fn execute_queries<'a, I>(&self, queries: I) -> Result<Vec<u64>>
+    where
+        I: IntoIterator<Item = Query<'a, Postgres, PgArguments>> + Send,
+        I::IntoIter: Send,
+    {
+        let mut tx = self.tx.lock().await;
+        let mut rows_affected = Vec::new();
+        for (index, query) in queries.into_iter().enumerate() {
+            let result = query
+                .unprepared_if(self.pgbouncer_mode)
+                .execute(&mut ***tx)
+                .await
+                .map_err(DbError::from)
+                .with_context(|| {
+                    format!("Failed to execute query in transaction at index {index}")
+                })?;
+            rows_affected.push(result.rows_affected());
+        }
+        Ok(rows_affected)
+    }
+
+    async fn fetch_one<'a, U, F>(
+        &self,
+        query: Map<'a, Postgres, F, PgArguments>,
+    ) -> Result<Option<U>>
+    where
+        U: Send + Unpin + 'static,
+        F: FnMut(PgRow) -> std::result::Result<U, sqlx::Error> + Send + 'static,
+    {
+        ensure_map_supported(self.pgbouncer_mode)?;
+        let mut tx = self.tx.lock().await;
+        transaction_executor::fetch_one(&mut tx, query).await
+    }
+
+    async fn fetch_all<'a, U, F>(&self, query: Map<'a, Postgres, F, PgArguments>) -> Result<Vec<U>>
+    where
+        U: Send + Unpin + 'static,
+        F: FnMut(PgRow) -> std::result::Result<U, sqlx::Error> + Send + 'static,
+    {
+        ensure_map_supported(self.pgbouncer_mode)?;
+        let mut tx = self.tx.lock().await;
+        transaction_executor::fetch_all(&mut tx, query).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::testing;
+    use sqlx::Row;
+
+    /// どの実行手段でも同じように動くことを確かめるためのリポジトリ関数です。
+    async fn rename_user(
+        db: &impl DatabaseExecutor,
+        table: &str,
+        id: i32,
+        name: &str,
+    ) -> Result<Option<String>> {
+        let update = format!("UPDATE {table} SET name = $1 WHERE id = $2");
+        let select = format!("SELECT name FROM {table} WHERE id = $1");
+        db.execute_query(sqlx::query(&update).bind(name).bind(id))
+            .await?;
+        db.fetch_one(
+            sqlx::query(&select)
+                .bind(id)
+                .try_map(|row: PgRow| row.try_get(0)),
+        )
+        .await
+    }
+
+    #[tokio::test]
+    #[ignore = "requires a PostgreSQL database (TEST_DATABASE_URL)"]
+    async fn repository_function_runs_through_every_executor() {
+        let pool = testing::pool().await;
+        let table = testing::unique_table("executor_users");
+        sqlx::query(&format!(
+            "CREATE TABLE {table} (id int PRIMARY KEY, name text NOT NULL)"
+        ))
+        .execute(&pool)
+        .await
+        .unwrap();
+        sqlx::query(&format!(
+            "INSERT INTO {table} VALUES (1, 'a'), (2, 'b'), (3, 'c'), (4, 'd')"
+        ))
+        .execute(&pool)
+        .await
+        .unwrap();
+
+        let standalone = rename_user(&QueryExecutor::new(pool.clone()), &table, 1, "alice")
+            .await
+            .unwrap();
+        let transactional = rename_user(&TransactionExecutor::new(pool.clone()), &table, 2, "bob")
+            .await
+            .unwrap();
+        let handled = TransactionExecutor::new(pool.clone())
+            .with_transaction(|tx| {
+                let table = table.clone();
+                Box::pin(async move {
+                    let handle = TransactionHandle::new(tx);
+                    let carol = rename_user(&handle, &table, 3, "carol").await?;
+                    let dave = rename_user(&handle, &table, 4, "dave").await?;
+                    Ok((carol, dave))
+                })
+            })
+            .await
+            .unwrap();
+
+        let names: Vec<String> =
+            sqlx::query_scalar(&format!("SELECT name FROM {table} ORDER BY id"))
+                .fetch_all(&pool)
+                .await
+                .unwrap();
+        sqlx::query(&format!("DROP TABLE {table}"))
+            .execute(&pool)
+            .await
+            .unwrap();
+        assert_eq!(standalone.as_deref(), Some("alice"));
+        assert_eq!(transactional.as_deref(), Some("bob"));
+        assert_eq!(
+            handled,
+            (Some("carol".to_string()), Some("dave".to_string()))
+        );
+        assert_eq!(names, ["alice", "bob", "carol", "dave"]);
+    }
+
+    #[cfg(feature = "test-util")]
+    #[tokio::test]
+    async fn repository_function_runs_against_the_mock_executor() {
+        use crate::database::mock_executor::{MockCallKind, MockExecutor};
+        let mock = MockExecutor::new()
+            .with_rows_affected("UPDATE users", 1)
+            .with_rows("SELECT name FROM users", vec!["alice".to_string()]);
+
+        let renamed = rename_user(&mock, "users", 1, "alice").await.unwrap();
+
+        let calls: Vec<MockCallKind> = mock.calls().into_iter().map(|call| call.kind).collect();
+        assert_eq!(renamed.as_deref(), Some("alice"));
+        assert_eq!(calls, [MockCallKind::Execute, MockCallKind::FetchOne]);
+        mock.assert_executed_containing("UPDATE users SET name = $1 WHERE id = $2");
+    }
+}
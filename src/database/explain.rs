@@ -0,0 +1,65 @@
+use crate::database::error::DbError;
+use anyhow::{Context, Result};
+use sqlx::{Row, postgres::PgRow};
+
+/// `EXPLAIN` の出力形式です。
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum ExplainFormat {
+    /// psql と同じ、1 行ずつのテキスト形式です。
+    #[default]
+    Text,
+    /// JSON 形式です。
+    Json,
+}
+
+/// `QueryExecutor::explain` に指定する `EXPLAIN` のオプションです。
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct ExplainOptions {
+    /// `true` の場合はステートメントを実際に実行し、実測の行数と時間を出力します。
+    pub analyze: bool,
+    /// `true` の場合は共有バッファの使用状況を出力します。
+    pub buffers: bool,
+    /// 出力形式です。
+    pub format: ExplainFormat,
+}
+
+/// `EXPLAIN` の結果です。
+#[derive(Debug, Clone, PartialEq)]
+pub enum ExplainPlan {
+    /// テキスト形式の実行計画の各行です。
+    Text(Vec<String>),
+    /// JSON 形式の実行計画です。
+    Json(serde_json::Value),
+}
+
+impl ExplainOptions {
+    /// `sql` の先頭にオプションに応じた `EXPLAIN (...)` を付与します。
+    pub(super) fn apply(&self, sql: &str) -> String {
+        let format = match self.format {
+            ExplainFormat::Text => "TEXT",
+            ExplainFormat::Json => "JSON",
+        };
+        format!(
+            "EXPLAIN (ANALYZE {}, BUFFERS {}, FORMAT {format}) {sql}",
+            self.analyze, self.buffers
+        )
+    }
+
+    /// `EXPLAIN` が返した行を実行計画に変換します。
+    pub(super) fn parse(&self, rows: Vec<PgRow>) -> Result<ExplainPlan> {
+        match self.format {
+            ExplainFormat::Text => rows
+                .iter()
+                .map(|row| row.try_get::<String, _>(0))
+                .collect::<sqlx::Result<Vec<_>>>()
+                .map(ExplainPlan::Text),
+            ExplainFormat::Json => rows
+                .first()
+                .ok_or(sqlx::Error::RowNotFound)
+                .and_then(|row| row.try_get::<serde_json::Value, _>(0))
+                .map(ExplainPlan::Json),
+        }
+        .map_err(DbError::from)
+        .context("Failed to read EXPLAIN output")
+    }
+}
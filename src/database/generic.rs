@@ -0,0 +1,441 @@
+use crate::database::{
+    backend::Backend,
+    error::DbError,
+    pool_config::{PoolConfig, SessionSetup},
+    transaction_executor::BoxFuture,
+};
+use anyhow::{Context, Result, ensure};
+use sqlx::{
+    Database, Executor, IntoArguments, Pool, Transaction,
+    pool::PoolOptions,
+    query::{Map, Query},
+};
+use std::time::Duration;
+
+#[cfg(feature = "mysql")]
+/// MySQL 用の接続プールです。
+pub type MySqlConnectionPool = GenericConnectionPool<sqlx::MySql>;
+#[cfg(feature = "mysql")]
+/// MySQL 用のトランザクション実行器です。
+pub type MySqlTransactionExecutor = GenericTransactionExecutor<sqlx::MySql>;
+/// PostgreSQL 用の汎用接続プールです。PostgreSQL 固有の機能は `ConnectionPool` を使ってください。
+pub type PgGenericConnectionPool = GenericConnectionPool<sqlx::Postgres>;
+/// PostgreSQL 用の汎用トランザクション実行器です。PostgreSQL 固有の機能は `TransactionExecutor` を使ってください。
+pub type PgGenericTransactionExecutor = GenericTransactionExecutor<sqlx::Postgres>;
+#[cfg(feature = "sqlite")]
+/// SQLite 用の接続プールです。
+pub type SqliteConnectionPool = GenericConnectionPool<sqlx::Sqlite>;
+#[cfg(feature = "sqlite")]
+/// SQLite 用のトランザクション実行器です。
+pub type SqliteTransactionExecutor = GenericTransactionExecutor<sqlx::Sqlite>;
+
+/// 任意の SQLx データベースに接続する接続プールです。
+///
+/// `PoolConfig` の接続先と接続数・タイムアウトなどの調整値を使います。リードレプリカ、ワークロードの分割、
+/// PgBouncer 互換モード、セッション設定、ステートメントキャッシュの容量は PostgreSQL 固有の設定のため、
+/// 既定値以外を指定した場合はエラーを返します。
+#[derive(Debug, Clone)]
+pub struct GenericConnectionPool<DB: Database> {
+    pool: Pool<DB>,
+}
+
+impl<DB: Database> GenericConnectionPool<DB> {
+    /// 環境変数から読み込んだ `PoolConfig` で接続プールを作成します。
+    ///
+    /// 接続 URL のスキームが `DB` と異なる場合はエラーを返します。
+    pub async fn from_env() -> Result<Self> {
+        Self::connect(&PoolConfig::from_env()?).await
+    }
+
+    /// `config` の接続先と調整値で接続プールを作成します。
+    pub async fn connect(config: &PoolConfig) -> Result<Self> {
+        let backend = Backend::from_url(config.database_url())?;
+        ensure!(
+            backend.name() == DB::NAME,
+            "Database URL is for {backend}, but the pool was requested for {}",
+            DB::NAME
+        );
+        ensure!(
+            config.replica_database_url().is_none()
+                && config.workload_split().is_none()
+                && !config.pgbouncer_mode()
+                && config.statement_cache_capacity().is_none()
+                && *config.session_setup() == SessionSetup::default()
+                && config.tls().is_empty(),
+            "Read replicas, workload split, pgbouncer_mode, statement cache capacity, session \
+             setup and TLS settings are only supported by the PostgreSQL ConnectionPool"
+        );
+        let pool = PoolOptions::<DB>::new()
+            .min_connections(config.min_connections())
+            .max_connections(config.max_connections())
+            .acquire_timeout(config.acquire_timeout())
+            .idle_timeout(config.idle_timeout())
+            .max_lifetime(config.max_lifetime())
+            .test_before_acquire(config.test_before_acquire())
+            .connect(config.database_url())
+            .await
+            .map_err(DbError::from)
+            .context("Failed to create database connection pool")?;
+        Ok(Self { pool })
+    }
+
+    /// 作成済みの SQLx の接続プールを包みます。
+    pub fn from_pool(pool: Pool<DB>) -> Self {
+        Self { pool }
+    }
+
+    /// 内部の SQLx の接続プールを返します。
+    pub fn pool(&self) -> &Pool<DB> {
+        &self.pool
+    }
+
+    /// このプールを使うトランザクション実行器を作成します。
+    pub fn executor(&self) -> GenericTransactionExecutor<DB> {
+        GenericTransactionExecutor::new(self.pool.clone())
+    }
+
+    /// 新しい接続の貸し出しを止め、貸し出し中の接続の返却を最大 `drain_timeout` 待ってから閉じます。
+    pub async fn close(&self, drain_timeout: Duration) -> Result<()> {
+        tokio::time::timeout(drain_timeout, self.pool.close())
+            .await
+            .map_err(|_| DbError::Timeout {
+                timeout: drain_timeout,
+            })
+            .context("Timed out waiting for pooled connections to be returned")
+    }
+}
+
+/// 任意の SQLx データベースでクエリを単一トランザクション内で実行する実行器です。
+///
+/// コミットとロールバックの扱いは `TransactionExecutor` と同じで、成功時はコミットし、失敗時はロールバック
+/// してエラーを返します。フック、オブザーバー、遅いステートメントのログ、同時実行数の制限、
+/// トランザクションの特性など PostgreSQL の `TransactionExecutor` が持つ機能は提供しません。
+///
+/// ```ignore
+/// let executor = SqliteConnectionPool::from_env().await?.executor();
+/// executor
+///     .execute_queries(vec![
+///         sqlx::query("INSERT INTO users (email) VALUES (?)").bind("a@example.com"),
+///         sqlx::query("INSERT INTO users (email) VALUES (?)").bind("b@example.com"),
+///     ])
+///     .await?;
+/// ```
+#[derive(Debug, Clone)]
+pub struct GenericTransactionExecutor<DB: Database> {
+    pool: Pool<DB>,
+}
+
+impl<DB: Database> GenericTransactionExecutor<DB> {
+    /// 指定した接続プールを使うトランザクション実行器を作成します。
+    pub fn new(pool: Pool<DB>) -> Self {
+        Self { pool }
+    }
+}
+
+impl<DB> GenericTransactionExecutor<DB>
+where
+    DB: Database,
+    DB::QueryResult: RowsAffected,
+    for<'c> &'c mut DB::Connection: Executor<'c, Database = DB>,
+{
+    /// 単一クエリをトランザクション内で実行し、影響を受けた行数を返します。
+    pub async fn execute_query<'a, A>(&self, query: Query<'a, DB, A>) -> Result<u64>
+    where
+        A: IntoArguments<'a, DB> + 'a,
+    {
+        let rows_affected = self.execute_queries(std::iter::once(query)).await?;
+        Ok(rows_affected.into_iter().sum())
+    }
+
+    /// 複数クエリを単一トランザクション内で実行し、クエリごとに影響を受けた行数を返します。
+    ///
+    /// いずれかのクエリが失敗した場合はトランザクションをロールバックし、エラーを返します。
+    pub async fn execute_queries<'a, A, I>(&self, queries: I) -> Result<Vec<u64>>
+    where
+        A: IntoArguments<'a, DB> + 'a,
+        I: IntoIterator<Item = Query<'a, DB, A>>,
+    {
+        let mut tx = self.begin().await?;
+        let mut rows_affected = Vec::new();
+        for (index, query) in queries.into_iter().enumerate() {
+            match query.execute(&mut *tx).await {
+                Ok(result) => rows_affected.push(result.rows_affected()),
+                Err(error) => {
+                    let error = Err(DbError::from(error)).with_context(|| {
+                        format!("Failed to execute query in transaction at index {index}")
+                    });
+                    return finish(tx, error).await;
+                }
+            }
+        }
+        finish(tx, Ok(rows_affected)).await
+    }
+
+    /// マッピング済みクエリを単一トランザクション内で実行し、最大 1 行を返します。
+    ///
+    /// クエリ結果が空の場合は `Ok(None)` を返します。
+    pub async fn fetch_one<'a, U, F, A>(&self, query: Map<'a, DB, F, A>) -> Result<Option<U>>
+    where
+        U: Send + Unpin,
+        F: FnMut(DB::Row) -> std::result::Result<U, sqlx::Error> + Send,
+        A: IntoArguments<'a, DB> + 'a,
+    {
+        let mut tx = self.begin().await?;
+        let result = query
+            .fetch_optional(&mut *tx)
+            .await
+            .map_err(DbError::from)
+            .context("Failed to fetch row in transaction");
+        finish(tx, result).await
+    }
+
+    /// マッピング済みクエリを単一トランザクション内で実行し、全行をベクタとして返します。
+    pub async fn fetch_all<'a, U, F, A>(&self, query: Map<'a, DB, F, A>) -> Result<Vec<U>>
+    where
+        U: Send + Unpin,
+        F: FnMut(DB::Row) -> std::result::Result<U, sqlx::Error> + Send,
+        A: IntoArguments<'a, DB> + 'a,
+    {
+        let mut tx = self.begin().await?;
+        let result = query
+            .fetch_all(&mut *tx)
+            .await
+            .map_err(DbError::from)
+            .context("Failed to fetch rows in transaction");
+        finish(tx, result).await
+    }
+
+    /// クロージャを単一トランザクション内で実行します。
+    ///
+    /// クロージャが `Ok` を返した場合はコミットし、`Err` を返した場合はロールバックしてそのエラーを返します。
+    pub async fn with_transaction<T, F>(&self, f: F) -> Result<T>
+    where
+        F: for<'c> FnOnce(&'c mut Transaction<'static, DB>) -> BoxFuture<'c, Result<T>>,
+    {
+        let mut tx = self.begin().await?;
+        let result = f(&mut tx).await;
+        finish(tx, result).await
+    }
+
+    async fn begin(&self) -> Result<Transaction<'static, DB>> {
+        let tx = self
+            .pool
+            .begin()
+            .await
+            .map_err(DbError::from)
+            .context("Failed to start database transaction")?;
+        Ok(tx)
+    }
+}
+
+/// `result` が `Ok` であればコミットし、`Err` であればロールバックしてそのエラーを返します。
+async fn finish<DB: Database, T>(tx: Transaction<'static, DB>, result: Result<T>) -> Result<T> {
+    match result {
+        Ok(value) => {
+            tx.commit()
+                .await
+                .map_err(DbError::from)
+                .context("Failed to commit transaction")?;
+            Ok(value)
+        }
+        Err(error) => {
+            tx.rollback()
+                .await
+                .map_err(DbError::from)
+                .context("Failed to rollback transaction")?;
+            Err(error)
+        }
+    }
+}
+
+/// データベースごとの実行結果から、影響を受けた行数を取り出します。
+///
+/// SQLx の `Database::QueryResult` には共通のトレイトがないため、対応するデータベースごとに実装します。
+pub trait RowsAffected {
+    /// 影響を受けた行数を返します。
+    fn rows_affected(&self) -> u64;
+}
+
+impl RowsAffected for sqlx::postgres::PgQueryResult {
+    fn rows_affected(&self) -> u64 {
+        self.rows_affected()
+    }
+}
+
+#[cfg(feature = "sqlite")]
+impl RowsAffected for sqlx::sqlite::SqliteQueryResult {
+    fn rows_affected(&self) -> u64 {
+        self.rows_affected()
+    }
+}
+
+#[cfg(feature = "mysql")]
+impl RowsAffected for sqlx::mysql::MySqlQueryResult {
+    fn rows_affected(&self) -> u64 {
+        self.rows_affected()
+    }
+}
+
+#[cfg(all(test, feature = "sqlite"))]
+mod tests {
+    use super::*;
+    use sqlx::Row;
+
+    /// 接続ごとに別のデータベースになるため、接続を 1 本に限った SQLite のインメモリデータベースに接続します。
+    async fn connect_memory() -> SqliteConnectionPool {
+        let config = PoolConfig::builder()
+            .database_url("sqlite::memory:")
+            .max_connections(1)
+            .min_connections(1)
+            .build()
+            .unwrap();
+        let connection_pool = SqliteConnectionPool::connect(&config).await.unwrap();
+        connection_pool
+            .executor()
+            .execute_query(sqlx::query(
+                "CREATE TABLE users (id INTEGER PRIMARY KEY, email TEXT NOT NULL UNIQUE)",
+            ))
+            .await
+            .unwrap();
+        connection_pool
+    }
+
+    async fn emails(executor: &SqliteTransactionExecutor) -> Vec<String> {
+        executor
+            .fetch_all(
+                sqlx::query("SELECT email FROM users ORDER BY id")
+                    .try_map(|row: sqlx::sqlite::SqliteRow| row.try_get(0)),
+            )
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn execute_queries_commits_and_returns_rows_affected() {
+        let executor = connect_memory().await.executor();
+
+        let rows_affected = executor
+            .execute_queries(vec![
+                sqlx::query("INSERT INTO users (email) VALUES (?)").bind("a@example.com"),
+                sqlx::query("INSERT INTO users (email) VALUES (?)").bind("b@example.com"),
+                sqlx::query("UPDATE users SET email = email"),
+            ])
+            .await
+            .unwrap();
+
+        assert_eq!(rows_affected, [1, 1, 2]);
+        assert_eq!(emails(&executor).await, ["a@example.com", "b@example.com"]);
+    }
+
+    #[tokio::test]
+    async fn execute_queries_rolls_back_when_a_query_fails() {
+        let executor = connect_memory().await.executor();
+
+        let error = executor
+            .execute_queries(vec![
+                sqlx::query("INSERT INTO users (email) VALUES (?)").bind("a@example.com"),
+                sqlx::query("INSERT INTO users (email) VALUES (?)").bind("a@example.com"),
+            ])
+            .await
+            .unwrap_err();
+
+        assert!(format!("{error:#}").contains("Failed to execute query in transaction at index 1"));
+        assert!(DbError::find(&error).is_some());
+        assert!(emails(&executor).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn fetch_one_returns_none_for_an_empty_result() {
+        let executor = connect_memory().await.executor();
+        executor
+            .execute_query(sqlx::query(
+                "INSERT INTO users (email) VALUES ('a@example.com')",
+            ))
+            .await
+            .unwrap();
+
+        let found: Option<i64> = executor
+            .fetch_one(
+                sqlx::query("SELECT id FROM users WHERE email = ?")
+                    .bind("a@example.com")
+                    .try_map(|row: sqlx::sqlite::SqliteRow| row.try_get(0)),
+            )
+            .await
+            .unwrap();
+        let missing: Option<i64> = executor
+            .fetch_one(
+                sqlx::query("SELECT id FROM users WHERE email = ?")
+                    .bind("b@example.com")
+                    .try_map(|row: sqlx::sqlite::SqliteRow| row.try_get(0)),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(found, Some(1));
+        assert_eq!(missing, None);
+    }
+
+    #[tokio::test]
+    async fn with_transaction_rolls_back_when_the_closure_fails() {
+        let executor = connect_memory().await.executor();
+
+        let error = executor
+            .with_transaction::<(), _>(|tx| {
+                Box::pin(async move {
+                    sqlx::query("INSERT INTO users (email) VALUES ('a@example.com')")
+                        .execute(&mut **tx)
+                        .await?;
+                    anyhow::bail!("abort after insert")
+                })
+            })
+            .await
+            .unwrap_err();
+        executor
+            .with_transaction(|tx| {
+                Box::pin(async move {
+                    sqlx::query("INSERT INTO users (email) VALUES ('b@example.com')")
+                        .execute(&mut **tx)
+                        .await?;
+                    Ok(())
+                })
+            })
+            .await
+            .unwrap();
+
+        assert_eq!(error.to_string(), "abort after insert");
+        assert_eq!(emails(&executor).await, ["b@example.com"]);
+    }
+
+    #[tokio::test]
+    async fn connect_rejects_a_url_for_another_backend() {
+        let config = PoolConfig::builder()
+            .database_url("postgres://user@localhost/app")
+            .build()
+            .unwrap();
+
+        let error = SqliteConnectionPool::connect(&config).await.unwrap_err();
+
+        assert!(
+            error
+                .to_string()
+                .contains("but the pool was requested for SQLite")
+        );
+    }
+
+    #[tokio::test]
+    async fn connect_rejects_postgres_only_settings() {
+        let config = PoolConfig::builder()
+            .database_url("sqlite::memory:")
+            .pgbouncer_mode(true)
+            .build()
+            .unwrap();
+
+        let error = SqliteConnectionPool::connect(&config).await.unwrap_err();
+
+        assert!(
+            error
+                .to_string()
+                .contains("only supported by the PostgreSQL ConnectionPool")
+        );
+    }
+}
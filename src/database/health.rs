@@ -0,0 +1,358 @@
+use crate::database::pool_stats::PoolStats;
+use anyhow::{Result, ensure};
+use std::{future::Future, mem, time::Duration};
+use tokio::{sync::watch, task::JoinHandle, time::MissedTickBehavior};
+
+const DEFAULT_MONITOR_INTERVAL: Duration = Duration::from_secs(10);
+const DEFAULT_MONITOR_TIMEOUT: Duration = Duration::from_secs(5);
+const DEFAULT_DEGRADED_LATENCY: Duration = Duration::from_millis(500);
+const DEFAULT_FAILURE_THRESHOLD: u32 = 3;
+const DEFAULT_RECOVERY_THRESHOLD: u32 = 2;
+
+/// ヘルスチェックの判定です。
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HealthStatus {
+    /// 応答時間がしきい値以内です。
+    Healthy,
+    /// 応答はあるものの、応答時間がしきい値を超えています。
+    Degraded {
+        /// 接続の取得から `SELECT 1` の応答までの時間です。
+        latency: Duration,
+    },
+    /// ヘルスモニタで連続して失敗し、失敗回数が閾値に達しました。`health_check` はこの判定を返しません。
+    Unhealthy {
+        /// 連続して失敗した回数です。
+        consecutive_failures: u32,
+        /// 直近の失敗のエラーです。
+        last_error: String,
+    },
+}
+
+impl HealthStatus {
+    /// `Unhealthy` でないかどうかを返します。
+    pub fn is_available(&self) -> bool {
+        !matches!(self, Self::Unhealthy { .. })
+    }
+}
+
+/// `ConnectionPool::health_check` の結果です。
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HealthReport {
+    /// 応答時間に基づく判定です。
+    pub status: HealthStatus,
+    /// 接続の取得から `SELECT 1` の応答までの時間です。
+    pub latency: Duration,
+    /// `SHOW server_version` で取得したサーバーのバージョンです。
+    pub server_version: String,
+    /// チェック完了時点でプールが閉じられているかどうかです。
+    pub is_closed: bool,
+    /// チェック完了時点のプールの使用状況です。
+    pub pool: PoolStats,
+}
+
+/// `ConnectionPool::spawn_health_monitor` の設定です。
+///
+/// 一時的な失敗で判定が揺れないよう、`failure_threshold` 回連続して失敗して初めて `Unhealthy` とし、
+/// `Unhealthy` からは `recovery_threshold` 回連続して成功して初めて復帰します。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HealthMonitorConfig {
+    interval: Duration,
+    timeout: Duration,
+    degraded_latency: Duration,
+    failure_threshold: u32,
+    recovery_threshold: u32,
+}
+
+impl Default for HealthMonitorConfig {
+    fn default() -> Self {
+        Self {
+            interval: DEFAULT_MONITOR_INTERVAL,
+            timeout: DEFAULT_MONITOR_TIMEOUT,
+            degraded_latency: DEFAULT_DEGRADED_LATENCY,
+            failure_threshold: DEFAULT_FAILURE_THRESHOLD,
+            recovery_threshold: DEFAULT_RECOVERY_THRESHOLD,
+        }
+    }
+}
+
+impl HealthMonitorConfig {
+    /// 既定値（10 秒ごと、タイムアウト 5 秒、500ms 超で `Degraded`、3 回失敗で `Unhealthy`、
+    /// 2 回成功で復帰）の設定を作成します。
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// チェックの間隔を指定します。
+    pub fn with_interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// 1 回のチェックのタイムアウトを指定します。超えた場合は失敗として数えます。
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// 応答時間がこれを超えた場合に `Degraded` と判定します。
+    pub fn with_degraded_latency(mut self, degraded_latency: Duration) -> Self {
+        self.degraded_latency = degraded_latency;
+        self
+    }
+
+    /// `Unhealthy` と判定するまでに連続して失敗する回数を指定します。
+    pub fn with_failure_threshold(mut self, failure_threshold: u32) -> Self {
+        self.failure_threshold = failure_threshold;
+        self
+    }
+
+    /// `Unhealthy` から復帰するまでに連続して成功する回数を指定します。
+    pub fn with_recovery_threshold(mut self, recovery_threshold: u32) -> Self {
+        self.recovery_threshold = recovery_threshold;
+        self
+    }
+
+    /// チェックの間隔を返します。
+    pub fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    /// 1 回のチェックのタイムアウトを返します。
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    /// `Degraded` と判定する応答時間を返します。
+    pub fn degraded_latency(&self) -> Duration {
+        self.degraded_latency
+    }
+
+    /// `Unhealthy` と判定するまでに連続して失敗する回数を返します。
+    pub fn failure_threshold(&self) -> u32 {
+        self.failure_threshold
+    }
+
+    /// `Unhealthy` から復帰するまでに連続して成功する回数を返します。
+    pub fn recovery_threshold(&self) -> u32 {
+        self.recovery_threshold
+    }
+
+    pub(super) fn validate(&self) -> Result<()> {
+        ensure!(!self.interval.is_zero(), "interval must be greater than 0");
+        ensure!(!self.timeout.is_zero(), "timeout must be greater than 0");
+        ensure!(
+            self.failure_threshold > 0,
+            "failure_threshold must be greater than 0"
+        );
+        ensure!(
+            self.recovery_threshold > 0,
+            "recovery_threshold must be greater than 0"
+        );
+        Ok(())
+    }
+}
+
+/// `ConnectionPool::spawn_health_monitor` で起動したヘルスモニタのハンドルです。
+///
+/// `subscribe` で判定の変化を受け取れます。`stop` を呼び出すか、ハンドルを破棄するとタスクは停止し、
+/// 受信側の `changed` はエラーを返します。
+#[derive(Debug)]
+pub struct HealthMonitorHandle {
+    status: watch::Receiver<HealthStatus>,
+    task: JoinHandle<()>,
+}
+
+impl HealthMonitorHandle {
+    /// 判定の変化を受け取る受信側を返します。最初のチェックが終わるまでは `Healthy` です。
+    pub fn subscribe(&self) -> watch::Receiver<HealthStatus> {
+        self.status.clone()
+    }
+
+    /// 現在の判定を返します。
+    pub fn status(&self) -> HealthStatus {
+        self.status.borrow().clone()
+    }
+
+    /// ヘルスモニタを停止します。
+    pub fn stop(self) {
+        self.task.abort();
+    }
+}
+
+impl Drop for HealthMonitorHandle {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// チェックの結果から、連続回数の閾値を考慮した判定を求めます。
+struct Hysteresis {
+    failure_threshold: u32,
+    recovery_threshold: u32,
+    consecutive_failures: u32,
+    consecutive_successes: u32,
+}
+
+impl Hysteresis {
+    /// `current` の判定に 1 回のチェックの結果を反映した判定を返します。
+    ///
+    /// 成功した場合の `checked` は応答時間に基づく判定（`Healthy` か `Degraded`）です。
+    fn apply(&mut self, current: &HealthStatus, checked: Result<HealthStatus>) -> HealthStatus {
+        match checked {
+            Ok(checked) => {
+                self.consecutive_failures = 0;
+                if current.is_available() {
+                    return checked;
+                }
+                self.consecutive_successes += 1;
+                if self.consecutive_successes >= self.recovery_threshold {
+                    self.consecutive_successes = 0;
+                    checked
+                } else {
+                    current.clone()
+                }
+            }
+            Err(error) => {
+                self.consecutive_successes = 0;
+                self.consecutive_failures = self.consecutive_failures.saturating_add(1);
+                tracing::warn!(
+                    consecutive_failures = self.consecutive_failures,
+                    "Health check failed: {error:#}"
+                );
+                if self.consecutive_failures >= self.failure_threshold {
+                    HealthStatus::Unhealthy {
+                        consecutive_failures: self.consecutive_failures,
+                        last_error: format!("{error:#}"),
+                    }
+                } else {
+                    current.clone()
+                }
+            }
+        }
+    }
+}
+
+/// `config.interval` ごとに `check` を実行し、判定を watch チャネルに送るタスクを起動します。
+///
+/// `check` が `None` を返すと（監視対象のプールが破棄・`close` された場合など）タスクは終了します。
+/// `check` のエラーは失敗として数えるだけで、タスクは終了しません。
+pub(super) fn spawn_monitor<C, Fut>(
+    config: HealthMonitorConfig,
+    mut check: C,
+) -> HealthMonitorHandle
+where
+    C: FnMut() -> Fut + Send + 'static,
+    Fut: Future<Output = Option<Result<HealthStatus>>> + Send,
+{
+    let (sender, status) = watch::channel(HealthStatus::Healthy);
+    let task = tokio::spawn(async move {
+        let mut hysteresis = Hysteresis {
+            failure_threshold: config.failure_threshold,
+            recovery_threshold: config.recovery_threshold,
+            consecutive_failures: 0,
+            consecutive_successes: 0,
+        };
+        let mut ticker = tokio::time::interval(config.interval);
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            ticker.tick().await;
+            let Some(checked) = check().await else {
+                break;
+            };
+            let next = hysteresis.apply(&sender.borrow(), checked);
+            sender.send_if_modified(|current| {
+                if *current == next {
+                    return false;
+                }
+                if mem::discriminant(current) != mem::discriminant(&next) {
+                    tracing::info!(from = ?current, to = ?next, "Health status changed");
+                }
+                *current = next;
+                true
+            });
+        }
+    });
+    HealthMonitorHandle { status, task }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::anyhow;
+    use std::{
+        collections::VecDeque,
+        sync::{Arc, Mutex},
+    };
+
+    type Script = Arc<Mutex<VecDeque<Result<HealthStatus>>>>;
+
+    /// 用意した結果を順に返し、尽きたら `None` を返してモニタを終了させるチェックです。
+    fn scripted(
+        results: Vec<Result<HealthStatus>>,
+    ) -> impl FnMut() -> std::future::Ready<Option<Result<HealthStatus>>> + Send + 'static {
+        let script: Script = Arc::new(Mutex::new(results.into()));
+        move || std::future::ready(script.lock().unwrap().pop_front())
+    }
+
+    /// 注入した失敗と成功の連続回数に応じて判定が遷移し、購読側がその変化を順に受け取ることを確認します。
+    #[tokio::test(start_paused = true)]
+    async fn subscribers_observe_transitions_with_hysteresis() {
+        let degraded = HealthStatus::Degraded {
+            latency: Duration::from_millis(800),
+        };
+        let config = HealthMonitorConfig::new()
+            .with_interval(Duration::from_secs(1))
+            .with_failure_threshold(2)
+            .with_recovery_threshold(2);
+        let handle = spawn_monitor(
+            config,
+            scripted(vec![
+                Ok(HealthStatus::Healthy),
+                // 1 回目の失敗では閾値に達しないため、判定は変わりません。
+                Err(anyhow!("connection refused")),
+                Ok(HealthStatus::Healthy),
+                Err(anyhow!("connection refused")),
+                Err(anyhow!("connection reset")),
+                // 復帰には 2 回連続の成功が必要です。
+                Ok(HealthStatus::Healthy),
+                Ok(HealthStatus::Healthy),
+                Ok(degraded.clone()),
+            ]),
+        );
+        let mut status = handle.subscribe();
+        assert_eq!(*status.borrow(), HealthStatus::Healthy);
+
+        status.changed().await.unwrap();
+        assert_eq!(
+            *status.borrow_and_update(),
+            HealthStatus::Unhealthy {
+                consecutive_failures: 2,
+                last_error: "connection reset".to_string(),
+            }
+        );
+        assert!(!handle.status().is_available());
+
+        status.changed().await.unwrap();
+        assert_eq!(*status.borrow_and_update(), HealthStatus::Healthy);
+
+        status.changed().await.unwrap();
+        assert_eq!(*status.borrow_and_update(), degraded);
+
+        // チェックが `None` を返すとタスクが終了し、`changed` はエラーを返します。
+        assert!(status.changed().await.is_err());
+        assert_eq!(handle.status(), degraded);
+    }
+
+    /// `stop` を呼び出すとタスクが停止し、購読側の `changed` がエラーを返すことを確認します。
+    #[tokio::test(start_paused = true)]
+    async fn stop_ends_the_subscription() {
+        let handle = spawn_monitor(
+            HealthMonitorConfig::new().with_interval(Duration::from_secs(1)),
+            || std::future::ready(Some(Ok(HealthStatus::Healthy))),
+        );
+        let mut status = handle.subscribe();
+        handle.stop();
+        assert!(status.changed().await.is_err());
+        assert_eq!(*status.borrow(), HealthStatus::Healthy);
+    }
+}
@@ -0,0 +1,76 @@
+use std::{
+    sync::{
+        Arc,
+        atomic::{AtomicBool, AtomicU64, Ordering},
+    },
+    time::{Duration, Instant},
+};
+use tokio::task::JoinHandle;
+
+/// 保持時間を監視するトランザクションに振る、プロセス内で単調増加する ID の払い出し元です。
+static TRANSACTION_ID_SEQUENCE: AtomicU64 = AtomicU64::new(1);
+
+/// 開いたままのトランザクションを監視し、保持時間が閾値を超えた時点で警告ログを出力します。
+///
+/// 閾値を超えるたびにバックグラウンドのタスクが実行中のまま警告するため、接続を長時間占有している
+/// トランザクションを終了前に特定できます。警告した場合は、破棄（コミット・ロールバック・ガードの破棄）の
+/// 時点で同じ ID を含む完了ログを出力し、両者を対応付けられるようにします。
+pub(super) struct HoldWatchdog {
+    id: u64,
+    label: Option<String>,
+    started_at: Instant,
+    warned: Arc<AtomicBool>,
+    task: JoinHandle<()>,
+}
+
+impl HoldWatchdog {
+    /// 監視を開始します。`threshold` が `None` または 0 の場合は監視しません。
+    pub(super) fn start(threshold: Option<Duration>, label: Option<&str>) -> Option<Self> {
+        let threshold = threshold.filter(|threshold| !threshold.is_zero())?;
+        let id = TRANSACTION_ID_SEQUENCE.fetch_add(1, Ordering::Relaxed);
+        let label = label.map(str::to_string);
+        let started_at = Instant::now();
+        let warned = Arc::new(AtomicBool::new(false));
+        let task = tokio::spawn({
+            let label = label.clone();
+            let warned = Arc::clone(&warned);
+            async move {
+                let mut interval = tokio::time::interval(threshold);
+                // 初回の tick は即座に完了するため読み捨てます。
+                interval.tick().await;
+                loop {
+                    interval.tick().await;
+                    warned.store(true, Ordering::Relaxed);
+                    tracing::warn!(
+                        transaction_id = id,
+                        label = label.as_deref(),
+                        held_for = ?started_at.elapsed(),
+                        ?threshold,
+                        "Transaction held open longer than threshold"
+                    );
+                }
+            }
+        });
+        Some(Self {
+            id,
+            label,
+            started_at,
+            warned,
+            task,
+        })
+    }
+}
+
+impl Drop for HoldWatchdog {
+    fn drop(&mut self) {
+        self.task.abort();
+        if self.warned.load(Ordering::Relaxed) {
+            tracing::warn!(
+                transaction_id = self.id,
+                label = self.label.as_deref(),
+                held_for = ?self.started_at.elapsed(),
+                "Long-held transaction finished"
+            );
+        }
+    }
+}
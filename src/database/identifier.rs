@@ -0,0 +1,25 @@
+use anyhow::{Result, ensure};
+
+/// SQL 識別子を二重引用符で囲み、SQL 文へ埋め込める形に変換します。
+///
+/// 識別子内の二重引用符はエスケープされるため、任意の文字列を安全に埋め込めます。
+/// 空文字列と NUL 文字を含む識別子は PostgreSQL で扱えないためエラーにします。
+pub fn quote_identifier(identifier: &str) -> Result<String> {
+    ensure!(!identifier.is_empty(), "SQL identifier must not be empty");
+    ensure!(
+        !identifier.contains('\0'),
+        "SQL identifier must not contain NUL characters"
+    );
+    Ok(format!("\"{}\"", identifier.replace('"', "\"\"")))
+}
+
+/// `schema.table` 形式の名前を `.` で分割し、各要素を識別子として引用符で囲みます。
+///
+/// `.` を含む識別子そのものは表現できないため、その場合は `quote_identifier` を使ってください。
+pub fn quote_qualified_identifier(name: &str) -> Result<String> {
+    let parts = name
+        .split('.')
+        .map(quote_identifier)
+        .collect::<Result<Vec<_>>>()?;
+    Ok(parts.join("."))
+}
@@ -0,0 +1,281 @@
+use crate::database::{metrics, transaction_options::TransactionOptions};
+use std::{fmt, future::Future, time::Duration};
+use tracing::{Instrument, Span};
+
+/// トランザクションの結果としてスパンに記録する値です。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum Outcome {
+    Committed,
+    RolledBack,
+}
+
+impl Outcome {
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Committed => "committed",
+            Self::RolledBack => "rolled_back",
+        }
+    }
+}
+
+/// トランザクション全体を表す `db.transaction` スパンを作成します。
+///
+/// `tracing-spans` フィーチャー（既定で有効）を無効にした場合は、スパンを作成せず `Span::none()` を返します。
+/// 警告ログなどのイベントはフィーチャーにかかわらず出力されます。
+#[cfg(feature = "tracing-spans")]
+pub(super) fn transaction_span(options: &TransactionOptions, label: Option<&str>) -> Span {
+    tracing::info_span!(
+        "db.transaction",
+        label,
+        isolation_level = options.isolation.map(|level| level.as_sql()),
+        read_only = options.read_only,
+        statement_count = tracing::field::Empty,
+        outcome = tracing::field::Empty,
+        duration_ms = tracing::field::Empty,
+        error = tracing::field::Empty,
+    )
+}
+
+#[cfg(not(feature = "tracing-spans"))]
+pub(super) fn transaction_span(_options: &TransactionOptions, _label: Option<&str>) -> Span {
+    Span::none()
+}
+
+/// トランザクション内の 1 ステートメントを表す `db.statement` スパンを `parent` の子として作成します。
+///
+/// `parent` が無効なスパンの場合は、ルートスパンを作らないよう無効なスパンを返します。
+pub(super) fn statement_span(parent: &Span, index: usize) -> Span {
+    if parent.is_none() {
+        return Span::none();
+    }
+    tracing::info_span!(
+        parent: parent,
+        "db.statement",
+        index,
+        rows_affected = tracing::field::Empty,
+        error = tracing::field::Empty,
+    )
+}
+
+/// トランザクション外の単独のクエリを表す `db.query` スパンを作成します。
+#[cfg(feature = "tracing-spans")]
+fn query_span() -> Span {
+    tracing::info_span!("db.query", error = tracing::field::Empty)
+}
+
+#[cfg(not(feature = "tracing-spans"))]
+fn query_span() -> Span {
+    Span::none()
+}
+
+/// 単独のクエリを `db.query` スパン内で実行し、失敗した場合はエラーをスパンに記録します。
+pub(super) async fn traced_query<T>(
+    future: impl Future<Output = sqlx::Result<T>>,
+) -> sqlx::Result<T> {
+    let span = query_span();
+    metrics::record_statement();
+    let result = future.instrument(span.clone()).await;
+    if let Err(error) = &result {
+        record_error(&span, error);
+    }
+    result
+}
+
+/// トランザクションの結果をスパンとメトリクスに記録します。
+pub(super) fn record_outcome(
+    span: &Span,
+    outcome: Outcome,
+    statement_count: usize,
+    duration: Duration,
+) {
+    span.record("outcome", outcome.as_str());
+    span.record("statement_count", statement_count);
+    span.record(
+        "duration_ms",
+        u64::try_from(duration.as_millis()).unwrap_or(u64::MAX),
+    );
+    metrics::record_transaction(outcome.as_str(), duration);
+}
+
+/// エラーをスパンの `error` フィールドに記録します。
+pub(super) fn record_error(span: &Span, error: impl fmt::Display) {
+    span.record("error", tracing::field::display(error));
+}
+
+#[cfg(all(test, feature = "tracing-spans"))]
+mod tests {
+    use crate::database::{testing, transaction_executor::TransactionExecutor};
+    use std::{
+        collections::BTreeMap,
+        fmt,
+        sync::{Arc, Mutex},
+    };
+    use tracing::{
+        Subscriber,
+        field::{Field, Visit},
+        span::{Attributes, Id, Record},
+    };
+    use tracing_subscriber::{
+        Layer,
+        layer::{Context, SubscriberExt},
+        registry::LookupSpan,
+    };
+
+    /// 記録されたスパン 1 つ分の名前、親スパンの名前、フィールドの値です。
+    #[derive(Debug, Clone)]
+    struct RecordedSpan {
+        name: &'static str,
+        parent: Option<&'static str>,
+        fields: BTreeMap<&'static str, String>,
+    }
+
+    struct FieldVisitor<'a>(&'a mut BTreeMap<&'static str, String>);
+
+    impl Visit for FieldVisitor<'_> {
+        fn record_str(&mut self, field: &Field, value: &str) {
+            self.0.insert(field.name(), value.to_string());
+        }
+
+        fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+            self.0.insert(field.name(), format!("{value:?}"));
+        }
+    }
+
+    /// 作成されたスパンと、後から記録されたフィールドを保持するレイヤーです。
+    #[derive(Clone, Default)]
+    struct SpanRecorder {
+        spans: Arc<Mutex<Vec<RecordedSpan>>>,
+    }
+
+    impl SpanRecorder {
+        fn spans(&self) -> Vec<RecordedSpan> {
+            self.spans.lock().unwrap().clone()
+        }
+    }
+
+    impl<S> Layer<S> for SpanRecorder
+    where
+        S: Subscriber + for<'a> LookupSpan<'a>,
+    {
+        fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+            let span = ctx.span(id).expect("new span is registered");
+            let mut recorded = RecordedSpan {
+                name: attrs.metadata().name(),
+                parent: span.parent().map(|parent| parent.name()),
+                fields: BTreeMap::new(),
+            };
+            attrs.record(&mut FieldVisitor(&mut recorded.fields));
+            let mut spans = self.spans.lock().unwrap();
+            span.extensions_mut().insert(spans.len());
+            spans.push(recorded);
+        }
+
+        fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+            let span = ctx.span(id).expect("recorded span is registered");
+            let Some(&index) = span.extensions().get::<usize>() else {
+                return;
+            };
+            values.record(&mut FieldVisitor(
+                &mut self.spans.lock().unwrap()[index].fields,
+            ));
+        }
+    }
+
+    /// `name` のスパンをすべて返します。
+    fn named<'a>(spans: &'a [RecordedSpan], name: &str) -> Vec<&'a RecordedSpan> {
+        spans.iter().filter(|span| span.name == name).collect()
+    }
+
+    #[tokio::test]
+    #[ignore = "requires a PostgreSQL database (TEST_DATABASE_URL)"]
+    async fn committed_transaction_records_its_statements_and_outcome() {
+        let pool = testing::pool().await;
+        let table = testing::unique_table("span_commit");
+        sqlx::query(&format!("CREATE TABLE {table} (id int)"))
+            .execute(&pool)
+            .await
+            .unwrap();
+        let recorder = SpanRecorder::default();
+        let guard =
+            tracing::subscriber::set_default(tracing_subscriber::registry().with(recorder.clone()));
+
+        TransactionExecutor::new(pool.clone())
+            .execute_queries([
+                sqlx::query(&format!("INSERT INTO {table} VALUES (1)")),
+                sqlx::query(&format!("INSERT INTO {table} VALUES (2), (3)")),
+            ])
+            .await
+            .unwrap();
+
+        drop(guard);
+        sqlx::query(&format!("DROP TABLE {table}"))
+            .execute(&pool)
+            .await
+            .unwrap();
+        let spans = recorder.spans();
+        let transactions = named(&spans, "db.transaction");
+        assert_eq!(transactions.len(), 1, "{spans:#?}");
+        let transaction = &transactions[0].fields;
+        assert_eq!(transaction["outcome"], "committed");
+        assert_eq!(transaction["statement_count"], "2");
+        assert!(transaction.contains_key("duration_ms"));
+        assert!(!transaction.contains_key("error"));
+        let statements = named(&spans, "db.statement");
+        assert_eq!(statements.len(), 2, "{spans:#?}");
+        for (index, (statement, rows_affected)) in statements.iter().zip(["1", "2"]).enumerate() {
+            assert_eq!(statement.parent, Some("db.transaction"));
+            assert_eq!(statement.fields["index"], index.to_string());
+            assert_eq!(statement.fields["rows_affected"], rows_affected);
+            assert!(!statement.fields.contains_key("error"));
+        }
+    }
+
+    #[tokio::test]
+    #[ignore = "requires a PostgreSQL database (TEST_DATABASE_URL)"]
+    async fn rolled_back_transaction_records_the_failing_statement_and_error() {
+        let pool = testing::pool().await;
+        let table = testing::unique_table("span_rollback");
+        sqlx::query(&format!("CREATE TABLE {table} (id int)"))
+            .execute(&pool)
+            .await
+            .unwrap();
+        let recorder = SpanRecorder::default();
+        let guard =
+            tracing::subscriber::set_default(tracing_subscriber::registry().with(recorder.clone()));
+
+        TransactionExecutor::new(pool.clone())
+            .execute_queries([
+                sqlx::query(&format!("INSERT INTO {table} VALUES (1)")),
+                sqlx::query(&format!("INSERT INTO {table}_missing VALUES (2)")),
+            ])
+            .await
+            .unwrap_err();
+
+        drop(guard);
+        sqlx::query(&format!("DROP TABLE {table}"))
+            .execute(&pool)
+            .await
+            .unwrap();
+        let spans = recorder.spans();
+        let transactions = named(&spans, "db.transaction");
+        assert_eq!(transactions.len(), 1, "{spans:#?}");
+        let transaction = &transactions[0].fields;
+        assert_eq!(transaction["outcome"], "rolled_back");
+        assert_eq!(transaction["statement_count"], "1");
+        assert!(
+            transaction["error"].contains("does not exist"),
+            "{transaction:?}"
+        );
+        let statements = named(&spans, "db.statement");
+        assert_eq!(statements.len(), 2, "{spans:#?}");
+        assert_eq!(statements[0].fields["rows_affected"], "1");
+        assert!(!statements[0].fields.contains_key("error"));
+        assert_eq!(statements[1].fields["index"], "1");
+        assert!(!statements[1].fields.contains_key("rows_affected"));
+        assert!(
+            statements[1].fields["error"].contains("does not exist"),
+            "{:?}",
+            statements[1].fields
+        );
+    }
+}
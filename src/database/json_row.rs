@@ -0,0 +1,243 @@
+use anyhow::{Result, bail};
+use base64::{Engine, engine::general_purpose::STANDARD as BASE64};
+use chrono::{DateTime, NaiveDate, NaiveDateTime, NaiveTime, Utc};
+use serde_json::{Map, Number, Value};
+use sqlx::{
+    Column, Decode, Postgres, Row, Type, TypeInfo, ValueRef,
+    error::BoxDynError,
+    postgres::{PgHasArrayType, PgRow, PgTypeInfo, PgValueFormat, PgValueRef},
+};
+use std::fmt::Write;
+use uuid::Uuid;
+
+/// 数値型（`numeric`）の符号を表すバイナリ形式の値です。
+const NUMERIC_NEGATIVE: u16 = 0x4000;
+const NUMERIC_NAN: u16 = 0xC000;
+const NUMERIC_POSITIVE_INFINITY: u16 = 0xD000;
+const NUMERIC_NEGATIVE_INFINITY: u16 = 0xF000;
+
+/// 行の各列を型に応じて JSON の値に変換し、列名をキーとするマップを返します。
+///
+/// 同じ名前の列が複数ある場合は、最初の列が元の名前を使い、以降の列には `_2`、`_3` と出現順に
+/// 接尾辞を付けます（付けた名前が既存の列名と衝突する場合はさらに番号を進めます）。
+/// 対応していない型の列は、`strict` が `false` の場合はテキスト表現に、`true` の場合はエラーにします。
+pub(super) fn row_to_json(row: &PgRow, strict: bool) -> Result<Map<String, Value>> {
+    let mut map = Map::new();
+    for (index, column) in row.columns().iter().enumerate() {
+        let name = column.name();
+        let value = column_value(row, index, column.type_info(), strict)?;
+        let mut key = name.to_string();
+        let mut suffix = 2;
+        while map.contains_key(&key) {
+            key = format!("{name}_{suffix}");
+            suffix += 1;
+        }
+        map.insert(key, value);
+    }
+    Ok(map)
+}
+
+fn column_value(row: &PgRow, index: usize, type_info: &PgTypeInfo, strict: bool) -> Result<Value> {
+    let value = match type_info.name() {
+        "BOOL" => scalar(row, index, Value::Bool)?,
+        "INT2" => scalar(row, index, |value: i16| Value::from(value))?,
+        "INT4" => scalar(row, index, |value: i32| Value::from(value))?,
+        "INT8" => scalar(row, index, |value: i64| Value::from(value))?,
+        "FLOAT4" => scalar(row, index, |value: f32| float(f64::from(value)))?,
+        "FLOAT8" => scalar(row, index, float)?,
+        "NUMERIC" => scalar(row, index, |value: NumericText| Value::String(value.0))?,
+        "TEXT" | "VARCHAR" | "CHAR" | "NAME" => scalar(row, index, Value::String)?,
+        "BYTEA" => scalar(row, index, |value: Vec<u8>| bytes(&value))?,
+        "TIMESTAMPTZ" => scalar(row, index, timestamptz)?,
+        "TIMESTAMP" => scalar(row, index, timestamp)?,
+        "DATE" => scalar(row, index, |value: NaiveDate| {
+            Value::String(value.to_string())
+        })?,
+        "TIME" => scalar(row, index, |value: NaiveTime| {
+            Value::String(value.to_string())
+        })?,
+        "UUID" => scalar(row, index, |value: Uuid| Value::String(value.to_string()))?,
+        "JSON" | "JSONB" => scalar(row, index, |value: Value| value)?,
+        "BOOL[]" => array(row, index, Value::Bool)?,
+        "INT2[]" => array(row, index, |value: i16| Value::from(value))?,
+        "INT4[]" => array(row, index, |value: i32| Value::from(value))?,
+        "INT8[]" => array(row, index, |value: i64| Value::from(value))?,
+        "FLOAT4[]" => array(row, index, |value: f32| float(f64::from(value)))?,
+        "FLOAT8[]" => array(row, index, float)?,
+        "NUMERIC[]" => array(row, index, |value: NumericText| Value::String(value.0))?,
+        "TEXT[]" | "VARCHAR[]" | "CHAR[]" | "NAME[]" => array(row, index, Value::String)?,
+        "BYTEA[]" => array(row, index, |value: Vec<u8>| bytes(&value))?,
+        "TIMESTAMPTZ[]" => array(row, index, timestamptz)?,
+        "TIMESTAMP[]" => array(row, index, timestamp)?,
+        "DATE[]" => array(row, index, |value: NaiveDate| {
+            Value::String(value.to_string())
+        })?,
+        "TIME[]" => array(row, index, |value: NaiveTime| {
+            Value::String(value.to_string())
+        })?,
+        "UUID[]" => array(row, index, |value: Uuid| Value::String(value.to_string()))?,
+        "JSON[]" | "JSONB[]" => array(row, index, |value: Value| value)?,
+        name if strict => bail!(
+            "Unsupported type {name} for column {}",
+            row.columns()[index].name()
+        ),
+        _ => fallback(row, index)?,
+    };
+    Ok(value)
+}
+
+/// 単一の値の列を `Option<T>` として読み、NULL 以外を `convert` で変換します。
+fn scalar<'r, T, F>(row: &'r PgRow, index: usize, convert: F) -> sqlx::Result<Value>
+where
+    T: Decode<'r, Postgres> + Type<Postgres>,
+    F: Fn(T) -> Value,
+{
+    Ok(row
+        .try_get::<Option<T>, _>(index)?
+        .map_or(Value::Null, convert))
+}
+
+/// 1 次元配列の列を読み、要素ごとに `convert` で変換します。NULL の要素は `null` になります。
+fn array<T, F>(row: &PgRow, index: usize, convert: F) -> sqlx::Result<Value>
+where
+    T: for<'r> Decode<'r, Postgres> + Type<Postgres> + PgHasArrayType,
+    F: Fn(T) -> Value,
+{
+    Ok(row
+        .try_get::<Option<Vec<Option<T>>>, _>(index)?
+        .map_or(Value::Null, |values| {
+            Value::Array(
+                values
+                    .into_iter()
+                    .map(|value| value.map_or(Value::Null, &convert))
+                    .collect(),
+            )
+        }))
+}
+
+/// 対応していない型の値をテキスト表現に変換します。
+///
+/// バイナリ形式で受信した値は UTF-8 として解釈し（列挙型や `citext` などはこれで元の文字列になります）、
+/// 解釈できない場合は `bytea` のテキスト出力と同じ `\x` で始まる 16 進表現にします。
+fn fallback(row: &PgRow, index: usize) -> sqlx::Result<Value> {
+    let value = row.try_get_raw(index)?;
+    if value.is_null() {
+        return Ok(Value::Null);
+    }
+    let text = match value.format() {
+        PgValueFormat::Text => value.as_str().map(str::to_string),
+        PgValueFormat::Binary => value
+            .as_bytes()
+            .map(|bytes| match std::str::from_utf8(bytes) {
+                Ok(text) => text.to_string(),
+                Err(_) => bytes.iter().fold("\\x".to_string(), |mut hex, byte| {
+                    let _ = write!(hex, "{byte:02x}");
+                    hex
+                }),
+            }),
+    }
+    .map_err(|source| sqlx::Error::ColumnDecode {
+        index: index.to_string(),
+        source,
+    })?;
+    Ok(Value::String(text))
+}
+
+/// 浮動小数点数を変換します。JSON で表せない `NaN` と無限大は文字列にします。
+fn float(value: f64) -> Value {
+    Number::from_f64(value).map_or_else(|| Value::String(value.to_string()), Value::Number)
+}
+
+fn bytes(value: &[u8]) -> Value {
+    Value::String(BASE64.encode(value))
+}
+
+fn timestamptz(value: DateTime<Utc>) -> Value {
+    Value::String(value.to_rfc3339())
+}
+
+fn timestamp(value: NaiveDateTime) -> Value {
+    Value::String(value.format("%Y-%m-%dT%H:%M:%S%.f").to_string())
+}
+
+/// `numeric` の値を精度を落とさずに 10 進数の文字列として読むための型です。
+struct NumericText(String);
+
+impl Type<Postgres> for NumericText {
+    fn type_info() -> PgTypeInfo {
+        PgTypeInfo::with_name("numeric")
+    }
+}
+
+impl PgHasArrayType for NumericText {
+    fn array_type_info() -> PgTypeInfo {
+        PgTypeInfo::with_name("_numeric")
+    }
+}
+
+impl<'r> Decode<'r, Postgres> for NumericText {
+    fn decode(value: PgValueRef<'r>) -> Result<Self, BoxDynError> {
+        match value.format() {
+            PgValueFormat::Text => Ok(Self(value.as_str()?.to_string())),
+            PgValueFormat::Binary => decode_numeric(value.as_bytes()?).map(Self),
+        }
+    }
+}
+
+/// バイナリ形式の `numeric`（桁数、重み、符号、表示桁数と、1 万進数の各桁）を 10 進数の文字列に変換します。
+fn decode_numeric(bytes: &[u8]) -> Result<String, BoxDynError> {
+    let read = |offset: usize| -> Result<u16, BoxDynError> {
+        let pair = bytes
+            .get(offset..offset + 2)
+            .ok_or("numeric value is truncated")?;
+        Ok(u16::from_be_bytes([pair[0], pair[1]]))
+    };
+    let digit_count = usize::from(read(0)?);
+    let weight = i64::from(read(2)? as i16);
+    let sign = read(4)?;
+    let scale = usize::from(read(6)?);
+    let digits = (0..digit_count)
+        .map(|position| read(8 + position * 2))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    match sign {
+        NUMERIC_NAN => return Ok("NaN".to_string()),
+        NUMERIC_POSITIVE_INFINITY => return Ok("Infinity".to_string()),
+        NUMERIC_NEGATIVE_INFINITY => return Ok("-Infinity".to_string()),
+        _ => {}
+    }
+    let digit = |position: i64| {
+        usize::try_from(position)
+            .ok()
+            .and_then(|position| digits.get(position).copied())
+            .unwrap_or(0)
+    };
+
+    let mut text = String::new();
+    if sign == NUMERIC_NEGATIVE {
+        text.push('-');
+    }
+    if weight < 0 {
+        text.push('0');
+    } else {
+        for position in 0..=weight {
+            if position == 0 {
+                write!(text, "{}", digit(position))?;
+            } else {
+                write!(text, "{:04}", digit(position))?;
+            }
+        }
+    }
+    if scale > 0 {
+        let mut fraction = String::new();
+        let mut position = weight + 1;
+        while fraction.len() < scale {
+            write!(fraction, "{:04}", digit(position))?;
+            position += 1;
+        }
+        fraction.truncate(scale);
+        text.push('.');
+        text.push_str(&fraction);
+    }
+    Ok(text)
+}
@@ -0,0 +1,81 @@
+use crate::database::error::DbError;
+use anyhow::{Context, Result, ensure};
+use futures_util::{Stream, stream};
+use sqlx::{PgPool, postgres::PgListener};
+use std::{
+    pin::Pin,
+    task::{Context as TaskContext, Poll},
+};
+
+/// `LISTEN` で受信した通知です。
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Notification {
+    /// 通知を受信したチャネル名です。
+    pub channel: String,
+    /// `NOTIFY` で送られたペイロードです。ペイロードを指定しなかった場合は空文字列です。
+    pub payload: String,
+}
+
+/// `ConnectionPool::listen` が返す通知のストリームです。
+///
+/// 購読したすべてのチャネルの通知が 1 本のストリームに流れるため、チャネルごとに処理を分ける場合は
+/// `Notification::channel` で振り分けてください。接続が切れた場合は次の受信時に自動で再接続して
+/// 購読し直します。切断中に送られた通知は受信できないため、再接続のたびに警告ログを出力します。
+/// 取りこぼしを許容できない場合は、警告をきっかけに状態を読み直してください。
+pub struct NotificationStream {
+    inner: Pin<Box<dyn Stream<Item = Result<Notification>> + Send>>,
+}
+
+impl NotificationStream {
+    fn new(listener: PgListener) -> Self {
+        let inner = stream::unfold(listener, |mut listener| async move {
+            loop {
+                match listener.try_recv().await {
+                    Ok(Some(notification)) => {
+                        let notification = Notification {
+                            channel: notification.channel().to_string(),
+                            payload: notification.payload().to_string(),
+                        };
+                        return Some((Ok(notification), listener));
+                    }
+                    Ok(None) => {
+                        tracing::warn!(
+                            "Lost the LISTEN connection; reconnecting. Notifications sent while disconnected were missed"
+                        );
+                    }
+                    Err(error) => {
+                        let error =
+                            Err(DbError::from(error)).context("Failed to receive notification");
+                        return Some((error, listener));
+                    }
+                }
+            }
+        });
+        Self {
+            inner: Box::pin(inner),
+        }
+    }
+}
+
+impl Stream for NotificationStream {
+    type Item = Result<Notification>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<Option<Self::Item>> {
+        self.inner.as_mut().poll_next(cx)
+    }
+}
+
+/// `pool` の接続で `channels` を購読し、通知のストリームを返します。
+pub(super) async fn listen(pool: &PgPool, channels: &[&str]) -> Result<NotificationStream> {
+    ensure!(!channels.is_empty(), "At least one channel must be given");
+    let mut listener = PgListener::connect_with(pool)
+        .await
+        .map_err(DbError::from)
+        .context("Failed to connect listener")?;
+    listener
+        .listen_all(channels.iter().copied())
+        .await
+        .map_err(DbError::from)
+        .with_context(|| format!("Failed to listen on channels {channels:?}"))?;
+    Ok(NotificationStream::new(listener))
+}
@@ -0,0 +1,216 @@
+#[cfg(feature = "metrics")]
+use crate::database::error::DbError;
+use sqlx::PgPool;
+use std::time::Duration;
+
+/// トランザクションの所要時間（秒）のヒストグラムです。`outcome` ラベルを持ちます。
+pub const TRANSACTION_DURATION_SECONDS: &str = "db_transaction_duration_seconds";
+/// 実行したステートメント数のカウンターです。
+pub const STATEMENTS_TOTAL: &str = "db_statements_total";
+/// エラーによるロールバック数のカウンターです。`error_kind` ラベルを持ちます。
+pub const ROLLBACKS_TOTAL: &str = "db_rollbacks_total";
+/// 接続プールからの接続取得の待ち時間（秒）のヒストグラムです。
+pub const POOL_ACQUIRE_WAIT_SECONDS: &str = "db_pool_acquire_wait_seconds";
+/// 接続プールが開いている接続数のゲージです。
+pub const POOL_SIZE: &str = "db_pool_size";
+/// 接続プールのアイドル接続数のゲージです。
+pub const POOL_IDLE: &str = "db_pool_idle";
+
+/// トランザクションの結果を表すラベル名です。値は `committed` または `rolled_back` です。
+pub const LABEL_OUTCOME: &str = "outcome";
+/// ロールバックの原因を表すラベル名です。値は `DbError::kind` が返す文字列で、
+/// `DbError` を含まないアプリケーション側のエラーは `application`、
+/// コミットもロールバックもされずに破棄されたトランザクションは `dropped` です。
+pub const LABEL_ERROR_KIND: &str = "error_kind";
+
+/// `DbError` を含まないエラーでロールバックした場合の `error_kind` です。
+pub const ERROR_KIND_APPLICATION: &str = "application";
+/// コミットもロールバックもされずに破棄された場合の `error_kind` です。
+pub const ERROR_KIND_DROPPED: &str = "dropped";
+
+/// トランザクションの所要時間を記録します。
+#[cfg(feature = "metrics")]
+pub(super) fn record_transaction(outcome: &'static str, duration: Duration) {
+    ::metrics::histogram!(TRANSACTION_DURATION_SECONDS, LABEL_OUTCOME => outcome)
+        .record(duration.as_secs_f64());
+}
+
+#[cfg(not(feature = "metrics"))]
+#[inline(always)]
+pub(super) fn record_transaction(_outcome: &'static str, _duration: Duration) {}
+
+/// ステートメントを 1 件実行したことを記録します。
+#[cfg(feature = "metrics")]
+pub(super) fn record_statement() {
+    ::metrics::counter!(STATEMENTS_TOTAL).increment(1);
+}
+
+#[cfg(not(feature = "metrics"))]
+#[inline(always)]
+pub(super) fn record_statement() {}
+
+/// 原因の種別を指定してロールバックを記録します。
+#[cfg(feature = "metrics")]
+pub(super) fn record_rollback(error_kind: &'static str) {
+    ::metrics::counter!(ROLLBACKS_TOTAL, LABEL_ERROR_KIND => error_kind).increment(1);
+}
+
+#[cfg(not(feature = "metrics"))]
+#[inline(always)]
+pub(super) fn record_rollback(_error_kind: &'static str) {}
+
+/// エラーのチェーンから原因の種別を判別してロールバックを記録します。
+#[cfg(feature = "metrics")]
+pub(super) fn record_rollback_error(error: &anyhow::Error) {
+    record_rollback(DbError::find(error).map_or(ERROR_KIND_APPLICATION, DbError::kind));
+}
+
+#[cfg(not(feature = "metrics"))]
+#[inline(always)]
+pub(super) fn record_rollback_error(_error: &anyhow::Error) {}
+
+/// 接続取得の待ち時間を記録し、プールの接続数をサンプリングします。
+#[cfg(feature = "metrics")]
+pub(super) fn record_acquire(pool: &PgPool, wait: Duration) {
+    ::metrics::histogram!(POOL_ACQUIRE_WAIT_SECONDS).record(wait.as_secs_f64());
+    ::metrics::gauge!(POOL_SIZE).set(f64::from(pool.size()));
+    ::metrics::gauge!(POOL_IDLE).set(pool.num_idle() as f64);
+}
+
+#[cfg(not(feature = "metrics"))]
+#[inline(always)]
+pub(super) fn record_acquire(_pool: &PgPool, _wait: Duration) {}
+
+#[cfg(all(test, feature = "metrics"))]
+mod tests {
+    use super::*;
+    use crate::database::{testing, transaction_executor::TransactionExecutor};
+    use anyhow::bail;
+    use metrics_util::{
+        MetricKind,
+        debugging::{DebugValue, DebuggingRecorder},
+    };
+
+    /// メトリクスの種類、名前、ラベル、値の組です。
+    type Metric = (MetricKind, String, Vec<(String, String)>, DebugValue);
+
+    /// スナップショットから名前とラベルが一致するメトリクスの値を返します。
+    fn value<'a>(
+        snapshot: &'a [Metric],
+        kind: MetricKind,
+        name: &str,
+        labels: &[(&str, &str)],
+    ) -> Option<&'a DebugValue> {
+        snapshot
+            .iter()
+            .find_map(|(metric_kind, metric_name, metric_labels, metric_value)| {
+                let matches = *metric_kind == kind
+                    && metric_name == name
+                    && metric_labels.len() == labels.len()
+                    && labels.iter().all(|(key, value)| {
+                        metric_labels.contains(&(key.to_string(), value.to_string()))
+                    });
+                matches.then_some(metric_value)
+            })
+    }
+
+    #[tokio::test]
+    #[ignore = "requires a PostgreSQL database (TEST_DATABASE_URL)"]
+    async fn transactions_record_the_documented_metric_keys() {
+        let pool = testing::pool().await;
+        let table = testing::unique_table("metrics");
+        sqlx::query(&format!("CREATE TABLE {table} (id int PRIMARY KEY)"))
+            .execute(&pool)
+            .await
+            .unwrap();
+        let recorder = DebuggingRecorder::new();
+        let snapshotter = recorder.snapshotter();
+        let guard = ::metrics::set_default_local_recorder(&recorder);
+        let executor = TransactionExecutor::new(pool.clone());
+        let insert = format!("INSERT INTO {table} VALUES (1)");
+
+        executor
+            .execute_queries([sqlx::query(&insert)])
+            .await
+            .unwrap();
+        executor
+            .execute_queries([sqlx::query(&insert)])
+            .await
+            .unwrap_err();
+        executor
+            .with_transaction::<(), _>(|_| Box::pin(async { bail!("application failure") }))
+            .await
+            .unwrap_err();
+
+        drop(guard);
+        sqlx::query(&format!("DROP TABLE {table}"))
+            .execute(&pool)
+            .await
+            .unwrap();
+        let snapshot: Vec<Metric> = snapshotter
+            .snapshot()
+            .into_vec()
+            .into_iter()
+            .map(|(key, _, _, value)| {
+                let (kind, key) = key.into_parts();
+                let labels = key
+                    .labels()
+                    .map(|label| (label.key().to_string(), label.value().to_string()))
+                    .collect();
+                (kind, key.name().to_string(), labels, value)
+            })
+            .collect();
+        let histogram_len = |name, labels: &[(&str, &str)]| match value(
+            &snapshot,
+            MetricKind::Histogram,
+            name,
+            labels,
+        ) {
+            Some(DebugValue::Histogram(values)) => values.len(),
+            other => panic!("unexpected {name} {labels:?}: {other:?}"),
+        };
+        assert_eq!(
+            histogram_len(
+                TRANSACTION_DURATION_SECONDS,
+                &[(LABEL_OUTCOME, "committed")]
+            ),
+            1
+        );
+        assert_eq!(
+            histogram_len(
+                TRANSACTION_DURATION_SECONDS,
+                &[(LABEL_OUTCOME, "rolled_back")]
+            ),
+            2
+        );
+        assert_eq!(
+            value(&snapshot, MetricKind::Counter, STATEMENTS_TOTAL, &[]),
+            Some(&DebugValue::Counter(2))
+        );
+        assert_eq!(
+            value(
+                &snapshot,
+                MetricKind::Counter,
+                ROLLBACKS_TOTAL,
+                &[(LABEL_ERROR_KIND, "unique_violation")]
+            ),
+            Some(&DebugValue::Counter(1))
+        );
+        assert_eq!(
+            value(
+                &snapshot,
+                MetricKind::Counter,
+                ROLLBACKS_TOTAL,
+                &[(LABEL_ERROR_KIND, ERROR_KIND_APPLICATION)]
+            ),
+            Some(&DebugValue::Counter(1))
+        );
+        assert_eq!(histogram_len(POOL_ACQUIRE_WAIT_SECONDS, &[]), 3);
+        for gauge in [POOL_SIZE, POOL_IDLE] {
+            assert!(
+                value(&snapshot, MetricKind::Gauge, gauge, &[]).is_some(),
+                "{gauge} was not recorded: {snapshot:?}"
+            );
+        }
+    }
+}
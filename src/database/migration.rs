@@ -0,0 +1,102 @@
+use anyhow::{Result, anyhow};
+use sqlx::{PgPool, migrate::MigrateError};
+use std::time::Duration;
+use thiserror::Error;
+
+const ENV_AUTO_MIGRATE: &str = "AUTO_MIGRATE";
+
+/// `ConnectionPool::run_migrations` が適用したマイグレーションの一覧です。
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MigrationReport {
+    /// 今回の呼び出しで適用したマイグレーションです。バージョンの昇順に並びます。
+    pub applied: Vec<AppliedMigration>,
+}
+
+/// 適用したマイグレーション 1 件の情報です。
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AppliedMigration {
+    pub version: i64,
+    pub description: String,
+    /// `_sqlx_migrations` に記録された実行時間です。
+    pub duration: Duration,
+}
+
+/// マイグレーションの失敗を種別ごとに分類したエラーです。
+///
+/// `ConnectionPool::run_migrations` のエラーの原因に含まれるため、`downcast_ref::<MigrationError>()` で判別できます。
+#[derive(Debug, Error)]
+pub enum MigrationError {
+    /// 途中まで適用されたマイグレーションが残っています。手動で修正し `_sqlx_migrations` の行を削除してください。
+    #[error("migration {version} is partially applied (dirty)")]
+    Dirty { version: i64 },
+    /// 適用済みのマイグレーションの内容が変更されています。
+    #[error("migration {version} was applied but has been modified")]
+    Modified { version: i64 },
+    /// 適用済みのマイグレーションがマイグレーションの一覧から失われています。
+    #[error("migration {version} was applied but is missing from the migration source")]
+    Missing { version: i64 },
+    /// マイグレーションの SQL の実行に失敗しました。そのマイグレーションはロールバックされています。
+    #[error("migration {version} failed")]
+    Failed {
+        version: i64,
+        #[source]
+        source: sqlx::Error,
+    },
+    /// 上記以外のエラーです。
+    #[error("failed to run migrations")]
+    Other(#[source] MigrateError),
+}
+
+impl From<MigrateError> for MigrationError {
+    fn from(error: MigrateError) -> Self {
+        match error {
+            MigrateError::Dirty(version) => Self::Dirty { version },
+            MigrateError::VersionMismatch(version) => Self::Modified { version },
+            MigrateError::VersionMissing(version) => Self::Missing { version },
+            MigrateError::ExecuteMigration(source, version) => Self::Failed { version, source },
+            error => Self::Other(error),
+        }
+    }
+}
+
+/// `AUTO_MIGRATE` 環境変数が起動時のマイグレーションを有効にしているかどうかを返します。
+///
+/// `1`・`true`・`yes`・`on`（大文字・小文字は区別しません）の場合は `true`、未設定または
+/// `0`・`false`・`no`・`off` の場合は `false` を返し、それ以外の値はエラーにします。
+pub fn auto_migrate_enabled() -> Result<bool> {
+    match std::env::var(ENV_AUTO_MIGRATE) {
+        Ok(value) => match value.trim().to_ascii_lowercase().as_str() {
+            "1" | "true" | "yes" | "on" => Ok(true),
+            "0" | "false" | "no" | "off" | "" => Ok(false),
+            _ => Err(anyhow!(
+                "{ENV_AUTO_MIGRATE} must be a boolean (true/false), got {value:?}"
+            )),
+        },
+        Err(std::env::VarError::NotPresent) => Ok(false),
+        Err(error) => Err(anyhow!("Failed to read {ENV_AUTO_MIGRATE}: {error}")),
+    }
+}
+
+/// `_sqlx_migrations` に記録された適用済みマイグレーションを返します。テーブルがない場合は空です。
+pub(super) async fn applied_migrations(pool: &PgPool) -> sqlx::Result<Vec<AppliedMigration>> {
+    let exists: bool = sqlx::query_scalar("SELECT to_regclass('_sqlx_migrations') IS NOT NULL")
+        .fetch_one(pool)
+        .await?;
+    if !exists {
+        return Ok(Vec::new());
+    }
+    let rows: Vec<(i64, String, i64)> = sqlx::query_as(
+        "SELECT version, description, execution_time FROM _sqlx_migrations \
+         WHERE success ORDER BY version",
+    )
+    .fetch_all(pool)
+    .await?;
+    Ok(rows
+        .into_iter()
+        .map(|(version, description, execution_time)| AppliedMigration {
+            version,
+            description,
+            duration: Duration::from_nanos(u64::try_from(execution_time).unwrap_or(0)),
+        })
+        .collect())
+}
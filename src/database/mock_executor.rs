@@ -0,0 +1,570 @@
+use crate::database::{
+    error::DbError,
+    executor::DatabaseExecutor,
+    query_spec::{BindValue, QuerySpec},
+};
+use anyhow::{Context, Result, anyhow};
+use sqlx::{
+    Execute, Postgres,
+    error::{DatabaseError, ErrorKind},
+    postgres::{PgArguments, PgRow},
+    query::{Map, Query},
+};
+use std::{
+    any::Any,
+    borrow::Cow,
+    collections::HashMap,
+    error::Error as StdError,
+    fmt,
+    sync::{Arc, Mutex, PoisonError},
+};
+
+type RowsFactory = Arc<dyn Fn() -> Box<dyn Any + Send> + Send + Sync>;
+
+/// データベースに接続せずに `DatabaseExecutor` を使うコードを単体テストするための実行器です。
+///
+/// 実行したステートメントを順に記録し、フェッチには事前に登録した行を返します。登録した条件に一致しない
+/// フェッチは空の結果を、`execute` 系は影響行数 0 を返します。`with_failure` で N 番目（0 始まり、
+/// フェッチを含むすべてのステートメントの通し番号）のステートメントを指定した SQLSTATE で失敗させ、
+/// ロールバックや再実行の経路を確認できます。
+///
+/// ```ignore
+/// // 1 回目の実行を直列化失敗にし、RetryPolicy が再実行することを確認します。
+/// let mock = MockExecutor::new().with_failure(0, "40001");
+/// RetryPolicy::new(3)
+///     .run(|| place_order(&mock, order_id))
+///     .await?;
+/// mock.assert_executed_containing("INSERT INTO orders");
+/// assert_eq!(mock.calls().len(), 2);
+/// ```
+#[derive(Default)]
+pub struct MockExecutor {
+    responses: Vec<(SqlMatcher, MockResponse)>,
+    failures: HashMap<usize, String>,
+    calls: Mutex<Vec<MockCall>>,
+}
+
+/// `MockExecutor` が記録した 1 回のステートメントの実行です。
+#[derive(Debug, Clone, PartialEq)]
+pub struct MockCall {
+    /// 実行の種類です。
+    pub kind: MockCallKind,
+    /// 実行した SQL です。
+    pub sql: String,
+    /// バインドした値です。`execute_specs` で実行した場合のみ記録されます。
+    pub values: Option<Vec<BindValue>>,
+}
+
+/// `MockCall` の実行の種類です。
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum MockCallKind {
+    /// `execute_query`・`execute_queries`・`execute_specs` による実行です。
+    Execute,
+    /// `fetch_one` による実行です。
+    FetchOne,
+    /// `fetch_all` による実行です。
+    FetchAll,
+}
+
+/// 登録した応答を返す SQL の条件です。
+#[derive(Clone)]
+pub enum SqlMatcher {
+    /// SQL が指定した文字列を含む場合に一致します。
+    Contains(String),
+    /// 述語が `true` を返す場合に一致します。
+    Predicate(Arc<dyn Fn(&str) -> bool + Send + Sync>),
+}
+
+enum MockResponse {
+    Rows(RowsFactory),
+    RowsAffected(u64),
+}
+
+impl SqlMatcher {
+    /// 述語で SQL を判定する条件を作成します。
+    pub fn predicate<P>(predicate: P) -> Self
+    where
+        P: Fn(&str) -> bool + Send + Sync + 'static,
+    {
+        Self::Predicate(Arc::new(predicate))
+    }
+
+    fn matches(&self, sql: &str) -> bool {
+        match self {
+            Self::Contains(fragment) => sql.contains(fragment.as_str()),
+            Self::Predicate(predicate) => predicate(sql),
+        }
+    }
+}
+
+impl From<&str> for SqlMatcher {
+    fn from(fragment: &str) -> Self {
+        Self::Contains(fragment.to_string())
+    }
+}
+
+impl From<String> for SqlMatcher {
+    fn from(fragment: String) -> Self {
+        Self::Contains(fragment)
+    }
+}
+
+impl fmt::Debug for SqlMatcher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Contains(fragment) => f.debug_tuple("Contains").field(fragment).finish(),
+            Self::Predicate(_) => f.write_str("Predicate(..)"),
+        }
+    }
+}
+
+impl MockExecutor {
+    /// 応答も失敗も登録していない実行器を作成します。
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// `matcher` に一致するフェッチに `rows` を返すよう登録します。
+    ///
+    /// 先に登録した条件が優先されます。行の型がフェッチの型と異なる場合、フェッチはエラーを返します。
+    /// `fetch_one` には先頭の行を返します。
+    pub fn with_rows<T>(mut self, matcher: impl Into<SqlMatcher>, rows: Vec<T>) -> Self
+    where
+        T: Clone + Send + Sync + 'static,
+    {
+        let factory: RowsFactory = Arc::new(move || Box::new(rows.clone()));
+        self.responses
+            .push((matcher.into(), MockResponse::Rows(factory)));
+        self
+    }
+
+    /// `matcher` に一致する `execute` 系の実行で、影響を受けた行数として `rows_affected` を返すよう登録します。
+    pub fn with_rows_affected(
+        mut self,
+        matcher: impl Into<SqlMatcher>,
+        rows_affected: u64,
+    ) -> Self {
+        self.responses
+            .push((matcher.into(), MockResponse::RowsAffected(rows_affected)));
+        self
+    }
+
+    /// `index` 番目（0 始まり）に実行したステートメントを、SQLSTATE `code` のデータベースエラーで失敗させます。
+    ///
+    /// エラーは実際のデータベースエラーと同じく `DbError` に分類されるため、`40001` なら
+    /// `DbError::SerializationFailure` として再実行の対象になります。失敗したステートメントも記録されます。
+    pub fn with_failure(mut self, index: usize, code: &str) -> Self {
+        self.failures.insert(index, code.to_string());
+        self
+    }
+
+    /// 記録した実行を順に返します。
+    pub fn calls(&self) -> Vec<MockCall> {
+        self.lock_calls().clone()
+    }
+
+    /// 記録した SQL を順に返します。
+    pub fn executed_sql(&self) -> Vec<String> {
+        self.lock_calls()
+            .iter()
+            .map(|call| call.sql.clone())
+            .collect()
+    }
+
+    /// `fragment` を含む SQL が実行されたことを確認します。
+    ///
+    /// # Panics
+    ///
+    /// 該当する SQL がない場合は、記録したすべての SQL を含めてパニックします。
+    #[track_caller]
+    pub fn assert_executed_containing(&self, fragment: &str) {
+        let executed = self.executed_sql();
+        assert!(
+            executed.iter().any(|sql| sql.contains(fragment)),
+            "expected a statement containing {fragment:?}, executed: {executed:#?}"
+        );
+    }
+
+    /// `fragment` を含む SQL が実行されていないことを確認します。
+    ///
+    /// # Panics
+    ///
+    /// 該当する SQL がある場合は、記録したすべての SQL を含めてパニックします。
+    #[track_caller]
+    pub fn assert_not_executed_containing(&self, fragment: &str) {
+        let executed = self.executed_sql();
+        assert!(
+            !executed.iter().any(|sql| sql.contains(fragment)),
+            "expected no statement containing {fragment:?}, executed: {executed:#?}"
+        );
+    }
+
+    /// 実行を記録し、注入した失敗があればエラーを返します。
+    fn record(&self, kind: MockCallKind, sql: &str, values: Option<Vec<BindValue>>) -> Result<()> {
+        let index = {
+            let mut calls = self.lock_calls();
+            calls.push(MockCall {
+                kind,
+                sql: sql.to_string(),
+                values,
+            });
+            calls.len() - 1
+        };
+        if let Some(code) = self.failures.get(&index) {
+            let error = sqlx::Error::Database(Box::new(MockDatabaseError { code: code.clone() }));
+            return Err(DbError::from(error))
+                .with_context(|| format!("Injected failure at statement {index}"));
+        }
+        Ok(())
+    }
+
+    fn execute_one(&self, sql: &str, values: Option<Vec<BindValue>>, index: usize) -> Result<u64> {
+        self.record(MockCallKind::Execute, sql, values)
+            .with_context(|| format!("Failed to execute query in transaction at index {index}"))?;
+        let rows_affected = self
+            .responses
+            .iter()
+            .find_map(|(matcher, response)| match response {
+                MockResponse::RowsAffected(rows_affected) if matcher.matches(sql) => {
+                    Some(*rows_affected)
+                }
+                _ => None,
+            });
+        Ok(rows_affected.unwrap_or(0))
+    }
+
+    fn fetch<U: 'static>(&self, kind: MockCallKind, sql: &str) -> Result<Vec<U>> {
+        self.record(kind, sql, None)?;
+        let factory = self
+            .responses
+            .iter()
+            .find_map(|(matcher, response)| match response {
+                MockResponse::Rows(factory) if matcher.matches(sql) => Some(factory),
+                _ => None,
+            });
+        match factory {
+            Some(factory) => factory()
+                .downcast::<Vec<U>>()
+                .map(|rows| *rows)
+                .map_err(|_| {
+                    anyhow!(
+                        "Canned rows for {sql:?} do not match the fetched type {}",
+                        std::any::type_name::<U>()
+                    )
+                }),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    fn lock_calls(&self) -> std::sync::MutexGuard<'_, Vec<MockCall>> {
+        self.calls.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl DatabaseExecutor for MockExecutor {
+    async fn execute_queries<'a, I>(&self, queries: I) -> Result<Vec<u64>>
+    where
+        I: IntoIterator<Item = Query<'a, Postgres, PgArguments>> + Send,
+        I::IntoIter: Send,
+    {
+        queries
+            .into_iter()
+            .enumerate()
+            .map(|(index, query)| self.execute_one(query.sql(), None, index))
+            .collect()
+    }
+
+    async fn execute_specs(&self, specs: Vec<QuerySpec>) -> Result<Vec<u64>> {
+        specs
+            .iter()
+            .enumerate()
+            .map(|(index, spec)| self.execute_one(spec.sql(), Some(spec.values().to_vec()), index))
+            .collect()
+    }
+
+    async fn fetch_one<'a, U, F>(
+        &self,
+        query: Map<'a, Postgres, F, PgArguments>,
+    ) -> Result<Option<U>>
+    where
+        U: Send + Unpin + 'static,
+        F: FnMut(PgRow) -> std::result::Result<U, sqlx::Error> + Send + 'static,
+    {
+        let rows = self.fetch(MockCallKind::FetchOne, query.sql())?;
+        Ok(rows.into_iter().next())
+    }
+
+    async fn fetch_all<'a, U, F>(&self, query: Map<'a, Postgres, F, PgArguments>) -> Result<Vec<U>>
+    where
+        U: Send + Unpin + 'static,
+        F: FnMut(PgRow) -> std::result::Result<U, sqlx::Error> + Send + 'static,
+    {
+        self.fetch(MockCallKind::FetchAll, query.sql())
+    }
+}
+
+/// `MockExecutor` が注入する、SQLSTATE だけを持つデータベースエラーです。
+#[derive(Debug)]
+struct MockDatabaseError {
+    code: String,
+}
+
+impl fmt::Display for MockDatabaseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "injected database error (SQLSTATE {})", self.code)
+    }
+}
+
+impl StdError for MockDatabaseError {}
+
+impl DatabaseError for MockDatabaseError {
+    fn message(&self) -> &str {
+        "injected database error"
+    }
+
+    fn code(&self) -> Option<Cow<'_, str>> {
+        Some(Cow::Borrowed(&self.code))
+    }
+
+    fn as_error(&self) -> &(dyn StdError + Send + Sync + 'static) {
+        self
+    }
+
+    fn as_error_mut(&mut self) -> &mut (dyn StdError + Send + Sync + 'static) {
+        self
+    }
+
+    fn into_error(self: Box<Self>) -> Box<dyn StdError + Send + Sync + 'static> {
+        self
+    }
+
+    fn kind(&self) -> ErrorKind {
+        ErrorKind::Other
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::retry::RetryPolicy;
+    use sqlx::Row;
+    use std::time::Duration;
+
+    fn fetch_ids(
+        sql: &str,
+    ) -> Map<'_, Postgres, impl FnMut(PgRow) -> sqlx::Result<i64>, PgArguments> {
+        sqlx::query(sql).try_map(|row: PgRow| row.try_get(0))
+    }
+
+    #[tokio::test]
+    async fn records_calls_in_order() {
+        let mock = MockExecutor::new();
+
+        mock.execute_queries([sqlx::query("INSERT INTO a"), sqlx::query("UPDATE b")])
+            .await
+            .unwrap();
+        mock.fetch_one(fetch_ids("SELECT id FROM c")).await.unwrap();
+        mock.fetch_all(fetch_ids("SELECT id FROM d")).await.unwrap();
+
+        let calls: Vec<(MockCallKind, String)> = mock
+            .calls()
+            .into_iter()
+            .map(|call| (call.kind, call.sql))
+            .collect();
+        assert_eq!(
+            calls,
+            [
+                (MockCallKind::Execute, "INSERT INTO a".to_string()),
+                (MockCallKind::Execute, "UPDATE b".to_string()),
+                (MockCallKind::FetchOne, "SELECT id FROM c".to_string()),
+                (MockCallKind::FetchAll, "SELECT id FROM d".to_string()),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn records_bound_values_of_specs() {
+        let mock = MockExecutor::new();
+
+        mock.execute_specs(vec![
+            QuerySpec::new("DELETE FROM users WHERE id = $1").bind(BindValue::Int8(Some(7))),
+        ])
+        .await
+        .unwrap();
+
+        assert_eq!(mock.calls()[0].values, Some(vec![BindValue::Int8(Some(7))]));
+    }
+
+    #[tokio::test]
+    async fn returns_canned_rows_for_the_first_matching_expectation() {
+        let mock = MockExecutor::new()
+            .with_rows("FROM users", vec![1_i64, 2])
+            .with_rows(
+                SqlMatcher::predicate(|sql| sql.contains("users")),
+                vec![9_i64],
+            );
+
+        let all = mock
+            .fetch_all(fetch_ids("SELECT id FROM users"))
+            .await
+            .unwrap();
+        let one = mock
+            .fetch_one(fetch_ids("SELECT id FROM users LIMIT 1"))
+            .await
+            .unwrap();
+        let unmatched = mock
+            .fetch_all(fetch_ids("SELECT id FROM orders"))
+            .await
+            .unwrap();
+
+        assert_eq!(all, [1, 2]);
+        assert_eq!(one, Some(1));
+        assert!(unmatched.is_empty());
+    }
+
+    #[tokio::test]
+    async fn rejects_canned_rows_of_a_different_type() {
+        let mock = MockExecutor::new().with_rows("FROM users", vec!["alice".to_string()]);
+
+        let error = mock
+            .fetch_all(fetch_ids("SELECT id FROM users"))
+            .await
+            .unwrap_err();
+
+        assert!(format!("{error:#}").contains("do not match the fetched type i64"));
+    }
+
+    #[tokio::test]
+    async fn returns_canned_rows_affected() {
+        let mock = MockExecutor::new()
+            .with_rows_affected("UPDATE users", 3)
+            .with_rows_affected("UPDATE", 1);
+
+        let rows_affected = mock
+            .execute_queries([
+                sqlx::query("UPDATE users SET active = false"),
+                sqlx::query("UPDATE orders SET total = 0"),
+                sqlx::query("DELETE FROM sessions"),
+            ])
+            .await
+            .unwrap();
+
+        assert_eq!(rows_affected, [3, 1, 0]);
+    }
+
+    #[tokio::test]
+    async fn fails_the_indexed_statement_with_the_given_sqlstate() {
+        let mock = MockExecutor::new().with_failure(1, "40001");
+
+        mock.execute_query(sqlx::query("SELECT 1")).await.unwrap();
+        let error = mock
+            .fetch_all(fetch_ids("SELECT id FROM users"))
+            .await
+            .unwrap_err();
+        mock.execute_query(sqlx::query("SELECT 2")).await.unwrap();
+
+        let db_error = DbError::find(&error).expect("injected failure is a DbError");
+        assert!(matches!(db_error, DbError::SerializationFailure(_)));
+        assert_eq!(db_error.sqlstate().as_deref(), Some("40001"));
+        assert!(format!("{error:#}").contains("Injected failure at statement 1"));
+        assert_eq!(mock.calls().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn assert_executed_containing_accepts_an_executed_statement() {
+        let mock = MockExecutor::new();
+        mock.execute_query(sqlx::query("INSERT INTO orders VALUES (1)"))
+            .await
+            .unwrap();
+
+        mock.assert_executed_containing("INSERT INTO orders");
+        mock.assert_not_executed_containing("DELETE");
+    }
+
+    #[test]
+    #[should_panic(expected = "expected a statement containing \"INSERT INTO orders\"")]
+    fn assert_executed_containing_panics_when_missing() {
+        MockExecutor::new().assert_executed_containing("INSERT INTO orders");
+    }
+
+    #[tokio::test]
+    #[should_panic(expected = "expected no statement containing \"DELETE\"")]
+    async fn assert_not_executed_containing_panics_when_executed() {
+        let mock = MockExecutor::new();
+        mock.execute_query(sqlx::query("DELETE FROM orders"))
+            .await
+            .unwrap();
+
+        mock.assert_not_executed_containing("DELETE");
+    }
+
+    /// 注文を登録して在庫を減らす、再実行の対象になるリポジトリ関数です。
+    async fn place_order(db: &impl DatabaseExecutor, order_id: i64) -> Result<Vec<u64>> {
+        db.execute_queries([
+            sqlx::query("INSERT INTO orders (id) VALUES ($1)").bind(order_id),
+            sqlx::query("UPDATE stock SET quantity = quantity - 1 WHERE item_id = $1")
+                .bind(order_id),
+        ])
+        .await
+    }
+
+    fn fast_retry(max_attempts: u32) -> RetryPolicy {
+        RetryPolicy {
+            base_delay: Duration::from_millis(1),
+            ..RetryPolicy::new(max_attempts)
+        }
+    }
+
+    #[tokio::test]
+    async fn retry_policy_reruns_the_transaction_after_injected_serialization_failures() {
+        // 1 回目は INSERT、2 回目は UPDATE で直列化失敗になり、3 回目で成功します。
+        let mock = MockExecutor::new()
+            .with_rows_affected("UPDATE stock", 1)
+            .with_rows_affected("INSERT INTO orders", 1)
+            .with_failure(0, "40001")
+            .with_failure(2, "40001");
+
+        let rows_affected = fast_retry(3).run(|| place_order(&mock, 7)).await.unwrap();
+
+        assert_eq!(rows_affected, [1, 1]);
+        let executed = mock.executed_sql();
+        assert_eq!(executed.len(), 5);
+        assert_eq!(
+            executed
+                .iter()
+                .filter(|sql| sql.starts_with("INSERT INTO orders"))
+                .count(),
+            3
+        );
+    }
+
+    #[tokio::test]
+    async fn retry_policy_gives_up_after_the_last_attempt_and_skips_other_sqlstates() {
+        let exhausted = MockExecutor::new()
+            .with_failure(0, "40001")
+            .with_failure(1, "40001");
+        let not_retryable = MockExecutor::new().with_failure(0, "23505");
+
+        let exhausted_error = fast_retry(2)
+            .run(|| place_order(&exhausted, 7))
+            .await
+            .unwrap_err();
+        let not_retryable_error = fast_retry(3)
+            .run(|| place_order(&not_retryable, 7))
+            .await
+            .unwrap_err();
+
+        assert!(
+            exhausted_error
+                .to_string()
+                .contains("Transaction failed after 2 attempts"),
+            "{exhausted_error:#}"
+        );
+        assert_eq!(exhausted.calls().len(), 2);
+        assert!(matches!(
+            DbError::find(&not_retryable_error),
+            Some(DbError::UniqueViolation { .. })
+        ));
+        assert_eq!(not_retryable.calls().len(), 1);
+    }
+}
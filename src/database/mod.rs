@@ -1,2 +1,40 @@
+pub mod advisory_lock;
+pub mod audit_log;
+pub mod backend;
 pub mod connection_pool;
-pub mod query_executor;
\ No newline at end of file
+pub mod copy_in;
+pub mod error;
+pub mod executor;
+pub mod explain;
+pub mod generic;
+pub mod health;
+mod hold_watchdog;
+pub mod identifier;
+mod instrumentation;
+mod json_row;
+pub mod listener;
+pub mod metrics;
+pub mod migration;
+#[cfg(feature = "test-util")]
+pub mod mock_executor;
+mod pgbouncer;
+pub mod pool_config;
+pub mod pool_stats;
+mod query_cache;
+pub mod query_executor;
+pub mod query_spec;
+pub mod query_tag;
+pub mod retry;
+pub mod scheduler;
+mod slow_query;
+pub mod sql_script;
+#[cfg(feature = "test-util")]
+pub mod test_support;
+#[cfg(test)]
+mod testing;
+pub mod tls;
+pub mod transaction_executor;
+pub mod transaction_options;
+pub mod transaction_report;
+pub mod transaction_scope;
+pub mod two_phase;
@@ -0,0 +1,94 @@
+use anyhow::{Result, ensure};
+use sqlx::{
+    Postgres,
+    postgres::PgArguments,
+    query::{Query, QueryAs, QueryScalar},
+};
+
+/// PgBouncer 互換モードで、クエリを名前付きのプリペアドステートメントとして保持しないよう設定します。
+///
+/// トランザクションプーリングでは同じクライアント接続でもトランザクションごとにサーバー接続が変わるため、
+/// 名前付きのステートメントは別のサーバー接続では存在せず、別のクライアントの同名のステートメントと
+/// 衝突することもあります。互換モードでは名前のないステートメントで実行し、実行後に破棄させます。
+/// 互換モードでない場合は呼び出し側の指定をそのまま残します。
+pub(super) trait Unprepared: Sized {
+    fn unprepared_if(self, pgbouncer_mode: bool) -> Self;
+}
+
+impl<'q> Unprepared for Query<'q, Postgres, PgArguments> {
+    fn unprepared_if(self, pgbouncer_mode: bool) -> Self {
+        if pgbouncer_mode {
+            self.persistent(false)
+        } else {
+            self
+        }
+    }
+}
+
+impl<'q, O> Unprepared for QueryAs<'q, Postgres, O, PgArguments> {
+    fn unprepared_if(self, pgbouncer_mode: bool) -> Self {
+        if pgbouncer_mode {
+            self.persistent(false)
+        } else {
+            self
+        }
+    }
+}
+
+impl<'q, O> Unprepared for QueryScalar<'q, Postgres, O, PgArguments> {
+    fn unprepared_if(self, pgbouncer_mode: bool) -> Self {
+        if pgbouncer_mode {
+            self.persistent(false)
+        } else {
+            self
+        }
+    }
+}
+
+/// PgBouncer 互換モードで `Map` を受け取る API が呼び出された場合にエラーを返します。
+///
+/// `Map`（`query(...).map(...)` など）は後から名前のないステートメントに切り替えられず、`map` の前に
+/// 指定した `persistent(false)` も外から確認できないため、互換モードでは受け付けません。
+pub(super) fn ensure_map_supported(pgbouncer_mode: bool) -> Result<()> {
+    ensure!(
+        !pgbouncer_mode,
+        "Mapped queries are not supported in pgbouncer_mode; pass a Query, QueryAs or QuerySpec instead"
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sqlx::Execute;
+
+    #[test]
+    fn unprepared_if_clears_persistence_only_in_pgbouncer_mode() {
+        let query = || sqlx::query("SELECT 1");
+        let query_as = || sqlx::query_as::<_, (i32,)>("SELECT 1");
+        let query_scalar = || sqlx::query_scalar::<_, i32>("SELECT 1");
+
+        assert!(Execute::persistent(&query().unprepared_if(false)));
+        assert!(!Execute::persistent(&query().unprepared_if(true)));
+        assert!(Execute::persistent(&query_as().unprepared_if(false)));
+        assert!(!Execute::persistent(&query_as().unprepared_if(true)));
+        assert!(Execute::persistent(&query_scalar().unprepared_if(false)));
+        assert!(!Execute::persistent(&query_scalar().unprepared_if(true)));
+    }
+
+    #[test]
+    fn unprepared_if_keeps_an_explicit_persistent_false() {
+        let query = sqlx::query("SELECT 1")
+            .persistent(false)
+            .unprepared_if(false);
+
+        assert!(!Execute::persistent(&query));
+    }
+
+    #[test]
+    fn mapped_queries_are_rejected_only_in_pgbouncer_mode() {
+        assert!(ensure_map_supported(false).is_ok());
+        let error = ensure_map_supported(true).unwrap_err();
+        assert!(error.to_string().contains("pgbouncer_mode"));
+    }
+}
@@ -0,0 +1,57 @@
+use sqlx::PgPool;
+use tokio::task::JoinHandle;
+
+/// 接続プールの使用状況のスナップショットです。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PoolStats {
+    /// 現在開いている接続数（使用中とアイドルの合計）です。
+    pub size: u32,
+    /// アイドル状態の接続数です。
+    pub idle: usize,
+    /// 使用中の接続数です。
+    pub in_use: usize,
+    /// 設定された最大接続数です。
+    pub max_connections: u32,
+}
+
+impl PoolStats {
+    /// プールの現在の状態からスナップショットを作成します。
+    pub(super) fn from_pool(pool: &PgPool) -> Self {
+        let size = pool.size();
+        let idle = pool.num_idle();
+        let in_use = usize::try_from(size)
+            .unwrap_or(usize::MAX)
+            .saturating_sub(idle);
+        Self {
+            size,
+            idle,
+            in_use,
+            max_connections: pool.options().get_max_connections(),
+        }
+    }
+}
+
+/// `ConnectionPool::spawn_stats_reporter` で起動した定期報告タスクのハンドルです。
+///
+/// `stop` を呼び出すか、ハンドルを破棄するとタスクは停止します。
+#[derive(Debug)]
+pub struct StatsReporterHandle {
+    task: JoinHandle<()>,
+}
+
+impl StatsReporterHandle {
+    pub(super) fn new(task: JoinHandle<()>) -> Self {
+        Self { task }
+    }
+
+    /// 定期報告タスクを停止します。
+    pub fn stop(self) {
+        self.task.abort();
+    }
+}
+
+impl Drop for StatsReporterHandle {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
@@ -0,0 +1,306 @@
+use anyhow::{Result, anyhow};
+use std::{
+    any::Any,
+    collections::HashMap,
+    future::Future,
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+    time::{Duration, Instant},
+};
+use tokio::sync::OnceCell;
+
+/// `QueryExecutor::fetch_all_cached` の結果を保持する、最大件数付きのキャッシュです。
+///
+/// 件数が上限に達した状態で新しいキーを登録すると、最も長く参照されていないエントリを破棄します。
+/// 同じキーへの同時の問い合わせは同じ `OnceCell` を待つため、データベースに問い合わせるのは 1 回だけです。
+pub(super) struct QueryCache {
+    max_entries: usize,
+    state: Mutex<CacheState>,
+}
+
+struct CacheState {
+    entries: HashMap<String, Slot>,
+    /// 参照の順序を表す、単調増加するカウンタです。
+    tick: u64,
+}
+
+struct Slot {
+    /// 取得済みの結果です。取得中は未初期化のまま、後続の呼び出しが完了を待ちます。
+    cell: Arc<OnceCell<CachedRows>>,
+    last_used: u64,
+}
+
+struct CachedRows {
+    /// `Vec<U>` です。キーごとに行の型が異なるため、型を消去して保持します。
+    rows: Arc<dyn Any + Send + Sync>,
+    expires_at: Instant,
+}
+
+impl QueryCache {
+    /// 最大 `max_entries` 件を保持するキャッシュを作成します。
+    pub(super) fn new(max_entries: usize) -> Self {
+        Self {
+            max_entries,
+            state: Mutex::new(CacheState {
+                entries: HashMap::new(),
+                tick: 0,
+            }),
+        }
+    }
+
+    /// `key` の有効な結果があればその複製を返し、なければ `fetch` の結果を `ttl` の間保持して返します。
+    ///
+    /// `fetch` が失敗した場合は結果を保持せず、取得を待っていた呼び出しのうち 1 つが改めて取得します。
+    pub(super) async fn get_or_fetch<U, Fut>(
+        &self,
+        key: &str,
+        ttl: Duration,
+        fetch: Fut,
+    ) -> Result<Vec<U>>
+    where
+        U: Clone + Send + Sync + 'static,
+        Fut: Future<Output = Result<Vec<U>>>,
+    {
+        let cell = self.slot(key);
+        let cached = cell
+            .get_or_try_init(|| async {
+                let rows = fetch.await?;
+                Ok::<_, anyhow::Error>(CachedRows {
+                    rows: Arc::new(rows),
+                    expires_at: Instant::now() + ttl,
+                })
+            })
+            .await?;
+        cached
+            .rows
+            .downcast_ref::<Vec<U>>()
+            .cloned()
+            .ok_or_else(|| anyhow!("Cached rows for key {key:?} have a different row type"))
+    }
+
+    /// `key` のエントリを破棄し、破棄した場合は `true` を返します。
+    pub(super) fn invalidate(&self, key: &str) -> bool {
+        self.lock().entries.remove(key).is_some()
+    }
+
+    /// すべてのエントリを破棄します。
+    pub(super) fn invalidate_all(&self) {
+        self.lock().entries.clear();
+    }
+
+    /// `key` の有効なエントリ（取得中を含む）を返します。期限切れまたは未登録の場合は空のエントリを登録します。
+    fn slot(&self, key: &str) -> Arc<OnceCell<CachedRows>> {
+        let mut state = self.lock();
+        state.tick += 1;
+        let tick = state.tick;
+        let now = Instant::now();
+        if let Some(slot) = state.entries.get_mut(key) {
+            if slot.cell.get().is_none_or(|cached| cached.expires_at > now) {
+                slot.last_used = tick;
+                return Arc::clone(&slot.cell);
+            }
+        } else if state.entries.len() >= self.max_entries {
+            let least_recent = state
+                .entries
+                .iter()
+                .min_by_key(|(_, slot)| slot.last_used)
+                .map(|(key, _)| key.clone());
+            if let Some(least_recent) = least_recent {
+                state.entries.remove(&least_recent);
+            }
+        }
+        let cell = Arc::new(OnceCell::new());
+        state.entries.insert(
+            key.to_string(),
+            Slot {
+                cell: Arc::clone(&cell),
+                last_used: tick,
+            },
+        );
+        cell
+    }
+
+    fn lock(&self) -> MutexGuard<'_, CacheState> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    const TTL: Duration = Duration::from_secs(60);
+
+    /// 呼び出し回数を数えながら `rows` を返す取得処理です。
+    async fn counted(fetches: &AtomicUsize, rows: Vec<i64>) -> Result<Vec<i64>> {
+        fetches.fetch_add(1, Ordering::SeqCst);
+        Ok(rows)
+    }
+
+    #[tokio::test]
+    async fn returns_cached_rows_until_invalidated() {
+        let cache = QueryCache::new(10);
+        let fetches = AtomicUsize::new(0);
+
+        let first = cache
+            .get_or_fetch("currencies", TTL, counted(&fetches, vec![1, 2]))
+            .await
+            .unwrap();
+        let second = cache
+            .get_or_fetch("currencies", TTL, counted(&fetches, vec![3]))
+            .await
+            .unwrap();
+        assert_eq!((first, second), (vec![1, 2], vec![1, 2]));
+        assert_eq!(fetches.load(Ordering::SeqCst), 1);
+
+        assert!(cache.invalidate("currencies"));
+        assert!(!cache.invalidate("currencies"));
+        let third = cache
+            .get_or_fetch("currencies", TTL, counted(&fetches, vec![3]))
+            .await
+            .unwrap();
+        assert_eq!(third, [3]);
+        assert_eq!(fetches.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn keeps_separate_entries_per_key() {
+        let cache = QueryCache::new(10);
+        let fetches = AtomicUsize::new(0);
+
+        let a = cache
+            .get_or_fetch("a", TTL, counted(&fetches, vec![1]))
+            .await
+            .unwrap();
+        let b = cache
+            .get_or_fetch("b", TTL, counted(&fetches, vec![2]))
+            .await
+            .unwrap();
+
+        assert_eq!((a, b), (vec![1], vec![2]));
+        assert_eq!(fetches.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn refetches_expired_entries() {
+        let cache = QueryCache::new(10);
+        let fetches = AtomicUsize::new(0);
+
+        cache
+            .get_or_fetch("flags", Duration::ZERO, counted(&fetches, vec![1]))
+            .await
+            .unwrap();
+        let refetched = cache
+            .get_or_fetch("flags", Duration::ZERO, counted(&fetches, vec![2]))
+            .await
+            .unwrap();
+
+        assert_eq!(refetched, [2]);
+        assert_eq!(fetches.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn evicts_the_least_recently_used_entry() {
+        let cache = QueryCache::new(2);
+        let fetches = AtomicUsize::new(0);
+
+        for key in ["a", "b", "a", "c"] {
+            cache
+                .get_or_fetch(key, TTL, counted(&fetches, vec![1]))
+                .await
+                .unwrap();
+        }
+        assert_eq!(fetches.load(Ordering::SeqCst), 3);
+
+        // `c` を登録した時点で最も長く参照されていない `b` を破棄し、`a` と `c` は残ります。
+        for key in ["a", "c"] {
+            cache
+                .get_or_fetch(key, TTL, counted(&fetches, vec![1]))
+                .await
+                .unwrap();
+        }
+        assert_eq!(fetches.load(Ordering::SeqCst), 3);
+        cache
+            .get_or_fetch("b", TTL, counted(&fetches, vec![1]))
+            .await
+            .unwrap();
+        assert_eq!(fetches.load(Ordering::SeqCst), 4);
+    }
+
+    #[tokio::test]
+    async fn deduplicates_concurrent_misses() {
+        let cache = QueryCache::new(10);
+        let fetches = AtomicUsize::new(0);
+        let slow_fetch = || async {
+            fetches.fetch_add(1, Ordering::SeqCst);
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            Ok(vec![7_i64])
+        };
+
+        let (first, second, third) = tokio::join!(
+            cache.get_or_fetch("rates", TTL, slow_fetch()),
+            cache.get_or_fetch("rates", TTL, slow_fetch()),
+            cache.get_or_fetch("rates", TTL, slow_fetch()),
+        );
+
+        assert_eq!(first.unwrap(), [7]);
+        assert_eq!(second.unwrap(), [7]);
+        assert_eq!(third.unwrap(), [7]);
+        assert_eq!(fetches.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn does_not_cache_failed_fetches() {
+        let cache = QueryCache::new(10);
+
+        let error = cache
+            .get_or_fetch::<i64, _>("rates", TTL, async { Err(anyhow!("connection reset")) })
+            .await
+            .unwrap_err();
+        let rows = cache
+            .get_or_fetch("rates", TTL, async { Ok(vec![1_i64]) })
+            .await
+            .unwrap();
+
+        assert_eq!(error.to_string(), "connection reset");
+        assert_eq!(rows, [1]);
+    }
+
+    #[tokio::test]
+    async fn rejects_a_different_row_type_for_the_same_key() {
+        let cache = QueryCache::new(10);
+        cache
+            .get_or_fetch("rates", TTL, async { Ok(vec![1_i64]) })
+            .await
+            .unwrap();
+
+        let error = cache
+            .get_or_fetch("rates", TTL, async { Ok(vec!["1".to_string()]) })
+            .await
+            .unwrap_err();
+
+        assert!(error.to_string().contains("different row type"));
+    }
+
+    #[tokio::test]
+    async fn invalidate_all_clears_every_entry() {
+        let cache = QueryCache::new(10);
+        let fetches = AtomicUsize::new(0);
+        for key in ["a", "b"] {
+            cache
+                .get_or_fetch(key, TTL, counted(&fetches, vec![1]))
+                .await
+                .unwrap();
+        }
+
+        cache.invalidate_all();
+        for key in ["a", "b"] {
+            cache
+                .get_or_fetch(key, TTL, counted(&fetches, vec![1]))
+                .await
+                .unwrap();
+        }
+
+        assert_eq!(fetches.load(Ordering::SeqCst), 4);
+    }
+}
@@ -1,61 +1,535 @@
-use anyhow::{Context, Result};
-use crate::database::connection_pool::SharedConnectionPool;
+use crate::database::{
+    connection_pool::{
+        ConnectionPool, SharedConnectionPool, acquire_connection, collect_limited,
+        discard_if_timed_out, exactly_one, within_read_timeout,
+    },
+    error::{DbError, ErrorDetail},
+    explain::{ExplainOptions, ExplainPlan},
+    identifier::{quote_identifier, quote_qualified_identifier},
+    instrumentation::traced_query,
+    json_row::row_to_json,
+    pgbouncer::{Unprepared, ensure_map_supported},
+    pool_config::Workload,
+    query_cache::QueryCache,
+    query_spec::QuerySpec,
+    query_tag::QueryTag,
+    retry::AcquireRetry,
+    slow_query::SlowQueryLog,
+    transaction_executor::TransactionExecutor,
+    transaction_options::{TransactionOptions, begin_with_options},
+};
+use anyhow::{Context, Result, anyhow, bail, ensure};
+use chrono::{DateTime, Utc};
+use futures_util::{
+    Stream, StreamExt, TryFutureExt, future,
+    stream::{self, FuturesUnordered},
+};
 use sqlx::{
-    PgPool, Postgres, Transaction,
-    postgres::{PgArguments, PgRow},
+    Decode, Encode, FromRow, PgPool, Postgres, Row, Type,
+    pool::PoolConnection,
+    postgres::{PgArgumentBuffer, PgArguments, PgRow},
     query::Map,
     query::Query,
+    query::QueryAs,
 };
+use std::{
+    future::Future,
+    ops::Range,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::sync::Semaphore;
+
+/// `fetch_in` で IN リストのプレースホルダ列に置き換えられる SQL 内のマーカーです。
+pub const IN_LIST_PLACEHOLDER: &str = "{in_list}";
+const DEFAULT_IN_LIST_MAX_KEYS: usize = 1000;
+const DEFAULT_IN_LIST_MAX_BYTES: usize = 1024 * 1024;
+const TEMPORAL_VALID_FROM_COLUMN: &str = "valid_from";
+const TEMPORAL_VALID_TO_COLUMN: &str = "valid_to";
+
+/// `fetch_in` が 1 回のクエリにまとめるキーの上限です。
+///
+/// キー数とエンコード後のおおよそのバイト数のどちらかが上限を超える時点でバッチを分割します。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InListLimits {
+    pub max_keys: usize,
+    pub max_bytes: usize,
+}
+
+impl Default for InListLimits {
+    fn default() -> Self {
+        Self {
+            max_keys: DEFAULT_IN_LIST_MAX_KEYS,
+            max_bytes: DEFAULT_IN_LIST_MAX_BYTES,
+        }
+    }
+}
+
+/// キーセットページネーションでキーを並べる向きです。
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PageDirection {
+    /// キーの昇順です。続きのページは直前のキーより大きい行から始まります。
+    #[default]
+    Ascending,
+    /// キーの降順です。続きのページは直前のキーより小さい行から始まります。
+    Descending,
+}
+
+impl PageDirection {
+    fn comparison_operator(self) -> &'static str {
+        match self {
+            Self::Ascending => ">",
+            Self::Descending => "<",
+        }
+    }
+
+    fn order_keyword(self) -> &'static str {
+        match self {
+            Self::Ascending => "ASC",
+            Self::Descending => "DESC",
+        }
+    }
+}
+
+/// `fetch_page` が返す 1 ページ分の行です。
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Page<U, K> {
+    /// このページの行です。
+    pub rows: Vec<U>,
+    /// 次のページを取得する際に `after` として渡すキーです。最終ページでは `None` です。
+    pub next_key: Option<K>,
+}
 
+/// クエリ実行器です。
+///
+/// 読み取り用レプリカが設定されている場合、`fetch_*` 系の読み取りはレプリカで実行し、
+/// `execute_*` 系の書き込みとトランザクション内の処理は常にプライマリで実行します。
+/// 書き込み直後の読み取りなどレプリカの遅延が問題になる場合は `*_primary` を使ってください。
 #[derive(Clone)]
 pub struct QueryExecutor {
     pool: PgPool,
+    replica: Option<PgPool>,
+    slow_query_threshold: Option<Duration>,
+    acquire_retry: AcquireRetry,
+    pgbouncer_mode: bool,
+    max_rows: Option<usize>,
+    read_timeout: Option<Duration>,
+    error_detail: ErrorDetail,
+    cache: Option<Arc<QueryCache>>,
 }
 
 impl QueryExecutor {
     /// 指定した接続プールを使うクエリ実行器を作成します。
     pub fn new(pool: PgPool) -> Self {
-        Self { pool }
+        Self {
+            pool,
+            replica: None,
+            slow_query_threshold: None,
+            acquire_retry: AcquireRetry::default(),
+            pgbouncer_mode: false,
+            max_rows: None,
+            read_timeout: None,
+            error_detail: ErrorDetail::default(),
+            cache: None,
+        }
+    }
+
+    /// 読み取りに使うレプリカのプールを指定します。
+    pub fn with_replica(mut self, replica: PgPool) -> Self {
+        self.replica = Some(replica);
+        self
+    }
+
+    /// 遅いステートメントとトランザクションとして警告ログを出力するまでの時間を指定します。
+    ///
+    /// `None` または 0 の場合は警告しません。
+    pub fn with_slow_query_threshold(mut self, threshold: Option<Duration>) -> Self {
+        self.slow_query_threshold = threshold;
+        self
+    }
+
+    /// 接続の取得がタイムアウトした場合の再試行の方針を指定します。
+    ///
+    /// `fetch_*` 系の読み取りと、トランザクションを開始する `execute_*` 系の処理に適用されます。
+    /// 再試行するのは接続の取得待ちのタイムアウトのみで、クエリ自体のエラーは再試行しません。
+    /// `fetch_stream` はストリームが接続を取得するため、この方針は適用されません。
+    pub fn with_acquire_retry(mut self, acquire_retry: AcquireRetry) -> Self {
+        self.acquire_retry = acquire_retry;
+        self
+    }
+
+    /// PgBouncer のトランザクションプーリング向けに、`Query`・`QueryAs`・`QuerySpec` と実行器が組み立てるクエリを
+    /// 名前のないステートメントで実行するかを指定します。
+    ///
+    /// 有効な場合、`Map` を受け取る API はエラーを返します。詳細は `PoolConfigBuilder::pgbouncer_mode` を
+    /// 参照してください。
+    pub fn with_pgbouncer_mode(mut self, pgbouncer_mode: bool) -> Self {
+        self.pgbouncer_mode = pgbouncer_mode;
+        self
+    }
+
+    /// 全行を返す `fetch_all` 系の読み取りで受信する行数の上限を指定します。
+    ///
+    /// 上限を超える行を受信した時点で読み取りをやめ、原因に `DbError::RowLimitExceeded` を含むエラーを
+    /// 返します。想定外に大きな結果でメモリを使い果たすことを防ぐためのもので、`None` の場合は制限しません。
+    /// 呼び出しごとに上限を変える場合は `fetch_all_limited` を使ってください。
+    pub fn with_max_rows(mut self, max_rows: Option<usize>) -> Self {
+        self.max_rows = max_rows;
+        self
+    }
+
+    /// `fetch_one`・`fetch_all` 系の読み取りでクエリの実行と受信を待つ時間の上限を指定します。
+    ///
+    /// `acquire_timeout` は接続の取得待ちにのみ適用されるため、ネットワークの分断やインデックスのない
+    /// 全件走査では読み取りが戻らなくなります。上限を過ぎると原因に `DbError::Timeout`（上限の時間を含む）を
+    /// 含むエラーを返し、受信途中の結果が残っている可能性がある接続はプールに戻さずに閉じます。
+    /// 接続の取得待ちの時間は含みません。`None` の場合は制限しません。呼び出しごとに上限を変える場合は
+    /// `fetch_one_timeout`・`fetch_all_timeout` を使ってください。
+    ///
+    /// この上限はクライアント側で待つのをやめるだけで、サーバー側のクエリは取り消しません。閉じた接続に
+    /// サーバーが気付くまでクエリが走り続けることがあるため、サーバー側でも止めたい場合は `statement_timeout`
+    /// （`SessionSetup::extra_statements` での `SET statement_timeout` や `TransactionOptions::statement_timeout`）を
+    /// 併用してください。両方を指定する場合は `statement_timeout` をこの上限より短くすると、通常はサーバーが
+    /// 取り消した `DbError::QueryCanceled` が先に返り、接続もそのまま再利用されます。
+    pub fn with_read_timeout(mut self, read_timeout: Option<Duration>) -> Self {
+        self.read_timeout = read_timeout;
+        self
+    }
+
+    /// `execute_*` 系の処理でステートメントが失敗した場合に、エラーに含める詳細の範囲を指定します。
+    ///
+    /// 詳細は `TransactionExecutor::with_error_detail` を参照してください。
+    pub fn with_error_detail(mut self, error_detail: ErrorDetail) -> Self {
+        self.error_detail = error_detail;
+        self
+    }
+
+    /// `fetch_all_cached` の結果を最大 `max_entries` 件までキャッシュします。
+    ///
+    /// キャッシュは実行器ごとに持ち、`clone` した実行器とは共有します。件数が上限に達した状態で新しいキーを
+    /// 登録すると、最も長く参照されていないキーを破棄します。`max_entries` が 0 の場合はエラーを返します。
+    pub fn with_result_cache(mut self, max_entries: usize) -> Result<Self> {
+        ensure!(max_entries > 0, "max_entries must be greater than 0");
+        self.cache = Some(Arc::new(QueryCache::new(max_entries)));
+        Ok(self)
     }
 
     /// 共有接続プールからクエリ実行器を作成します。
+    ///
+    /// 共有接続プールにレプリカが設定されている場合は、読み取りをレプリカへ振り分けます。
+    /// 遅いステートメントの閾値、接続取得の再試行の方針と PgBouncer 互換モードはプールの設定
+    /// （`PoolConfig::slow_query_threshold`・`PoolConfig::acquire_retry`・`PoolConfig::pgbouncer_mode`）を
+    /// 引き継ぎます。対話的な処理（`Workload::Interactive`）用のプールを使います。
     pub fn from_shared_pool(connection_pool: &SharedConnectionPool) -> Self {
-        Self::new(connection_pool.get().clone())
+        Self::for_workload(connection_pool, Workload::Interactive)
     }
 
-    /// 単一クエリをトランザクション内で実行します。
+    /// 共有接続プールから、`workload` の処理用のプールを使うクエリ実行器を作成します。
+    ///
+    /// プールの接続数を処理の種類ごとに分割している場合（`PoolConfigBuilder::workload_split`）、
+    /// 書き込みとトランザクションは `workload` 用のプールで実行します。その他は `from_shared_pool` と同じです。
+    pub fn for_workload(connection_pool: &SharedConnectionPool, workload: Workload) -> Self {
+        Self {
+            pool: connection_pool.for_workload(workload).clone(),
+            replica: connection_pool.replica().cloned(),
+            slow_query_threshold: connection_pool.slow_query_threshold(),
+            acquire_retry: connection_pool.acquire_retry(),
+            pgbouncer_mode: connection_pool.pgbouncer_mode(),
+            max_rows: None,
+            read_timeout: None,
+            error_detail: ErrorDetail::default(),
+            cache: None,
+        }
+    }
+
+    /// 読み取りに使うプールを返します。レプリカがない場合はプライマリです。
+    fn read_pool(&self) -> &PgPool {
+        self.replica.as_ref().unwrap_or(&self.pool)
+    }
+
+    /// `pool` から接続を取得します。取得待ちがタイムアウトした場合は再試行の方針に従って再試行します。
+    async fn acquire(&self, pool: &PgPool) -> Result<PoolConnection<Postgres>> {
+        acquire_connection(pool, &self.acquire_retry)
+            .await
+            .context("Failed to acquire database connection")
+    }
+
+    /// 単独の読み取りを `db.query` スパン内で実行し、所要時間が閾値を超えた場合は警告ログを出力します。
+    async fn timed<T>(&self, future: impl Future<Output = sqlx::Result<T>>) -> sqlx::Result<T> {
+        let started_at = Instant::now();
+        let output = traced_query(future).await;
+        SlowQueryLog::new(self.slow_query_threshold).statement(None, started_at.elapsed());
+        output
+    }
+
+    /// `rows` を `timed` の中で受信し、`max_rows` を超えた場合は `DbError::RowLimitExceeded` を返します。
+    async fn collect_rows<U>(
+        &self,
+        rows: impl Stream<Item = sqlx::Result<U>>,
+        max_rows: Option<usize>,
+    ) -> std::result::Result<Vec<U>, DbError> {
+        match self.timed(collect_limited(rows, max_rows)).await {
+            Ok(Some(rows)) => Ok(rows),
+            Ok(None) => Err(DbError::RowLimitExceeded {
+                limit: max_rows.unwrap_or_default(),
+            }),
+            Err(error) => Err(DbError::from(error)),
+        }
+    }
+
+    /// `pool` から取得した接続でクエリを `read_timeout` 以内に実行し、最大 1 行を返します。
+    async fn read_one<'a, U, F>(
+        &self,
+        pool: &PgPool,
+        query: Map<'a, Postgres, F, PgArguments>,
+        read_timeout: Option<Duration>,
+    ) -> Result<Option<U>>
+    where
+        U: Send + Unpin,
+        F: FnMut(PgRow) -> std::result::Result<U, sqlx::Error> + Send + 'static,
+    {
+        let mut connection = self.acquire(pool).await?;
+        let row = within_read_timeout(
+            read_timeout,
+            self.timed(query.fetch_optional(&mut *connection))
+                .map_err(DbError::from),
+        )
+        .await
+        .inspect_err(|error| discard_if_timed_out(&mut connection, error))
+        .context("Failed to fetch optional row")?;
+        Ok(row)
+    }
+
+    /// `pool` から取得した接続でクエリを `read_timeout` 以内に実行し、最大 `max_rows` 行を返します。
+    async fn read_all<'a, U, F>(
+        &self,
+        pool: &PgPool,
+        query: Map<'a, Postgres, F, PgArguments>,
+        max_rows: Option<usize>,
+        read_timeout: Option<Duration>,
+    ) -> Result<Vec<U>>
+    where
+        U: Send + Unpin,
+        F: FnMut(PgRow) -> std::result::Result<U, sqlx::Error> + Send + 'static,
+    {
+        let mut connection = self.acquire(pool).await?;
+        let rows = within_read_timeout(
+            read_timeout,
+            self.collect_rows(query.fetch(&mut *connection), max_rows),
+        )
+        .await
+        .inspect_err(|error| discard_if_timed_out(&mut connection, error))
+        .context("Failed to fetch rows")?;
+        Ok(rows)
+    }
+
+    /// 名前付き共有接続プールからクエリ実行器を作成します。
+    ///
+    /// プールが未作成の場合は `ConnectionPool::shared_named` と同じく環境変数から作成します。
+    pub async fn from_named_pool(name: &str) -> Result<Self> {
+        let connection_pool = ConnectionPool::shared_named(name).await?;
+        Ok(Self::from_shared_pool(&connection_pool))
+    }
+
+    /// 単一クエリをトランザクション内で実行し、影響を受けた行数を返します。
     ///
     /// 成功時はコミットし、失敗時はロールバックします。
-    pub async fn execute_query<'a>(&self, query: Query<'a, Postgres, PgArguments>) -> Result<()> {
-        self.execute_queries(std::iter::once(query)).await
+    pub async fn execute_query<'a>(&self, query: Query<'a, Postgres, PgArguments>) -> Result<u64> {
+        let rows_affected = self.execute_queries(std::iter::once(query)).await?;
+        Ok(rows_affected.into_iter().sum())
+    }
+
+    /// `channel` に `payload` を `NOTIFY` で送信します。
+    ///
+    /// `pg_notify` にバインド変数として渡すため、チャネル名とペイロードに任意の文字列を使えます。
+    /// 通知はトランザクションのコミット時に配信されます。
+    pub async fn notify(&self, channel: &str, payload: &str) -> Result<()> {
+        self.execute_query(
+            sqlx::query("SELECT pg_notify($1, $2)")
+                .bind(channel)
+                .bind(payload),
+        )
+        .await
+        .with_context(|| format!("Failed to notify channel {channel}"))?;
+        Ok(())
     }
 
-    /// 複数クエリを単一トランザクション内で実行します。
+    /// 複数クエリを単一トランザクション内で実行し、クエリごとに影響を受けた行数を返します。
     ///
     /// いずれかのクエリが失敗した場合はトランザクションをロールバックし、エラーを返します。
-    pub async fn execute_queries<'a, I>(&self, queries: I) -> Result<()>
+    pub async fn execute_queries<'a, I>(&self, queries: I) -> Result<Vec<u64>>
     where
         I: IntoIterator<Item = Query<'a, Postgres, PgArguments>>,
     {
-        let mut tx: Transaction<'_, Postgres> = self
-            .pool
-            .begin()
+        self.execute_queries_with_options(&TransactionOptions::default(), queries)
             .await
-            .context("Failed to start database transaction")?;
+    }
 
-        for (index, query) in queries.into_iter().enumerate() {
-            if let Err(error) = query.execute(&mut *tx).await {
-                tx.rollback()
-                    .await
-                    .context("Failed to rollback transaction")?;
-                return Err(error).with_context(|| {
-                    format!("Failed to execute query in transaction at index {index}")
-                });
+    /// 互いに独立したクエリを、それぞれ別の接続・トランザクションで最大 `max_concurrency` 件ずつ並行に実行します。
+    ///
+    /// 結果は入力と同じ順序で、クエリごとに影響を受けた行数またはエラーを返します。同時実行数は
+    /// プールの最大接続数を超えないよう切り詰めます。`fail_fast` が `false` の場合は、あるクエリが
+    /// 失敗しても残りのクエリをすべて実行します。`true` の場合は最初の失敗の時点で実行中のクエリを
+    /// 破棄してロールバックし、未完了のクエリの結果は原因に `DbError::Cancelled` を含むエラーになります。
+    /// クエリ同士の原子性はないため、成功したクエリは他のクエリの失敗にかかわらずコミットされます。
+    pub async fn execute_parallel<'a, I>(
+        &self,
+        queries: I,
+        max_concurrency: usize,
+        fail_fast: bool,
+    ) -> Result<Vec<Result<u64>>>
+    where
+        I: IntoIterator<Item = Query<'a, Postgres, PgArguments>>,
+    {
+        ensure!(
+            max_concurrency > 0,
+            "max_concurrency must be greater than 0"
+        );
+        let max_connections =
+            usize::try_from(self.pool.options().get_max_connections()).unwrap_or(usize::MAX);
+        let semaphore = Semaphore::new(max_concurrency.min(max_connections));
+
+        let mut pending: FuturesUnordered<_> = queries
+            .into_iter()
+            .enumerate()
+            .map(|(index, query)| {
+                let semaphore = &semaphore;
+                async move {
+                    let _permit = semaphore
+                        .acquire()
+                        .await
+                        .expect("semaphore is never closed");
+                    (index, self.execute_query(query).await)
+                }
+            })
+            .collect();
+
+        let mut results: Vec<Option<Result<u64>>> = (0..pending.len()).map(|_| None).collect();
+        while let Some((index, result)) = pending.next().await {
+            let failed = result.is_err();
+            results[index] = Some(result);
+            if failed && fail_fast {
+                break;
             }
         }
+        drop(pending);
 
-        tx.commit().await.context("Failed to commit transaction")?;
-        Ok(())
+        Ok(results
+            .into_iter()
+            .enumerate()
+            .map(|(index, result)| {
+                result.unwrap_or_else(|| {
+                    Err(DbError::Cancelled).with_context(|| {
+                        format!(
+                            "Query at index {index} was not completed because another query failed"
+                        )
+                    })
+                })
+            })
+            .collect())
+    }
+
+    /// トランザクションの特性を指定して複数クエリを単一トランザクション内で実行します。
+    ///
+    /// `options.read_only` が `true` の場合に書き込みを行うと、PostgreSQL のエラーが
+    /// 失敗したクエリのインデックス付きで返り、トランザクションはロールバックされます。
+    pub async fn execute_queries_with_options<'a, I>(
+        &self,
+        options: &TransactionOptions,
+        queries: I,
+    ) -> Result<Vec<u64>>
+    where
+        I: IntoIterator<Item = Query<'a, Postgres, PgArguments>>,
+    {
+        self.run_queries(options, None, queries).await
+    }
+
+    /// SQL とバインド引数の組を単一トランザクション内で順に実行し、クエリごとに影響を受けた行数を返します。
+    ///
+    /// 各 SQL の先頭に `tag` をコメントとして付与してから実行します。`Query` は SQL を書き換えられないため、
+    /// タグを付ける場合は SQL の文字列と `PgArguments` を渡してください。失敗時の挙動は `execute_queries` と同じです。
+    pub async fn execute_queries_tagged<S, I>(
+        &self,
+        tag: &QueryTag,
+        statements: I,
+    ) -> Result<Vec<u64>>
+    where
+        S: AsRef<str>,
+        I: IntoIterator<Item = (S, PgArguments)>,
+    {
+        let (sqls, arguments): (Vec<String>, Vec<PgArguments>) = statements
+            .into_iter()
+            .map(|(sql, args)| (tag.apply(sql.as_ref()), args))
+            .unzip();
+        let queries = sqls
+            .iter()
+            .zip(arguments)
+            .map(|(sql, args)| sqlx::query_with(sql, args));
+        self.run_queries(&TransactionOptions::default(), None, queries)
+            .await
+    }
+
+    /// `execute_queries` と同じく複数クエリを単一トランザクション内で実行し、遅いステートメントや
+    /// トランザクションの警告ログに `label` を含めます。
+    ///
+    /// ログには SQL を出力しないため、バッチ名などで対象を識別できるようにするためのものです。
+    pub async fn execute_queries_labeled<'a, I>(&self, label: &str, queries: I) -> Result<Vec<u64>>
+    where
+        I: IntoIterator<Item = Query<'a, Postgres, PgArguments>>,
+    {
+        self.run_queries(&TransactionOptions::default(), Some(label), queries)
+            .await
+    }
+
+    async fn run_queries<'a, I>(
+        &self,
+        options: &TransactionOptions,
+        label: Option<&str>,
+        queries: I,
+    ) -> Result<Vec<u64>>
+    where
+        I: IntoIterator<Item = Query<'a, Postgres, PgArguments>>,
+    {
+        TransactionExecutor::new(self.pool.clone())
+            .with_slow_query_threshold(self.slow_query_threshold)
+            .with_acquire_retry(self.acquire_retry)
+            .with_pgbouncer_mode(self.pgbouncer_mode)
+            .with_error_detail(self.error_detail)
+            .run_queries_labeled(options, label, queries)
+            .await
+    }
+
+    /// 複数クエリを単一トランザクション内で実行し、コミットまでに生成された WAL のバイト数を返します。
+    ///
+    /// トランザクション開始前とコミット後に `pg_current_wal_insert_lsn()` を読み取り、
+    /// `pg_wal_lsn_diff` で差分を求めます。WAL はクラスタ全体で共有されるため、
+    /// 同時に実行されている他セッションの書き込みも値に含まれる点に注意してください。
+    pub async fn execute_queries_with_wal_bytes<'a, I>(&self, queries: I) -> Result<u64>
+    where
+        I: IntoIterator<Item = Query<'a, Postgres, PgArguments>>,
+    {
+        let start_lsn: String = sqlx::query_scalar("SELECT pg_current_wal_insert_lsn()::text")
+            .unprepared_if(self.pgbouncer_mode)
+            .fetch_one(&mut *self.acquire(&self.pool).await?)
+            .await
+            .map_err(DbError::from)
+            .context("Failed to read WAL position before transaction")?;
+
+        self.execute_queries(queries).await?;
+
+        let wal_bytes: i64 = sqlx::query_scalar(
+            "SELECT pg_wal_lsn_diff(pg_current_wal_insert_lsn(), $1::pg_lsn)::bigint",
+        )
+        .bind(&start_lsn)
+        .unprepared_if(self.pgbouncer_mode)
+        .fetch_one(&mut *self.acquire(&self.pool).await?)
+        .await
+        .map_err(DbError::from)
+        .context("Failed to read WAL position after transaction")?;
+
+        u64::try_from(wal_bytes).with_context(|| {
+            format!("WAL position moved backwards by {wal_bytes} bytes during transaction")
+        })
     }
 
     /// マッピング済みクエリを実行し、最大 1 行を返します。
@@ -69,11 +543,140 @@ impl QueryExecutor {
         U: Send + Unpin,
         F: FnMut(PgRow) -> std::result::Result<U, sqlx::Error> + Send + 'static,
     {
-        let row = query
-            .fetch_optional(&self.pool)
+        ensure_map_supported(self.pgbouncer_mode)?;
+        self.read_one(self.read_pool(), query, self.read_timeout)
             .await
-            .context("Failed to fetch optional row")?;
-        Ok(row)
+    }
+
+    /// マッピング済みクエリを `timeout` 以内に実行し、最大 1 行を返します。
+    ///
+    /// `with_read_timeout` の上限より優先します。期限を過ぎた場合は原因に `DbError::Timeout` を含むエラーを返し、
+    /// 使っていた接続はプールに戻さずに閉じます。
+    pub async fn fetch_one_timeout<'a, U, F>(
+        &self,
+        query: Map<'a, Postgres, F, PgArguments>,
+        timeout: Duration,
+    ) -> Result<Option<U>>
+    where
+        U: Send + Unpin,
+        F: FnMut(PgRow) -> std::result::Result<U, sqlx::Error> + Send + 'static,
+    {
+        ensure_map_supported(self.pgbouncer_mode)?;
+        self.read_one(self.read_pool(), query, Some(timeout)).await
+    }
+
+    /// マッピング済みクエリをレプリカの有無にかかわらずプライマリで実行し、最大 1 行を返します。
+    ///
+    /// 書き込み直後にその結果を読む場合など、レプリカの遅延が許容できない読み取りに使います。
+    pub async fn fetch_one_primary<'a, U, F>(
+        &self,
+        query: Map<'a, Postgres, F, PgArguments>,
+    ) -> Result<Option<U>>
+    where
+        U: Send + Unpin,
+        F: FnMut(PgRow) -> std::result::Result<U, sqlx::Error> + Send + 'static,
+    {
+        ensure_map_supported(self.pgbouncer_mode)?;
+        self.read_one(&self.pool, query, self.read_timeout).await
+    }
+
+    /// マッピング済みクエリをレプリカの有無にかかわらずプライマリで実行し、全行をベクタとして返します。
+    pub async fn fetch_all_primary<'a, U, F>(
+        &self,
+        query: Map<'a, Postgres, F, PgArguments>,
+    ) -> Result<Vec<U>>
+    where
+        U: Send + Unpin,
+        F: FnMut(PgRow) -> std::result::Result<U, sqlx::Error> + Send + 'static,
+    {
+        ensure_map_supported(self.pgbouncer_mode)?;
+        self.read_all(&self.pool, query, self.max_rows, self.read_timeout)
+            .await
+    }
+
+    /// マッピング済みクエリを実行し、ちょうど 1 行であることを確認して返します。
+    ///
+    /// `fetch_one` は結果が空の場合に `Ok(None)` を返し、2 行目以降を読み捨てます。
+    /// このメソッドは 0 行なら `DbError::NotFound`、2 行以上なら `DbError::TooManyRows` を
+    /// エラーの原因に含めて返すため、主キー検索で該当なしや重複を見逃したくない場合に使います。
+    /// 行数を数えるために全行を受信する点に注意してください。
+    pub async fn fetch_exactly_one<'a, U, F>(
+        &self,
+        query: Map<'a, Postgres, F, PgArguments>,
+    ) -> Result<U>
+    where
+        U: Send + Unpin,
+        F: FnMut(PgRow) -> std::result::Result<U, sqlx::Error> + Send + 'static,
+    {
+        ensure_map_supported(self.pgbouncer_mode)?;
+        let rows = self
+            .read_all(self.read_pool(), query, None, self.read_timeout)
+            .await?;
+        exactly_one(rows)
+    }
+
+    /// クエリを実行し、ちょうど 1 行であることを確認して `FromRow` 実装型に変換して返します。
+    ///
+    /// 行数の扱いは `fetch_exactly_one` と同じです。`Map` を受け付けない PgBouncer 互換モードでも使えます。
+    pub async fn fetch_exactly_one_as<'a, T>(
+        &self,
+        query: Query<'a, Postgres, PgArguments>,
+    ) -> Result<T>
+    where
+        T: for<'r> FromRow<'r, PgRow> + Send + Unpin,
+    {
+        let rows = self
+            .read_all(
+                self.read_pool(),
+                query
+                    .unprepared_if(self.pgbouncer_mode)
+                    .try_map(|row: PgRow| T::from_row(&row)),
+                None,
+                self.read_timeout,
+            )
+            .await?;
+        exactly_one(rows)
+    }
+
+    /// マッピング済みクエリを読み取り専用トランザクション内で実行し、全行をベクタとして返します。
+    ///
+    /// クエリが書き込みを試みた場合はデータベースがエラーを返し、トランザクションはロールバックされます。
+    pub async fn fetch_all_read_only<'a, U, F>(
+        &self,
+        query: Map<'a, Postgres, F, PgArguments>,
+    ) -> Result<Vec<U>>
+    where
+        U: Send + Unpin,
+        F: FnMut(PgRow) -> std::result::Result<U, sqlx::Error> + Send + 'static,
+    {
+        ensure_map_supported(self.pgbouncer_mode)?;
+        let mut tx = begin_with_options(
+            &self.pool,
+            &TransactionOptions::read_only(),
+            &self.acquire_retry,
+        )
+        .await?;
+        let rows = match collect_limited(query.fetch(&mut *tx), self.max_rows).await {
+            Ok(Some(rows)) => rows,
+            result => {
+                tx.rollback()
+                    .await
+                    .map_err(DbError::from)
+                    .context("Failed to rollback transaction")?;
+                let error = match result {
+                    Err(error) => DbError::from(error),
+                    _ => DbError::RowLimitExceeded {
+                        limit: self.max_rows.unwrap_or_default(),
+                    },
+                };
+                return Err(error).context("Failed to fetch rows in read-only transaction");
+            }
+        };
+        tx.commit()
+            .await
+            .map_err(DbError::from)
+            .context("Failed to commit transaction")?;
+        Ok(rows)
     }
 
     /// マッピング済みクエリを実行し、全行をベクタとして返します。
@@ -85,10 +688,997 @@ impl QueryExecutor {
         U: Send + Unpin,
         F: FnMut(PgRow) -> std::result::Result<U, sqlx::Error> + Send + 'static,
     {
-        let rows = query
-            .fetch_all(&self.pool)
+        ensure_map_supported(self.pgbouncer_mode)?;
+        self.read_all(self.read_pool(), query, self.max_rows, self.read_timeout)
+            .await
+    }
+
+    /// マッピング済みクエリを `timeout` 以内に実行し、全行をベクタとして返します。
+    ///
+    /// 期限は全行の受信までに適用されます。期限を過ぎた場合の扱いは `fetch_one_timeout` と同じです。
+    pub async fn fetch_all_timeout<'a, U, F>(
+        &self,
+        query: Map<'a, Postgres, F, PgArguments>,
+        timeout: Duration,
+    ) -> Result<Vec<U>>
+    where
+        U: Send + Unpin,
+        F: FnMut(PgRow) -> std::result::Result<U, sqlx::Error> + Send + 'static,
+    {
+        ensure_map_supported(self.pgbouncer_mode)?;
+        self.read_all(self.read_pool(), query, self.max_rows, Some(timeout))
+            .await
+    }
+
+    /// マッピング済みクエリを実行し、最大 `max_rows` 行を全行のベクタとして返します。
+    ///
+    /// 結果を 1 行ずつ受信して数え、`max_rows` を超える行を受信した時点で読み取りをやめて、原因に
+    /// `DbError::RowLimitExceeded` を含むエラーを返します。SQL に `LIMIT` を付け加えないため、呼び出し側の
+    /// SQL の意味は変わりません。読み取りをやめた接続の未受信の結果は SQLx がプールへの返却時に読み捨てるため、
+    /// 接続はそのまま再利用されます。`with_max_rows` の上限より優先します。
+    pub async fn fetch_all_limited<'a, U, F>(
+        &self,
+        query: Map<'a, Postgres, F, PgArguments>,
+        max_rows: usize,
+    ) -> Result<Vec<U>>
+    where
+        U: Send + Unpin,
+        F: FnMut(PgRow) -> std::result::Result<U, sqlx::Error> + Send + 'static,
+    {
+        ensure_map_supported(self.pgbouncer_mode)?;
+        self.read_all(self.read_pool(), query, Some(max_rows), self.read_timeout)
+            .await
+    }
+
+    /// `key` に対応する結果のキャッシュが有効であればその複製を返し、なければ `fetch_all` で取得して
+    /// `ttl` の間キャッシュします。
+    ///
+    /// 通貨や機能フラグなど、頻繁に読まれる小さな参照用テーブル向けです。同じ `key` への問い合わせが同時に
+    /// 行われた場合、データベースに問い合わせるのは 1 回だけで、他の呼び出しはその結果を待ちます。
+    /// 取得に失敗した場合はキャッシュせずにエラーを返します。`key` は SQL とバインドする値の組ごとに
+    /// 一意にしてください。同じ `key` で異なる行の型を要求した場合はエラーを返します。
+    /// 書き込み後に古い結果を返さないようにするには `invalidate` を呼んでください。
+    /// `with_result_cache` でキャッシュを有効にしていない場合はエラーを返します。
+    pub async fn fetch_all_cached<'a, U, F>(
+        &self,
+        key: &str,
+        ttl: Duration,
+        query: Map<'a, Postgres, F, PgArguments>,
+    ) -> Result<Vec<U>>
+    where
+        U: Clone + Send + Sync + Unpin + 'static,
+        F: FnMut(PgRow) -> std::result::Result<U, sqlx::Error> + Send + 'static,
+    {
+        let Some(cache) = &self.cache else {
+            bail!("Result cache is not enabled for this executor");
+        };
+        cache
+            .get_or_fetch(key, ttl, self.fetch_all(query))
             .await
-            .context("Failed to fetch rows")?;
+            .with_context(|| format!("Failed to fetch cached rows for key {key:?}"))
+    }
+
+    /// `key` のキャッシュを破棄し、破棄した場合は `true` を返します。取得中の結果は、取得を待っている
+    /// 呼び出しには返りますが、キャッシュには残りません。
+    pub fn invalidate(&self, key: &str) -> bool {
+        self.cache
+            .as_ref()
+            .is_some_and(|cache| cache.invalidate(key))
+    }
+
+    /// すべてのキャッシュを破棄します。
+    pub fn invalidate_all(&self) {
+        if let Some(cache) = &self.cache {
+            cache.invalidate_all();
+        }
+    }
+
+    /// `QuerySpec` を実行し、全行を `FromRow` 実装型に変換したベクタとして返します。
+    ///
+    /// エラーにはバインドした値の位置と型（`$1 int8, $2 text` など）を含めます。
+    pub async fn fetch_all_spec<T>(&self, spec: &QuerySpec) -> Result<Vec<T>>
+    where
+        T: for<'r> FromRow<'r, PgRow> + Send + Unpin,
+    {
+        let mut connection = self.acquire(self.read_pool()).await?;
+        let rows = within_read_timeout(
+            self.read_timeout,
+            self.collect_rows(
+                spec.query()
+                    .unprepared_if(self.pgbouncer_mode)
+                    .try_map(|row: PgRow| T::from_row(&row))
+                    .fetch(&mut *connection),
+                self.max_rows,
+            ),
+        )
+        .await
+        .inspect_err(|error| discard_if_timed_out(&mut connection, error))
+        .with_context(|| {
+            format!(
+                "Failed to fetch rows for query spec ({})",
+                spec.describe_parameters()
+            )
+        })?;
         Ok(rows)
     }
+
+    /// 列が事前にわからない SQL を実行し、各行を列名をキーとする JSON のマップとして返します。
+    ///
+    /// 値は型に応じて変換します。整数・浮動小数点数・真偽値・文字列・`json`/`jsonb` はそのまま、
+    /// `numeric` は精度を保つため文字列、`bytea` は Base64、日時は RFC 3339 形式（タイムゾーンのない
+    /// `timestamp` はオフセットなし）、`uuid` は文字列にし、これらの 1 次元配列は要素ごとに変換します。
+    /// NULL は `null` になります。対応していない型の値はテキスト表現にします。
+    ///
+    /// 同じ名前の列が複数ある場合は、最初の列が元の名前を使い、以降の列には `_2`、`_3` と出現順に
+    /// 接尾辞を付けます。リードレプリカが設定されている場合はレプリカで実行します。
+    pub async fn fetch_all_json(
+        &self,
+        sql: &str,
+        args: PgArguments,
+    ) -> Result<Vec<serde_json::Map<String, serde_json::Value>>> {
+        self.fetch_json(sql, args, false).await
+    }
+
+    /// `fetch_all_json` と同じく実行しますが、対応していない型の列があった場合はエラーを返します。
+    pub async fn fetch_all_json_strict(
+        &self,
+        sql: &str,
+        args: PgArguments,
+    ) -> Result<Vec<serde_json::Map<String, serde_json::Value>>> {
+        self.fetch_json(sql, args, true).await
+    }
+
+    async fn fetch_json(
+        &self,
+        sql: &str,
+        args: PgArguments,
+        strict: bool,
+    ) -> Result<Vec<serde_json::Map<String, serde_json::Value>>> {
+        let mut connection = self.acquire(self.read_pool()).await?;
+        let rows = within_read_timeout(
+            self.read_timeout,
+            self.collect_rows(
+                sqlx::query_with(sql, args)
+                    .unprepared_if(self.pgbouncer_mode)
+                    .fetch(&mut *connection),
+                self.max_rows,
+            ),
+        )
+        .await
+        .inspect_err(|error| discard_if_timed_out(&mut connection, error))
+        .context("Failed to fetch rows")?;
+        rows.iter()
+            .enumerate()
+            .map(|(index, row)| {
+                row_to_json(row, strict)
+                    .with_context(|| format!("Failed to convert row {index} to JSON"))
+            })
+            .collect()
+    }
+
+    /// `sql` の実行計画を `EXPLAIN` で取得します。
+    ///
+    /// `args` は `sql` のプレースホルダにバインドする値です。`options.analyze` が `true` の場合は
+    /// ステートメントが実際に実行されるため、DML の副作用が残らないよう、常にプライマリの
+    /// トランザクション内で実行してからロールバックします。
+    pub async fn explain(
+        &self,
+        sql: &str,
+        args: PgArguments,
+        options: ExplainOptions,
+    ) -> Result<ExplainPlan> {
+        ensure!(
+            !sql.trim().is_empty(),
+            "Cannot explain an empty SQL statement"
+        );
+        let explain_sql = options.apply(sql);
+        let mut tx = begin_with_options(
+            &self.pool,
+            &TransactionOptions::default(),
+            &self.acquire_retry,
+        )
+        .await?;
+        let rows = self
+            .timed(
+                sqlx::query_with(&explain_sql, args)
+                    .unprepared_if(self.pgbouncer_mode)
+                    .fetch_all(&mut *tx),
+            )
+            .await
+            .map_err(DbError::from)
+            .context("Failed to explain query");
+        tx.rollback()
+            .await
+            .map_err(DbError::from)
+            .context("Failed to roll back EXPLAIN transaction")?;
+        options.parse(rows?)
+    }
+
+    /// 先頭に `tag` をコメントとして付与した SQL を実行し、全行を `FromRow` 実装型に変換したベクタとして返します。
+    pub async fn fetch_all_tagged<T>(
+        &self,
+        tag: &QueryTag,
+        sql: &str,
+        args: PgArguments,
+    ) -> Result<Vec<T>>
+    where
+        T: for<'r> FromRow<'r, PgRow> + Send + Unpin,
+    {
+        let sql = tag.apply(sql);
+        let mut connection = self.acquire(self.read_pool()).await?;
+        let rows = within_read_timeout(
+            self.read_timeout,
+            self.collect_rows(
+                sqlx::query_as_with(&sql, args)
+                    .unprepared_if(self.pgbouncer_mode)
+                    .fetch(&mut *connection),
+                self.max_rows,
+            ),
+        )
+        .await
+        .inspect_err(|error| discard_if_timed_out(&mut connection, error))
+        .context("Failed to fetch rows")?;
+        Ok(rows)
+    }
+
+    /// クエリを実行し、最大 1 行を `FromRow` 実装型に変換して返します。
+    ///
+    /// クエリ結果が空の場合は `Ok(None)` を返します。
+    pub async fn fetch_optional_as<'a, T>(
+        &self,
+        query: Query<'a, Postgres, PgArguments>,
+    ) -> Result<Option<T>>
+    where
+        T: for<'r> FromRow<'r, PgRow> + Send + Unpin,
+    {
+        self.read_one(
+            self.read_pool(),
+            query
+                .unprepared_if(self.pgbouncer_mode)
+                .try_map(|row: PgRow| T::from_row(&row)),
+            self.read_timeout,
+        )
+        .await
+    }
+
+    /// マッピング済みクエリを実行し、行を 1 行ずつ返すストリームを返します。
+    ///
+    /// `fetch_all` と異なり結果をベクタにまとめないため、大量の行を一定のメモリで順に処理できます。
+    /// ストリームは取得元の接続を保持し続けるため、処理が終わるか不要になった時点で破棄してください。
+    /// 途中で発生したエラーはストリームの要素として返り、その時点でストリームは終了します。
+    pub fn fetch_stream<'a, U, F>(
+        &'a self,
+        query: Map<'a, Postgres, F, PgArguments>,
+    ) -> impl Stream<Item = Result<U>> + Send + 'a
+    where
+        U: Send + Unpin + 'a,
+        F: FnMut(PgRow) -> std::result::Result<U, sqlx::Error> + Send + 'static,
+    {
+        if let Err(error) = ensure_map_supported(self.pgbouncer_mode) {
+            return stream::once(future::ready(Err(error))).left_stream();
+        }
+        query
+            .fetch(self.read_pool())
+            .map(|row| {
+                row.map_err(DbError::from)
+                    .context("Failed to fetch row from stream")
+            })
+            .right_stream()
+    }
+
+    /// クエリを実行し、行を 1 行ずつ `FromRow` 実装型に変換して返すストリームを返します。
+    ///
+    /// ストリームの扱いは `fetch_stream` と同じです。`Map` を受け付けない PgBouncer 互換モードでも使えます。
+    pub fn fetch_stream_as<'a, T>(
+        &'a self,
+        query: Query<'a, Postgres, PgArguments>,
+    ) -> impl Stream<Item = Result<T>> + Send + 'a
+    where
+        T: for<'r> FromRow<'r, PgRow> + Send + Unpin + 'a,
+    {
+        query
+            .unprepared_if(self.pgbouncer_mode)
+            .try_map(|row: PgRow| T::from_row(&row))
+            .fetch(self.read_pool())
+            .map(|row| {
+                row.map_err(DbError::from)
+                    .context("Failed to fetch row from stream")
+            })
+    }
+
+    /// クエリを実行し、全行を `FromRow` 実装型に変換したベクタとして返します。
+    pub async fn fetch_all_as<'a, T>(
+        &self,
+        query: Query<'a, Postgres, PgArguments>,
+    ) -> Result<Vec<T>>
+    where
+        T: for<'r> FromRow<'r, PgRow> + Send + Unpin,
+    {
+        self.read_all(
+            self.read_pool(),
+            query
+                .unprepared_if(self.pgbouncer_mode)
+                .try_map(|row: PgRow| T::from_row(&row)),
+            self.max_rows,
+            self.read_timeout,
+        )
+        .await
+    }
+
+    /// `sqlx::query_as` で作成したクエリを実行し、最大 1 行を返します。
+    ///
+    /// `fetch_optional_as` と同じく結果が空の場合は `Ok(None)` を返します。
+    /// `bind` を連結した `query_as` をそのまま渡せるため、列数の多い構造体を
+    /// `#[derive(FromRow)]` で受け取る場合に使います。NULL を許容する列は `Option` で受け取ってください。
+    pub async fn fetch_one_query_as<'a, T>(
+        &self,
+        query: QueryAs<'a, Postgres, T, PgArguments>,
+    ) -> Result<Option<T>>
+    where
+        T: for<'r> FromRow<'r, PgRow> + Send + Unpin,
+    {
+        let mut connection = self.acquire(self.read_pool()).await?;
+        let row = within_read_timeout(
+            self.read_timeout,
+            self.timed(
+                query
+                    .unprepared_if(self.pgbouncer_mode)
+                    .fetch_optional(&mut *connection),
+            )
+            .map_err(DbError::from),
+        )
+        .await
+        .inspect_err(|error| discard_if_timed_out(&mut connection, error))
+        .context("Failed to fetch optional row")?;
+        Ok(row)
+    }
+
+    /// `sqlx::query_as` で作成したクエリを実行し、全行をベクタとして返します。
+    pub async fn fetch_all_query_as<'a, T>(
+        &self,
+        query: QueryAs<'a, Postgres, T, PgArguments>,
+    ) -> Result<Vec<T>>
+    where
+        T: for<'r> FromRow<'r, PgRow> + Send + Unpin,
+    {
+        let mut connection = self.acquire(self.read_pool()).await?;
+        let rows = within_read_timeout(
+            self.read_timeout,
+            self.collect_rows(
+                query
+                    .unprepared_if(self.pgbouncer_mode)
+                    .fetch(&mut *connection),
+                self.max_rows,
+            ),
+        )
+        .await
+        .inspect_err(|error| discard_if_timed_out(&mut connection, error))
+        .context("Failed to fetch rows")?;
+        Ok(rows)
+    }
+
+    /// IN リストを含むクエリをキーのバッチごとに実行し、全行を連結して返します。
+    ///
+    /// `sql` 内の `IN_LIST_PLACEHOLDER` は各バッチの `$1, $2, ...` に置き換えられます。
+    /// バッチはキー数が `limits.max_keys` を超えるか、エンコード後のキーの合計バイト数が
+    /// `limits.max_bytes` を超える時点で分割されます。単独で `max_bytes` を超えるキーは
+    /// 1 件だけのバッチとして送信されます。
+    pub async fn fetch_in<K, T>(
+        &self,
+        sql: &str,
+        keys: &[K],
+        limits: InListLimits,
+    ) -> Result<Vec<T>>
+    where
+        K: for<'q> Encode<'q, Postgres> + Type<Postgres> + Sync,
+        T: for<'r> FromRow<'r, PgRow> + Send + Unpin,
+    {
+        ensure!(
+            sql.contains(IN_LIST_PLACEHOLDER),
+            "SQL for fetch_in must contain {IN_LIST_PLACEHOLDER}"
+        );
+        ensure!(limits.max_keys > 0, "max_keys must be greater than 0");
+        ensure!(limits.max_bytes > 0, "max_bytes must be greater than 0");
+
+        let sizes = keys
+            .iter()
+            .enumerate()
+            .map(|(index, key)| {
+                encoded_size(key)
+                    .with_context(|| format!("Failed to encode IN list key at index {index}"))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let mut rows = Vec::new();
+        for (batch_index, batch) in split_in_list_batches(&sizes, limits)
+            .into_iter()
+            .enumerate()
+        {
+            let placeholders = (1..=batch.len())
+                .map(|position| format!("${position}"))
+                .collect::<Vec<_>>()
+                .join(", ");
+            let batch_sql = sql.replace(IN_LIST_PLACEHOLDER, &placeholders);
+
+            let mut query = sqlx::query(&batch_sql);
+            for key in &keys[batch] {
+                query = query.bind(key);
+            }
+
+            let batch_rows = self
+                .fetch_all_as::<T>(query)
+                .await
+                .with_context(|| format!("Failed to fetch IN list batch {batch_index}"))?;
+            rows.extend(batch_rows);
+        }
+
+        Ok(rows)
+    }
+
+    /// `TABLESAMPLE BERNOULLI` を使い、テーブルから無作為抽出した行を返します。
+    ///
+    /// `percentage` は抽出する行の割合を百分率で指定し、`(0, 100]` の範囲である必要があります。
+    /// `seed` を指定すると `REPEATABLE` 句が付与され、テーブルが変更されない限り同じ行が返ります。
+    /// 行ごとに独立して抽出するため、返る行数は割合どおりになるとは限りません。
+    pub async fn fetch_sample<T>(
+        &self,
+        table: &str,
+        percentage: f64,
+        seed: Option<f64>,
+    ) -> Result<Vec<T>>
+    where
+        T: for<'r> FromRow<'r, PgRow> + Send + Unpin,
+    {
+        ensure!(
+            percentage.is_finite() && percentage > 0.0 && percentage <= 100.0,
+            "Sample percentage must be in (0, 100], got {percentage}"
+        );
+        let table = quote_qualified_identifier(table)?;
+
+        // 数値は検証済みの有限値のみを埋め込むため、文字列連結でも安全です。
+        let mut sql = format!("SELECT * FROM {table} TABLESAMPLE BERNOULLI ({percentage})");
+        if let Some(seed) = seed {
+            ensure!(seed.is_finite(), "Sample seed must be finite, got {seed}");
+            sql.push_str(&format!(" REPEATABLE ({seed})"));
+        }
+
+        self.fetch_all_as::<T>(sqlx::query(&sql))
+            .await
+            .with_context(|| format!("Failed to fetch sample from {table}"))
+    }
+
+    /// キーセットページネーションで `base_sql` の結果から 1 ページ分の行を返します。
+    ///
+    /// `base_sql` をサブクエリとして包み、`key_column` の順に並べて `page_size` 行を取得します。
+    /// `after` を指定した場合は、そのキーより後ろ（`direction` が `Descending` なら前）の行から始めます。
+    /// キーは `$1` としてバインドされるため、`base_sql` 自体はパラメータを持てません。
+    /// `OFFSET` と異なり読み飛ばす行を走査しないため、ページが深くなっても速度が落ちません。
+    ///
+    /// `key_column` は一意で、`base_sql` の結果に含まれている必要があります。
+    /// 続きがあるかを判定するために `page_size + 1` 行を取得し、続きがない場合の `next_key` は `None` です。
+    pub async fn fetch_page<U, K>(
+        &self,
+        base_sql: &str,
+        key_column: &str,
+        after: Option<K>,
+        page_size: usize,
+        direction: PageDirection,
+    ) -> Result<Page<U, K>>
+    where
+        U: for<'r> FromRow<'r, PgRow> + Send + Unpin + 'static,
+        K: for<'q> Encode<'q, Postgres>
+            + for<'r> Decode<'r, Postgres>
+            + Type<Postgres>
+            + Send
+            + Unpin
+            + 'static,
+    {
+        ensure!(page_size > 0, "page_size must be greater than 0");
+        let quoted_key = quote_identifier(key_column)?;
+        let operator = direction.comparison_operator();
+        let order = direction.order_keyword();
+
+        let filter = if after.is_some() {
+            format!(" WHERE page.{quoted_key} {operator} $1")
+        } else {
+            String::new()
+        };
+        // 件数は検証済みの整数のみを埋め込むため、文字列連結でも安全です。
+        let sql = format!(
+            "SELECT * FROM ({base_sql}) AS page{filter} \
+             ORDER BY page.{quoted_key} {order} LIMIT {}",
+            page_size + 1
+        );
+
+        let mut query = sqlx::query(&sql);
+        if let Some(after) = after {
+            query = query.bind(after);
+        }
+        let key_column = key_column.to_string();
+        let query = query
+            .unprepared_if(self.pgbouncer_mode)
+            .try_map(move |row: PgRow| {
+                let key = row.try_get::<K, _>(key_column.as_str())?;
+                Ok((U::from_row(&row)?, key))
+            });
+        let mut connection = self.acquire(self.read_pool()).await?;
+        let mut rows = within_read_timeout(
+            self.read_timeout,
+            self.timed(query.fetch_all(&mut *connection))
+                .map_err(DbError::from),
+        )
+        .await
+        .inspect_err(|error| discard_if_timed_out(&mut connection, error))
+        .with_context(|| format!("Failed to fetch page ordered by {quoted_key}"))?;
+
+        let has_next = rows.len() > page_size;
+        rows.truncate(page_size);
+        let (rows, keys): (Vec<U>, Vec<K>) = rows.into_iter().unzip();
+        let next_key = if has_next {
+            keys.into_iter().last()
+        } else {
+            None
+        };
+        Ok(Page { rows, next_key })
+    }
+
+    /// `valid_from`/`valid_to` 列を持つテーブルに対し、指定時点で有効だった行を返します。
+    ///
+    /// `base_sql` をサブクエリとして包み、
+    /// `valid_from <= as_of AND (valid_to IS NULL OR valid_to > as_of)` の条件を付与します。
+    /// `base_sql` の結果には `valid_from` と `valid_to` 列が含まれている必要があり、
+    /// 時点は `$1` としてバインドされるため、`base_sql` 自体はパラメータを持てません。
+    pub async fn fetch_as_of<T>(&self, base_sql: &str, as_of: DateTime<Utc>) -> Result<Vec<T>>
+    where
+        T: for<'r> FromRow<'r, PgRow> + Send + Unpin,
+    {
+        let sql = format!(
+            "SELECT * FROM ({base_sql}) AS temporal \
+             WHERE temporal.{TEMPORAL_VALID_FROM_COLUMN} <= $1 \
+             AND (temporal.{TEMPORAL_VALID_TO_COLUMN} IS NULL \
+             OR temporal.{TEMPORAL_VALID_TO_COLUMN} > $1)"
+        );
+
+        self.fetch_all_as::<T>(sqlx::query(&sql).bind(as_of))
+            .await
+            .with_context(|| format!("Failed to fetch rows as of {as_of}"))
+    }
+}
+
+/// 値を PostgreSQL のバインドパラメータとしてエンコードした際のバイト数を返します。
+fn encoded_size<K>(key: &K) -> Result<usize>
+where
+    K: for<'q> Encode<'q, Postgres>,
+{
+    let mut buffer = PgArgumentBuffer::default();
+    // NULL はパラメータ本体を持たないため、バイト数の見積もりには `IsNull` を使いません。
+    let _is_null = key
+        .encode_by_ref(&mut buffer)
+        .map_err(|error| anyhow!(error))?;
+    Ok(buffer.len())
+}
+
+/// キーごとのバイト数をもとに、キー数とバイト数の上限を守るバッチ範囲へ分割します。
+fn split_in_list_batches(sizes: &[usize], limits: InListLimits) -> Vec<Range<usize>> {
+    let mut batches = Vec::new();
+    let mut start = 0;
+    let mut batch_bytes = 0;
+
+    for (index, size) in sizes.iter().copied().enumerate() {
+        let batch_len = index - start;
+        let exceeds_keys = batch_len >= limits.max_keys;
+        let exceeds_bytes = batch_len > 0 && batch_bytes + size > limits.max_bytes;
+        if exceeds_keys || exceeds_bytes {
+            batches.push(start..index);
+            start = index;
+            batch_bytes = 0;
+        }
+        batch_bytes += size;
+    }
+
+    if start < sizes.len() {
+        batches.push(start..sizes.len());
+    }
+    batches
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::testing;
+    use sqlx::Row;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    /// 接続しないクエリ実行器を作成します。最初のクエリまでプールは接続しません。
+    fn unconnected_executor() -> QueryExecutor {
+        QueryExecutor::new(PgPool::connect_lazy("postgres://localhost/unused").unwrap())
+    }
+
+    fn fetch_now<'a>() -> Map<'a, Postgres, impl FnMut(PgRow) -> sqlx::Result<String>, PgArguments>
+    {
+        sqlx::query("SELECT clock_timestamp()::text").try_map(|row: PgRow| row.try_get(0))
+    }
+
+    #[tokio::test]
+    async fn with_result_cache_rejects_zero_entries() {
+        let Err(error) = unconnected_executor().with_result_cache(0) else {
+            panic!("a cache of 0 entries must be rejected");
+        };
+
+        assert_eq!(error.to_string(), "max_entries must be greater than 0");
+    }
+
+    #[tokio::test]
+    async fn fetch_all_cached_requires_an_enabled_cache() {
+        let error = unconnected_executor()
+            .fetch_all_cached("now", Duration::from_secs(60), fetch_now())
+            .await
+            .unwrap_err();
+
+        assert!(error.to_string().contains("Result cache is not enabled"));
+    }
+
+    #[tokio::test]
+    #[ignore = "requires a PostgreSQL database (TEST_DATABASE_URL)"]
+    async fn fetch_all_cached_reuses_rows_until_invalidated() {
+        let connection_pool = testing::connect().await;
+        let executor = QueryExecutor::from_shared_pool(&connection_pool)
+            .with_result_cache(10)
+            .unwrap();
+        let ttl = Duration::from_secs(60);
+
+        let first = executor
+            .fetch_all_cached("now", ttl, fetch_now())
+            .await
+            .unwrap();
+        let cached = executor
+            .clone()
+            .fetch_all_cached("now", ttl, fetch_now())
+            .await
+            .unwrap();
+        assert!(executor.invalidate("now"));
+        let refetched = executor
+            .fetch_all_cached("now", ttl, fetch_now())
+            .await
+            .unwrap();
+
+        assert_eq!(first, cached);
+        assert_ne!(first, refetched);
+    }
+
+    #[tokio::test]
+    #[ignore = "requires a PostgreSQL database (TEST_DATABASE_URL)"]
+    async fn execute_queries_with_wal_bytes_reports_the_wal_of_a_bulk_insert() {
+        let pool = testing::pool().await;
+        let table = testing::unique_table("wal_bytes");
+        sqlx::query(&format!("CREATE TABLE {table} (id int, payload text)"))
+            .execute(&pool)
+            .await
+            .unwrap();
+        let insert = format!(
+            "INSERT INTO {table} SELECT n, repeat('x', 100) FROM generate_series(1, 1000) AS n"
+        );
+
+        let wal_bytes = QueryExecutor::new(pool.clone())
+            .execute_queries_with_wal_bytes([sqlx::query(&insert)])
+            .await
+            .unwrap();
+
+        sqlx::query(&format!("DROP TABLE {table}"))
+            .execute(&pool)
+            .await
+            .unwrap();
+        assert!(wal_bytes > 100_000, "{wal_bytes}");
+    }
+
+    /// `fetch_page` で最初から最後までページをたどり、キーの並びとページ数を返します。
+    async fn walk_pages(
+        executor: &QueryExecutor,
+        base_sql: &str,
+        page_size: usize,
+        direction: PageDirection,
+    ) -> (Vec<i32>, usize) {
+        let mut keys = Vec::new();
+        let mut pages = 0;
+        let mut after = None;
+        loop {
+            let page: Page<(i32,), i32> = executor
+                .fetch_page(base_sql, "id", after, page_size, direction)
+                .await
+                .unwrap();
+            assert!(page.rows.len() <= page_size);
+            pages += 1;
+            keys.extend(page.rows.into_iter().map(|(id,)| id));
+            match page.next_key {
+                Some(next_key) => after = Some(next_key),
+                None => break,
+            }
+        }
+        (keys, pages)
+    }
+
+    #[tokio::test]
+    #[ignore = "requires a PostgreSQL database (TEST_DATABASE_URL)"]
+    async fn fetch_page_walks_every_row_once_in_both_directions() {
+        let pool = testing::pool().await;
+        let table = testing::unique_table("fetch_page");
+        sqlx::query(&format!("CREATE TABLE {table} (id int PRIMARY KEY)"))
+            .execute(&pool)
+            .await
+            .unwrap();
+        // キーが連続しない場合も読み飛ばしや重複がないことを確かめるため、欠番を作ります。
+        sqlx::query(&format!(
+            "INSERT INTO {table} SELECT n * 3 FROM generate_series(1, 100) AS n WHERE n % 7 <> 0"
+        ))
+        .execute(&pool)
+        .await
+        .unwrap();
+        let expected: Vec<i32> = (1..=100).filter(|n| n % 7 != 0).map(|n| n * 3).collect();
+        let base_sql = format!("SELECT id FROM {table}");
+        let executor = QueryExecutor::new(pool.clone());
+
+        let ascending = walk_pages(&executor, &base_sql, 7, PageDirection::Ascending).await;
+        let descending = walk_pages(&executor, &base_sql, 7, PageDirection::Descending).await;
+        // 件数がページサイズで割り切れる場合、最後のページで終わり空のページは返りません。
+        let exact = walk_pages(&executor, &base_sql, 43, PageDirection::Ascending).await;
+
+        sqlx::query(&format!("DROP TABLE {table}"))
+            .execute(&pool)
+            .await
+            .unwrap();
+        assert_eq!(expected.len(), 86);
+        assert_eq!(ascending, (expected.clone(), 13));
+        let reversed: Vec<i32> = expected.iter().rev().copied().collect();
+        assert_eq!(descending, (reversed, 13));
+        assert_eq!(exact, (expected, 2));
+    }
+
+    #[tokio::test]
+    #[ignore = "requires a PostgreSQL database (TEST_DATABASE_URL)"]
+    async fn execute_parallel_keeps_input_order_within_the_concurrency_cap() {
+        let pool = testing::pool().await;
+        let marker = testing::unique_table("parallel");
+        // 後ろのクエリほど早く終わるため、完了順は入力順と逆になります。
+        let queries: Vec<String> = (0..6)
+            .map(|index| {
+                format!(
+                    "SELECT '{marker}', n FROM pg_sleep({}), generate_series(1, {}) AS n",
+                    0.1 + 0.05 * f64::from(5 - index),
+                    index + 1
+                )
+            })
+            .collect();
+        let stop = Arc::new(AtomicBool::new(false));
+        let monitor = tokio::spawn({
+            let (pool, stop) = (pool.clone(), Arc::clone(&stop));
+            let count = format!(
+                "SELECT count(*) FROM pg_stat_activity \
+                 WHERE state = 'active' AND pid <> pg_backend_pid() AND query LIKE '%{marker}%'"
+            );
+            async move {
+                let mut peak = 0;
+                while !stop.load(Ordering::SeqCst) {
+                    let active: i64 = sqlx::query_scalar(&count).fetch_one(&pool).await.unwrap();
+                    peak = peak.max(active);
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                }
+                peak
+            }
+        });
+
+        let results = QueryExecutor::new(pool.clone())
+            .execute_parallel(queries.iter().map(|sql| sqlx::query(sql)), 2, false)
+            .await
+            .unwrap();
+
+        stop.store(true, Ordering::SeqCst);
+        let peak = monitor.await.unwrap();
+        let rows: Vec<u64> = results.into_iter().map(Result::unwrap).collect();
+        assert_eq!(rows, vec![1, 2, 3, 4, 5, 6]);
+        assert_eq!(peak, 2);
+    }
+
+    #[tokio::test]
+    #[ignore = "requires a PostgreSQL database (TEST_DATABASE_URL)"]
+    async fn execute_parallel_fail_fast_discards_pending_queries() {
+        let pool = testing::pool().await;
+        let table = testing::unique_table("parallel_fail_fast");
+        sqlx::query(&format!("CREATE TABLE {table} (id int)"))
+            .execute(&pool)
+            .await
+            .unwrap();
+        let slow_insert = format!("INSERT INTO {table} SELECT 1 FROM pg_sleep(0.5)");
+        let queries = || {
+            [
+                sqlx::query(&slow_insert),
+                sqlx::query("SELECT 1 / 0"),
+                sqlx::query(&slow_insert),
+                sqlx::query(&slow_insert),
+            ]
+        };
+        let executor = QueryExecutor::new(pool.clone());
+        let count = format!("SELECT count(*) FROM {table}");
+
+        let collected = executor
+            .execute_parallel(queries(), 4, false)
+            .await
+            .unwrap();
+        let collected_count: i64 = sqlx::query_scalar(&count).fetch_one(&pool).await.unwrap();
+        let failed_fast = executor.execute_parallel(queries(), 4, true).await.unwrap();
+        // 破棄されたクエリの接続が戻りロールバックされるまで待ちます。
+        tokio::time::sleep(Duration::from_millis(700)).await;
+        let failed_fast_count: i64 = sqlx::query_scalar(&count).fetch_one(&pool).await.unwrap();
+
+        sqlx::query(&format!("DROP TABLE {table}"))
+            .execute(&pool)
+            .await
+            .unwrap();
+        assert_eq!(collected.len(), 4);
+        for (index, result) in collected.iter().enumerate() {
+            match result {
+                Ok(rows) => assert!(index != 1 && *rows == 1, "{index}: {rows}"),
+                Err(error) => {
+                    assert_eq!(index, 1);
+                    assert_eq!(testing::sqlstate(error).as_deref(), Some("22012"));
+                }
+            }
+        }
+        assert_eq!(collected_count, 3);
+        assert_eq!(failed_fast.len(), 4);
+        assert_eq!(
+            testing::sqlstate(failed_fast[1].as_ref().unwrap_err()).as_deref(),
+            Some("22012")
+        );
+        for index in [0, 2, 3] {
+            let error = failed_fast[index].as_ref().unwrap_err();
+            assert!(
+                matches!(
+                    error.root_cause().downcast_ref::<DbError>(),
+                    Some(DbError::Cancelled)
+                ),
+                "{index}: {error:#}"
+            );
+        }
+        assert_eq!(failed_fast_count, 3);
+    }
+
+    #[tokio::test]
+    #[ignore = "requires a PostgreSQL database (TEST_DATABASE_URL)"]
+    async fn read_timeout_applies_to_every_fetch_and_keeps_the_pool_usable() {
+        const SLEEP: &str = "SELECT 1 AS id FROM pg_sleep(5)";
+        let pool = sqlx::postgres::PgPoolOptions::new()
+            .max_connections(1)
+            .connect(&testing::database_url())
+            .await
+            .unwrap();
+        let executor = QueryExecutor::new(pool).with_read_timeout(Some(Duration::from_millis(200)));
+
+        let started_at = std::time::Instant::now();
+        let results = [
+            executor
+                .fetch_optional_as::<(i32,)>(sqlx::query(SLEEP))
+                .await
+                .map(drop),
+            executor
+                .fetch_all_as::<(i32,)>(sqlx::query(SLEEP))
+                .await
+                .map(drop),
+            executor
+                .fetch_one_query_as(sqlx::query_as::<_, (i32,)>(SLEEP))
+                .await
+                .map(drop),
+            executor
+                .fetch_all_query_as(sqlx::query_as::<_, (i32,)>(SLEEP))
+                .await
+                .map(drop),
+            executor
+                .fetch_all_spec::<(i32,)>(&QuerySpec::new(SLEEP))
+                .await
+                .map(drop),
+            executor
+                .fetch_all_json(SLEEP, PgArguments::default())
+                .await
+                .map(drop),
+            executor
+                .fetch_all_tagged::<(i32,)>(&QueryTag::new(), SLEEP, PgArguments::default())
+                .await
+                .map(drop),
+            executor
+                .fetch_page::<(i32,), i32>(SLEEP, "id", None, 10, PageDirection::Ascending)
+                .await
+                .map(drop),
+        ];
+
+        assert!(started_at.elapsed() < Duration::from_secs(4));
+        for result in results {
+            let error = result.unwrap_err();
+            assert!(
+                matches!(DbError::find(&error), Some(DbError::Timeout { .. })),
+                "{error:#}"
+            );
+        }
+        let row = executor
+            .fetch_optional_as::<(i32,)>(sqlx::query("SELECT 1"))
+            .await
+            .unwrap();
+        assert_eq!(row, Some((1,)));
+    }
+
+    fn limits(max_keys: usize, max_bytes: usize) -> InListLimits {
+        InListLimits {
+            max_keys,
+            max_bytes,
+        }
+    }
+
+    #[test]
+    fn split_in_list_batches_returns_nothing_for_empty_input() {
+        assert!(split_in_list_batches(&[], InListLimits::default()).is_empty());
+    }
+
+    #[test]
+    fn split_in_list_batches_gives_an_oversized_key_its_own_batch() {
+        assert_eq!(split_in_list_batches(&[100], limits(10, 10)), vec![0..1]);
+        assert_eq!(
+            split_in_list_batches(&[1, 100, 1], limits(10, 10)),
+            vec![0..1, 1..2, 2..3]
+        );
+    }
+
+    #[test]
+    fn split_in_list_batches_fills_batches_up_to_the_key_limit() {
+        assert_eq!(split_in_list_batches(&[1; 3], limits(3, 100)), vec![0..3]);
+        assert_eq!(
+            split_in_list_batches(&[1; 4], limits(2, 100)),
+            vec![0..2, 2..4]
+        );
+    }
+
+    #[test]
+    fn split_in_list_batches_fills_batches_up_to_the_byte_limit() {
+        assert_eq!(
+            split_in_list_batches(&[5, 5, 5], limits(100, 10)),
+            vec![0..2, 2..3]
+        );
+        assert_eq!(
+            split_in_list_batches(&[5, 6, 4], limits(100, 10)),
+            vec![0..1, 1..3]
+        );
+    }
+
+    #[test]
+    fn split_in_list_batches_covers_a_large_key_set_within_limits() {
+        let key_size = encoded_size(&0_i64).unwrap();
+        let sizes = vec![key_size; 100_000];
+        let limits = InListLimits::default();
+
+        let batches = split_in_list_batches(&sizes, limits);
+
+        assert_eq!(batches.len(), 100_000 / DEFAULT_IN_LIST_MAX_KEYS);
+        let mut expected_start = 0;
+        for batch in &batches {
+            assert_eq!(batch.start, expected_start);
+            assert!(batch.len() <= limits.max_keys);
+            assert!(sizes[batch.clone()].iter().sum::<usize>() <= limits.max_bytes);
+            expected_start = batch.end;
+        }
+        assert_eq!(expected_start, sizes.len());
+    }
+
+    #[tokio::test]
+    async fn mapped_queries_are_rejected_in_pgbouncer_mode() {
+        let executor = unconnected_executor().with_pgbouncer_mode(true);
+
+        let exactly_one = executor.fetch_exactly_one(fetch_now()).await.unwrap_err();
+        let streamed: Vec<Result<()>> = executor
+            .fetch_stream(sqlx::query("SELECT 1").map(|_: PgRow| ()))
+            .collect()
+            .await;
+
+        assert!(exactly_one.to_string().contains("pgbouncer_mode"));
+        let [Err(error)] = streamed.as_slice() else {
+            panic!("the stream must yield a single error");
+        };
+        assert!(error.to_string().contains("pgbouncer_mode"));
+    }
 }
@@ -0,0 +1,249 @@
+use chrono::{DateTime, NaiveDate, NaiveDateTime, Utc};
+use sqlx::{Postgres, postgres::PgArguments, query::Query};
+use uuid::Uuid;
+
+/// `QuerySpec` にバインドする値です。
+///
+/// 各バリアントは `Option` を保持し、`None` はその型の NULL としてバインドされます。
+#[derive(Debug, Clone, PartialEq)]
+pub enum BindValue {
+    Int2(Option<i16>),
+    Int4(Option<i32>),
+    Int8(Option<i64>),
+    Float8(Option<f64>),
+    Bool(Option<bool>),
+    Text(Option<String>),
+    Timestamptz(Option<DateTime<Utc>>),
+    Timestamp(Option<NaiveDateTime>),
+    Date(Option<NaiveDate>),
+    Uuid(Option<Uuid>),
+    Jsonb(Option<serde_json::Value>),
+}
+
+impl BindValue {
+    /// バインド時の PostgreSQL の型名を返します。
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Self::Int2(_) => "int2",
+            Self::Int4(_) => "int4",
+            Self::Int8(_) => "int8",
+            Self::Float8(_) => "float8",
+            Self::Bool(_) => "bool",
+            Self::Text(_) => "text",
+            Self::Timestamptz(_) => "timestamptz",
+            Self::Timestamp(_) => "timestamp",
+            Self::Date(_) => "date",
+            Self::Uuid(_) => "uuid",
+            Self::Jsonb(_) => "jsonb",
+        }
+    }
+
+    fn bind_to<'q>(
+        &self,
+        query: Query<'q, Postgres, PgArguments>,
+    ) -> Query<'q, Postgres, PgArguments> {
+        match self.clone() {
+            Self::Int2(value) => query.bind(value),
+            Self::Int4(value) => query.bind(value),
+            Self::Int8(value) => query.bind(value),
+            Self::Float8(value) => query.bind(value),
+            Self::Bool(value) => query.bind(value),
+            Self::Text(value) => query.bind(value),
+            Self::Timestamptz(value) => query.bind(value),
+            Self::Timestamp(value) => query.bind(value),
+            Self::Date(value) => query.bind(value),
+            Self::Uuid(value) => query.bind(value),
+            Self::Jsonb(value) => query.bind(value),
+        }
+    }
+}
+
+macro_rules! impl_from_for_bind_value {
+    ($($ty:ty => $variant:ident),* $(,)?) => {
+        $(
+            impl From<$ty> for BindValue {
+                fn from(value: $ty) -> Self {
+                    Self::$variant(Some(value.into()))
+                }
+            }
+
+            impl From<Option<$ty>> for BindValue {
+                fn from(value: Option<$ty>) -> Self {
+                    Self::$variant(value.map(Into::into))
+                }
+            }
+        )*
+    };
+}
+
+impl_from_for_bind_value! {
+    i16 => Int2,
+    i32 => Int4,
+    i64 => Int8,
+    f64 => Float8,
+    bool => Bool,
+    String => Text,
+    &str => Text,
+    DateTime<Utc> => Timestamptz,
+    NaiveDateTime => Timestamp,
+    NaiveDate => Date,
+    Uuid => Uuid,
+    serde_json::Value => Jsonb,
+}
+
+/// SQL とバインドする値を所有するクエリの定義です。
+///
+/// `sqlx::Query` は SQL を借用するため、ループ内で組み立てた SQL を `Vec` に集めたり、
+/// `tokio::spawn` で別タスクへ渡したりできません。`QuerySpec` はすべてを所有し `Send + 'static` なので、
+/// バッチを動的に組み立てて await をまたいで受け渡せます。実行時に `sqlx::query(..).bind(..)` を組み立て直します。
+///
+/// ```ignore
+/// let spec = QuerySpec::new("INSERT INTO items (id, name) VALUES ($1, $2)")
+///     .bind(42i64)
+///     .bind("abc");
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct QuerySpec {
+    sql: String,
+    values: Vec<BindValue>,
+    persistent: bool,
+}
+
+impl QuerySpec {
+    /// バインドする値のないクエリを作成します。
+    pub fn new(sql: impl Into<String>) -> Self {
+        Self {
+            sql: sql.into(),
+            values: Vec::new(),
+            persistent: true,
+        }
+    }
+
+    /// 次のプレースホルダ（`$1`, `$2`, ...）にバインドする値を追加します。
+    pub fn bind(mut self, value: impl Into<BindValue>) -> Self {
+        self.values.push(value.into());
+        self
+    }
+
+    /// 接続のステートメントキャッシュにプリペアドステートメントとして保持するかどうかを指定します。
+    ///
+    /// 既定値は `true` です。一度しか実行しない動的な SQL に `false` を指定すると、名前のない
+    /// ステートメントで実行して破棄するため、繰り返し実行するクエリのキャッシュを追い出しません。
+    pub fn persistent(mut self, persistent: bool) -> Self {
+        self.persistent = persistent;
+        self
+    }
+
+    /// プリペアドステートメントとして保持するかどうかを返します。
+    pub fn is_persistent(&self) -> bool {
+        self.persistent
+    }
+
+    /// SQL を返します。
+    pub fn sql(&self) -> &str {
+        &self.sql
+    }
+
+    /// バインドする値を返します。
+    pub fn values(&self) -> &[BindValue] {
+        &self.values
+    }
+
+    /// エラーメッセージ用に、プレースホルダの位置とバインドした型の一覧を返します（例: `$1 int8, $2 text`）。
+    pub fn describe_parameters(&self) -> String {
+        if self.values.is_empty() {
+            return "no parameters".to_string();
+        }
+        self.values
+            .iter()
+            .enumerate()
+            .map(|(index, value)| format!("${} {}", index + 1, value.type_name()))
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+
+    /// SQL と値から SQLx のクエリを組み立てます。
+    pub(super) fn query(&self) -> Query<'_, Postgres, PgArguments> {
+        self.values
+            .iter()
+            .fold(sqlx::query(&self.sql), |query, value| value.bind_to(query))
+            .persistent(self.persistent)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::{
+        query_executor::QueryExecutor, testing, transaction_executor::TransactionExecutor,
+    };
+    use sqlx::Execute;
+
+    #[test]
+    fn query_carries_the_persistence_of_the_spec() {
+        let spec = QuerySpec::new("SELECT 1");
+
+        assert!(spec.is_persistent());
+        assert!(Execute::persistent(&spec.query()));
+        assert!(!Execute::persistent(&spec.persistent(false).query()));
+    }
+
+    #[tokio::test]
+    #[ignore = "requires a PostgreSQL database (TEST_DATABASE_URL)"]
+    async fn unprepared_spec_runs_repeatedly_without_being_cached() {
+        // 接続を 1 本にして、同じセッションの `pg_prepared_statements` を確認します。
+        let pool = sqlx::postgres::PgPoolOptions::new()
+            .max_connections(1)
+            .connect(&testing::database_url())
+            .await
+            .unwrap();
+        let table = testing::unique_table("unprepared_spec");
+        sqlx::query(&format!("CREATE TABLE {table} (id int)"))
+            .execute(&pool)
+            .await
+            .unwrap();
+        let insert = format!("INSERT INTO {table} VALUES ($1)");
+        let select = format!("SELECT id * 2 FROM {table} WHERE id = $1");
+        let query_executor = QueryExecutor::new(pool.clone());
+
+        let rows_affected = TransactionExecutor::new(pool.clone())
+            .execute_specs(
+                (1..=3)
+                    .map(|id| QuerySpec::new(&insert).bind(id).persistent(false))
+                    .collect(),
+            )
+            .await
+            .unwrap();
+        let mut doubled = Vec::new();
+        for id in 1..=3 {
+            let spec = QuerySpec::new(&select).bind(id).persistent(false);
+            let rows: Vec<(i32,)> = query_executor.fetch_all_spec(&spec).await.unwrap();
+            doubled.extend(rows.into_iter().map(|(value,)| value));
+        }
+
+        let count_prepared = || {
+            sqlx::query_scalar::<_, i64>(
+                "SELECT count(*) FROM pg_prepared_statements WHERE statement IN ($1, $2)",
+            )
+            .bind(&insert)
+            .bind(&select)
+            .fetch_one(&pool)
+        };
+        let prepared_unpersisted = count_prepared().await.unwrap();
+        // 既定の `persistent(true)` ではキャッシュされることを対照として確かめます。
+        let _: Vec<(i32,)> = query_executor
+            .fetch_all_spec(&QuerySpec::new(&select).bind(1))
+            .await
+            .unwrap();
+        let prepared_persisted = count_prepared().await.unwrap();
+
+        sqlx::query(&format!("DROP TABLE {table}"))
+            .execute(&pool)
+            .await
+            .unwrap();
+        assert_eq!(rows_affected, [1, 1, 1]);
+        assert_eq!(doubled, [2, 4, 6]);
+        assert_eq!(prepared_unpersisted, 0);
+        assert_eq!(prepared_persisted, 1);
+    }
+}
@@ -0,0 +1,160 @@
+const KEY_APP: &str = "app";
+const KEY_FEATURE: &str = "feature";
+const KEY_JOB: &str = "job";
+
+/// SQL の先頭にコメントとして付与し、`pg_stat_statements` や `pg_stat_activity` で
+/// 実行元を判別できるようにするタグです。
+///
+/// `QueryTag::new().app("settlement").feature("rebill").job_id("1234")` は
+/// `/* app=settlement feature=rebill job=1234 */ ` を SQL の前に付与します。
+/// キーと値に含まれる `*/`・`/*`・制御文字は取り除き、空白は `_` に置き換えるため、
+/// 外部から受け取った値を指定してもコメントの外へ抜け出すことはありません。
+///
+/// `pg_stat_statements` はコメントを除いて正規化するため、タグごとに統計が分かれることはありません。
+/// タグは `pg_stat_activity.query` やサーバーログで参照してください。
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct QueryTag {
+    pairs: Vec<(String, String)>,
+}
+
+impl QueryTag {
+    /// 空のタグを作成します。空のタグは SQL を変更しません。
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// アプリケーション名を `app` として追加します。
+    pub fn app(self, app: impl AsRef<str>) -> Self {
+        self.with(KEY_APP, app)
+    }
+
+    /// 機能名を `feature` として追加します。
+    pub fn feature(self, feature: impl AsRef<str>) -> Self {
+        self.with(KEY_FEATURE, feature)
+    }
+
+    /// ジョブの識別子を `job` として追加します。
+    pub fn job_id(self, job_id: impl AsRef<str>) -> Self {
+        self.with(KEY_JOB, job_id)
+    }
+
+    /// 任意のキーと値を追加します。サニタイズ後に空になるキーや値は無視します。
+    pub fn with(mut self, key: impl AsRef<str>, value: impl AsRef<str>) -> Self {
+        let key = sanitize(key.as_ref()).replace('=', "_");
+        let value = sanitize(value.as_ref());
+        if !key.is_empty() && !value.is_empty() {
+            self.pairs.push((key, value));
+        }
+        self
+    }
+
+    /// タグが空かどうかを返します。
+    pub fn is_empty(&self) -> bool {
+        self.pairs.is_empty()
+    }
+
+    /// タグをコメントとして `sql` の前に付与した SQL を返します。タグが空の場合は `sql` をそのまま返します。
+    pub fn apply(&self, sql: &str) -> String {
+        if self.is_empty() {
+            return sql.to_string();
+        }
+        let pairs: Vec<String> = self
+            .pairs
+            .iter()
+            .map(|(key, value)| format!("{key}={value}"))
+            .collect();
+        format!("/* {} */ {sql}", pairs.join(" "))
+    }
+}
+
+/// コメントの区切りと制御文字を取り除き、空白を `_` に置き換えます。
+fn sanitize(value: &str) -> String {
+    let mut sanitized: String = value
+        .chars()
+        .filter(|c| !c.is_control())
+        .map(|c| if c.is_whitespace() { '_' } else { c })
+        .collect();
+    // `*/*/` のように取り除いた結果として区切りが現れる場合があるため、なくなるまで繰り返します。
+    while sanitized.contains("*/") || sanitized.contains("/*") {
+        sanitized = sanitized.replace("*/", "").replace("/*", "");
+    }
+    sanitized
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn apply_prefixes_sql_with_the_tag_comment() {
+        let tag = QueryTag::new()
+            .app("settlement")
+            .feature("rebill")
+            .job_id("1234");
+
+        assert_eq!(
+            tag.apply("SELECT 1"),
+            "/* app=settlement feature=rebill job=1234 */ SELECT 1"
+        );
+    }
+
+    #[test]
+    fn apply_leaves_sql_unchanged_for_an_empty_tag() {
+        assert_eq!(QueryTag::new().apply("SELECT 1"), "SELECT 1");
+    }
+
+    #[test]
+    fn sanitize_removes_comment_delimiters() {
+        assert_eq!(
+            sanitize("x */ DROP TABLE users; /*"),
+            "x__DROP_TABLE_users;_"
+        );
+        assert_eq!(sanitize("a/*b*/c"), "abc");
+    }
+
+    #[test]
+    fn sanitize_removes_delimiters_formed_by_removal() {
+        assert_eq!(sanitize("*/*/"), "");
+        assert_eq!(sanitize("**//"), "");
+        assert_eq!(sanitize("/*/**/*/"), "");
+        assert_eq!(sanitize("*\n/"), "");
+    }
+
+    #[test]
+    fn sanitize_removes_control_characters_and_replaces_whitespace() {
+        assert_eq!(sanitize("a\u{0}b\u{7f}c\u{1b}[0m"), "abc[0m");
+        assert_eq!(sanitize("a b\tc\nd\u{3000}e"), "a_bcd_e");
+    }
+
+    #[test]
+    fn injected_values_cannot_close_the_comment() {
+        let sql = QueryTag::new()
+            .app("x */ DELETE FROM users; --")
+            .feature("*/*/")
+            .with("k*/ey", "v/*al")
+            .apply("SELECT 1");
+
+        assert_eq!(sql, "/* app=x__DELETE_FROM_users;_-- key=val */ SELECT 1");
+        assert_eq!(sql.matches("*/").count(), 1);
+        assert_eq!(sql.matches("/*").count(), 1);
+    }
+
+    #[test]
+    fn with_replaces_equals_signs_in_keys_only() {
+        let tag = QueryTag::new().with("a=b", "c=d");
+
+        assert_eq!(tag.apply("SELECT 1"), "/* a_b=c=d */ SELECT 1");
+    }
+
+    #[test]
+    fn with_drops_pairs_that_are_empty_after_sanitizing() {
+        let tag = QueryTag::new()
+            .with("", "value")
+            .with("key", "")
+            .with("*/", "value")
+            .with("key", "\u{0}/**/");
+
+        assert!(tag.is_empty());
+        assert_eq!(tag, QueryTag::new());
+    }
+}
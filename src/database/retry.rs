@@ -0,0 +1,355 @@
+use crate::database::error::DbError;
+use anyhow::{Result, ensure};
+use std::{
+    future::Future,
+    hash::{BuildHasher, RandomState},
+    time::{Duration, Instant},
+};
+
+const DEFAULT_MAX_ATTEMPTS: u32 = 3;
+const DEFAULT_BASE_DELAY: Duration = Duration::from_millis(50);
+const DEFAULT_MAX_DELAY: Duration = Duration::from_secs(2);
+const DEFAULT_ACQUIRE_RETRY_BACKOFF: Duration = Duration::from_millis(100);
+
+/// 直列化失敗（`40001`）とデッドロック検出（`40P01`）のように、再実行で成功し得る SQLSTATE です。
+const RETRYABLE_SQLSTATES: [&str; 2] = ["40001", "40P01"];
+
+/// 再試行可能なエラーで失敗したトランザクションを再実行する方針です。
+///
+/// 待機時間は `base_delay * 2^(試行回数 - 1)` を `max_delay` で頭打ちにした値を上限とする
+/// フルジッターで決まります。`deadline` を指定すると、最初の試行からの経過時間が
+/// 期限を超える再試行は行いません。
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// 最初の試行を含む最大試行回数です。
+    pub max_attempts: u32,
+    /// 1 回目の再試行前に待機する時間の上限です。
+    pub base_delay: Duration,
+    /// 再試行前に待機する時間の上限です。
+    pub max_delay: Duration,
+    /// 全試行を通した期限です。`None` の場合は試行回数のみで打ち切ります。
+    pub deadline: Option<Duration>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: DEFAULT_MAX_ATTEMPTS,
+            base_delay: DEFAULT_BASE_DELAY,
+            max_delay: DEFAULT_MAX_DELAY,
+            deadline: None,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// 最大試行回数を指定し、その他を既定値とした方針を作成します。
+    pub fn new(max_attempts: u32) -> Self {
+        Self {
+            max_attempts,
+            ..Self::default()
+        }
+    }
+
+    /// `operation` を実行し、再試行可能なエラーで失敗した場合は方針に従って再実行します。
+    ///
+    /// 再試行できないエラーは直ちにそのまま返します。試行回数または期限を使い切った場合は、
+    /// 最後のエラーに試行回数を示すコンテキストを付けて返します。
+    pub async fn run<T, F, Fut>(&self, mut operation: F) -> Result<T>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        ensure!(self.max_attempts > 0, "max_attempts must be greater than 0");
+
+        let started_at = Instant::now();
+        let mut attempt = 1;
+        loop {
+            let error = match operation().await {
+                Ok(value) => return Ok(value),
+                Err(error) if is_retryable(&error) => error,
+                Err(error) => return Err(error),
+            };
+
+            if attempt >= self.max_attempts {
+                return Err(error.context(format!("Transaction failed after {attempt} attempts")));
+            }
+
+            let delay = self.backoff(attempt);
+            if let Some(deadline) = self.deadline
+                && started_at.elapsed() + delay > deadline
+            {
+                return Err(error.context(format!(
+                    "Transaction failed after {attempt} attempts (retry deadline of {deadline:?} exceeded)"
+                )));
+            }
+
+            tokio::time::sleep(delay).await;
+            attempt += 1;
+        }
+    }
+
+    /// `attempt` 回目の失敗後に待機する時間を返します。
+    fn backoff(&self, attempt: u32) -> Duration {
+        let exponent = attempt.saturating_sub(1).min(31);
+        let ceiling = self
+            .base_delay
+            .saturating_mul(1 << exponent)
+            .min(self.max_delay);
+        jitter(ceiling)
+    }
+}
+
+/// 接続プールからの接続の取得がタイムアウトした場合に、取得をやり直す方針です。
+///
+/// 接続の取得待ち（`acquire_timeout`）がタイムアウトした場合のみ再試行し、それ以外のエラーは
+/// 直ちに返します。`max_retries` が 0（既定値）の場合は再試行しません。
+/// 再試行前の待機時間は `backoff * 2^(再試行回数 - 1)` で、`jitter` が `true` の場合は
+/// 0 以上その値以下の一様な時間になります。`deadline` を指定すると、最初の取得からの経過時間が
+/// 期限に達した時点で取得待ちを打ち切り、それ以上再試行しません。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AcquireRetry {
+    /// 最初の取得に続けて行う再試行の最大回数です。
+    pub max_retries: u32,
+    /// 1 回目の再試行前に待機する時間です。
+    pub backoff: Duration,
+    /// 待機時間にジッターを加えるかどうかです。
+    pub jitter: bool,
+    /// 全試行を通した接続取得の期限です。`None` の場合は再試行回数のみで打ち切ります。
+    pub deadline: Option<Duration>,
+}
+
+impl Default for AcquireRetry {
+    fn default() -> Self {
+        Self {
+            max_retries: 0,
+            backoff: DEFAULT_ACQUIRE_RETRY_BACKOFF,
+            jitter: true,
+            deadline: None,
+        }
+    }
+}
+
+impl AcquireRetry {
+    /// 再試行の最大回数を指定し、その他を既定値とした方針を作成します。
+    pub fn new(max_retries: u32) -> Self {
+        Self {
+            max_retries,
+            ..Self::default()
+        }
+    }
+
+    /// `attempt` 回目の取得がタイムアウトした後に待機する時間を返します。
+    ///
+    /// 再試行回数を使い切った場合や、待機すると期限を超える場合は `None` を返します。
+    pub(super) fn delay(&self, attempt: u32, elapsed: Duration) -> Option<Duration> {
+        if attempt > self.max_retries {
+            return None;
+        }
+        let exponent = attempt.saturating_sub(1).min(31);
+        let ceiling = self.backoff.saturating_mul(1 << exponent);
+        let delay = if self.jitter {
+            jitter(ceiling)
+        } else {
+            ceiling
+        };
+        match self.deadline {
+            Some(deadline) if elapsed + delay >= deadline => None,
+            _ => Some(delay),
+        }
+    }
+}
+
+/// エラーの原因が直列化失敗（`40001`）またはデッドロック検出（`40P01`）かどうかを判定します。
+///
+/// クロージャの中で `?` によりそのまま返された `sqlx::Error` も、SQLSTATE で判定します。
+pub fn is_retryable(error: &anyhow::Error) -> bool {
+    error
+        .chain()
+        .any(|cause| match cause.downcast_ref::<DbError>() {
+            Some(db_error) => db_error.is_retryable(),
+            None => cause
+                .downcast_ref::<sqlx::Error>()
+                .and_then(sqlx::Error::as_database_error)
+                .and_then(|database_error| database_error.code())
+                .is_some_and(|code| RETRYABLE_SQLSTATES.contains(&code.as_ref())),
+        })
+}
+
+/// `0` 以上 `ceiling` 以下の一様な待機時間を返します。
+fn jitter(ceiling: Duration) -> Duration {
+    let random = RandomState::new().hash_one(Instant::now());
+    let nanos = u64::try_from(ceiling.as_nanos()).unwrap_or(u64::MAX);
+    Duration::from_nanos(random % nanos.saturating_add(1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::{testing, transaction_executor::TransactionExecutor};
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    #[tokio::test]
+    #[ignore = "requires a PostgreSQL database (TEST_DATABASE_URL)"]
+    async fn run_retries_only_serialization_failures_and_deadlocks() {
+        let pool = testing::pool().await;
+        let policy = RetryPolicy {
+            base_delay: Duration::from_millis(1),
+            max_delay: Duration::from_millis(1),
+            ..RetryPolicy::new(3)
+        };
+
+        for (code, expected_attempts) in [("40001", 3), ("40P01", 3), ("23505", 1), ("57014", 1)] {
+            let sql =
+                format!("DO $$ BEGIN RAISE EXCEPTION 'injected' USING ERRCODE = '{code}'; END $$");
+            let attempts = AtomicU32::new(0);
+
+            let error = policy
+                .run(|| {
+                    attempts.fetch_add(1, Ordering::Relaxed);
+                    async {
+                        sqlx::query(&sql).execute(&pool).await?;
+                        Ok(())
+                    }
+                })
+                .await
+                .unwrap_err();
+
+            assert_eq!(attempts.into_inner(), expected_attempts, "{code}");
+            assert_eq!(testing::sqlstate(&error).as_deref(), Some(code));
+        }
+    }
+
+    fn acquire_retry(max_retries: u32, backoff: Duration, jitter: bool) -> AcquireRetry {
+        AcquireRetry {
+            max_retries,
+            backoff,
+            jitter,
+            deadline: None,
+        }
+    }
+
+    #[test]
+    fn acquire_retry_delay_doubles_per_attempt_without_jitter() {
+        let retry = acquire_retry(3, Duration::from_millis(100), false);
+
+        assert_eq!(
+            retry.delay(1, Duration::ZERO),
+            Some(Duration::from_millis(100))
+        );
+        assert_eq!(
+            retry.delay(2, Duration::ZERO),
+            Some(Duration::from_millis(200))
+        );
+        assert_eq!(
+            retry.delay(3, Duration::ZERO),
+            Some(Duration::from_millis(400))
+        );
+    }
+
+    #[test]
+    fn acquire_retry_delay_stops_after_max_retries() {
+        assert_eq!(
+            acquire_retry(2, Duration::from_millis(100), false).delay(3, Duration::ZERO),
+            None
+        );
+        assert_eq!(AcquireRetry::default().delay(1, Duration::ZERO), None);
+    }
+
+    #[test]
+    fn acquire_retry_delay_allows_a_zero_backoff() {
+        let retry = acquire_retry(2, Duration::ZERO, true);
+
+        assert_eq!(retry.delay(1, Duration::ZERO), Some(Duration::ZERO));
+        assert_eq!(retry.delay(2, Duration::ZERO), Some(Duration::ZERO));
+    }
+
+    #[test]
+    fn acquire_retry_delay_caps_the_exponent_and_saturates() {
+        let retry = acquire_retry(u32::MAX, Duration::from_nanos(1), false);
+        assert_eq!(
+            retry.delay(100, Duration::ZERO),
+            Some(Duration::from_nanos(1 << 31))
+        );
+
+        let retry = acquire_retry(u32::MAX, Duration::MAX, false);
+        assert_eq!(retry.delay(40, Duration::ZERO), Some(Duration::MAX));
+    }
+
+    #[test]
+    fn acquire_retry_delay_respects_the_deadline() {
+        let retry = AcquireRetry {
+            deadline: Some(Duration::from_secs(1)),
+            ..acquire_retry(5, Duration::from_millis(300), false)
+        };
+
+        assert_eq!(
+            retry.delay(1, Duration::from_millis(600)),
+            Some(Duration::from_millis(300))
+        );
+        assert_eq!(retry.delay(1, Duration::from_millis(700)), None);
+        assert_eq!(retry.delay(2, Duration::from_millis(400)), None);
+    }
+
+    #[test]
+    fn acquire_retry_delay_with_jitter_stays_within_the_ceiling() {
+        let retry = acquire_retry(3, Duration::from_millis(100), true);
+
+        let delays: Vec<Duration> = (0..200)
+            .map(|_| retry.delay(3, Duration::ZERO).unwrap())
+            .collect();
+
+        assert!(
+            delays
+                .iter()
+                .all(|delay| *delay <= Duration::from_millis(400))
+        );
+        // 200 回とも同じ値になることは、ジッターが効いていなければ起こりません。
+        assert!(delays.iter().any(|delay| *delay != delays[0]));
+    }
+
+    #[tokio::test]
+    #[ignore = "requires a PostgreSQL database (TEST_DATABASE_URL)"]
+    async fn acquire_retry_waits_for_a_connection_released_by_another_task() {
+        let pool = sqlx::postgres::PgPoolOptions::new()
+            .max_connections(1)
+            .acquire_timeout(Duration::from_millis(100))
+            .connect(&testing::database_url())
+            .await
+            .unwrap();
+        let held = pool.acquire().await.unwrap();
+        let release = tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(400)).await;
+            drop(held);
+        });
+        let without_retry = TransactionExecutor::new(pool.clone());
+        let with_retry = TransactionExecutor::new(pool.clone()).with_acquire_retry(acquire_retry(
+            5,
+            Duration::from_millis(100),
+            false,
+        ));
+
+        // 唯一の接続が使用中のため、再試行しない場合はこれまでどおり取得待ちのタイムアウトで失敗します。
+        let error = without_retry
+            .execute_queries([sqlx::query("SELECT 1")])
+            .await
+            .unwrap_err();
+        let rows_affected = with_retry
+            .execute_queries([sqlx::query("SELECT 1")])
+            .await
+            .unwrap();
+
+        release.await.unwrap();
+        assert!(matches!(DbError::find(&error), Some(DbError::PoolTimeout)));
+        assert!(
+            format!("{error:#}").contains("Pool exhausted after 1 attempt(s)"),
+            "{error:#}"
+        );
+        assert_eq!(rows_affected, [1]);
+    }
+
+    #[test]
+    fn jitter_of_zero_is_zero() {
+        assert_eq!(jitter(Duration::ZERO), Duration::ZERO);
+    }
+}
@@ -0,0 +1,685 @@
+use crate::database::transaction_executor::{BoxFuture, TransactionExecutor};
+use anyhow::{Context, Result, anyhow, bail, ensure};
+use chrono::{DateTime, Datelike, Duration as ChronoDuration, DurationRound, Timelike, Utc};
+use std::{
+    collections::HashMap,
+    fmt,
+    future::Future,
+    str::FromStr,
+    sync::{Arc, Mutex, PoisonError},
+    time::{Duration, Instant},
+};
+use tokio::{sync::watch, task::JoinHandle};
+
+const DEFAULT_SHUTDOWN_GRACE: Duration = Duration::from_secs(30);
+/// 次回の実行時刻を探す範囲です。この範囲に一致する時刻がない cron 式（2 月 30 日など）は実行されません。
+const CRON_SEARCH_LIMIT_DAYS: i64 = 366 * 5;
+
+type JobFn = Arc<dyn Fn(TransactionExecutor) -> BoxFuture<'static, Result<()>> + Send + Sync>;
+
+/// ジョブを実行する間隔です。
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum JobSchedule {
+    /// 前回の予定時刻から一定の間隔ごとに実行します。最初の実行は開始から 1 間隔後です。
+    Every(Duration),
+    /// cron 式（UTC）に一致する時刻ごとに実行します。
+    Cron(CronSchedule),
+}
+
+impl JobSchedule {
+    /// cron 式から実行間隔を作成します。
+    pub fn cron(expression: &str) -> Result<Self> {
+        expression.parse().map(Self::Cron)
+    }
+}
+
+/// 前回の実行が終わっていない時刻に次の実行が来た場合の扱いです。
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OverlapPolicy {
+    /// その回の実行を見送ります。
+    #[default]
+    Skip,
+    /// 前回の実行が終わるのを待ってから実行します。待っている間に来た実行はまとめて 1 回になります。
+    Queue,
+}
+
+/// `JobScheduler` に登録するジョブです。
+///
+/// ```ignore
+/// let job = Job::new("purge_sessions", JobSchedule::Every(Duration::from_secs(60)), |executor| {
+///     Box::pin(async move {
+///         executor
+///             .execute_query(sqlx::query("DELETE FROM sessions WHERE expires_at < now()"))
+///             .await?;
+///         Ok(())
+///     })
+/// })
+/// .with_retries(2, Duration::from_secs(1));
+/// ```
+#[derive(Clone)]
+pub struct Job {
+    name: String,
+    schedule: JobSchedule,
+    overlap: OverlapPolicy,
+    max_retries: u32,
+    retry_delay: Duration,
+    run: JobFn,
+}
+
+impl Job {
+    /// `schedule` に従って `run` を実行するジョブを作成します。失敗しても再試行しません。
+    pub fn new<F>(name: impl Into<String>, schedule: JobSchedule, run: F) -> Self
+    where
+        F: Fn(TransactionExecutor) -> BoxFuture<'static, Result<()>> + Send + Sync + 'static,
+    {
+        Self {
+            name: name.into(),
+            schedule,
+            overlap: OverlapPolicy::default(),
+            max_retries: 0,
+            retry_delay: Duration::ZERO,
+            run: Arc::new(run),
+        }
+    }
+
+    /// 前回の実行が終わっていない場合の扱いを指定します。既定値は `OverlapPolicy::Skip` です。
+    pub fn with_overlap(mut self, overlap: OverlapPolicy) -> Self {
+        self.overlap = overlap;
+        self
+    }
+
+    /// 失敗した場合に `retry_delay` だけ待って最大 `max_retries` 回再実行するよう指定します。
+    pub fn with_retries(mut self, max_retries: u32, retry_delay: Duration) -> Self {
+        self.max_retries = max_retries;
+        self.retry_delay = retry_delay;
+        self
+    }
+
+    /// ジョブ名を返します。
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+impl fmt::Debug for Job {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Job")
+            .field("name", &self.name)
+            .field("schedule", &self.schedule)
+            .field("overlap", &self.overlap)
+            .field("max_retries", &self.max_retries)
+            .field("retry_delay", &self.retry_delay)
+            .finish_non_exhaustive()
+    }
+}
+
+/// ジョブの直近の実行結果です。
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum JobOutcome {
+    /// 成功しました。
+    Succeeded,
+    /// 再試行を含めてすべて失敗しました。最後のエラーを保持します。
+    Failed(String),
+}
+
+/// ヘルスチェックなどで参照する、ジョブの実行状況です。
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct JobStatus {
+    /// 実行中かどうかです。
+    pub running: bool,
+    /// 実行を開始した回数です。再試行は数えません。
+    pub run_count: u64,
+    /// 前回の実行が終わっていなかったために見送った回数です。
+    pub skipped_count: u64,
+    /// 直近の実行を開始した時刻です。
+    pub last_started_at: Option<DateTime<Utc>>,
+    /// 直近の実行が終わった時刻です。
+    pub last_finished_at: Option<DateTime<Utc>>,
+    /// 直近の実行の所要時間（再試行の待機を含む）です。
+    pub last_duration: Option<Duration>,
+    /// 直近の実行結果です。
+    pub last_outcome: Option<JobOutcome>,
+}
+
+/// 登録したジョブを Tokio のタイマーで定期的に実行するスケジューラです。
+///
+/// 各ジョブは独立したタスクで動き、`TransactionExecutor` の複製を受け取ります。実行ごとに開始・終了・
+/// 所要時間・結果をログに出力し、`status` で直近の状況を参照できます。`run` は終了の合図を受け取ると
+/// 新しい実行を止め、実行中のジョブの終了を最大 `shutdown_grace` 待ってから戻ります。
+///
+/// ```ignore
+/// let scheduler = Arc::new(
+///     JobScheduler::new(TransactionExecutor::for_workload(&pool, Workload::Batch))
+///         .with_job(job)?,
+/// );
+/// let status_source = Arc::clone(&scheduler);
+/// scheduler.run(async { shutdown.wait_for(|requested| *requested).await.ok(); }).await?;
+/// ```
+pub struct JobScheduler {
+    executor: TransactionExecutor,
+    jobs: Vec<Job>,
+    shutdown_grace: Duration,
+    statuses: Arc<Mutex<HashMap<String, JobStatus>>>,
+}
+
+impl JobScheduler {
+    /// ジョブに `executor` の複製を渡すスケジューラを作成します。
+    pub fn new(executor: TransactionExecutor) -> Self {
+        Self {
+            executor,
+            jobs: Vec::new(),
+            shutdown_grace: DEFAULT_SHUTDOWN_GRACE,
+            statuses: Arc::default(),
+        }
+    }
+
+    /// ジョブを登録します。同じ名前のジョブが登録済みの場合はエラーを返します。
+    pub fn with_job(mut self, job: Job) -> Result<Self> {
+        ensure!(
+            !self
+                .jobs
+                .iter()
+                .any(|registered| registered.name == job.name),
+            "Job {:?} is already registered",
+            job.name
+        );
+        if let JobSchedule::Every(interval) = job.schedule {
+            ensure!(
+                !interval.is_zero(),
+                "Interval of job {:?} must be greater than 0",
+                job.name
+            );
+        }
+        self.statuses
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(job.name.clone(), JobStatus::default());
+        self.jobs.push(job);
+        Ok(self)
+    }
+
+    /// 終了時に実行中のジョブを待つ上限時間を指定します。既定値は 30 秒です。
+    pub fn with_shutdown_grace(mut self, shutdown_grace: Duration) -> Self {
+        self.shutdown_grace = shutdown_grace;
+        self
+    }
+
+    /// `name` のジョブの実行状況を返します。登録されていない場合は `None` です。
+    pub fn status(&self, name: &str) -> Option<JobStatus> {
+        self.lock_statuses().get(name).cloned()
+    }
+
+    /// すべてのジョブの実行状況をジョブ名とともに返します。
+    pub fn statuses(&self) -> Vec<(String, JobStatus)> {
+        let mut statuses: Vec<_> = self
+            .lock_statuses()
+            .iter()
+            .map(|(name, status)| (name.clone(), status.clone()))
+            .collect();
+        statuses.sort_by(|(left, _), (right, _)| left.cmp(right));
+        statuses
+    }
+
+    /// `shutdown` が完了するまでジョブを実行します。
+    ///
+    /// `shutdown` の完了後は新しい実行を始めず、実行中のジョブを最大 `shutdown_grace` 待ちます。
+    /// 期限までに終わらなかった実行は中断し、エラーを返します。
+    pub async fn run(&self, shutdown: impl Future<Output = ()>) -> Result<()> {
+        let (stop_sender, stop) = watch::channel(false);
+        let loops: Vec<_> = self
+            .jobs
+            .iter()
+            .map(|job| {
+                tokio::spawn(job_loop(
+                    job.clone(),
+                    self.executor.clone(),
+                    Arc::clone(&self.statuses),
+                    stop.clone(),
+                ))
+            })
+            .collect();
+
+        shutdown.await;
+        let _ = stop_sender.send(true);
+        tracing::info!(
+            grace = ?self.shutdown_grace,
+            "Stopping job scheduler; waiting for running jobs"
+        );
+
+        let mut in_flight = Vec::new();
+        for job_loop in loops {
+            if let Some(run) = job_loop.await.context("Job scheduler task panicked")? {
+                in_flight.push(run);
+            }
+        }
+        let deadline = tokio::time::Instant::now() + self.shutdown_grace;
+        let mut aborted = Vec::new();
+        for (name, mut run) in in_flight {
+            if tokio::time::timeout_at(deadline, &mut run).await.is_err() {
+                run.abort();
+                tracing::warn!(job = %name, "Aborted job still running after shutdown grace period");
+                aborted.push(name);
+            }
+        }
+        if !aborted.is_empty() {
+            bail!(
+                "Jobs did not finish within the shutdown grace period of {:?}: {}",
+                self.shutdown_grace,
+                aborted.join(", ")
+            );
+        }
+        Ok(())
+    }
+
+    fn lock_statuses(&self) -> std::sync::MutexGuard<'_, HashMap<String, JobStatus>> {
+        self.statuses.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// 1 つのジョブの予定時刻を待って実行を始めることを、終了の合図まで繰り返します。
+///
+/// 終了時に実行中の実行があれば、その名前とハンドルを返します。
+async fn job_loop(
+    job: Job,
+    executor: TransactionExecutor,
+    statuses: Arc<Mutex<HashMap<String, JobStatus>>>,
+    mut stop: watch::Receiver<bool>,
+) -> Option<(String, JoinHandle<()>)> {
+    let mut next_at = tokio::time::Instant::now();
+    let mut in_flight: Option<JoinHandle<()>> = None;
+    loop {
+        let delay = match next_delay(&job.schedule, &mut next_at) {
+            Ok(delay) => delay,
+            Err(error) => {
+                tracing::error!(job = %job.name, "Stopping job: {error:#}");
+                break;
+            }
+        };
+        tokio::select! {
+            _ = stop.wait_for(|stopped| *stopped) => break,
+            () = tokio::time::sleep(delay) => {}
+        }
+
+        if in_flight.as_ref().is_some_and(JoinHandle::is_finished) {
+            in_flight = None;
+        }
+        if let Some(previous) = in_flight.take() {
+            match job.overlap {
+                OverlapPolicy::Skip => {
+                    update_status(&statuses, &job.name, |status| status.skipped_count += 1);
+                    tracing::warn!(job = %job.name, "Skipped job run; previous run is still in progress");
+                    in_flight = Some(previous);
+                    continue;
+                }
+                OverlapPolicy::Queue => {
+                    let mut previous = previous;
+                    tokio::select! {
+                        _ = stop.wait_for(|stopped| *stopped) => {
+                            in_flight = Some(previous);
+                            break;
+                        }
+                        _ = &mut previous => {}
+                    }
+                }
+            }
+        }
+        in_flight = Some(tokio::spawn(run_job(
+            job.clone(),
+            executor.clone(),
+            Arc::clone(&statuses),
+        )));
+    }
+    in_flight
+        .filter(|run| !run.is_finished())
+        .map(|run| (job.name, run))
+}
+
+/// 次の予定時刻までの待ち時間を返し、`next_at` を更新します。
+fn next_delay(schedule: &JobSchedule, next_at: &mut tokio::time::Instant) -> Result<Duration> {
+    let now = tokio::time::Instant::now();
+    match schedule {
+        JobSchedule::Every(interval) => {
+            // 実行が遅れて予定時刻を過ぎた場合は、過ぎた回をまとめて現在時刻から数え直します。
+            *next_at = (*next_at + *interval).max(now);
+        }
+        JobSchedule::Cron(cron) => {
+            let current = Utc::now();
+            let fire_at = cron
+                .next_after(current)
+                .ok_or_else(|| anyhow!("Cron expression {cron} never matches"))?;
+            let wait = (fire_at - current).to_std().unwrap_or_default();
+            *next_at = now + wait;
+        }
+    }
+    Ok(next_at.saturating_duration_since(now))
+}
+
+/// ジョブを 1 回実行し、失敗した場合は再試行します。実行状況とログを記録します。
+async fn run_job(
+    job: Job,
+    executor: TransactionExecutor,
+    statuses: Arc<Mutex<HashMap<String, JobStatus>>>,
+) {
+    let started_at = Instant::now();
+    update_status(&statuses, &job.name, |status| {
+        status.running = true;
+        status.run_count += 1;
+        status.last_started_at = Some(Utc::now());
+    });
+    tracing::info!(job = %job.name, "Job started");
+
+    let mut attempt = 0;
+    let outcome = loop {
+        attempt += 1;
+        match (job.run)(executor.clone()).await {
+            Ok(()) => break JobOutcome::Succeeded,
+            Err(error) if attempt <= job.max_retries => {
+                tracing::warn!(
+                    job = %job.name,
+                    attempt,
+                    retry_in = ?job.retry_delay,
+                    "Job failed; retrying: {error:#}"
+                );
+                tokio::time::sleep(job.retry_delay).await;
+            }
+            Err(error) => break JobOutcome::Failed(format!("{error:#}")),
+        }
+    };
+
+    let duration = started_at.elapsed();
+    match &outcome {
+        JobOutcome::Succeeded => {
+            tracing::info!(job = %job.name, attempt, ?duration, "Job succeeded");
+        }
+        JobOutcome::Failed(error) => {
+            tracing::error!(job = %job.name, attempt, ?duration, "Job failed: {error}");
+        }
+    }
+    update_status(&statuses, &job.name, |status| {
+        status.running = false;
+        status.last_finished_at = Some(Utc::now());
+        status.last_duration = Some(duration);
+        status.last_outcome = Some(outcome);
+    });
+}
+
+fn update_status(
+    statuses: &Mutex<HashMap<String, JobStatus>>,
+    name: &str,
+    update: impl FnOnce(&mut JobStatus),
+) {
+    let mut statuses = statuses.lock().unwrap_or_else(PoisonError::into_inner);
+    update(statuses.entry(name.to_string()).or_default());
+}
+
+/// 5 フィールド（分 時 日 月 曜日）の cron 式です。時刻は UTC で評価します。
+///
+/// 各フィールドには `*`、数値、範囲（`1-5`）、間隔（`*/15`、`0-30/10`）とそれらのカンマ区切りの
+/// リストを指定できます。曜日は 0（日曜）から 6（土曜）で、7 も日曜として扱います。日と曜日の両方を
+/// 制限した場合は、一般的な cron と同じくいずれかに一致する日に実行します。
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CronSchedule {
+    expression: String,
+    minutes: u64,
+    hours: u64,
+    days_of_month: u64,
+    months: u64,
+    days_of_week: u64,
+    day_of_month_restricted: bool,
+    day_of_week_restricted: bool,
+}
+
+impl CronSchedule {
+    /// `after` より後で式に一致する最初の時刻（分単位）を返します。
+    pub fn next_after(&self, after: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        let mut candidate =
+            after.duration_trunc(ChronoDuration::minutes(1)).ok()? + ChronoDuration::minutes(1);
+        let limit = after + ChronoDuration::days(CRON_SEARCH_LIMIT_DAYS);
+        while candidate <= limit {
+            if !contains(self.months, candidate.month()) {
+                let (year, month) = match candidate.month() {
+                    12 => (candidate.year() + 1, 1),
+                    month => (candidate.year(), month + 1),
+                };
+                candidate = candidate
+                    .with_day(1)?
+                    .with_year(year)?
+                    .with_month(month)?
+                    .with_hour(0)?
+                    .with_minute(0)?;
+            } else if !self.matches_day(&candidate) {
+                candidate = (candidate + ChronoDuration::days(1))
+                    .with_hour(0)?
+                    .with_minute(0)?;
+            } else if !contains(self.hours, candidate.hour()) {
+                candidate = (candidate + ChronoDuration::hours(1)).with_minute(0)?;
+            } else if !contains(self.minutes, candidate.minute()) {
+                candidate += ChronoDuration::minutes(1);
+            } else {
+                return Some(candidate);
+            }
+        }
+        None
+    }
+
+    fn matches_day(&self, date: &DateTime<Utc>) -> bool {
+        let day_of_month = contains(self.days_of_month, date.day());
+        let day_of_week = contains(self.days_of_week, date.weekday().num_days_from_sunday());
+        match (self.day_of_month_restricted, self.day_of_week_restricted) {
+            (true, true) => day_of_month || day_of_week,
+            _ => day_of_month && day_of_week,
+        }
+    }
+}
+
+impl FromStr for CronSchedule {
+    type Err = anyhow::Error;
+
+    fn from_str(expression: &str) -> Result<Self> {
+        let fields: Vec<&str> = expression.split_whitespace().collect();
+        let [minute, hour, day_of_month, month, day_of_week] = fields[..] else {
+            bail!(
+                "Cron expression {expression:?} must have 5 fields (minute hour day month weekday)"
+            );
+        };
+        let parse = |field: &str, name: &str, min: u32, max: u32| {
+            parse_field(field, min, max)
+                .with_context(|| format!("Invalid {name} field in cron expression {expression:?}"))
+        };
+        let mut days_of_week = parse(day_of_week, "weekday", 0, 7)?;
+        if contains(days_of_week, 7) {
+            days_of_week |= 1;
+        }
+        Ok(Self {
+            expression: expression.to_string(),
+            minutes: parse(minute, "minute", 0, 59)?,
+            hours: parse(hour, "hour", 0, 23)?,
+            days_of_month: parse(day_of_month, "day", 1, 31)?,
+            months: parse(month, "month", 1, 12)?,
+            days_of_week,
+            day_of_month_restricted: day_of_month != "*",
+            day_of_week_restricted: day_of_week != "*",
+        })
+    }
+}
+
+impl fmt::Display for CronSchedule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.expression)
+    }
+}
+
+/// cron 式の 1 フィールドを、一致する値のビットを立てたマスクに変換します。
+fn parse_field(field: &str, min: u32, max: u32) -> Result<u64> {
+    let mut mask = 0;
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => {
+                let step: u32 = step
+                    .parse()
+                    .with_context(|| format!("Invalid step {step:?}"))?;
+                ensure!(step > 0, "Step must be greater than 0");
+                (range, step)
+            }
+            None => (part, 1),
+        };
+        let (start, end) = match range {
+            "*" => (min, max),
+            range => match range.split_once('-') {
+                Some((start, end)) => (parse_value(start)?, parse_value(end)?),
+                None => {
+                    let value = parse_value(range)?;
+                    // `5/10` のような開始値と間隔の指定は、最大値までの範囲として扱います。
+                    (value, if part.contains('/') { max } else { value })
+                }
+            },
+        };
+        ensure!(
+            min <= start && start <= end && end <= max,
+            "Range {start}-{end} is outside {min}-{max}"
+        );
+        for value in (start..=end).step_by(step as usize) {
+            mask |= 1 << value;
+        }
+    }
+    Ok(mask)
+}
+
+fn parse_value(value: &str) -> Result<u32> {
+    value
+        .parse()
+        .with_context(|| format!("Invalid value {value:?}"))
+}
+
+fn contains(mask: u64, value: u32) -> bool {
+    mask & (1 << value) != 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sqlx::PgPool;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    /// 接続しない実行器です。テストのジョブはデータベースを使いません。
+    fn unconnected_executor() -> TransactionExecutor {
+        TransactionExecutor::new(PgPool::connect_lazy("postgres://localhost/unused").unwrap())
+    }
+
+    /// 実行のたびに `runs` を加算し、`duration` だけかかるジョブを作成します。
+    ///
+    /// 同時に実行している数の最大値を `peak` に記録します。
+    fn counting_job(
+        interval: Duration,
+        duration: Duration,
+        runs: &Arc<AtomicU32>,
+        peak: &Arc<AtomicU32>,
+    ) -> Job {
+        let running = Arc::new(AtomicU32::new(0));
+        let (runs, peak) = (Arc::clone(runs), Arc::clone(peak));
+        Job::new("counting", JobSchedule::Every(interval), move |_| {
+            let (runs, peak, running) =
+                (Arc::clone(&runs), Arc::clone(&peak), Arc::clone(&running));
+            Box::pin(async move {
+                runs.fetch_add(1, Ordering::SeqCst);
+                let concurrent = running.fetch_add(1, Ordering::SeqCst) + 1;
+                peak.fetch_max(concurrent, Ordering::SeqCst);
+                tokio::time::sleep(duration).await;
+                running.fetch_sub(1, Ordering::SeqCst);
+                Ok(())
+            })
+        })
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn every_schedule_runs_the_job_once_per_interval() {
+        let (runs, peak) = (Arc::default(), Arc::default());
+        let job = counting_job(Duration::from_secs(10), Duration::ZERO, &runs, &peak);
+        let scheduler = JobScheduler::new(unconnected_executor())
+            .with_job(job)
+            .unwrap();
+
+        // 10・20・30 秒後に実行し、35 秒後に終了します。
+        scheduler
+            .run(tokio::time::sleep(Duration::from_secs(35)))
+            .await
+            .unwrap();
+
+        let status = scheduler.status("counting").unwrap();
+        assert_eq!(runs.load(Ordering::SeqCst), 3);
+        assert_eq!(status.run_count, 3);
+        assert_eq!(status.skipped_count, 0);
+        assert!(!status.running);
+        assert_eq!(status.last_outcome, Some(JobOutcome::Succeeded));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn skip_policy_does_not_overlap_a_running_job() {
+        let (runs, peak) = (Arc::default(), Arc::default());
+        let job = counting_job(
+            Duration::from_secs(10),
+            Duration::from_secs(25),
+            &runs,
+            &peak,
+        );
+        let scheduler = JobScheduler::new(unconnected_executor())
+            .with_job(job)
+            .unwrap();
+
+        // 10 秒後の実行が 35 秒後まで続くため 20・30 秒後を見送り、40 秒後に 2 回目を実行します。
+        scheduler
+            .run(tokio::time::sleep(Duration::from_secs(45)))
+            .await
+            .unwrap();
+
+        let status = scheduler.status("counting").unwrap();
+        assert_eq!(runs.load(Ordering::SeqCst), 2);
+        assert_eq!(status.run_count, 2);
+        assert_eq!(status.skipped_count, 2);
+        assert_eq!(peak.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn shutdown_stops_further_runs() {
+        let (runs, peak) = (Arc::default(), Arc::default());
+        let job = counting_job(Duration::from_secs(10), Duration::ZERO, &runs, &peak);
+        let scheduler = JobScheduler::new(unconnected_executor())
+            .with_job(job)
+            .unwrap();
+
+        scheduler
+            .run(tokio::time::sleep(Duration::from_secs(25)))
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_secs(100)).await;
+
+        assert_eq!(runs.load(Ordering::SeqCst), 2);
+        assert_eq!(scheduler.status("counting").unwrap().run_count, 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn shutdown_aborts_a_run_that_outlives_the_grace_period() {
+        let (runs, peak) = (Arc::default(), Arc::default());
+        let job = counting_job(
+            Duration::from_secs(10),
+            Duration::from_secs(100),
+            &runs,
+            &peak,
+        );
+        let scheduler = JobScheduler::new(unconnected_executor())
+            .with_job(job)
+            .unwrap()
+            .with_shutdown_grace(Duration::from_secs(5));
+
+        let error = scheduler
+            .run(tokio::time::sleep(Duration::from_secs(15)))
+            .await
+            .unwrap_err();
+
+        assert_eq!(
+            error.to_string(),
+            "Jobs did not finish within the shutdown grace period of 5s: counting"
+        );
+        assert_eq!(runs.load(Ordering::SeqCst), 1);
+    }
+}
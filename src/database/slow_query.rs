@@ -0,0 +1,60 @@
+use std::time::Duration;
+
+/// 実行に時間がかかったステートメントやトランザクションを警告ログに出力する設定です。
+///
+/// SQL にはバインドした顧客データが含まれ得るため、ログには SQL を出力せず、
+/// 呼び出し側が指定したラベルとトランザクション内でのインデックスで対象を識別します。
+#[derive(Debug, Clone, Default)]
+pub(super) struct SlowQueryLog {
+    threshold: Option<Duration>,
+    label: Option<String>,
+}
+
+impl SlowQueryLog {
+    /// `threshold` を超えた処理を記録する設定を作成します。`None` または 0 の場合は記録しません。
+    pub(super) fn new(threshold: Option<Duration>) -> Self {
+        Self {
+            threshold: threshold.filter(|threshold| !threshold.is_zero()),
+            label: None,
+        }
+    }
+
+    /// ログに含めるラベルを指定します。
+    pub(super) fn with_label(mut self, label: Option<&str>) -> Self {
+        self.label = label.map(str::to_string);
+        self
+    }
+
+    /// `elapsed` が閾値を超えているかどうかを返します。
+    pub(super) fn is_slow(&self, elapsed: Duration) -> bool {
+        self.threshold.is_some_and(|threshold| elapsed > threshold)
+    }
+
+    /// ステートメントの所要時間が閾値を超えていれば警告ログを出力します。
+    ///
+    /// `index` はトランザクション内でのインデックスで、トランザクション外の単独の読み取りでは `None` です。
+    pub(super) fn statement(&self, index: Option<usize>, elapsed: Duration) {
+        if self.is_slow(elapsed) {
+            tracing::warn!(
+                label = self.label.as_deref(),
+                index,
+                ?elapsed,
+                threshold = ?self.threshold,
+                "Slow statement"
+            );
+        }
+    }
+
+    /// トランザクション全体の所要時間が閾値を超えていれば警告ログを出力します。
+    pub(super) fn transaction(&self, elapsed: Duration, statement_count: usize) {
+        if self.is_slow(elapsed) {
+            tracing::warn!(
+                label = self.label.as_deref(),
+                statement_count,
+                ?elapsed,
+                threshold = ?self.threshold,
+                "Slow transaction"
+            );
+        }
+    }
+}
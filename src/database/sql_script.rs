@@ -0,0 +1,288 @@
+/// SQL スクリプトから切り出した 1 つのステートメントです。
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScriptStatement {
+    /// 末尾のセミコロンを除いたステートメントです。前後の空白は取り除きます。
+    pub sql: String,
+    /// ステートメントが始まる行番号（1 始まり）です。
+    pub line: usize,
+}
+
+/// SQL スクリプトをセミコロンでステートメントに分割します。
+///
+/// 文字列リテラル（`'...'`・`E'...'`）、引用符付き識別子（`"..."`）、ドル引用（`$$ ... $$`・`$tag$ ... $tag$`）、
+/// 行コメント（`--`）、入れ子のブロックコメント（`/* ... */`）の中のセミコロンでは分割しません。
+/// 空白とコメントだけのステートメントは取り除きます。閉じられていない引用やコメントは、
+/// スクリプトの末尾までを 1 つのステートメントとして扱い、実行時のエラーに委ねます。
+pub fn split_statements(script: &str) -> Vec<ScriptStatement> {
+    let mut statements = Vec::new();
+    let mut start = 0;
+    let mut line = 1;
+    // 現在のステートメントで、空白とコメント以外の最初の文字が現れた行です。
+    let mut statement_line = None;
+    let mut chars = script.char_indices().peekable();
+
+    while let Some((index, c)) = chars.next() {
+        match c {
+            '\n' => line += 1,
+            c if c.is_whitespace() => {}
+            '-' if chars.next_if(|&(_, next)| next == '-').is_some() => {
+                for (_, c) in chars.by_ref() {
+                    if c == '\n' {
+                        line += 1;
+                        break;
+                    }
+                }
+            }
+            '/' if chars.next_if(|&(_, next)| next == '*').is_some() => {
+                let mut depth = 1;
+                while depth > 0 {
+                    match chars.next() {
+                        Some((_, '\n')) => line += 1,
+                        Some((_, '*')) if chars.next_if(|&(_, next)| next == '/').is_some() => {
+                            depth -= 1;
+                        }
+                        Some((_, '/')) if chars.next_if(|&(_, next)| next == '*').is_some() => {
+                            depth += 1;
+                        }
+                        Some(_) => {}
+                        None => break,
+                    }
+                }
+            }
+            ';' => {
+                push_statement(&mut statements, &script[start..index], statement_line);
+                start = index + 1;
+                statement_line = None;
+            }
+            _ => {
+                statement_line.get_or_insert(line);
+                match c {
+                    '\'' => {
+                        let backslash_escapes = is_escape_string_prefix(&script[..index]);
+                        skip_quoted(&mut chars, &mut line, '\'', backslash_escapes);
+                    }
+                    '"' => skip_quoted(&mut chars, &mut line, '"', false),
+                    // 識別子の途中の `$`（`a$b`）はドル引用の開始ではありません。
+                    '$' if !script[..index].ends_with(is_identifier_char) => {
+                        if let Some(tag) = dollar_quote_tag(&script[index..]) {
+                            skip_dollar_quoted(script, index, tag, &mut chars, &mut line);
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+    push_statement(&mut statements, &script[start..], statement_line);
+    statements
+}
+
+fn push_statement(statements: &mut Vec<ScriptStatement>, sql: &str, line: Option<usize>) {
+    // 空白とコメントだけの区間では `line` が設定されません。
+    if let Some(line) = line {
+        statements.push(ScriptStatement {
+            sql: sql.trim().to_string(),
+            line,
+        });
+    }
+}
+
+/// 引用符の直前が、バックスラッシュエスケープを有効にする `E` 接頭辞かどうかを返します。
+pub(super) fn is_escape_string_prefix(before_quote: &str) -> bool {
+    let mut preceding = before_quote.chars().rev();
+    preceding
+        .next()
+        .is_some_and(|prefix| prefix.eq_ignore_ascii_case(&'e'))
+        && !preceding.next().is_some_and(is_identifier_char)
+}
+
+pub(super) fn is_identifier_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_' || c == '$'
+}
+
+/// 開き引用符の直後から、対応する閉じ引用符までを読み飛ばします。引用符の重ね書きは 1 文字として扱います。
+pub(super) fn skip_quoted(
+    chars: &mut std::iter::Peekable<std::str::CharIndices<'_>>,
+    line: &mut usize,
+    quote: char,
+    backslash_escapes: bool,
+) {
+    while let Some((_, c)) = chars.next() {
+        match c {
+            '\n' => *line += 1,
+            '\\' if backslash_escapes => {
+                if let Some((_, '\n')) = chars.next() {
+                    *line += 1;
+                }
+            }
+            c if c == quote && chars.next_if(|&(_, next)| next == quote).is_none() => return,
+            _ => {}
+        }
+    }
+}
+
+/// `text` が `$tag$` または `$$` で始まる場合、そのタグ全体を返します。
+pub(super) fn dollar_quote_tag(text: &str) -> Option<&str> {
+    let rest = &text[1..];
+    let end = rest.find('$')?;
+    let tag = &rest[..end];
+    let mut tag_chars = tag.chars();
+    let valid = match tag_chars.next() {
+        None => true,
+        Some(first) => {
+            (first.is_alphabetic() || first == '_')
+                && tag_chars.all(|c| c.is_alphanumeric() || c == '_')
+        }
+    };
+    valid.then(|| &text[..end + 2])
+}
+
+/// 開きのドル引用タグの直後から、同じタグで閉じられるまでを読み飛ばします。
+pub(super) fn skip_dollar_quoted(
+    script: &str,
+    open_index: usize,
+    tag: &str,
+    chars: &mut std::iter::Peekable<std::str::CharIndices<'_>>,
+    line: &mut usize,
+) {
+    let body_start = open_index + tag.len();
+    let body_end = script[body_start..]
+        .find(tag)
+        .map_or(script.len(), |offset| body_start + offset + tag.len());
+    while let Some((_, c)) = chars.next_if(|&(index, _)| index < body_end) {
+        if c == '\n' {
+            *line += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sqls(script: &str) -> Vec<String> {
+        split_statements(script)
+            .into_iter()
+            .map(|statement| statement.sql)
+            .collect()
+    }
+
+    #[test]
+    fn splits_on_semicolons_and_drops_empty_statements() {
+        assert_eq!(
+            sqls("SELECT 1; SELECT 2;; ; -- only a comment\n"),
+            ["SELECT 1", "SELECT 2"]
+        );
+        assert!(split_statements("").is_empty());
+    }
+
+    #[test]
+    fn keeps_semicolons_inside_dollar_quotes() {
+        assert_eq!(
+            sqls("CREATE FUNCTION f() RETURNS int AS $$ SELECT 1; $$ LANGUAGE sql; SELECT 2"),
+            [
+                "CREATE FUNCTION f() RETURNS int AS $$ SELECT 1; $$ LANGUAGE sql",
+                "SELECT 2"
+            ]
+        );
+    }
+
+    #[test]
+    fn keeps_semicolons_inside_tagged_dollar_quotes() {
+        assert_eq!(
+            sqls("DO $body$ BEGIN PERFORM $$;$$; END $body$; SELECT 2"),
+            ["DO $body$ BEGIN PERFORM $$;$$; END $body$", "SELECT 2"]
+        );
+    }
+
+    #[test]
+    fn keeps_semicolons_inside_string_literals() {
+        assert_eq!(
+            sqls("SELECT 'it''s; fine'; SELECT 2"),
+            ["SELECT 'it''s; fine'", "SELECT 2"]
+        );
+        assert_eq!(
+            sqls(r"SELECT E'it\'s; fine'; SELECT 2"),
+            [r"SELECT E'it\'s; fine'", "SELECT 2"]
+        );
+        assert_eq!(
+            sqls(r"SELECT e'\\'; SELECT 2"),
+            [r"SELECT e'\\'", "SELECT 2"]
+        );
+    }
+
+    #[test]
+    fn treats_backslashes_in_standard_strings_literally() {
+        assert_eq!(
+            sqls(r"SELECT 'C:\'; SELECT 2"),
+            [r"SELECT 'C:\'", "SELECT 2"]
+        );
+        // `type` の末尾の `e` は識別子の一部であり、`E` 接頭辞ではありません。
+        assert_eq!(
+            sqls(r"SELECT type'\'; SELECT 2"),
+            [r"SELECT type'\'", "SELECT 2"]
+        );
+    }
+
+    #[test]
+    fn keeps_semicolons_inside_quoted_identifiers() {
+        assert_eq!(
+            sqls(r#"SELECT 1 AS "a;""b"; SELECT 2"#),
+            [r#"SELECT 1 AS "a;""b""#, "SELECT 2"]
+        );
+    }
+
+    #[test]
+    fn skips_nested_block_comments() {
+        assert_eq!(
+            sqls("/* outer /* inner; */ still; */ SELECT 1; SELECT 2"),
+            ["/* outer /* inner; */ still; */ SELECT 1", "SELECT 2"]
+        );
+    }
+
+    #[test]
+    fn skips_line_comments_containing_semicolons() {
+        assert_eq!(
+            sqls("SELECT 1 -- trailing; comment\n; SELECT 2"),
+            ["SELECT 1 -- trailing; comment", "SELECT 2"]
+        );
+    }
+
+    #[test]
+    fn does_not_start_dollar_quotes_inside_identifiers() {
+        assert_eq!(
+            sqls("SELECT a$b$c FROM t; SELECT 2"),
+            ["SELECT a$b$c FROM t", "SELECT 2"]
+        );
+        assert_eq!(sqls("SELECT $1; SELECT 2"), ["SELECT $1", "SELECT 2"]);
+    }
+
+    #[test]
+    fn reports_the_line_where_each_statement_starts() {
+        let script =
+            "\n-- header\nSELECT 1;\n/* a\n   b */\nSELECT\n  'x\ny';\n\nSELECT $$\n$$;\nSELECT 4";
+        let lines: Vec<usize> = split_statements(script)
+            .into_iter()
+            .map(|statement| statement.line)
+            .collect();
+        assert_eq!(lines, [3, 6, 10, 12]);
+    }
+
+    #[test]
+    fn keeps_an_unterminated_quote_to_the_end_of_the_script() {
+        assert_eq!(
+            split_statements("SELECT 1;\nSELECT 'abc; SELECT 2"),
+            [
+                ScriptStatement {
+                    sql: "SELECT 1".to_string(),
+                    line: 1,
+                },
+                ScriptStatement {
+                    sql: "SELECT 'abc; SELECT 2".to_string(),
+                    line: 2,
+                },
+            ]
+        );
+        assert_eq!(sqls("SELECT $$abc; SELECT 2"), ["SELECT $$abc; SELECT 2"]);
+    }
+}
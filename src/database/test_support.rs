@@ -0,0 +1,283 @@
+use crate::database::{
+    connection_pool::ConnectionPool,
+    error::DbError,
+    executor::TransactionHandle,
+    pool_config::{PoolConfig, PoolEnvConfig, Workload},
+    transaction_executor,
+};
+use anyhow::{Context, Result};
+use dotenv::dotenv;
+use sqlx::{
+    PgPool, Postgres, Transaction,
+    postgres::{PgArguments, PgRow},
+    query::{Map, Query},
+};
+use std::ops::{Deref, DerefMut};
+
+/// テスト用の接続先を読み取る環境変数名です。未設定の場合は `DATABASE_URL` を使います。
+const ENV_TEST_DATABASE_URL: &str = "TEST_DATABASE_URL";
+/// `TestTransaction::execute_queries` が作成するセーブポイントの名前です。
+const TEST_SAVEPOINT: &str = "test_queries";
+
+/// 統合テスト用の接続プールです。
+///
+/// `begin` で取得した `TestTransaction` は破棄時に必ずロールバックされるため、テスト同士が
+/// データを共有せず、並行に実行できます。テーブルを `TRUNCATE` する必要はありません。
+/// 次の例は、このモジュールのテスト（`first_test`・`second_test`）として実際に実行しています。
+///
+/// ```ignore
+/// // 2 つのテストが同じ一意キーを同時に挿入しても、どちらのトランザクションもコミットされないため衝突しません。
+/// // 後から挿入した側は先のテストのロールバックまで待機してから成功します。
+/// async fn insert_user(email: &str) -> anyhow::Result<()> {
+///     let db = TestDb::connect().await?;
+///     let mut tx = db.begin().await?;
+///     tx.execute_query(sqlx::query("INSERT INTO users (email) VALUES ($1)").bind(email))
+///         .await?;
+///     let users = tx
+///         .fetch_all(sqlx::query("SELECT email FROM users").map(|_| ()))
+///         .await?;
+///     assert_eq!(users.len(), 1);
+///     Ok(())
+/// }
+///
+/// #[tokio::test]
+/// async fn first_test() -> anyhow::Result<()> {
+///     insert_user("same@example.com").await
+/// }
+///
+/// #[tokio::test]
+/// async fn second_test() -> anyhow::Result<()> {
+///     insert_user("same@example.com").await
+/// }
+/// ```
+pub struct TestDb {
+    connection_pool: ConnectionPool,
+}
+
+impl TestDb {
+    /// `TEST_DATABASE_URL`（未設定の場合は `DATABASE_URL`）に接続するプールを作成します。
+    ///
+    /// 接続数などの調整値は `PoolConfig::from_env` と同じ環境変数から読み込みます。
+    pub async fn connect() -> Result<Self> {
+        dotenv().ok();
+        let mut env_config = PoolEnvConfig::default();
+        if std::env::var_os(ENV_TEST_DATABASE_URL).is_some() {
+            env_config.database_url_var = ENV_TEST_DATABASE_URL.to_string();
+        }
+        let config = PoolConfig::from_env_config(&env_config)?;
+        Self::connect_with(&config).await
+    }
+
+    /// `config` の接続先と調整値でテスト用のプールを作成します。
+    pub async fn connect_with(config: &PoolConfig) -> Result<Self> {
+        let connection_pool = ConnectionPool::connect(config)
+            .await
+            .context("Failed to create test database pool")?;
+        Ok(Self { connection_pool })
+    }
+
+    /// 破棄時にロールバックされるトランザクションを開始します。
+    pub async fn begin(&self) -> Result<TestTransaction> {
+        let tx = self
+            .pool()
+            .begin()
+            .await
+            .map_err(DbError::from)
+            .context("Failed to begin test transaction")?;
+        Ok(TestTransaction { tx })
+    }
+
+    /// テスト用の接続プールを返します。
+    pub fn pool(&self) -> &PgPool {
+        self.connection_pool.for_workload(Workload::Interactive)
+    }
+}
+
+/// 1 本の接続で開いたままのトランザクションです。コミットする手段はなく、破棄時にロールバックされます。
+///
+/// 本番の実行器と同じ `execute_query`・`execute_queries`・`fetch_one`・`fetch_all` を持ち、
+/// 同じトランザクション内で実行するため、先に書き込んだ行をその後の読み取りで参照できます。
+/// `Deref`/`DerefMut` で内部の `Transaction` にアクセスすることもできます。
+pub struct TestTransaction {
+    tx: Transaction<'static, Postgres>,
+}
+
+impl TestTransaction {
+    /// 単一クエリを実行し、影響を受けた行数を返します。
+    pub async fn execute_query<'a>(
+        &mut self,
+        query: Query<'a, Postgres, PgArguments>,
+    ) -> Result<u64> {
+        let rows_affected = self.execute_queries(std::iter::once(query)).await?;
+        Ok(rows_affected.into_iter().sum())
+    }
+
+    /// 複数クエリを順に実行し、クエリごとに影響を受けた行数を返します。
+    ///
+    /// 本番の実行器と同じく全体として原子的に扱うため、セーブポイントの中で実行し、いずれかのクエリが
+    /// 失敗した場合はこの呼び出しで行った変更だけを取り消してからエラーを返します。
+    pub async fn execute_queries<'a, I>(&mut self, queries: I) -> Result<Vec<u64>>
+    where
+        I: IntoIterator<Item = Query<'a, Postgres, PgArguments>>,
+    {
+        self.savepoint_command("SAVEPOINT").await?;
+        let mut rows_affected = Vec::new();
+        for (index, query) in queries.into_iter().enumerate() {
+            match query.execute(&mut *self.tx).await {
+                Ok(result) => rows_affected.push(result.rows_affected()),
+                Err(error) => {
+                    self.savepoint_command("ROLLBACK TO SAVEPOINT").await?;
+                    return Err(DbError::from(error)).with_context(|| {
+                        format!("Failed to execute query in transaction at index {index}")
+                    });
+                }
+            }
+        }
+        self.savepoint_command("RELEASE SAVEPOINT").await?;
+        Ok(rows_affected)
+    }
+
+    /// マッピング済みクエリを実行し、最大 1 行を返します。クエリ結果が空の場合は `Ok(None)` を返します。
+    pub async fn fetch_one<'a, U, F>(
+        &mut self,
+        query: Map<'a, Postgres, F, PgArguments>,
+    ) -> Result<Option<U>>
+    where
+        U: Send + Unpin,
+        F: FnMut(PgRow) -> std::result::Result<U, sqlx::Error> + Send + 'static,
+    {
+        transaction_executor::fetch_one(&mut self.tx, query).await
+    }
+
+    /// マッピング済みクエリを実行し、全行をベクタとして返します。
+    pub async fn fetch_all<'a, U, F>(
+        &mut self,
+        query: Map<'a, Postgres, F, PgArguments>,
+    ) -> Result<Vec<U>>
+    where
+        U: Send + Unpin,
+        F: FnMut(PgRow) -> std::result::Result<U, sqlx::Error> + Send + 'static,
+    {
+        transaction_executor::fetch_all(&mut self.tx, query).await
+    }
+
+    /// このトランザクションを `DatabaseExecutor` として返します。
+    ///
+    /// `impl DatabaseExecutor` を受け取る本番コードに、テスト用のトランザクションをそのまま渡せます。
+    pub fn executor(&mut self) -> TransactionHandle<'_> {
+        TransactionHandle::new(&mut self.tx)
+    }
+
+    /// `execute_queries` のセーブポイントを作成・解放・ロールバックします。
+    async fn savepoint_command(&mut self, command: &str) -> Result<()> {
+        sqlx::query(&format!("{command} {TEST_SAVEPOINT}"))
+            .execute(&mut *self.tx)
+            .await
+            .map_err(DbError::from)
+            .with_context(|| format!("Failed to run {command} {TEST_SAVEPOINT}"))?;
+        Ok(())
+    }
+}
+
+impl Deref for TestTransaction {
+    type Target = Transaction<'static, Postgres>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.tx
+    }
+}
+
+impl DerefMut for TestTransaction {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.tx
+    }
+}
+
+#[cfg(all(test, feature = "test-util"))]
+mod tests {
+    use super::*;
+
+    /// 並行に実行される 2 つのテストが、同じ一意キーを挿入します。
+    const SHARED_EMAIL: &str = "same@example.com";
+
+    /// テスト用のテーブルを作成します。別プロセスのテストと同時に作成しないよう、アドバイザリロックで直列化します。
+    async fn ensure_users_table(db: &TestDb) -> Result<()> {
+        let mut tx = db.pool().begin().await?;
+        sqlx::query("SELECT pg_advisory_xact_lock(hashtext('test_support_users'))")
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query("CREATE TABLE IF NOT EXISTS test_support_users (email text PRIMARY KEY)")
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn insert_user(email: &str) -> Result<()> {
+        let db = TestDb::connect().await?;
+        ensure_users_table(&db).await?;
+        let mut tx = db.begin().await?;
+        tx.execute_query(
+            sqlx::query("INSERT INTO test_support_users (email) VALUES ($1)").bind(email),
+        )
+        .await?;
+        let users = tx
+            .fetch_all(
+                sqlx::query("SELECT email FROM test_support_users WHERE email = $1")
+                    .bind(email)
+                    .map(|_| ()),
+            )
+            .await?;
+        assert_eq!(users.len(), 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[ignore = "requires a PostgreSQL database (TEST_DATABASE_URL)"]
+    async fn first_test() -> Result<()> {
+        insert_user(SHARED_EMAIL).await
+    }
+
+    #[tokio::test]
+    #[ignore = "requires a PostgreSQL database (TEST_DATABASE_URL)"]
+    async fn second_test() -> Result<()> {
+        insert_user(SHARED_EMAIL).await
+    }
+
+    #[tokio::test]
+    #[ignore = "requires a PostgreSQL database (TEST_DATABASE_URL)"]
+    async fn execute_queries_rolls_back_only_the_failed_call() -> Result<()> {
+        let db = TestDb::connect().await?;
+        ensure_users_table(&db).await?;
+        let mut tx = db.begin().await?;
+        tx.execute_query(
+            sqlx::query("INSERT INTO test_support_users (email) VALUES ($1)")
+                .bind("kept@example.com"),
+        )
+        .await?;
+
+        let error = tx
+            .execute_queries([
+                sqlx::query("INSERT INTO test_support_users (email) VALUES ($1)")
+                    .bind("discarded@example.com"),
+                sqlx::query("INSERT INTO test_support_users (email) VALUES ($1)")
+                    .bind("kept@example.com"),
+            ])
+            .await
+            .unwrap_err();
+
+        assert!(matches!(
+            DbError::find(&error),
+            Some(DbError::UniqueViolation { .. })
+        ));
+        let emails = tx
+            .fetch_all(
+                sqlx::query("SELECT email FROM test_support_users WHERE email = ANY($1)")
+                    .bind(["kept@example.com", "discarded@example.com"])
+                    .try_map(|row: PgRow| sqlx::Row::try_get::<String, _>(&row, 0)),
+            )
+            .await?;
+        assert_eq!(emails, ["kept@example.com"]);
+        Ok(())
+    }
+}
@@ -0,0 +1,71 @@
+use crate::database::{
+    connection_pool::{ConnectionPool, SharedConnectionPool},
+    pool_config::PoolConfig,
+};
+use dotenv::dotenv;
+use sqlx::PgPool;
+use std::{
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// テスト用の接続先を読み取る環境変数名です。未設定の場合は `DATABASE_URL` を使います。
+const ENV_TEST_DATABASE_URL: &str = "TEST_DATABASE_URL";
+const ENV_DATABASE_URL: &str = "DATABASE_URL";
+
+/// PostgreSQL を必要とするテストの接続 URL を返します。
+///
+/// これらのテストは `#[ignore]` を付けており、`cargo test -- --ignored` で実行します。
+pub(crate) fn database_url() -> String {
+    dotenv().ok();
+    std::env::var(ENV_TEST_DATABASE_URL)
+        .or_else(|_| std::env::var(ENV_DATABASE_URL))
+        .expect("TEST_DATABASE_URL or DATABASE_URL must be set for database tests")
+}
+
+/// 並行して実行するテストや過去の実行と重複しない、`prefix` で始まるテーブル名を返します。
+pub(crate) fn unique_table(prefix: &str) -> String {
+    static SEQUENCE: AtomicU64 = AtomicU64::new(0);
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("system clock is after the Unix epoch")
+        .as_nanos();
+    format!(
+        "{prefix}_{}_{nanos}_{}",
+        std::process::id(),
+        SEQUENCE.fetch_add(1, Ordering::Relaxed)
+    )
+}
+
+/// エラーの原因に含まれるデータベースエラーの SQLSTATE を返します。
+pub(crate) fn sqlstate(error: &anyhow::Error) -> Option<String> {
+    error.chain().find_map(|cause| {
+        let code = cause
+            .downcast_ref::<sqlx::Error>()?
+            .as_database_error()?
+            .code()?;
+        Some(code.into_owned())
+    })
+}
+
+/// テスト用の接続 URL に接続した SQLx のプールを返します。
+pub(crate) async fn pool() -> PgPool {
+    PgPool::connect(&database_url())
+        .await
+        .expect("failed to connect to the test database")
+}
+
+/// テスト用の接続 URL で、既定の調整値の接続プールを作成します。
+pub(crate) async fn connect() -> SharedConnectionPool {
+    let config = PoolConfig::builder()
+        .database_url(database_url())
+        .build()
+        .expect("test pool config is valid");
+    let connection_pool = ConnectionPool::connect(&config)
+        .await
+        .expect("failed to connect to the test database");
+    Arc::new(connection_pool)
+}
@@ -0,0 +1,226 @@
+use anyhow::{Context, Result, anyhow, ensure};
+use sqlx::postgres::{PgConnectOptions, PgSslMode};
+use std::{fmt, fs::File, path::PathBuf, str::FromStr};
+
+/// 接続時の TLS の要求水準です。PostgreSQL の `sslmode` に対応します。
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SslMode {
+    /// TLS を使いません。
+    Disable,
+    /// サーバーが対応していれば TLS を使います。
+    Prefer,
+    /// TLS を必須とし、証明書は検証しません。
+    Require,
+    /// TLS を必須とし、証明書が信頼できる認証局に署名されていることを検証します。
+    VerifyCa,
+    /// `VerifyCa` に加えて、証明書のホスト名が接続先と一致することを検証します。
+    VerifyFull,
+}
+
+impl SslMode {
+    /// `sslmode` での表記を返します。
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Disable => "disable",
+            Self::Prefer => "prefer",
+            Self::Require => "require",
+            Self::VerifyCa => "verify-ca",
+            Self::VerifyFull => "verify-full",
+        }
+    }
+}
+
+impl From<SslMode> for PgSslMode {
+    fn from(mode: SslMode) -> Self {
+        match mode {
+            SslMode::Disable => Self::Disable,
+            SslMode::Prefer => Self::Prefer,
+            SslMode::Require => Self::Require,
+            SslMode::VerifyCa => Self::VerifyCa,
+            SslMode::VerifyFull => Self::VerifyFull,
+        }
+    }
+}
+
+impl FromStr for SslMode {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "disable" => Ok(Self::Disable),
+            "prefer" => Ok(Self::Prefer),
+            "require" => Ok(Self::Require),
+            "verify-ca" => Ok(Self::VerifyCa),
+            "verify-full" => Ok(Self::VerifyFull),
+            _ => Err(anyhow!(
+                "SSL mode must be disable, prefer, require, verify-ca or verify-full, got {value:?}"
+            )),
+        }
+    }
+}
+
+impl fmt::Display for SslMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// 証明書または秘密鍵の読み込み元です。
+#[derive(Clone, PartialEq, Eq)]
+pub enum CertSource {
+    /// PEM 形式のファイルのパスです。接続プールの作成時に読み取れることを確認します。
+    Path(PathBuf),
+    /// PEM 形式の内容です。
+    Pem(Vec<u8>),
+}
+
+impl CertSource {
+    /// ファイルのパスから読み込み元を作成します。
+    pub fn path(path: impl Into<PathBuf>) -> Self {
+        Self::Path(path.into())
+    }
+
+    /// PEM 形式の内容から読み込み元を作成します。
+    pub fn pem(pem: impl Into<Vec<u8>>) -> Self {
+        Self::Pem(pem.into())
+    }
+
+    /// パスの場合はファイルを開けることを確認します。
+    fn ensure_readable(&self, description: &str) -> Result<()> {
+        if let Self::Path(path) = self {
+            File::open(path)
+                .with_context(|| format!("Failed to read {description} at {}", path.display()))?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for CertSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // 秘密鍵を含み得るため、PEM の内容は出力しません。
+        match self {
+            Self::Path(path) => f.debug_tuple("Path").field(path).finish(),
+            Self::Pem(pem) => write!(f, "Pem(<{} bytes>)", pem.len()),
+        }
+    }
+}
+
+/// 接続プールが PostgreSQL に接続する際の TLS 設定です。
+///
+/// 指定した項目は接続 URL の `sslmode`・`sslrootcert`・`sslcert`・`sslkey` より優先します。
+/// 指定しない項目は接続 URL の値（URL にもなければ SQLx の既定値）を使います。
+///
+/// ```ignore
+/// let config = PoolConfig::builder()
+///     .database_url("postgres://app@db.example.com/app")
+///     .tls(
+///         TlsConfig::new()
+///             .with_ssl_mode(SslMode::VerifyFull)
+///             .with_root_cert(CertSource::path("/etc/ssl/certs/db-root.pem")),
+///     )
+///     .build()?;
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TlsConfig {
+    ssl_mode: Option<SslMode>,
+    root_cert: Option<CertSource>,
+    client_cert: Option<CertSource>,
+    client_key: Option<CertSource>,
+}
+
+impl TlsConfig {
+    /// 何も指定しない（接続 URL の設定に従う）設定を作成します。
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// TLS の要求水準を指定します。
+    pub fn with_ssl_mode(mut self, ssl_mode: SslMode) -> Self {
+        self.ssl_mode = Some(ssl_mode);
+        self
+    }
+
+    /// サーバー証明書を検証するルート証明書を指定します。
+    pub fn with_root_cert(mut self, root_cert: CertSource) -> Self {
+        self.root_cert = Some(root_cert);
+        self
+    }
+
+    /// 相互 TLS 認証に使うクライアント証明書と秘密鍵を指定します。
+    pub fn with_client_cert(mut self, client_cert: CertSource, client_key: CertSource) -> Self {
+        self.client_cert = Some(client_cert);
+        self.client_key = Some(client_key);
+        self
+    }
+
+    /// TLS の要求水準を返します。`None` の場合は接続 URL の設定に従います。
+    pub fn ssl_mode(&self) -> Option<SslMode> {
+        self.ssl_mode
+    }
+
+    /// ルート証明書の読み込み元を返します。
+    pub fn root_cert(&self) -> Option<&CertSource> {
+        self.root_cert.as_ref()
+    }
+
+    /// クライアント証明書の読み込み元を返します。
+    pub fn client_cert(&self) -> Option<&CertSource> {
+        self.client_cert.as_ref()
+    }
+
+    /// クライアント証明書の秘密鍵の読み込み元を返します。
+    pub fn client_key(&self) -> Option<&CertSource> {
+        self.client_key.as_ref()
+    }
+
+    /// いずれの項目も指定されていない場合に `true` を返します。
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// 設定の組み合わせを検証します。
+    pub(super) fn validate(&self) -> Result<()> {
+        ensure!(
+            self.client_cert.is_some() == self.client_key.is_some(),
+            "TLS client certificate and client key must be set together"
+        );
+        ensure!(
+            self.ssl_mode != Some(SslMode::Disable)
+                || (self.root_cert.is_none() && self.client_cert.is_none()),
+            "TLS certificates must not be set when ssl_mode is disable"
+        );
+        Ok(())
+    }
+
+    /// 接続 URL から作成した `options` に設定を上書きします。
+    ///
+    /// 証明書をファイルで指定した場合は、ここで読み取れることを確認し、最初のクエリではなく
+    /// 接続プールの作成時にエラーを返します。
+    pub(super) fn apply(&self, mut options: PgConnectOptions) -> Result<PgConnectOptions> {
+        if let Some(ssl_mode) = self.ssl_mode {
+            options = options.ssl_mode(ssl_mode.into());
+        }
+        if let Some(root_cert) = &self.root_cert {
+            root_cert.ensure_readable("TLS root certificate")?;
+            options = match root_cert {
+                CertSource::Path(path) => options.ssl_root_cert(path),
+                CertSource::Pem(pem) => options.ssl_root_cert_from_pem(pem.clone()),
+            };
+        }
+        if let Some(client_cert) = &self.client_cert {
+            client_cert.ensure_readable("TLS client certificate")?;
+            options = match client_cert {
+                CertSource::Path(path) => options.ssl_client_cert(path),
+                CertSource::Pem(pem) => options.ssl_client_cert_from_pem(pem),
+            };
+        }
+        if let Some(client_key) = &self.client_key {
+            client_key.ensure_readable("TLS client key")?;
+            options = match client_key {
+                CertSource::Path(path) => options.ssl_client_key(path),
+                CertSource::Pem(pem) => options.ssl_client_key_from_pem(pem),
+            };
+        }
+        Ok(options)
+    }
+}
@@ -0,0 +1,4297 @@
+use crate::database::{
+    advisory_lock::{self, AdvisoryLockKey, LockAttempt},
+    audit_log::{AuditEntry, AuditLog},
+    connection_pool::SharedConnectionPool,
+    copy_in::{CopyValue, encode_row},
+    error::{DbError, DeadlinePhase, ErrorDetail},
+    hold_watchdog::HoldWatchdog,
+    identifier::{quote_identifier, quote_qualified_identifier},
+    instrumentation::{Outcome, record_error, record_outcome, statement_span, transaction_span},
+    metrics,
+    pgbouncer::{Unprepared, ensure_map_supported},
+    pool_config::Workload,
+    query_spec::QuerySpec,
+    query_tag::QueryTag,
+    retry::{AcquireRetry, RetryPolicy},
+    slow_query::SlowQueryLog,
+    sql_script::split_statements,
+    transaction_options::{IsolationLevel, TransactionOptions, begin_with_options},
+    transaction_report::{DryRunFailure, DryRunReport, StatementStat, TransactionReport},
+    transaction_scope::{self, AmbientTransaction, Propagation},
+};
+use anyhow::{Context, Result, anyhow, bail, ensure};
+use chrono::{DateTime, Utc};
+use futures_util::{FutureExt, Stream, StreamExt};
+use sqlx::{
+    Execute, Executor, FromRow, PgPool, Postgres, Transaction,
+    postgres::{PgArguments, PgQueryResult, PgRow},
+    query::{Map, Query},
+};
+use std::{
+    future::Future,
+    ops::{Deref, DerefMut},
+    path::Path,
+    pin::{Pin, pin},
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, Instant},
+};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tracing::{Instrument, Span};
+use uuid::Uuid;
+
+const MAX_SAVEPOINT_PREFIX_LEN: usize = 32;
+/// 期限切れのトランザクションの取り消しとロールバックのそれぞれに許す時間です。
+const DEADLINE_ABORT_GRACE: Duration = Duration::from_secs(5);
+/// `fetch_in_chunks` が宣言するカーソル名の接頭辞です。カーソルはトランザクション終了時に破棄されます。
+const CHUNK_CURSOR_PREFIX: &str = "fetch_in_chunks_cursor";
+/// `copy_in` が 1 回の送信にまとめるデータのおおよそのバイト数です。
+const COPY_BUFFER_SIZE: usize = 64 * 1024;
+/// `execute_queries_best_effort` がステートメントごとに作成するセーブポイント名の接頭辞です。
+const BEST_EFFORT_SAVEPOINT_PREFIX: &str = "best_effort";
+
+static SAVEPOINT_SEQUENCE: AtomicU64 = AtomicU64::new(0);
+static CURSOR_SEQUENCE: AtomicU64 = AtomicU64::new(0);
+
+/// フックやクロージャから返される、スレッド間で送信可能な boxed future です。
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+type BeforeCommitHook = Arc<
+    dyn for<'c> Fn(&'c mut Transaction<'static, Postgres>) -> BoxFuture<'c, Result<()>>
+        + Send
+        + Sync,
+>;
+type AfterHook = Arc<dyn Fn() -> BoxFuture<'static, ()> + Send + Sync>;
+
+/// トランザクションのライフサイクル上の決まった時点で実行されるフックです。
+///
+/// フックは次の順序で実行されます。
+/// 1. すべてのクエリを実行する
+/// 2. `before_commit` を同じトランザクション上で実行する
+/// 3. コミットに成功した場合は `after_commit`、ロールバックした場合は `after_rollback` を実行する
+///
+/// `before_commit` がエラーを返した場合はコミットせずにロールバックします。
+/// `after_commit` と `after_rollback` はトランザクション終了後に呼ばれるため、結果を変更できません。
+#[derive(Clone, Default)]
+pub struct TransactionHooks {
+    before_commit: Option<BeforeCommitHook>,
+    after_commit: Option<AfterHook>,
+    after_rollback: Option<AfterHook>,
+}
+
+impl TransactionHooks {
+    /// フックが登録されていない状態を作成します。
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// コミット直前に同じトランザクション上で実行するフックを登録します。
+    ///
+    /// フック内で実行したステートメントはコミット対象に含まれます。
+    /// エラーを返すとトランザクションはロールバックされ、そのエラーが呼び出し元へ返ります。
+    pub fn before_commit<F>(mut self, hook: F) -> Self
+    where
+        F: for<'c> Fn(&'c mut Transaction<'static, Postgres>) -> BoxFuture<'c, Result<()>>
+            + Send
+            + Sync
+            + 'static,
+    {
+        self.before_commit = Some(Arc::new(hook));
+        self
+    }
+
+    /// コミット成功後に実行するフックを登録します。
+    pub fn after_commit<F, Fut>(mut self, hook: F) -> Self
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        self.after_commit = Some(Arc::new(move || Box::pin(hook())));
+        self
+    }
+
+    /// ロールバック後に実行するフックを登録します。
+    ///
+    /// クエリの失敗、`before_commit` の失敗、コミットの失敗のいずれでも呼ばれます。
+    pub fn after_rollback<F, Fut>(mut self, hook: F) -> Self
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        self.after_rollback = Some(Arc::new(move || Box::pin(hook())));
+        self
+    }
+
+    async fn run_before_commit(&self, tx: &mut Transaction<'static, Postgres>) -> Result<()> {
+        match &self.before_commit {
+            Some(hook) => hook(tx).await.context("before_commit hook failed"),
+            None => Ok(()),
+        }
+    }
+
+    async fn run_after_commit(&self) {
+        if let Some(hook) = &self.after_commit {
+            hook().await;
+        }
+    }
+
+    async fn run_after_rollback(&self) {
+        if let Some(hook) = &self.after_rollback {
+            hook().await;
+        }
+    }
+}
+
+/// トランザクションの結果を受け取るオブザーバーです。
+///
+/// メトリクス基盤に依存せずにコミット数・ロールバック数・所要時間を収集するために使います。
+/// コールバックはトランザクション終了後に同期的に呼ばれるため、重い処理は避けてください。
+pub trait TransactionObserver: Send + Sync {
+    /// コミットに成功したときに、開始からコミット完了までの時間と実行したクエリ数を受け取ります。
+    fn on_commit(&self, duration: Duration, query_count: usize);
+
+    /// ロールバックしたときに、開始からロールバックまでの時間と失敗したクエリのインデックスを受け取ります。
+    ///
+    /// フックやコミットの失敗など、特定のクエリに起因しない場合の `error_index` は `None` です。
+    fn on_rollback(&self, duration: Duration, error_index: Option<usize>);
+}
+
+/// 開始済みトランザクションのガードです。
+///
+/// `Deref`/`DerefMut` で内部の `Transaction` にアクセスでき、任意の SQLx クエリを実行できます。
+/// 変更を確定するには `commit` を明示的に呼び出してください。`commit` も `rollback` も呼ばれずに
+/// 破棄された場合（future の破棄やパニックを含む）は、開いていた時間と実行したステートメント数を
+/// 警告ログに出力し、SQLx の既定動作によりロールバックされます。
+///
+/// `TransactionExecutor` のクエリ列・クロージャ API も内部でこのガードを使います。
+pub struct TransactionGuard {
+    tx: Option<Transaction<'static, Postgres>>,
+    started_at: Instant,
+    statement_count: usize,
+    slow_query: SlowQueryLog,
+    span: Span,
+    /// 同時実行数を制限した実行器から開始した場合の実行枠です。ガードの破棄とともに返却されます。
+    _permit: Option<OwnedSemaphorePermit>,
+    /// 保持時間の監視です。ガードの破棄とともに停止します。
+    _watchdog: Option<HoldWatchdog>,
+    /// PgBouncer 互換モードで開始したかどうかです。
+    pgbouncer_mode: bool,
+    /// 監査ログに記録するラベルです。
+    label: Option<String>,
+    /// 監査ログに記録する開始時刻です。
+    begun_at: DateTime<Utc>,
+    /// このガードを通して実行した書き込みで影響を受けた行数の合計です。
+    rows_affected: u64,
+    /// 失敗したステートメントのエラーに含める詳細の範囲です。
+    error_detail: ErrorDetail,
+}
+
+/// `TransactionGuard` の旧名です。
+#[deprecated(note = "renamed to TransactionGuard")]
+pub type ManagedTransaction = TransactionGuard;
+
+impl TransactionGuard {
+    fn new(
+        tx: Transaction<'static, Postgres>,
+        slow_query: SlowQueryLog,
+        span: Span,
+        permit: Option<OwnedSemaphorePermit>,
+        watchdog: Option<HoldWatchdog>,
+        pgbouncer_mode: bool,
+        label: Option<&str>,
+    ) -> Self {
+        Self {
+            tx: Some(tx),
+            started_at: Instant::now(),
+            statement_count: 0,
+            slow_query,
+            span,
+            _permit: permit,
+            _watchdog: watchdog,
+            pgbouncer_mode,
+            label: label.map(str::to_string),
+            begun_at: Utc::now(),
+            rows_affected: 0,
+            error_detail: ErrorDetail::default(),
+        }
+    }
+
+    /// トランザクションを開始してからの経過時間を返します。
+    pub fn elapsed(&self) -> Duration {
+        self.started_at.elapsed()
+    }
+
+    /// このガードを通して実行したステートメント数を返します。
+    ///
+    /// `Deref` 経由で直接実行したクエリは数えません。
+    pub fn statement_count(&self) -> usize {
+        self.statement_count
+    }
+
+    /// クエリをこのトランザクション上で実行し、影響を受けた行数を返します。
+    pub async fn execute<'a>(&mut self, query: Query<'a, Postgres, PgArguments>) -> Result<u64> {
+        let result = self
+            .execute_raw(query)
+            .await
+            .map_err(DbError::from)
+            .context("Failed to execute query in transaction")?;
+        Ok(result.rows_affected())
+    }
+
+    /// マッピング済みクエリをこのトランザクション上で実行し、最大 1 行を返します。
+    pub async fn fetch_one<'a, U, F>(
+        &mut self,
+        query: Map<'a, Postgres, F, PgArguments>,
+    ) -> Result<Option<U>>
+    where
+        U: Send + Unpin,
+        F: FnMut(PgRow) -> std::result::Result<U, sqlx::Error> + Send + 'static,
+    {
+        ensure_map_supported(self.pgbouncer_mode)?;
+        let index = self.statement_count;
+        let span = statement_span(&self.span, index);
+        metrics::record_statement();
+        let started_at = Instant::now();
+        let row = fetch_one(self, query).instrument(span.clone()).await;
+        if let Err(error) = &row {
+            record_error(&span, format_args!("{error:#}"));
+        }
+        self.slow_query.statement(Some(index), started_at.elapsed());
+        self.statement_count += 1;
+        row
+    }
+
+    /// マッピング済みクエリをこのトランザクション上で実行し、全行をベクタとして返します。
+    pub async fn fetch_all<'a, U, F>(
+        &mut self,
+        query: Map<'a, Postgres, F, PgArguments>,
+    ) -> Result<Vec<U>>
+    where
+        U: Send + Unpin,
+        F: FnMut(PgRow) -> std::result::Result<U, sqlx::Error> + Send + 'static,
+    {
+        ensure_map_supported(self.pgbouncer_mode)?;
+        let index = self.statement_count;
+        let span = statement_span(&self.span, index);
+        metrics::record_statement();
+        let started_at = Instant::now();
+        let rows = fetch_all(self, query).instrument(span.clone()).await;
+        if let Err(error) = &rows {
+            record_error(&span, format_args!("{error:#}"));
+        }
+        self.slow_query.statement(Some(index), started_at.elapsed());
+        self.statement_count += 1;
+        rows
+    }
+
+    /// `RETURNING` 句を持つ書き込みをマッピング済みクエリとしてこのトランザクション上で実行し、返された行を返します。
+    ///
+    /// 返された行がない場合は空のベクタを返します。生成された ID を同じトランザクションの後続の
+    /// ステートメントに渡す場合に使います。
+    pub async fn execute_returning<'a, U, F>(
+        &mut self,
+        query: Map<'a, Postgres, F, PgArguments>,
+    ) -> Result<Vec<U>>
+    where
+        U: Send + Unpin,
+        F: FnMut(PgRow) -> std::result::Result<U, sqlx::Error> + Send + 'static,
+    {
+        ensure_map_supported(self.pgbouncer_mode)?;
+        let index = self.statement_count;
+        let span = statement_span(&self.span, index);
+        metrics::record_statement();
+        let started_at = Instant::now();
+        let rows = execute_returning(self, query)
+            .instrument(span.clone())
+            .await;
+        match &rows {
+            Ok(rows) => self.rows_affected += rows.len() as u64,
+            Err(error) => record_error(&span, format_args!("{error:#}")),
+        }
+        self.slow_query.statement(Some(index), started_at.elapsed());
+        self.statement_count += 1;
+        rows
+    }
+
+    /// トランザクションをコミットします。
+    pub async fn commit(mut self) -> Result<()> {
+        let Some(tx) = self.tx.take() else {
+            return Ok(());
+        };
+        let result = tx.commit().instrument(self.span.clone()).await;
+        let outcome = match &result {
+            Ok(()) => Outcome::Committed,
+            Err(error) => {
+                record_error(&self.span, error);
+                Outcome::RolledBack
+            }
+        };
+        self.record_outcome(outcome);
+        result.map_err(commit_error)
+    }
+
+    /// トランザクションをロールバックします。
+    pub async fn rollback(mut self) -> Result<()> {
+        let Some(tx) = self.tx.take() else {
+            return Ok(());
+        };
+        let result = tx.rollback().instrument(self.span.clone()).await;
+        self.record_outcome(Outcome::RolledBack);
+        result
+            .map_err(DbError::from)
+            .context("Failed to rollback transaction")
+    }
+
+    /// トランザクションの結果と所要時間をスパンに記録します。
+    fn record_outcome(&self, outcome: Outcome) {
+        record_outcome(
+            &self.span,
+            outcome,
+            self.statement_count,
+            self.started_at.elapsed(),
+        );
+    }
+
+    /// クエリを実行し、成功した場合はステートメント数を加算します。
+    ///
+    /// 所要時間が遅いステートメントの閾値を超えた場合は、成否にかかわらず警告ログを出力します。
+    async fn execute_raw<'a>(
+        &mut self,
+        query: Query<'a, Postgres, PgArguments>,
+    ) -> sqlx::Result<PgQueryResult> {
+        let span = statement_span(&self.span, self.statement_count);
+        metrics::record_statement();
+        let started_at = Instant::now();
+        let result = query
+            .unprepared_if(self.pgbouncer_mode)
+            .execute(&mut ***self)
+            .instrument(span.clone())
+            .await;
+        self.slow_query
+            .statement(Some(self.statement_count), started_at.elapsed());
+        match &result {
+            Ok(result) => {
+                span.record("rows_affected", result.rows_affected());
+            }
+            Err(error) => record_error(&span, error),
+        }
+        let result = result?;
+        self.statement_count += 1;
+        self.rows_affected += result.rows_affected();
+        Ok(result)
+    }
+
+    /// クエリ列の `index` 番目のステートメントとしてクエリを実行します。
+    ///
+    /// 失敗した場合は、`error_detail` に従ってステートメントの位置や SQLSTATE などをコンテキストに含めます。
+    async fn execute_statement<'a>(
+        &mut self,
+        index: usize,
+        query: Query<'a, Postgres, PgArguments>,
+    ) -> Result<PgQueryResult> {
+        let sql = query.sql();
+        match self.execute_raw(query).await {
+            Ok(result) => Ok(result),
+            Err(error) => {
+                let error = DbError::from(error);
+                let context = self.error_detail.statement_context(index, sql, &error);
+                Err(error).context(context)
+            }
+        }
+    }
+}
+
+impl Deref for TransactionGuard {
+    type Target = Transaction<'static, Postgres>;
+
+    fn deref(&self) -> &Self::Target {
+        self.tx
+            .as_ref()
+            .expect("transaction is present until commit or rollback")
+    }
+}
+
+impl DerefMut for TransactionGuard {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.tx
+            .as_mut()
+            .expect("transaction is present until commit or rollback")
+    }
+}
+
+impl Drop for TransactionGuard {
+    fn drop(&mut self) {
+        if self.tx.is_some() {
+            self.record_outcome(Outcome::RolledBack);
+            metrics::record_rollback(metrics::ERROR_KIND_DROPPED);
+            tracing::warn!(
+                open_for = ?self.started_at.elapsed(),
+                statement_count = self.statement_count,
+                "Transaction dropped without commit or rollback; the transaction will be rolled back"
+            );
+        }
+    }
+}
+
+type CommitCallback = BoxFuture<'static, Result<()>>;
+type RollbackCallback = BoxFuture<'static, ()>;
+
+/// `with_transaction_context` のクロージャに渡される、コールバックを登録できるトランザクションです。
+///
+/// `Deref`/`DerefMut` で `TransactionGuard` にアクセスでき、ガードと同じくクエリを実行できます。
+/// `on_commit` で登録した処理はコミットに成功した後にだけ、`on_rollback` で登録した処理は
+/// ロールバックした後にだけ、それぞれ登録した順に実行されます。キューへのメッセージ送信のように、
+/// データが確定してから行うべき処理をクロージャの中から予約するために使います。
+pub struct TransactionContext {
+    guard: TransactionGuard,
+    on_commit: Vec<CommitCallback>,
+    on_rollback: Vec<RollbackCallback>,
+}
+
+impl TransactionContext {
+    fn new(guard: TransactionGuard) -> Self {
+        Self {
+            guard,
+            on_commit: Vec::new(),
+            on_rollback: Vec::new(),
+        }
+    }
+
+    /// コミットに成功した後に実行する処理を登録します。
+    ///
+    /// 処理が失敗してもコミットは取り消されず、エラーは `CommitOutcome::CommittedWithHookErrors` として返ります。
+    pub fn on_commit<Fut>(&mut self, callback: Fut)
+    where
+        Fut: Future<Output = Result<()>> + Send + 'static,
+    {
+        self.on_commit.push(Box::pin(callback));
+    }
+
+    /// ロールバックした後に実行する処理を登録します。コミットした場合は実行せずに破棄します。
+    pub fn on_rollback<Fut>(&mut self, callback: Fut)
+    where
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        self.on_rollback.push(Box::pin(callback));
+    }
+}
+
+impl Deref for TransactionContext {
+    type Target = TransactionGuard;
+
+    fn deref(&self) -> &Self::Target {
+        &self.guard
+    }
+}
+
+impl DerefMut for TransactionContext {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.guard
+    }
+}
+
+/// `with_transaction_context` でコミットしたトランザクションの結果です。
+#[derive(Debug)]
+#[must_use]
+pub enum CommitOutcome<T> {
+    /// コミットし、登録されたコールバックもすべて成功しました。
+    Committed(T),
+    /// コミットしましたが、失敗したコールバックがありました。
+    CommittedWithHookErrors {
+        value: T,
+        /// 失敗したコールバックのエラーです。登録した順に並びます。
+        errors: Vec<anyhow::Error>,
+    },
+}
+
+impl<T> CommitOutcome<T> {
+    /// クロージャが返した値を返します。コールバックの成否にかかわらずトランザクションはコミット済みです。
+    pub fn into_value(self) -> T {
+        match self {
+            Self::Committed(value) | Self::CommittedWithHookErrors { value, .. } => value,
+        }
+    }
+
+    /// 失敗したコールバックのエラーを返します。
+    pub fn hook_errors(&self) -> &[anyhow::Error] {
+        match self {
+            Self::Committed(_) => &[],
+            Self::CommittedWithHookErrors { errors, .. } => errors,
+        }
+    }
+}
+
+/// トランザクション内で実行したクエリの進捗です。
+#[derive(Debug, Default)]
+struct StatementProgress {
+    error_index: Option<usize>,
+    /// `Some` の場合は、成功したステートメントごとの所要時間を記録します。
+    statement_stats: Option<Vec<StatementStat>>,
+}
+
+/// `execute_in_chunks` が各チャンクのコミット後に通知する進捗です。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChunkProgress {
+    /// コミットしたチャンクの番号（0 始まり）です。
+    pub chunk_index: usize,
+    /// これまでにコミットしたステートメントの数です。
+    pub statements_done: usize,
+    /// ステートメントの総数です。クエリ列の長さが事前にわからない場合は `None` です。
+    pub total: Option<usize>,
+}
+
+/// `execute_queries_best_effort` の実行結果です。
+#[derive(Debug)]
+pub struct BatchOutcome {
+    /// ステートメントごとの結果です。成功した場合は影響を受けた行数、失敗した場合はそのエラーです。
+    pub results: Vec<Result<u64>>,
+}
+
+impl BatchOutcome {
+    /// すべてのステートメントが成功したかどうかを返します。
+    pub fn is_complete(&self) -> bool {
+        self.results.iter().all(Result::is_ok)
+    }
+
+    /// 成功したステートメントの数を返します。
+    pub fn succeeded(&self) -> usize {
+        self.results.iter().filter(|result| result.is_ok()).count()
+    }
+
+    /// 失敗したステートメントのインデックスとエラーを返します。
+    pub fn failures(&self) -> impl Iterator<Item = (usize, &anyhow::Error)> {
+        self.results
+            .iter()
+            .enumerate()
+            .filter_map(|(index, result)| result.as_ref().err().map(|error| (index, error)))
+    }
+}
+
+/// `execute_query_stream_with` の実行方法です。
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct QueryStreamOptions {
+    /// 実行するステートメント数の上限です。超えた場合はロールバックします。`None` の場合は制限しません。
+    pub max_statements: Option<usize>,
+    /// この数のステートメントを実行するたびに進捗をログに出力します。`None` の場合は出力しません。
+    pub progress_interval: Option<usize>,
+}
+
+/// `execute_query_stream` の実行結果です。
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct QueryStreamReport {
+    /// 実行したステートメントの数です。
+    pub statements: usize,
+    /// 影響を受けた行数の合計です。
+    pub rows_affected: u64,
+}
+
+/// コミットの所要時間と完了時刻です。
+struct CommitTiming {
+    duration: Duration,
+    committed_at: Instant,
+}
+
+/// 実行器ごとの同時実行トランザクション数の上限です。
+///
+/// 実行器を複製しても同じセマフォを共有するため、複製した実行器の合計で上限が適用されます。
+#[derive(Debug, Clone)]
+struct ConcurrencyLimit {
+    semaphore: Arc<Semaphore>,
+    max_in_flight: usize,
+    queue_timeout: Option<Duration>,
+}
+
+impl ConcurrencyLimit {
+    /// 実行枠を確保します。待機の上限時間を超えた場合は `DbError::ExecutorSaturated` を返します。
+    async fn acquire(&self) -> Result<OwnedSemaphorePermit> {
+        let acquire = Arc::clone(&self.semaphore).acquire_owned();
+        let permit = match self.queue_timeout {
+            Some(queue_timeout) => tokio::time::timeout(queue_timeout, acquire)
+                .await
+                .map_err(|_| DbError::ExecutorSaturated { queue_timeout })?,
+            None => acquire.await,
+        };
+        Ok(permit.expect("semaphore is never closed"))
+    }
+}
+
+#[derive(Clone)]
+pub struct TransactionExecutor {
+    pool: PgPool,
+    hooks: TransactionHooks,
+    observer: Option<Arc<dyn TransactionObserver>>,
+    slow_query_threshold: Option<Duration>,
+    hold_warn_threshold: Option<Duration>,
+    acquire_retry: AcquireRetry,
+    pgbouncer_mode: bool,
+    audit_log: Option<Arc<AuditLog>>,
+    error_detail: ErrorDetail,
+    limit: Option<ConcurrencyLimit>,
+}
+
+impl TransactionExecutor {
+    /// 指定した接続プールを使うトランザクション実行器を作成します。
+    pub fn new(pool: PgPool) -> Self {
+        Self {
+            pool,
+            hooks: TransactionHooks::default(),
+            observer: None,
+            slow_query_threshold: None,
+            hold_warn_threshold: None,
+            acquire_retry: AcquireRetry::default(),
+            pgbouncer_mode: false,
+            audit_log: None,
+            error_detail: ErrorDetail::default(),
+            limit: None,
+        }
+    }
+
+    /// 同時に実行するトランザクションを最大 `max_in_flight` 件に制限した実行器を作成します。
+    ///
+    /// 上限に達している間に開始しようとした呼び出しは、実行中のトランザクションが終わるまで待機します。
+    /// クエリ列・クロージャ API と `begin` のいずれにも適用され、実行枠はコミット・ロールバック・
+    /// 破棄（future の破棄やパニックを含む）のいずれでもガードとともに返却されます。
+    /// 同じプールを共有する他の処理が接続を取得できなくなることを防ぐための制限です。
+    /// `max_in_flight` が 0 の場合はエラーを返します。
+    pub fn with_limit(pool: PgPool, max_in_flight: usize) -> Result<Self> {
+        ensure!(max_in_flight > 0, "max_in_flight must be greater than 0");
+        let mut executor = Self::new(pool);
+        executor.limit = Some(ConcurrencyLimit {
+            semaphore: Arc::new(Semaphore::new(max_in_flight)),
+            max_in_flight,
+            queue_timeout: None,
+        });
+        Ok(executor)
+    }
+
+    /// 実行枠を待つ上限時間を指定します。
+    ///
+    /// 超えた場合は `DbError::ExecutorSaturated` を原因とするエラーを返します。`None` の場合は
+    /// 無期限に待機します。`with_limit` で作成していない実行器では効果がありません。
+    pub fn with_queue_timeout(mut self, queue_timeout: Option<Duration>) -> Self {
+        if let Some(limit) = &mut self.limit {
+            limit.queue_timeout = queue_timeout;
+        }
+        self
+    }
+
+    /// 実行中のトランザクション数を返します。同時実行数を制限していない場合は `None` です。
+    pub fn in_flight(&self) -> Option<usize> {
+        self.limit
+            .as_ref()
+            .map(|limit| limit.max_in_flight - limit.semaphore.available_permits())
+    }
+
+    /// 新たに開始できるトランザクション数を返します。同時実行数を制限していない場合は `None` です。
+    pub fn available_permits(&self) -> Option<usize> {
+        self.limit
+            .as_ref()
+            .map(|limit| limit.semaphore.available_permits())
+    }
+
+    /// 共有接続プールからトランザクション実行器を作成します。
+    ///
+    /// 遅いステートメントの閾値、保持時間の警告の閾値、接続取得の再試行の方針と PgBouncer 互換モードは
+    /// プールの設定（`PoolConfig::slow_query_threshold`・`PoolConfig::hold_warn_threshold`・
+    /// `PoolConfig::acquire_retry`・`PoolConfig::pgbouncer_mode`）を引き継ぎます。
+    /// 対話的な処理（`Workload::Interactive`）用のプールを使います。
+    pub fn from_shared_pool(connection_pool: &SharedConnectionPool) -> Self {
+        Self::for_workload(connection_pool, Workload::Interactive)
+    }
+
+    /// 共有接続プールから、`workload` の処理用のプールを使うトランザクション実行器を作成します。
+    ///
+    /// プールの接続数を処理の種類ごとに分割している場合（`PoolConfigBuilder::workload_split`）、
+    /// バッチ処理が接続を使い切っても対話的な処理は影響を受けません。その他は `from_shared_pool` と同じです。
+    pub fn for_workload(connection_pool: &SharedConnectionPool, workload: Workload) -> Self {
+        Self::new(connection_pool.for_workload(workload).clone())
+            .with_slow_query_threshold(connection_pool.slow_query_threshold())
+            .with_hold_warn_threshold(connection_pool.hold_warn_threshold())
+            .with_acquire_retry(connection_pool.acquire_retry())
+            .with_pgbouncer_mode(connection_pool.pgbouncer_mode())
+    }
+
+    /// トランザクションのライフサイクルフックを設定します。
+    pub fn with_hooks(mut self, hooks: TransactionHooks) -> Self {
+        self.hooks = hooks;
+        self
+    }
+
+    /// `execute_queries` の終了ごとに結果を通知するオブザーバーを登録します。
+    pub fn with_observer(mut self, observer: Arc<dyn TransactionObserver>) -> Self {
+        self.observer = Some(observer);
+        self
+    }
+
+    /// 遅いステートメントとトランザクションとして警告ログを出力するまでの時間を指定します。
+    ///
+    /// 個々のステートメントとトランザクション全体（開始からコミットまたはロールバックまで）の
+    /// それぞれを閾値と比較します。`None` または 0 の場合は警告しません。
+    pub fn with_slow_query_threshold(mut self, threshold: Option<Duration>) -> Self {
+        self.slow_query_threshold = threshold;
+        self
+    }
+
+    /// トランザクションが開いたまま `threshold` を超えた時点で警告ログを出力するよう指定します。
+    ///
+    /// 警告はトランザクションの実行中に、閾値を超えるたびに出力されます。ログにはプロセス内で単調増加する
+    /// トランザクション ID とラベル（ラベル付きの API の場合）を含め、警告したトランザクションが終了した時点で
+    /// 同じ ID の完了ログを出力します。`None` または 0 の場合は監視しません。
+    pub fn with_hold_warn_threshold(mut self, threshold: Option<Duration>) -> Self {
+        self.hold_warn_threshold = threshold;
+        self
+    }
+
+    /// トランザクション開始時の接続の取得がタイムアウトした場合の再試行の方針を指定します。
+    ///
+    /// 再試行するのは接続の取得待ちのタイムアウトのみで、トランザクション内のクエリは再実行しません。
+    /// `execute_queries_with_deadline` などの期限付き API では、再試行を含めた接続の取得も期限内に制限されます。
+    pub fn with_acquire_retry(mut self, acquire_retry: AcquireRetry) -> Self {
+        self.acquire_retry = acquire_retry;
+        self
+    }
+
+    /// PgBouncer のトランザクションプーリング向けに、`Query` と実行器が組み立てるクエリを名前のないステートメントで
+    /// 実行するかを指定します。
+    ///
+    /// 対象はクエリ列の API と `TransactionGuard` のメソッドで実行するクエリです。有効な場合、`Map` を受け取る
+    /// API はエラーを返します。クロージャの中でトランザクションに直接実行するクエリは対象外です。詳細は
+    /// `PoolConfigBuilder::pgbouncer_mode` を参照してください。
+    pub fn with_pgbouncer_mode(mut self, pgbouncer_mode: bool) -> Self {
+        self.pgbouncer_mode = pgbouncer_mode;
+        self
+    }
+
+    /// クエリ列の API でステートメントが失敗した場合に、エラーに含める詳細の範囲を指定します。
+    ///
+    /// 既定（`ErrorDetail::Minimal`）では SQL 文を含めず、ステートメントの位置・SQLSTATE・制約名と
+    /// プレースホルダの位置のみを含めます。バインドした値はどの設定でも含めません。
+    pub fn with_error_detail(mut self, error_detail: ErrorDetail) -> Self {
+        self.error_detail = error_detail;
+        self
+    }
+
+    /// コミットしたトランザクションを `audit_log` のテーブルに記録します。
+    ///
+    /// 記録はコミットの直前に同じトランザクション内で追加するため、処理の結果と原子的に確定します。
+    /// 記録に失敗した場合はトランザクション全体をロールバックします。`execute_queries_labeled` のラベルを
+    /// 記録に含めます。`begin` で取得したガードを呼び出し側がコミットした場合は記録しません。
+    pub fn with_audit_log(mut self, audit_log: AuditLog) -> Self {
+        self.audit_log = Some(Arc::new(audit_log));
+        self
+    }
+
+    /// `with_audit_log` で指定した監査ログのテーブルがなければ作成します。
+    pub async fn ensure_audit_table(&self) -> Result<()> {
+        let Some(audit_log) = &self.audit_log else {
+            bail!("Audit log is not enabled for this executor");
+        };
+        audit_log.ensure_table(&self.pool).await
+    }
+
+    /// トランザクションを開始し、呼び出し側が直接操作できるハンドルを返します。
+    ///
+    /// クロージャやクエリ列では表現しにくい処理のための手段です。
+    /// 返されたトランザクションにはフックが適用されません。
+    pub async fn begin(&self) -> Result<TransactionGuard> {
+        self.begin_guard(&TransactionOptions::default(), None).await
+    }
+
+    /// 単一クエリをトランザクション内で実行し、影響を受けた行数を返します。
+    ///
+    /// 成功時はコミットし、失敗時はロールバックします。
+    pub async fn execute_query<'a>(&self, query: Query<'a, Postgres, PgArguments>) -> Result<u64> {
+        let rows_affected = self.execute_queries(std::iter::once(query)).await?;
+        Ok(rows_affected.into_iter().sum())
+    }
+
+    /// 複数クエリを単一トランザクション内で実行し、クエリごとに影響を受けた行数を返します。
+    ///
+    /// いずれかのクエリまたは `before_commit` フックが失敗した場合は
+    /// トランザクションをロールバックし、エラーを返します。
+    /// `scope` の中で呼び出した場合は、スコープのトランザクションで実行し、コミットはスコープの終了時に行います。
+    /// `execute_query`・`fetch_one`・`fetch_all`・`execute_query_returning` も同様です。
+    pub async fn execute_queries<'a, I>(&self, queries: I) -> Result<Vec<u64>>
+    where
+        I: IntoIterator<Item = Query<'a, Postgres, PgArguments>>,
+    {
+        if let Some(ambient) = transaction_scope::current() {
+            let mut tx = ambient.lock().await;
+            let mut rows_affected = Vec::new();
+            for (index, query) in queries.into_iter().enumerate() {
+                let result = tx.execute_statement(index, query).await?;
+                rows_affected.push(result.rows_affected());
+            }
+            return Ok(rows_affected);
+        }
+        self.run_queries(&TransactionOptions::default(), queries)
+            .await
+    }
+
+    /// マッピング済みクエリを単一トランザクション内で実行し、最大 1 行を返します。
+    ///
+    /// クエリ結果が空の場合は `Ok(None)` を返します。フックは `execute_queries` と同様に適用されます。
+    pub async fn fetch_one<'a, U, F>(
+        &self,
+        query: Map<'a, Postgres, F, PgArguments>,
+    ) -> Result<Option<U>>
+    where
+        U: Send + Unpin,
+        F: FnMut(PgRow) -> std::result::Result<U, sqlx::Error> + Send + 'static,
+    {
+        if let Some(ambient) = transaction_scope::current() {
+            return ambient.lock().await.fetch_one(query).await;
+        }
+        let mut tx = self
+            .begin_guard(&TransactionOptions::default(), None)
+            .await?;
+        let result = tx.fetch_one(query).await;
+        self.finish(tx, result, StatementProgress::default()).await
+    }
+
+    /// マッピング済みクエリを単一トランザクション内で実行し、全行をベクタとして返します。
+    ///
+    /// フックは `execute_queries` と同様に適用されます。
+    pub async fn fetch_all<'a, U, F>(
+        &self,
+        query: Map<'a, Postgres, F, PgArguments>,
+    ) -> Result<Vec<U>>
+    where
+        U: Send + Unpin,
+        F: FnMut(PgRow) -> std::result::Result<U, sqlx::Error> + Send + 'static,
+    {
+        if let Some(ambient) = transaction_scope::current() {
+            return ambient.lock().await.fetch_all(query).await;
+        }
+        let mut tx = self
+            .begin_guard(&TransactionOptions::default(), None)
+            .await?;
+        let result = tx.fetch_all(query).await;
+        self.finish(tx, result, StatementProgress::default()).await
+    }
+
+    /// `RETURNING` 句を持つ書き込みをマッピング済みクエリとして単一トランザクション内で実行し、返された行を返します。
+    ///
+    /// 成功時はコミットし、失敗時はロールバックします。返された行がない場合は空のベクタを返します。
+    /// 返された値を同じトランザクションの後続のステートメントで使う場合は、クロージャ API の中で
+    /// `execute_returning` を使ってください。フックは `execute_queries` と同様に適用されます。
+    pub async fn execute_query_returning<'a, U, F>(
+        &self,
+        query: Map<'a, Postgres, F, PgArguments>,
+    ) -> Result<Vec<U>>
+    where
+        U: Send + Unpin,
+        F: FnMut(PgRow) -> std::result::Result<U, sqlx::Error> + Send + 'static,
+    {
+        if let Some(ambient) = transaction_scope::current() {
+            return ambient.lock().await.execute_returning(query).await;
+        }
+        let mut tx = self
+            .begin_guard(&TransactionOptions::default(), None)
+            .await?;
+        let result = tx.execute_returning(query).await;
+        self.finish(tx, result, StatementProgress::default()).await
+    }
+
+    /// クロージャの中で呼び出した `execute_query`・`execute_queries`・`fetch_one`・`fetch_all`・
+    /// `execute_query_returning` を 1 つのトランザクションにまとめて実行します。
+    ///
+    /// それぞれトランザクションを開始するリポジトリ関数をサービス層からまとめて原子的に実行する場合に使います。
+    /// クロージャが `Ok` を返した場合はコミットし、`Err` を返した場合はロールバックします。
+    /// スコープの中でさらに `scope` を呼び出した場合は外側のトランザクションに参加します
+    /// （`Propagation::Join`）。扱いを変える場合は `scope_with` を使ってください。
+    ///
+    /// スコープは `tokio::task_local!` で現在のタスクに結び付くため、`tokio::spawn` で起動したタスクには
+    /// 引き継がれず、そこでの呼び出しは従来どおり呼び出しごとのトランザクションで実行します。
+    /// スコープの中の呼び出しは、どの `TransactionExecutor` から行ってもスコープのトランザクションを使います。
+    ///
+    /// ```ignore
+    /// executor
+    ///     .scope(|| async {
+    ///         let order_id = orders::insert(&executor, &order).await?;
+    ///         inventory::reserve(&executor, order_id, &order.items).await?;
+    ///         Ok(order_id)
+    ///     })
+    ///     .await?;
+    /// ```
+    pub async fn scope<T, F, Fut>(&self, f: F) -> Result<T>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        self.scope_with(Propagation::Join, f).await
+    }
+
+    /// 外側のスコープがある場合の扱いを `propagation` で指定して `scope` を実行します。
+    pub async fn scope_with<T, F, Fut>(&self, propagation: Propagation, f: F) -> Result<T>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        match (propagation, transaction_scope::current()) {
+            (Propagation::Join, Some(_)) => f().await,
+            (Propagation::Nested, Some(ambient)) => {
+                let savepoint = savepoint_name("scope")?;
+                scope_savepoint_command(&ambient, "SAVEPOINT", &savepoint).await?;
+                match f().await {
+                    Ok(value) => {
+                        scope_savepoint_command(&ambient, "RELEASE SAVEPOINT", &savepoint).await?;
+                        Ok(value)
+                    }
+                    Err(error) => {
+                        // 呼び出し側が原因を判別できるよう、ロールバックの失敗は元のエラーの文脈として付けます。
+                        let rollback =
+                            scope_savepoint_command(&ambient, "ROLLBACK TO SAVEPOINT", &savepoint)
+                                .await;
+                        Err(match rollback {
+                            Ok(()) => error,
+                            Err(rollback_error) => error.context(format!("{rollback_error:#}")),
+                        })
+                    }
+                }
+            }
+            _ => {
+                let tx = self
+                    .begin_guard(&TransactionOptions::default(), None)
+                    .await?;
+                let span = tx.span.clone();
+                let ambient = Arc::new(tokio::sync::Mutex::new(tx));
+                let result =
+                    transaction_scope::run_in(Arc::clone(&ambient), async move { f().await })
+                        .instrument(span)
+                        .await;
+                // スコープの外に参照が残ることはないため、ここで唯一の所有者になります。
+                let tx = Arc::try_unwrap(ambient)
+                    .map_err(|_| {
+                        anyhow!("Scoped transaction is still in use after its scope ended")
+                    })?
+                    .into_inner();
+                self.finish(tx, result, StatementProgress::default()).await
+            }
+        }
+    }
+
+    /// `COPY ... FROM STDIN` で `rows` を `table` の `columns` に一括で書き込み、書き込んだ行数を返します。
+    ///
+    /// 行は COPY の text 形式にエンコードし、`COPY_BUFFER_SIZE` ごとにまとめて送信します。
+    /// 書き込みは単一トランザクション内で行うため、途中で失敗した場合やストリームの行の列数が
+    /// `columns` と一致しない場合は COPY を中断してロールバックし、テーブルには 1 行も残りません。
+    /// `table` は `schema.table` 形式で指定でき、テーブル名と列名は引用符で囲んで埋め込みます。
+    pub async fn copy_in<S>(&self, table: &str, columns: &[&str], rows: S) -> Result<u64>
+    where
+        S: Stream<Item = Vec<CopyValue>>,
+    {
+        ensure!(!columns.is_empty(), "copy_in requires at least one column");
+        let quoted_columns = columns
+            .iter()
+            .map(|column| quote_identifier(column))
+            .collect::<Result<Vec<_>>>()?;
+        let statement = format!(
+            "COPY {} ({}) FROM STDIN",
+            quote_qualified_identifier(table)?,
+            quoted_columns.join(", ")
+        );
+
+        let mut tx = self
+            .begin_guard(&TransactionOptions::default(), None)
+            .await?;
+        let result = copy_rows(&mut tx, &statement, columns.len(), rows).await;
+        self.finish(tx, result, StatementProgress::default()).await
+    }
+
+    /// SQL スクリプトをステートメントに分割し、単一トランザクション内で順に実行します。
+    ///
+    /// 分割の規則は `split_statements` を参照してください。いずれかのステートメントが失敗した場合は
+    /// トランザクション全体をロールバックし、失敗したステートメントのインデックスと開始行をエラーに含めます。
+    /// `VACUUM` や `CREATE INDEX CONCURRENTLY` などトランザクション内で実行できないステートメントは使えません。
+    /// 成功時はステートメントごとに影響を受けた行数を返します。
+    pub async fn execute_script(&self, script: &str) -> Result<Vec<u64>> {
+        let statements = split_statements(script);
+        let mut tx = self
+            .begin_guard(&TransactionOptions::default(), None)
+            .await?;
+        let mut progress = StatementProgress::default();
+        let queries = statements
+            .iter()
+            .map(|statement| sqlx::query(&statement.sql).persistent(false));
+        let result = execute_all(&mut tx, queries, &mut progress)
+            .await
+            .with_context(|| {
+                match progress
+                    .error_index
+                    .and_then(|index| Some((index, statements.get(index)?)))
+                {
+                    Some((index, statement)) => format!(
+                        "Failed to execute script statement {index} at line {}",
+                        statement.line
+                    ),
+                    None => "Failed to execute script".to_string(),
+                }
+            });
+        self.finish(tx, result, progress).await
+    }
+
+    /// ファイルから SQL スクリプトを読み込み、`execute_script` と同じく単一トランザクション内で実行します。
+    pub async fn execute_script_file(&self, path: impl AsRef<Path>) -> Result<Vec<u64>> {
+        let path = path.as_ref();
+        let script = tokio::fs::read_to_string(path)
+            .await
+            .with_context(|| format!("Failed to read SQL script {}", path.display()))?;
+        self.execute_script(&script)
+            .await
+            .with_context(|| format!("Failed to execute SQL script {}", path.display()))
+    }
+
+    /// 複数クエリを単一トランザクション内で実行し、成否にかかわらず必ずロールバックします。
+    ///
+    /// 本番環境で危険な保守用 SQL を実行する前に、影響を受ける行数を確認するために使います。
+    /// ステートメントが失敗した場合はそのエラーをレポートに記録し、以降のステートメントは実行しません。
+    /// `CREATE INDEX CONCURRENTLY` のようにトランザクション内で実行できないステートメントは、
+    /// PostgreSQL が返したエラーがそのままレポートに記録されます。
+    ///
+    /// 接続の取得やロールバックに失敗した場合のみエラーを返します。フックとオブザーバーは呼び出しません。
+    pub async fn execute_queries_dry_run<'a, I>(&self, queries: I) -> Result<DryRunReport>
+    where
+        I: IntoIterator<Item = Query<'a, Postgres, PgArguments>>,
+    {
+        self.run_dry(queries, |_| None).await
+    }
+
+    /// SQL スクリプトを `execute_script` と同じく分割して実行し、成否にかかわらず必ずロールバックします。
+    ///
+    /// 挙動は `execute_queries_dry_run` と同じです。失敗したステートメントのエラーには、スクリプト内の行番号を含めます。
+    pub async fn execute_script_dry_run(&self, script: &str) -> Result<DryRunReport> {
+        let statements = split_statements(script);
+        let queries = statements
+            .iter()
+            .map(|statement| sqlx::query(&statement.sql).persistent(false));
+        self.run_dry(queries, |index| {
+            let statement = statements.get(index)?;
+            Some(format!(
+                "Failed to execute script statement {index} at line {}",
+                statement.line
+            ))
+        })
+        .await
+    }
+
+    /// ファイルから SQL スクリプトを読み込み、`execute_script_dry_run` と同じく実行してロールバックします。
+    pub async fn execute_script_file_dry_run(
+        &self,
+        path: impl AsRef<Path>,
+    ) -> Result<DryRunReport> {
+        let path = path.as_ref();
+        let script = tokio::fs::read_to_string(path)
+            .await
+            .with_context(|| format!("Failed to read SQL script {}", path.display()))?;
+        self.execute_script_dry_run(&script)
+            .await
+            .with_context(|| format!("Failed to dry-run SQL script {}", path.display()))
+    }
+
+    /// クエリを実行してからロールバックし、ドライランのレポートを返します。
+    ///
+    /// `describe_failure` は失敗したステートメントのインデックスから、エラーに添えるコンテキストを返します。
+    async fn run_dry<'a, I, D>(&self, queries: I, describe_failure: D) -> Result<DryRunReport>
+    where
+        I: IntoIterator<Item = Query<'a, Postgres, PgArguments>>,
+        D: Fn(usize) -> Option<String>,
+    {
+        let mut tx = self
+            .begin_guard(&TransactionOptions::default(), Some("dry_run"))
+            .await?;
+        let started_at = tx.started_at;
+        let mut progress = StatementProgress {
+            statement_stats: Some(Vec::new()),
+            ..StatementProgress::default()
+        };
+        let result = execute_all(&mut tx, queries, &mut progress).await;
+        tx.rollback()
+            .await
+            .context("Failed to roll back dry run transaction")?;
+
+        let failure = match (result, progress.error_index) {
+            (Err(error), Some(index)) => Some(DryRunFailure {
+                index,
+                error: match describe_failure(index) {
+                    Some(description) => error.context(description),
+                    None => error,
+                },
+            }),
+            (Err(error), None) => return Err(error),
+            (Ok(_), _) => None,
+        };
+        Ok(DryRunReport {
+            statements: progress.statement_stats.unwrap_or_default(),
+            failure,
+            duration: started_at.elapsed(),
+        })
+    }
+
+    /// `QuerySpec` の列を単一トランザクション内で順に実行し、クエリごとに影響を受けた行数を返します。
+    ///
+    /// 失敗時の挙動は `execute_queries` と同じです。エラーには失敗したクエリのインデックスに加え、
+    /// バインドした値の位置と型（`$1 int8, $2 text` など）を含めるため、型の食い違いを特定できます。
+    pub async fn execute_specs(&self, specs: Vec<QuerySpec>) -> Result<Vec<u64>> {
+        let mut tx = self
+            .begin_guard(&TransactionOptions::default(), None)
+            .await?;
+        let mut progress = StatementProgress::default();
+        let result = execute_all(&mut tx, specs.iter().map(QuerySpec::query), &mut progress)
+            .await
+            .with_context(
+                || match progress.error_index.and_then(|index| specs.get(index)) {
+                    Some(spec) => format!("Query spec parameters: {}", spec.describe_parameters()),
+                    None => "Failed to execute query specs".to_string(),
+                },
+            );
+        self.finish(tx, result, progress).await
+    }
+
+    /// ストリームから受け取った `QuerySpec` を単一トランザクション内で順に実行し、ストリームの終了時にコミットします。
+    ///
+    /// ファイルの読み込みや API のページングなど、非同期に生成されるステートメントをベクタに集めずに実行するためのものです。
+    /// ストリームからは 1 件ずつ受け取り、実行が終わってから次を要求するため、保持するステートメントは常に 1 件です。
+    /// ストリームが `Err` を返した場合やステートメントが失敗した場合は全体をロールバックし、そのステートメントの
+    /// インデックスを含むエラーを返します。フックは `execute_queries` と同様に適用されます。
+    pub async fn execute_query_stream<S>(&self, stream: S) -> Result<QueryStreamReport>
+    where
+        S: Stream<Item = Result<QuerySpec>>,
+    {
+        self.execute_query_stream_with(&QueryStreamOptions::default(), stream)
+            .await
+    }
+
+    /// `options` に従って `execute_query_stream` と同じく実行します。
+    ///
+    /// `options.max_statements` を超えるステートメントを受け取った場合は、それ以上ストリームを読まずに
+    /// ロールバックします。`options.progress_interval` ごとに、実行したステートメント数と経過時間を
+    /// info レベルでログに出力します。
+    pub async fn execute_query_stream_with<S>(
+        &self,
+        options: &QueryStreamOptions,
+        stream: S,
+    ) -> Result<QueryStreamReport>
+    where
+        S: Stream<Item = Result<QuerySpec>>,
+    {
+        ensure!(
+            options
+                .progress_interval
+                .is_none_or(|interval| interval > 0),
+            "progress_interval must be greater than 0"
+        );
+        let mut tx = self
+            .begin_guard(&TransactionOptions::default(), None)
+            .await?;
+        let mut progress = StatementProgress::default();
+        let result = execute_stream(&mut tx, stream, options, &mut progress).await;
+        self.finish(tx, result, progress).await
+    }
+
+    /// SQL とバインド引数の組を単一トランザクション内で順に実行し、クエリごとに影響を受けた行数を返します。
+    ///
+    /// 各 SQL の先頭に `tag` をコメントとして付与してから実行します。`Query` は SQL を書き換えられないため、
+    /// タグを付ける場合は SQL の文字列と `PgArguments` を渡してください。失敗時の挙動は `execute_queries` と同じです。
+    pub async fn execute_queries_tagged<S, I>(
+        &self,
+        tag: &QueryTag,
+        statements: I,
+    ) -> Result<Vec<u64>>
+    where
+        S: AsRef<str>,
+        I: IntoIterator<Item = (S, PgArguments)>,
+    {
+        let (sqls, arguments): (Vec<String>, Vec<PgArguments>) = statements
+            .into_iter()
+            .map(|(sql, args)| (tag.apply(sql.as_ref()), args))
+            .unzip();
+        let queries = sqls
+            .iter()
+            .zip(arguments)
+            .map(|(sql, args)| sqlx::query_with(sql, args));
+        self.run_queries(&TransactionOptions::default(), queries)
+            .await
+    }
+
+    /// `execute_queries` と同じく複数クエリを単一トランザクション内で実行し、遅いステートメントや
+    /// トランザクションの警告ログに `label` を含めます。
+    ///
+    /// ログには SQL を出力しないため、バッチ名などで対象を識別できるようにするためのものです。
+    pub async fn execute_queries_labeled<'a, I>(&self, label: &str, queries: I) -> Result<Vec<u64>>
+    where
+        I: IntoIterator<Item = Query<'a, Postgres, PgArguments>>,
+    {
+        self.run_queries_labeled(&TransactionOptions::default(), Some(label), queries)
+            .await
+    }
+
+    /// `execute_queries` と同じく複数クエリを単一トランザクション内で実行し、ステートメントごとの
+    /// 所要時間と影響を受けた行数、コミットにかかった時間を `TransactionReport` として返します。
+    ///
+    /// 失敗した場合はロールバックし、それまでに成功したステートメントの内訳を `TransactionReport` として
+    /// エラーのコンテキストに添付します。失敗したクエリのインデックスはその下のコンテキストに含まれます。
+    pub async fn execute_queries_reporting<'a, I>(&self, queries: I) -> Result<TransactionReport>
+    where
+        I: IntoIterator<Item = Query<'a, Postgres, PgArguments>>,
+    {
+        let mut tx = self
+            .begin_guard(&TransactionOptions::default(), None)
+            .await?;
+        let started_at = tx.started_at;
+        let mut progress = StatementProgress {
+            statement_stats: Some(Vec::new()),
+            ..StatementProgress::default()
+        };
+        let result = execute_all(&mut tx, queries, &mut progress).await;
+        let statements = progress.statement_stats.take().unwrap_or_default();
+
+        match self.finish_timed(tx, result, progress).await {
+            Ok((_, commit)) => Ok(TransactionReport {
+                started_at,
+                committed_at: Some(commit.committed_at),
+                statements,
+                commit_duration: Some(commit.duration),
+            }),
+            Err(error) => Err(error.context(TransactionReport {
+                started_at,
+                committed_at: None,
+                statements,
+                commit_duration: None,
+            })),
+        }
+    }
+
+    /// 複数クエリを単一トランザクション内で実行し、一意制約違反を何もしなかった成功として扱います。
+    ///
+    /// クラッシュ後の再実行などで同じ挿入が重複適用される場合のための呼び出し単位のオプトインです。
+    /// いずれかのクエリが一意制約違反（SQLSTATE `23505`）で失敗した場合は、トランザクション全体を
+    /// ロールバックしたうえで `Ok(())` を返します。そのため、違反より前のクエリも反映されません。
+    /// それ以外のエラーは `execute_queries` と同じくロールバックしてエラーを返します。
+    ///
+    /// 制約違反を黙って握りつぶすことになるため、重複が既適用を意味する処理でのみ使用してください。
+    pub async fn execute_queries_idempotent<'a, I>(&self, queries: I) -> Result<()>
+    where
+        I: IntoIterator<Item = Query<'a, Postgres, PgArguments>>,
+    {
+        match self
+            .run_queries(&TransactionOptions::default(), queries)
+            .await
+        {
+            Ok(_) => Ok(()),
+            Err(error) if is_unique_violation(&error) => Ok(()),
+            Err(error) => Err(error),
+        }
+    }
+
+    /// 分離レベルを指定して複数クエリを単一トランザクション内で実行します。
+    ///
+    /// `begin()` 直後、いずれのクエリよりも先に `SET TRANSACTION ISOLATION LEVEL` を発行します。
+    /// 分離レベル以外の挙動は `execute_queries` と同じです。
+    pub async fn execute_queries_with_isolation<'a, I>(
+        &self,
+        level: IsolationLevel,
+        queries: I,
+    ) -> Result<Vec<u64>>
+    where
+        I: IntoIterator<Item = Query<'a, Postgres, PgArguments>>,
+    {
+        self.run_queries(&TransactionOptions::with_isolation(level), queries)
+            .await
+    }
+
+    /// トランザクションの特性を指定して複数クエリを単一トランザクション内で実行します。
+    ///
+    /// `options.read_only` が `true` の場合に書き込みを行うと、PostgreSQL のエラーが
+    /// 失敗したクエリのインデックス付きで返り、トランザクションはロールバックされます。
+    pub async fn execute_queries_with_options<'a, I>(
+        &self,
+        options: &TransactionOptions,
+        queries: I,
+    ) -> Result<Vec<u64>>
+    where
+        I: IntoIterator<Item = Query<'a, Postgres, PgArguments>>,
+    {
+        self.run_queries(options, queries).await
+    }
+
+    /// 複数クエリを単一トランザクション内で実行し、直列化失敗やデッドロックで失敗した場合は
+    /// `policy` に従ってトランザクション全体を再実行します。
+    ///
+    /// `Query` は一度しか実行できないため、試行ごとに `make_queries` を呼び出してクエリを作り直します。
+    /// 一意制約違反や構文エラーなど再試行できないエラーは直ちに返します。
+    pub async fn execute_queries_with_retry<'a, I, M>(
+        &self,
+        policy: &RetryPolicy,
+        options: &TransactionOptions,
+        mut make_queries: M,
+    ) -> Result<Vec<u64>>
+    where
+        I: IntoIterator<Item = Query<'a, Postgres, PgArguments>>,
+        M: FnMut() -> I,
+    {
+        policy
+            .run(|| self.run_queries(options, make_queries()))
+            .await
+    }
+
+    /// クロージャを単一トランザクション内で実行し、その戻り値を返します。
+    ///
+    /// クロージャが `Ok` を返した場合はコミットし、`Err` を返した場合はロールバックします。
+    /// クロージャ内でパニックした場合も、トランザクションは破棄時に SQLx によってロールバックされます。
+    /// トランザクション内で読み取った値に基づいて書き込むなど、途中で判断が必要な処理に使います。
+    ///
+    /// ```ignore
+    /// let balance = executor
+    ///     .with_transaction(|tx| {
+    ///         Box::pin(async move {
+    ///             let balance: i64 = sqlx::query_scalar("SELECT balance FROM accounts WHERE id = 1 FOR UPDATE")
+    ///                 .fetch_one(&mut **tx)
+    ///                 .await?;
+    ///             sqlx::query("UPDATE accounts SET balance = $1 WHERE id = 1")
+    ///                 .bind(balance - 100)
+    ///                 .execute(&mut **tx)
+    ///                 .await?;
+    ///             Ok(balance - 100)
+    ///         })
+    ///     })
+    ///     .await?;
+    /// ```
+    ///
+    /// フックは `execute_queries` と同様に適用されます。
+    /// オブザーバーにはクエリ数を把握できないため `0` が通知されます。
+    pub async fn with_transaction<T, F>(&self, f: F) -> Result<T>
+    where
+        F: for<'c> FnOnce(&'c mut Transaction<'static, Postgres>) -> BoxFuture<'c, Result<T>>,
+    {
+        self.with_transaction_options(&TransactionOptions::default(), f)
+            .await
+    }
+
+    /// 分離レベルを指定してクロージャを単一トランザクション内で実行します。
+    ///
+    /// 分離レベル以外の挙動は `with_transaction` と同じです。
+    pub async fn with_transaction_isolation<T, F>(&self, level: IsolationLevel, f: F) -> Result<T>
+    where
+        F: for<'c> FnOnce(&'c mut Transaction<'static, Postgres>) -> BoxFuture<'c, Result<T>>,
+    {
+        self.with_transaction_options(&TransactionOptions::with_isolation(level), f)
+            .await
+    }
+
+    /// トランザクションの特性を指定してクロージャを単一トランザクション内で実行します。
+    ///
+    /// 特性以外の挙動は `with_transaction` と同じです。
+    pub async fn with_transaction_options<T, F>(
+        &self,
+        options: &TransactionOptions,
+        f: F,
+    ) -> Result<T>
+    where
+        F: for<'c> FnOnce(&'c mut Transaction<'static, Postgres>) -> BoxFuture<'c, Result<T>>,
+    {
+        let mut tx = self.begin_guard(options, None).await?;
+        let span = tx.span.clone();
+        let result = f(&mut tx).instrument(span).await;
+        self.finish(tx, result, StatementProgress::default()).await
+    }
+
+    /// `TransactionContext` を渡してクロージャを単一トランザクション内で実行します。
+    ///
+    /// クロージャの中で `on_commit` に登録した処理は、コミットに成功した後に登録した順で実行します。
+    /// ロールバックした場合は `on_commit` の処理を破棄し、`on_rollback` の処理を実行してから
+    /// エラーを返します。`on_commit` の処理が失敗しても残りの処理は続けて実行し、失敗は警告ログに
+    /// 出力したうえで `CommitOutcome::CommittedWithHookErrors` として返します。
+    ///
+    /// フックとオブザーバーは `with_transaction` と同様に適用されます。
+    pub async fn with_transaction_context<T, F>(&self, f: F) -> Result<CommitOutcome<T>>
+    where
+        F: for<'c> FnOnce(&'c mut TransactionContext) -> BoxFuture<'c, Result<T>>,
+    {
+        let tx = self
+            .begin_guard(&TransactionOptions::default(), None)
+            .await?;
+        let span = tx.span.clone();
+        let mut context = TransactionContext::new(tx);
+        let result = f(&mut context).instrument(span).await;
+        let TransactionContext {
+            guard,
+            on_commit,
+            on_rollback,
+        } = context;
+
+        let value = match self
+            .finish(guard, result, StatementProgress::default())
+            .await
+        {
+            Ok(value) => value,
+            Err(error) => {
+                for callback in on_rollback {
+                    callback.await;
+                }
+                return Err(error);
+            }
+        };
+
+        let mut errors = Vec::new();
+        for (index, callback) in on_commit.into_iter().enumerate() {
+            if let Err(error) = callback.await {
+                let error = error.context(format!("on_commit callback at index {index} failed"));
+                tracing::warn!(
+                    error = %format!("{error:#}"),
+                    "Callback failed after the transaction was committed"
+                );
+                errors.push(error);
+            }
+        }
+        if errors.is_empty() {
+            Ok(CommitOutcome::Committed(value))
+        } else {
+            Ok(CommitOutcome::CommittedWithHookErrors { value, errors })
+        }
+    }
+
+    /// トランザクション単位のアドバイザリロックを取得してから、クロージャを単一トランザクション内で実行します。
+    ///
+    /// 他のトランザクションがロックを保持している場合は解放されるまで待機します。ロックは
+    /// `pg_advisory_xact_lock` で取得するため、コミットまたはロールバックの時点で自動的に解放されます。
+    /// 複数のインスタンスで同時に実行してはならないバッチ処理の排他に使います。
+    pub async fn with_advisory_lock<T, F>(&self, key: impl Into<AdvisoryLockKey>, f: F) -> Result<T>
+    where
+        F: for<'c> FnOnce(&'c mut Transaction<'static, Postgres>) -> BoxFuture<'c, Result<T>>,
+    {
+        let key = key.into();
+        let mut tx = self
+            .begin_guard(&TransactionOptions::default(), None)
+            .await?;
+        let span = tx.span.clone();
+        let result = async {
+            advisory_lock::lock(&mut tx, key, self.pgbouncer_mode).await?;
+            f(&mut tx).await
+        }
+        .instrument(span)
+        .await;
+        self.finish(tx, result, StatementProgress::default()).await
+    }
+
+    /// アドバイザリロックを待たずに取得しようとし、取得できた場合のみクロージャを実行します。
+    ///
+    /// 他のトランザクションがロックを保持していた場合は、クロージャを実行せずにトランザクションを
+    /// ロールバックして `LockAttempt::LockNotAcquired` を返します。この場合はフックとオブザーバーを
+    /// 呼び出しません。
+    pub async fn try_with_advisory_lock<T, F>(
+        &self,
+        key: impl Into<AdvisoryLockKey>,
+        f: F,
+    ) -> Result<LockAttempt<T>>
+    where
+        F: for<'c> FnOnce(&'c mut Transaction<'static, Postgres>) -> BoxFuture<'c, Result<T>>,
+    {
+        let key = key.into();
+        let mut tx = self
+            .begin_guard(&TransactionOptions::default(), None)
+            .await?;
+        let span = tx.span.clone();
+        match advisory_lock::try_lock(&mut tx, key, self.pgbouncer_mode)
+            .instrument(span.clone())
+            .await
+        {
+            Ok(true) => {}
+            Ok(false) => {
+                tx.rollback().await?;
+                return Ok(LockAttempt::LockNotAcquired);
+            }
+            Err(error) => {
+                return self
+                    .finish(tx, Err(error), StatementProgress::default())
+                    .await;
+            }
+        }
+        let result = f(&mut tx).instrument(span).await;
+        self.finish(tx, result, StatementProgress::default())
+            .await
+            .map(LockAttempt::Acquired)
+    }
+
+    /// アドバイザリロックを取得してから、複数クエリを単一トランザクション内で実行します。
+    ///
+    /// ロックの扱いは `with_advisory_lock` と、クエリの実行は `execute_queries` と同じです。
+    pub async fn execute_queries_with_advisory_lock<'a, I>(
+        &self,
+        key: impl Into<AdvisoryLockKey>,
+        queries: I,
+    ) -> Result<Vec<u64>>
+    where
+        I: IntoIterator<Item = Query<'a, Postgres, PgArguments>>,
+    {
+        let key = key.into();
+        let mut tx = self
+            .begin_guard(&TransactionOptions::default(), None)
+            .await?;
+        let span = tx.span.clone();
+        let mut progress = StatementProgress::default();
+        let result = match advisory_lock::lock(&mut tx, key, self.pgbouncer_mode)
+            .instrument(span)
+            .await
+        {
+            Ok(()) => execute_all(&mut tx, queries, &mut progress).await,
+            Err(error) => Err(error),
+        };
+        self.finish(tx, result, progress).await
+    }
+
+    /// アドバイザリロックを待たずに取得しようとし、取得できた場合のみ複数クエリを実行します。
+    ///
+    /// ロックの扱いは `try_with_advisory_lock` と、クエリの実行は `execute_queries` と同じです。
+    pub async fn try_execute_queries_with_advisory_lock<'a, I>(
+        &self,
+        key: impl Into<AdvisoryLockKey>,
+        queries: I,
+    ) -> Result<LockAttempt<Vec<u64>>>
+    where
+        I: IntoIterator<Item = Query<'a, Postgres, PgArguments>>,
+    {
+        let key = key.into();
+        let mut tx = self
+            .begin_guard(&TransactionOptions::default(), None)
+            .await?;
+        let span = tx.span.clone();
+        match advisory_lock::try_lock(&mut tx, key, self.pgbouncer_mode)
+            .instrument(span)
+            .await
+        {
+            Ok(true) => {}
+            Ok(false) => {
+                tx.rollback().await?;
+                return Ok(LockAttempt::LockNotAcquired);
+            }
+            Err(error) => {
+                return self
+                    .finish(tx, Err(error), StatementProgress::default())
+                    .await;
+            }
+        }
+        let mut progress = StatementProgress::default();
+        let result = execute_all(&mut tx, queries, &mut progress).await;
+        self.finish(tx, result, progress)
+            .await
+            .map(LockAttempt::Acquired)
+    }
+
+    async fn run_queries<'a, I>(&self, options: &TransactionOptions, queries: I) -> Result<Vec<u64>>
+    where
+        I: IntoIterator<Item = Query<'a, Postgres, PgArguments>>,
+    {
+        self.run_queries_labeled(options, None, queries).await
+    }
+
+    pub(super) async fn run_queries_labeled<'a, I>(
+        &self,
+        options: &TransactionOptions,
+        label: Option<&str>,
+        queries: I,
+    ) -> Result<Vec<u64>>
+    where
+        I: IntoIterator<Item = Query<'a, Postgres, PgArguments>>,
+    {
+        let mut tx = self.begin_guard(options, label).await?;
+        let mut progress = StatementProgress::default();
+        let result = execute_all(&mut tx, queries, &mut progress).await;
+        self.finish(tx, result, progress).await
+    }
+
+    /// クロージャを単一トランザクション内で実行し、直列化失敗やデッドロックで失敗した場合は
+    /// `policy` に従って新しいトランザクションでクロージャを再実行します。
+    ///
+    /// クロージャは複数回呼ばれる可能性があるため、トランザクション外への副作用を持たせないでください。
+    pub async fn with_transaction_retry<T, F>(
+        &self,
+        policy: &RetryPolicy,
+        options: &TransactionOptions,
+        f: F,
+    ) -> Result<T>
+    where
+        F: for<'c> Fn(&'c mut Transaction<'static, Postgres>) -> BoxFuture<'c, Result<T>>,
+    {
+        policy
+            .run(|| self.with_transaction_options(options, &f))
+            .await
+    }
+
+    /// 複数クエリを単一トランザクション内で実行し、`cancel` が完了した時点で中断します。
+    ///
+    /// 各ステートメントを開始する前に `cancel` を確認し、完了していればそれ以降のステートメントを
+    /// 実行せずにロールバックして、原因に `DbError::Cancelled` を含むエラーを返します。
+    /// 実行中のステートメントは完了まで待ちます。すべてのステートメントの実行後は `cancel` を確認しないため、
+    /// コミットが始まった後の取り消し要求は無視され、コミットされた場合は必ず成功として返ります。
+    pub async fn execute_queries_with_cancel<'a, I, C>(
+        &self,
+        cancel: C,
+        queries: I,
+    ) -> Result<Vec<u64>>
+    where
+        I: IntoIterator<Item = Query<'a, Postgres, PgArguments>>,
+        C: Future<Output = ()>,
+    {
+        let mut cancel = pin!(cancel);
+        let mut tx = self
+            .begin_guard(&TransactionOptions::default(), None)
+            .await?;
+        let mut progress = StatementProgress::default();
+        let result = execute_all_until(&mut tx, queries, &mut progress, || {
+            cancel.as_mut().now_or_never().is_some()
+        })
+        .await;
+        self.finish(tx, result, progress).await
+    }
+
+    /// クロージャを単一トランザクション内で実行し、`cancel` が先に完了した場合は中断します。
+    ///
+    /// クロージャの実行中に `cancel` が完了すると、クロージャの future を破棄してロールバックし、
+    /// 原因に `DbError::Cancelled` を含むエラーを返します。クロージャが完了した後は `cancel` を確認しないため、
+    /// コミットが始まった後の取り消し要求は無視されます。
+    pub async fn with_transaction_cancellable<T, F, C>(
+        &self,
+        cancel: C,
+        options: &TransactionOptions,
+        f: F,
+    ) -> Result<T>
+    where
+        F: for<'c> FnOnce(&'c mut Transaction<'static, Postgres>) -> BoxFuture<'c, Result<T>>,
+        C: Future<Output = ()>,
+    {
+        let mut tx = self.begin_guard(options, None).await?;
+        let span = tx.span.clone();
+        let result = tokio::select! {
+            biased;
+            () = cancel => Err(DbError::Cancelled).context("Transaction rolled back"),
+            result = f(&mut tx).instrument(span) => result,
+        };
+        self.finish(tx, result, StatementProgress::default()).await
+    }
+
+    /// 複数クエリを単一トランザクション内で実行し、接続の取得からコミットまでを `deadline` 以内に制限します。
+    ///
+    /// 期限を超えた場合は実行中のステートメントを `pg_cancel_backend` で取り消してから明示的に
+    /// ロールバックし、原因に `DbError::DeadlineExceeded` を含むエラーを返します。エラーには
+    /// 期限を超えた時点の処理（開始・何番目のステートメントか・コミット）が含まれます。
+    /// コミット中に期限を超えた場合は、コミットされたかどうかを判別できない点に注意してください。
+    pub async fn execute_queries_with_deadline<'a, I>(
+        &self,
+        deadline: Duration,
+        queries: I,
+    ) -> Result<Vec<u64>>
+    where
+        I: IntoIterator<Item = Query<'a, Postgres, PgArguments>>,
+    {
+        let expires_at = tokio::time::Instant::now() + deadline;
+        let (mut tx, backend_pid) = self
+            .begin_with_deadline(&TransactionOptions::default(), deadline, expires_at)
+            .await?;
+        let mut progress = StatementProgress::default();
+        let result =
+            tokio::time::timeout_at(expires_at, execute_all(&mut tx, queries, &mut progress)).await;
+
+        match result {
+            Ok(result) => {
+                self.finish_with_deadline(tx, result, progress, deadline, expires_at)
+                    .await
+            }
+            Err(_) => {
+                let index = tx.statement_count;
+                self.abort_after_deadline(tx, backend_pid, Some(index))
+                    .await;
+                Err(DbError::DeadlineExceeded {
+                    deadline,
+                    phase: DeadlinePhase::Statement { index },
+                })
+                .context("Transaction rolled back")
+            }
+        }
+    }
+
+    /// クロージャを単一トランザクション内で実行し、接続の取得からコミットまでを `deadline` 以内に制限します。
+    ///
+    /// 期限を超えた場合の挙動は `execute_queries_with_deadline` と同じです。
+    pub async fn with_transaction_deadline<T, F>(
+        &self,
+        deadline: Duration,
+        options: &TransactionOptions,
+        f: F,
+    ) -> Result<T>
+    where
+        F: for<'c> FnOnce(&'c mut Transaction<'static, Postgres>) -> BoxFuture<'c, Result<T>>,
+    {
+        let expires_at = tokio::time::Instant::now() + deadline;
+        let (mut tx, backend_pid) = self
+            .begin_with_deadline(options, deadline, expires_at)
+            .await?;
+        let span = tx.span.clone();
+        let result = tokio::time::timeout_at(expires_at, f(&mut tx).instrument(span)).await;
+
+        match result {
+            Ok(result) => {
+                self.finish_with_deadline(
+                    tx,
+                    result,
+                    StatementProgress::default(),
+                    deadline,
+                    expires_at,
+                )
+                .await
+            }
+            Err(_) => {
+                self.abort_after_deadline(tx, backend_pid, None).await;
+                Err(DbError::DeadlineExceeded {
+                    deadline,
+                    phase: DeadlinePhase::Body,
+                })
+                .context("Transaction rolled back")
+            }
+        }
+    }
+
+    /// 期限内にトランザクションを開始し、取り消しに使うバックエンドの PID を取得します。
+    async fn begin_with_deadline(
+        &self,
+        options: &TransactionOptions,
+        deadline: Duration,
+        expires_at: tokio::time::Instant,
+    ) -> Result<(TransactionGuard, i32)> {
+        let begin = async {
+            let mut tx = self.begin_guard(options, None).await?;
+            let backend_pid: i32 = sqlx::query_scalar("SELECT pg_backend_pid()")
+                .unprepared_if(tx.pgbouncer_mode)
+                .fetch_one(&mut **tx)
+                .await
+                .map_err(DbError::from)
+                .context("Failed to read backend pid")?;
+            Ok::<_, anyhow::Error>((tx, backend_pid))
+        };
+        match tokio::time::timeout_at(expires_at, begin).await {
+            Ok(result) => result,
+            Err(_) => Err(DbError::DeadlineExceeded {
+                deadline,
+                phase: DeadlinePhase::Begin,
+            })
+            .context("Failed to start database transaction"),
+        }
+    }
+
+    /// 本体が期限内に終わったトランザクションを終了します。
+    ///
+    /// 本体が成功した場合のコミットは残りの期限内に制限し、失敗した場合は通常どおりロールバックします。
+    async fn finish_with_deadline<T>(
+        &self,
+        tx: TransactionGuard,
+        result: Result<T>,
+        progress: StatementProgress,
+        deadline: Duration,
+        expires_at: tokio::time::Instant,
+    ) -> Result<T> {
+        if result.is_err() {
+            return self.finish(tx, result, progress).await;
+        }
+        match tokio::time::timeout_at(expires_at, self.finish(tx, result, progress)).await {
+            Ok(result) => result,
+            Err(_) => Err(DbError::DeadlineExceeded {
+                deadline,
+                phase: DeadlinePhase::Commit,
+            })
+            .context("Transaction outcome is unknown"),
+        }
+    }
+
+    /// 期限を超えたトランザクションの実行中ステートメントを取り消し、ロールバックします。
+    ///
+    /// 取り消しとロールバックはそれぞれ `DEADLINE_ABORT_GRACE` 以内に制限し、
+    /// ロールバックできなかった場合は警告ログを出力して接続を破棄します。
+    async fn abort_after_deadline(
+        &self,
+        tx: TransactionGuard,
+        backend_pid: i32,
+        error_index: Option<usize>,
+    ) {
+        let started_at = tx.started_at;
+        metrics::record_rollback("deadline_exceeded");
+        let cancel = sqlx::query("SELECT pg_cancel_backend($1)")
+            .bind(backend_pid)
+            .unprepared_if(self.pgbouncer_mode)
+            .execute(&self.pool);
+        if !matches!(
+            tokio::time::timeout(DEADLINE_ABORT_GRACE, cancel).await,
+            Ok(Ok(_))
+        ) {
+            tracing::warn!(
+                backend_pid,
+                "Failed to cancel statement after transaction deadline"
+            );
+        }
+        if !matches!(
+            tokio::time::timeout(DEADLINE_ABORT_GRACE, tx.rollback()).await,
+            Ok(Ok(()))
+        ) {
+            tracing::warn!(
+                backend_pid,
+                "Failed to roll back transaction after deadline; discarding connection"
+            );
+        }
+        self.finish_rollback(started_at, error_index).await;
+    }
+
+    /// トランザクション本体の結果に応じてコミットまたはロールバックし、フックとオブザーバーを実行します。
+    ///
+    /// `execute_queries` とクロージャ API はいずれもこの処理でトランザクションを終了します。
+    async fn finish<T>(
+        &self,
+        tx: TransactionGuard,
+        result: Result<T>,
+        progress: StatementProgress,
+    ) -> Result<T> {
+        let (value, _) = self.finish_timed(tx, result, progress).await?;
+        Ok(value)
+    }
+
+    /// `finish` と同じくトランザクションを終了し、コミットにかかった時間もあわせて返します。
+    async fn finish_timed<T>(
+        &self,
+        mut tx: TransactionGuard,
+        result: Result<T>,
+        progress: StatementProgress,
+    ) -> Result<(T, CommitTiming)> {
+        let result = match result {
+            Ok(value) => self.hooks.run_before_commit(&mut tx).await.map(|()| value),
+            Err(error) => Err(error),
+        };
+        let audit_entry = self.audit_log.as_ref().map(|_| AuditEntry {
+            transaction_id: Uuid::new_v4(),
+            label: tx.label.clone(),
+            statement_count: tx.statement_count,
+            rows_affected: tx.rows_affected,
+            started_at: tx.begun_at,
+        });
+        let result = match (result, &self.audit_log, &audit_entry) {
+            (Ok(value), Some(audit_log), Some(entry)) => {
+                let persistent = !tx.pgbouncer_mode;
+                audit_log
+                    .record_commit(&mut tx, entry, persistent)
+                    .await
+                    .map(|()| value)
+            }
+            (result, _, _) => result,
+        };
+
+        let started_at = tx.started_at;
+        let statement_count = tx.statement_count;
+        let pgbouncer_mode = tx.pgbouncer_mode;
+        let slow_query = std::mem::take(&mut tx.slow_query);
+        let value = match result {
+            Ok(value) => value,
+            Err(error) => {
+                record_error(&tx.span, format_args!("{error:#}"));
+                metrics::record_rollback_error(&error);
+                let rollback = tx.rollback().await;
+                slow_query.transaction(started_at.elapsed(), statement_count);
+                self.audit_rollback(audit_entry.as_ref(), &error, pgbouncer_mode)
+                    .await;
+                self.finish_rollback(started_at, progress.error_index).await;
+                // 元のエラーを残し、ロールバックの失敗はその文脈として付けます。
+                return Err(match rollback {
+                    Ok(()) => error,
+                    Err(rollback_error) => error.context(format!("{rollback_error:#}")),
+                });
+            }
+        };
+
+        let commit_started_at = Instant::now();
+        let commit = tx.commit().await;
+        slow_query.transaction(started_at.elapsed(), statement_count);
+        if let Err(error) = commit {
+            metrics::record_rollback_error(&error);
+            self.audit_rollback(audit_entry.as_ref(), &error, pgbouncer_mode)
+                .await;
+            self.finish_rollback(started_at, None).await;
+            return Err(error);
+        }
+        let committed_at = Instant::now();
+        if let Some(observer) = &self.observer {
+            observer.on_commit(started_at.elapsed(), statement_count);
+        }
+        self.hooks.run_after_commit().await;
+        Ok((
+            value,
+            CommitTiming {
+                duration: committed_at.duration_since(commit_started_at),
+                committed_at,
+            },
+        ))
+    }
+
+    /// この実行器が使う接続プールを返します。
+    pub(super) fn pool(&self) -> &PgPool {
+        &self.pool
+    }
+
+    /// 同時実行数を制限している場合は実行枠を確保して返します。制限していない場合は `None` です。
+    pub(super) async fn acquire_slot(&self) -> Result<Option<OwnedSemaphorePermit>> {
+        match &self.limit {
+            Some(limit) => limit.acquire().await.map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// トランザクションを開始し、遅いステートメントの閾値とスパンを設定したガードを返します。
+    ///
+    /// 同時実行数を制限している場合は、接続を取得する前に実行枠を確保します。
+    async fn begin_guard(
+        &self,
+        options: &TransactionOptions,
+        label: Option<&str>,
+    ) -> Result<TransactionGuard> {
+        let span = transaction_span(options, label);
+        let permit = match self.acquire_slot().instrument(span.clone()).await {
+            Ok(permit) => permit,
+            Err(error) => {
+                record_error(&span, format_args!("{error:#}"));
+                return Err(error).context("Failed to begin transaction");
+            }
+        };
+        let tx = match begin_with_options(&self.pool, options, &self.acquire_retry)
+            .instrument(span.clone())
+            .await
+        {
+            Ok(tx) => tx,
+            Err(error) => {
+                record_error(&span, format_args!("{error:#}"));
+                return Err(error);
+            }
+        };
+        let slow_query = SlowQueryLog::new(self.slow_query_threshold).with_label(label);
+        let watchdog = HoldWatchdog::start(self.hold_warn_threshold, label);
+        let mut guard = TransactionGuard::new(
+            tx,
+            slow_query,
+            span,
+            permit,
+            watchdog,
+            self.pgbouncer_mode,
+            label,
+        );
+        guard.error_detail = self.error_detail;
+        Ok(guard)
+    }
+
+    /// 監査ログでロールバックも記録する設定の場合に、ロールバックしたトランザクションを記録します。
+    async fn audit_rollback(
+        &self,
+        entry: Option<&AuditEntry>,
+        error: &anyhow::Error,
+        pgbouncer_mode: bool,
+    ) {
+        if let (Some(audit_log), Some(entry)) = (&self.audit_log, entry)
+            && audit_log.records_rollbacks()
+        {
+            audit_log
+                .record_rollback(&self.pool, entry, error, !pgbouncer_mode)
+                .await;
+        }
+    }
+
+    /// ロールバック後のオブザーバー通知と `after_rollback` フックを実行します。
+    async fn finish_rollback(&self, started_at: Instant, error_index: Option<usize>) {
+        if let Some(observer) = &self.observer {
+            observer.on_rollback(started_at.elapsed(), error_index);
+        }
+        self.hooks.run_after_rollback().await;
+    }
+
+    /// クエリを `chunk_size` 件ずつのチャンクに分け、チャンクごとに別トランザクションで順に実行します。
+    ///
+    /// 各チャンクはコミットしてから次のチャンクへ進むため、トランザクションの大きさとロック保持時間を
+    /// 抑えられる一方で、全体としての原子性は失われます。チャンク N が失敗した場合は
+    /// チャンク N のみがロールバックされ、それ以前にコミットされたチャンクは残ります。
+    /// エラーには失敗したチャンク番号と、そのチャンクが担当したクエリのインデックス範囲が含まれます。
+    ///
+    /// フックはチャンクごとのトランザクションに対して適用されます。
+    /// 成功時はコミットしたチャンク数を返します。
+    pub async fn execute_chunked<'a, I>(&self, queries: I, chunk_size: usize) -> Result<usize>
+    where
+        I: IntoIterator<Item = Query<'a, Postgres, PgArguments>>,
+    {
+        self.execute_in_chunks(queries, chunk_size, |_| async { Ok(()) })
+            .await
+    }
+
+    /// `execute_chunked` と同じくチャンクごとに別トランザクションで実行し、各チャンクのコミット後に
+    /// `progress` を呼び出します。
+    ///
+    /// `progress` にはコミットしたチャンクの番号と、それまでに完了したステートメント数を渡すため、
+    /// 呼び出し側はチェックポイントの記録や進捗の報告に使えます。`progress` がエラーを返した場合は
+    /// 以降のチャンクを実行せずにエラーを返します（そのチャンクはコミット済みです）。
+    /// 失敗したステートメントのエラーには、クエリ列全体での通し番号とチャンク番号が含まれるため、
+    /// その位置から再開できます。成功時はコミットしたチャンク数を返します。
+    pub async fn execute_in_chunks<'a, I, P, Fut>(
+        &self,
+        queries: I,
+        chunk_size: usize,
+        mut progress: P,
+    ) -> Result<usize>
+    where
+        I: IntoIterator<Item = Query<'a, Postgres, PgArguments>>,
+        P: FnMut(ChunkProgress) -> Fut,
+        Fut: Future<Output = Result<()>>,
+    {
+        ensure!(chunk_size > 0, "chunk_size must be greater than 0");
+
+        let queries = queries.into_iter();
+        let total = match queries.size_hint() {
+            (lower, Some(upper)) if lower == upper => Some(lower),
+            _ => None,
+        };
+        let mut queries = queries.peekable();
+        let mut committed_chunks = 0;
+        let mut statements_done = 0;
+        while queries.peek().is_some() {
+            let chunk: Vec<_> = queries.by_ref().take(chunk_size).collect();
+            let chunk_index = committed_chunks;
+            let start = statements_done;
+            let end = start + chunk.len();
+
+            let mut tx = self
+                .begin_guard(&TransactionOptions::default(), None)
+                .await
+                .with_context(|| format!("Failed to start chunk {chunk_index}"))?;
+            let mut statement_progress = StatementProgress::default();
+            let result = execute_all(&mut tx, chunk, &mut statement_progress).await;
+            let failed_at = statement_progress.error_index.map(|index| start + index);
+            self.finish(tx, result, statement_progress)
+                .await
+                .with_context(|| match failed_at {
+                    Some(index) => format!(
+                        "Failed at statement {index} in chunk {chunk_index} \
+                         (queries {start}..{end}); {chunk_index} earlier chunks remain committed"
+                    ),
+                    None => format!(
+                        "Failed to execute chunk {chunk_index} (queries {start}..{end}); \
+                         {chunk_index} earlier chunks remain committed"
+                    ),
+                })?;
+            committed_chunks += 1;
+            statements_done = end;
+
+            progress(ChunkProgress {
+                chunk_index,
+                statements_done,
+                total,
+            })
+            .await
+            .with_context(|| {
+                format!("Progress callback failed after committing chunk {chunk_index}")
+            })?;
+        }
+
+        Ok(committed_chunks)
+    }
+
+    /// サーバー側カーソルを使い、結果を `chunk_size` 行ずつ `on_chunk` に渡します。
+    ///
+    /// 単一トランザクション内で `DECLARE ... CURSOR` を実行し、`FETCH FORWARD` で行がなくなるまで
+    /// 読み進めます。次のチャンクは `on_chunk` の完了後に取得するため、呼び出し側の処理速度に合わせて
+    /// メモリ使用量を一定に保てます。すべてのチャンクを処理するとカーソルを閉じてコミットし、
+    /// 渡した行数の合計を返します。`on_chunk` がエラーを返した場合はトランザクションをロールバックします。
+    pub async fn fetch_in_chunks<U, F, Fut>(
+        &self,
+        sql: &str,
+        args: PgArguments,
+        chunk_size: usize,
+        on_chunk: F,
+    ) -> Result<usize>
+    where
+        U: for<'r> FromRow<'r, PgRow> + Send + Unpin,
+        F: FnMut(Vec<U>) -> Fut,
+        Fut: Future<Output = Result<()>>,
+    {
+        ensure!(chunk_size > 0, "chunk_size must be greater than 0");
+
+        let mut tx = self
+            .begin_guard(&TransactionOptions::default(), None)
+            .await?;
+        let result = fetch_cursor_chunks(&mut tx, sql, args, chunk_size, on_chunk).await;
+        self.finish(tx, result, StatementProgress::default()).await
+    }
+
+    /// 複数クエリを単一トランザクション内で実行し、失敗したクエリだけを取り消して残りをコミットします。
+    ///
+    /// 各クエリをセーブポイントで囲み、失敗した場合はそのセーブポイントまでロールバックして
+    /// エラーを記録し、次のクエリへ進みます。すべてのクエリを試した後、成功したクエリの変更をコミットします。
+    /// 個々のクエリの失敗は `BatchOutcome` に記録されるため、戻り値が `Err` になるのは
+    /// トランザクションの開始・セーブポイント操作・`before_commit` フック・コミットのいずれかが失敗した場合だけです。
+    ///
+    /// 全件成功か全件取り消しかの挙動が必要な場合は `execute_queries` を使用してください。
+    pub async fn execute_queries_best_effort<'a, I>(&self, queries: I) -> Result<BatchOutcome>
+    where
+        I: IntoIterator<Item = Query<'a, Postgres, PgArguments>>,
+    {
+        let mut tx = self
+            .begin_guard(&TransactionOptions::default(), None)
+            .await?;
+        let result = execute_best_effort(&mut tx, queries).await;
+        self.finish(tx, result, StatementProgress::default()).await
+    }
+}
+
+/// 開始済みトランザクション上でクエリを順に実行し、クエリごとに影響を受けた行数を返します。
+///
+/// 実行状況は `progress` に記録します。
+async fn execute_all<'a, I>(
+    tx: &mut TransactionGuard,
+    queries: I,
+    progress: &mut StatementProgress,
+) -> Result<Vec<u64>>
+where
+    I: IntoIterator<Item = Query<'a, Postgres, PgArguments>>,
+{
+    execute_all_until(tx, queries, progress, || false).await
+}
+
+/// `execute_all` と同じくクエリを順に実行しますが、各クエリの前に `is_cancelled` を確認し、
+/// `true` を返した時点で中断して `DbError::Cancelled` を返します。
+async fn execute_all_until<'a, I, P>(
+    tx: &mut TransactionGuard,
+    queries: I,
+    progress: &mut StatementProgress,
+    mut is_cancelled: P,
+) -> Result<Vec<u64>>
+where
+    I: IntoIterator<Item = Query<'a, Postgres, PgArguments>>,
+    P: FnMut() -> bool,
+{
+    let mut rows_affected = Vec::new();
+    for (index, query) in queries.into_iter().enumerate() {
+        if is_cancelled() {
+            progress.error_index = Some(index);
+            return Err(DbError::Cancelled)
+                .with_context(|| format!("Transaction cancelled before query at index {index}"));
+        }
+        let statement_started_at = Instant::now();
+        match tx.execute_statement(index, query).await {
+            Ok(result) => {
+                if let Some(stats) = &mut progress.statement_stats {
+                    stats.push(StatementStat {
+                        index,
+                        duration: statement_started_at.elapsed(),
+                        rows_affected: result.rows_affected(),
+                    });
+                }
+                rows_affected.push(result.rows_affected());
+            }
+            Err(error) => {
+                progress.error_index = Some(index);
+                return Err(error);
+            }
+        }
+    }
+    Ok(rows_affected)
+}
+
+/// ストリームから受け取った `QuerySpec` を 1 件ずつ順に実行します。
+async fn execute_stream<S>(
+    tx: &mut TransactionGuard,
+    stream: S,
+    options: &QueryStreamOptions,
+    progress: &mut StatementProgress,
+) -> Result<QueryStreamReport>
+where
+    S: Stream<Item = Result<QuerySpec>>,
+{
+    let mut stream = pin!(stream);
+    let mut report = QueryStreamReport::default();
+    while let Some(item) = stream.next().await {
+        let index = report.statements;
+        if let Some(max_statements) = options.max_statements.filter(|max| index >= *max) {
+            progress.error_index = Some(index);
+            bail!("Query stream exceeded max_statements ({max_statements}) at index {index}");
+        }
+        let spec = item.map_err(|error| {
+            progress.error_index = Some(index);
+            error.context(format!(
+                "Query stream failed to produce statement at index {index}"
+            ))
+        })?;
+        let result = tx
+            .execute_statement(index, spec.query())
+            .await
+            .map_err(|error| {
+                progress.error_index = Some(index);
+                error.context(format!(
+                    "Query spec parameters: {}",
+                    spec.describe_parameters()
+                ))
+            })?;
+        report.statements += 1;
+        report.rows_affected += result.rows_affected();
+        if options
+            .progress_interval
+            .is_some_and(|interval| report.statements % interval == 0)
+        {
+            tracing::info!(
+                statements = report.statements,
+                rows_affected = report.rows_affected,
+                elapsed = ?tx.elapsed(),
+                "Query stream progress"
+            );
+        }
+    }
+    Ok(report)
+}
+
+/// カーソルを宣言し、`chunk_size` 行ずつ取得して `on_chunk` に渡した後にカーソルを閉じます。
+async fn fetch_cursor_chunks<U, F, Fut>(
+    tx: &mut TransactionGuard,
+    sql: &str,
+    args: PgArguments,
+    chunk_size: usize,
+    mut on_chunk: F,
+) -> Result<usize>
+where
+    U: for<'r> FromRow<'r, PgRow> + Send + Unpin,
+    F: FnMut(Vec<U>) -> Fut,
+    Fut: Future<Output = Result<()>>,
+{
+    // カーソル名は呼び出しごとに異なるため、いずれの文も名前付きのステートメントとして残しません。
+    let cursor = cursor_name();
+    sqlx::query_with(
+        &format!("DECLARE {cursor} NO SCROLL CURSOR FOR {sql}"),
+        args,
+    )
+    .persistent(false)
+    .execute(&mut ***tx)
+    .await
+    .map_err(DbError::from)
+    .context("Failed to declare cursor")?;
+    tx.statement_count += 1;
+
+    let fetch_sql = format!("FETCH FORWARD {chunk_size} FROM {cursor}");
+    let mut delivered = 0;
+    let mut chunk_index = 0;
+    loop {
+        let rows = sqlx::query(&fetch_sql)
+            .persistent(false)
+            .try_map(|row: PgRow| U::from_row(&row))
+            .fetch_all(&mut ***tx)
+            .await
+            .map_err(DbError::from)
+            .with_context(|| format!("Failed to fetch chunk {chunk_index} from cursor"))?;
+        tx.statement_count += 1;
+        if rows.is_empty() {
+            break;
+        }
+
+        let len = rows.len();
+        on_chunk(rows)
+            .await
+            .with_context(|| format!("Failed to process chunk {chunk_index}"))?;
+        delivered += len;
+        chunk_index += 1;
+        if len < chunk_size {
+            break;
+        }
+    }
+
+    sqlx::query(&format!("CLOSE {cursor}"))
+        .persistent(false)
+        .execute(&mut ***tx)
+        .await
+        .map_err(DbError::from)
+        .context("Failed to close cursor")?;
+    tx.statement_count += 1;
+    Ok(delivered)
+}
+
+/// COPY を開始して `rows` を送信し、サーバーが報告した行数を返します。失敗した場合は COPY を中断します。
+async fn copy_rows<S>(
+    tx: &mut TransactionGuard,
+    statement: &str,
+    column_count: usize,
+    rows: S,
+) -> Result<u64>
+where
+    S: Stream<Item = Vec<CopyValue>>,
+{
+    let mut copy = tx
+        .copy_in_raw(statement)
+        .await
+        .map_err(DbError::from)
+        .context("Failed to start COPY")?;
+    let mut rows = pin!(rows);
+    let mut buffer = String::new();
+    let mut row_index = 0;
+    let sent = async {
+        while let Some(row) = rows.next().await {
+            ensure!(
+                row.len() == column_count,
+                "Row {row_index} has {} values, expected {column_count}",
+                row.len()
+            );
+            encode_row(&row, &mut buffer);
+            row_index += 1;
+            if buffer.len() >= COPY_BUFFER_SIZE {
+                copy.send(buffer.as_bytes())
+                    .await
+                    .map_err(DbError::from)
+                    .with_context(|| format!("Failed to send COPY data before row {row_index}"))?;
+                buffer.clear();
+            }
+        }
+        if !buffer.is_empty() {
+            copy.send(buffer.as_bytes())
+                .await
+                .map_err(DbError::from)
+                .context("Failed to send COPY data")?;
+        }
+        Ok(())
+    }
+    .await;
+
+    if let Err(error) = sent {
+        // 中断の結果はサーバーが返す取り消しエラーになるため、元のエラーを優先して返します。
+        let _ = copy.abort(format!("{error:#}")).await;
+        return Err(error);
+    }
+    let written = copy
+        .finish()
+        .await
+        .map_err(DbError::from)
+        .context("Failed to finish COPY")?;
+    tx.statement_count += 1;
+    Ok(written)
+}
+
+/// 各クエリをセーブポイントで囲んで順に実行し、クエリごとの結果を返します。
+///
+/// クエリの失敗は結果に記録して続行し、セーブポイント操作の失敗はエラーとして返します。
+async fn execute_best_effort<'a, I>(tx: &mut TransactionGuard, queries: I) -> Result<BatchOutcome>
+where
+    I: IntoIterator<Item = Query<'a, Postgres, PgArguments>>,
+{
+    let savepoint = savepoint_name(BEST_EFFORT_SAVEPOINT_PREFIX)?;
+    let mut results = Vec::new();
+    for (index, query) in queries.into_iter().enumerate() {
+        run_savepoint_command(tx, "SAVEPOINT", &savepoint)
+            .await
+            .with_context(|| format!("Failed to create savepoint before query at index {index}"))?;
+
+        match tx.execute_statement(index, query).await {
+            Ok(result) => results.push(Ok(result.rows_affected())),
+            Err(error) => {
+                run_savepoint_command(tx, "ROLLBACK TO SAVEPOINT", &savepoint)
+                    .await
+                    .with_context(|| {
+                        format!("Failed to rollback to savepoint after query at index {index}")
+                    })?;
+                results.push(Err(error));
+            }
+        }
+
+        run_savepoint_command(tx, "RELEASE SAVEPOINT", &savepoint)
+            .await
+            .with_context(|| format!("Failed to release savepoint after query at index {index}"))?;
+    }
+    Ok(BatchOutcome { results })
+}
+
+/// エラーの原因が一意制約違反（SQLSTATE `23505`）かどうかを判定します。
+fn is_unique_violation(error: &anyhow::Error) -> bool {
+    matches!(DbError::find(error), Some(DbError::UniqueViolation { .. }))
+}
+
+/// コミットのエラーを分類し、文脈を付けます。
+///
+/// `COMMIT` で制約違反が返るのは遅延した制約（`DEFERRABLE INITIALLY DEFERRED` または
+/// `SET CONSTRAINTS ... DEFERRED`）の検査に失敗した場合のみのため、その旨を明示します。
+fn commit_error(error: sqlx::Error) -> anyhow::Error {
+    let error = DbError::from(error);
+    let context = match &error {
+        DbError::UniqueViolation { .. }
+        | DbError::ForeignKeyViolation { .. }
+        | DbError::CheckViolation { .. } => format!(
+            "Failed to commit transaction: deferred constraint{} was violated at COMMIT",
+            error
+                .constraint()
+                .map(|constraint| format!(" {constraint}"))
+                .unwrap_or_default()
+        ),
+        _ => "Failed to commit transaction".to_string(),
+    };
+    anyhow::Error::from(error).context(context)
+}
+
+/// マッピング済みクエリを開始済みトランザクション上で実行し、最大 1 行を返します。
+///
+/// 同じトランザクション内で先に行った未コミットの書き込みも読み取れるため、
+/// `SELECT ... FOR UPDATE` で読んだ値をもとに更新するような処理に使います。
+/// クエリ結果が空の場合は `Ok(None)` を返します。
+pub async fn fetch_one<'a, U, F>(
+    tx: &mut Transaction<'_, Postgres>,
+    query: Map<'a, Postgres, F, PgArguments>,
+) -> Result<Option<U>>
+where
+    U: Send + Unpin,
+    F: FnMut(PgRow) -> std::result::Result<U, sqlx::Error> + Send + 'static,
+{
+    let row = query
+        .fetch_optional(&mut **tx)
+        .await
+        .map_err(DbError::from)
+        .context("Failed to fetch optional row in transaction")?;
+    Ok(row)
+}
+
+/// マッピング済みクエリを開始済みトランザクション上で実行し、全行をベクタとして返します。
+pub async fn fetch_all<'a, U, F>(
+    tx: &mut Transaction<'_, Postgres>,
+    query: Map<'a, Postgres, F, PgArguments>,
+) -> Result<Vec<U>>
+where
+    U: Send + Unpin,
+    F: FnMut(PgRow) -> std::result::Result<U, sqlx::Error> + Send + 'static,
+{
+    let rows = query
+        .fetch_all(&mut **tx)
+        .await
+        .map_err(DbError::from)
+        .context("Failed to fetch rows in transaction")?;
+    Ok(rows)
+}
+
+/// `RETURNING` 句を持つ書き込みをマッピング済みクエリとして開始済みトランザクション上で実行し、返された行を返します。
+///
+/// `with_transaction` などのクロージャ内で、挿入した行の ID を受け取って後続のステートメントに渡す場合に使います。
+/// 返された行がない場合は空のベクタを返します。
+///
+/// ```ignore
+/// executor
+///     .with_transaction(|tx| {
+///         Box::pin(async move {
+///             let ids = execute_returning(
+///                 tx,
+///                 sqlx::query("INSERT INTO orders (customer_id) VALUES ($1) RETURNING id")
+///                     .bind(customer_id)
+///                     .map(|row: PgRow| row.get::<i64, _>("id")),
+///             )
+///             .await?;
+///             let order_id = ids.into_iter().next().context("INSERT returned no id")?;
+///             sqlx::query("INSERT INTO order_items (order_id, sku) VALUES ($1, $2)")
+///                 .bind(order_id)
+///                 .bind(sku)
+///                 .execute(&mut **tx)
+///                 .await?;
+///             Ok(order_id)
+///         })
+///     })
+///     .await?;
+/// ```
+pub async fn execute_returning<'a, U, F>(
+    tx: &mut Transaction<'_, Postgres>,
+    query: Map<'a, Postgres, F, PgArguments>,
+) -> Result<Vec<U>>
+where
+    U: Send + Unpin,
+    F: FnMut(PgRow) -> std::result::Result<U, sqlx::Error> + Send + 'static,
+{
+    let rows = query
+        .fetch_all(&mut **tx)
+        .await
+        .map_err(DbError::from)
+        .context("Failed to execute returning query in transaction")?;
+    Ok(rows)
+}
+
+/// 開始済みトランザクション内にセーブポイントを作成し、クロージャをその範囲で実行します。
+///
+/// クロージャが `Ok` を返した場合は `RELEASE SAVEPOINT` で変更を外側のトランザクションに残し、
+/// `Err` を返した場合は `ROLLBACK TO SAVEPOINT` でクロージャ内の変更だけを取り消してからエラーを返します。
+/// 外側のトランザクションは継続するため、呼び出し側はエラーを処理したうえでコミットできます。
+///
+/// `name` はセーブポイント名の接頭辞で、英字またはアンダースコアで始まる英数字とアンダースコアのみを
+/// 受け付けます。実際の名前にはプロセス内で一意な連番が付与されるため、同じ接頭辞で入れ子にしても衝突しません。
+pub async fn with_savepoint<T, F>(
+    tx: &mut Transaction<'static, Postgres>,
+    name: &str,
+    f: F,
+) -> Result<T>
+where
+    F: for<'c> FnOnce(&'c mut Transaction<'static, Postgres>) -> BoxFuture<'c, Result<T>>,
+{
+    let savepoint = savepoint_name(name)?;
+
+    run_savepoint_command(tx, "SAVEPOINT", &savepoint)
+        .await
+        .with_context(|| format!("Failed to create savepoint {savepoint}"))?;
+
+    match f(tx).await {
+        Ok(value) => {
+            run_savepoint_command(tx, "RELEASE SAVEPOINT", &savepoint)
+                .await
+                .with_context(|| format!("Failed to release savepoint {savepoint}"))?;
+            Ok(value)
+        }
+        Err(error) => {
+            run_savepoint_command(tx, "ROLLBACK TO SAVEPOINT", &savepoint)
+                .await
+                .with_context(|| format!("Failed to rollback to savepoint {savepoint}"))?;
+            Err(error)
+        }
+    }
+}
+
+/// セーブポイントを操作するコマンドを実行します。
+///
+/// セーブポイント名は呼び出しごとに異なるため、名前付きのステートメントとして残さないよう単純クエリで実行します。
+async fn run_savepoint_command(
+    tx: &mut Transaction<'static, Postgres>,
+    command: &str,
+    savepoint: &str,
+) -> Result<()> {
+    tx.execute(sqlx::raw_sql(&format!("{command} {savepoint}")))
+        .await
+        .map_err(DbError::from)?;
+    Ok(())
+}
+
+/// `Propagation::Nested` のスコープのセーブポイントを作成・解放・ロールバックします。
+async fn scope_savepoint_command(
+    ambient: &AmbientTransaction,
+    command: &str,
+    savepoint: &str,
+) -> Result<()> {
+    let mut tx = ambient.lock().await;
+    run_savepoint_command(&mut tx, command, savepoint)
+        .await
+        .with_context(|| format!("Failed to run {command} {savepoint}"))
+}
+
+/// `fetch_in_chunks` 用に、連番を付与したプロセス内で一意なカーソル名を返します。
+fn cursor_name() -> String {
+    let sequence = CURSOR_SEQUENCE.fetch_add(1, Ordering::Relaxed);
+    format!("{CHUNK_CURSOR_PREFIX}_{sequence}")
+}
+
+/// 接頭辞を検証し、連番を付与した引用符付きのセーブポイント名を返します。
+fn savepoint_name(prefix: &str) -> Result<String> {
+    let mut chars = prefix.chars();
+    let valid_start = chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_');
+    ensure!(
+        valid_start && chars.all(|c| c.is_ascii_alphanumeric() || c == '_'),
+        "Savepoint name must match [A-Za-z_][A-Za-z0-9_]*, got {prefix:?}"
+    );
+    ensure!(
+        prefix.len() <= MAX_SAVEPOINT_PREFIX_LEN,
+        "Savepoint name must be at most {MAX_SAVEPOINT_PREFIX_LEN} characters"
+    );
+
+    let sequence = SAVEPOINT_SEQUENCE.fetch_add(1, Ordering::Relaxed);
+    quote_identifier(&format!("{prefix}_{sequence}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::{
+        query_executor::QueryExecutor, testing, transaction_options::DeferredConstraints,
+    };
+    use anyhow::bail;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[tokio::test]
+    #[ignore = "requires a PostgreSQL database (TEST_DATABASE_URL)"]
+    async fn before_commit_writes_are_committed_with_the_transaction() {
+        let pool = testing::pool().await;
+        let table = testing::unique_table("before_commit");
+        sqlx::query(&format!("CREATE TABLE {table} (id int)"))
+            .execute(&pool)
+            .await
+            .unwrap();
+        let hook_sql = format!("INSERT INTO {table} VALUES (2)");
+        let hooks = TransactionHooks::new().before_commit(move |tx| {
+            let hook_sql = hook_sql.clone();
+            Box::pin(async move {
+                sqlx::query(&hook_sql).execute(&mut **tx).await?;
+                Ok(())
+            })
+        });
+
+        TransactionExecutor::new(pool.clone())
+            .with_hooks(hooks)
+            .execute_query(sqlx::query(&format!("INSERT INTO {table} VALUES (1)")))
+            .await
+            .unwrap();
+
+        let ids: Vec<i32> = sqlx::query_scalar(&format!("SELECT id FROM {table} ORDER BY id"))
+            .fetch_all(&pool)
+            .await
+            .unwrap();
+        sqlx::query(&format!("DROP TABLE {table}"))
+            .execute(&pool)
+            .await
+            .unwrap();
+        assert_eq!(ids, [1, 2]);
+    }
+
+    #[tokio::test]
+    #[ignore = "requires a PostgreSQL database (TEST_DATABASE_URL)"]
+    async fn after_hooks_fire_only_for_the_matching_outcome() {
+        let events = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let (on_commit, on_rollback) = (Arc::clone(&events), Arc::clone(&events));
+        let hooks = TransactionHooks::new()
+            .after_commit(move || {
+                let events = Arc::clone(&on_commit);
+                async move { events.lock().unwrap().push("commit") }
+            })
+            .after_rollback(move || {
+                let events = Arc::clone(&on_rollback);
+                async move { events.lock().unwrap().push("rollback") }
+            });
+        let executor = TransactionExecutor::new(testing::pool().await).with_hooks(hooks);
+
+        executor
+            .execute_query(sqlx::query("SELECT 1"))
+            .await
+            .unwrap();
+        assert_eq!(*events.lock().unwrap(), ["commit"]);
+
+        executor
+            .execute_query(sqlx::query("SELECT 1 / 0"))
+            .await
+            .unwrap_err();
+        assert_eq!(*events.lock().unwrap(), ["commit", "rollback"]);
+    }
+
+    #[tokio::test]
+    #[ignore = "requires a PostgreSQL database (TEST_DATABASE_URL)"]
+    async fn reads_inside_the_transaction_see_its_uncommitted_writes() {
+        let pool = testing::pool().await;
+        let table = testing::unique_table("read_own_writes");
+        sqlx::query(&format!("CREATE TABLE {table} (id int)"))
+            .execute(&pool)
+            .await
+            .unwrap();
+        let select = format!("SELECT id FROM {table} ORDER BY id");
+
+        let (inside, outside) = TransactionExecutor::new(pool.clone())
+            .with_transaction(|tx| {
+                let (pool, table, select) = (pool.clone(), table.clone(), select.clone());
+                Box::pin(async move {
+                    sqlx::query(&format!("INSERT INTO {table} VALUES (1), (2)"))
+                        .execute(&mut **tx)
+                        .await?;
+                    let read_id = |row: PgRow| sqlx::Row::try_get::<i32, _>(&row, 0);
+                    let inside = fetch_all(tx, sqlx::query(&select).try_map(read_id)).await?;
+                    let first = fetch_one(tx, sqlx::query(&select).try_map(read_id)).await?;
+                    let outside: Vec<i32> = sqlx::query_scalar(&select).fetch_all(&pool).await?;
+                    assert_eq!(first, Some(1));
+                    Ok((inside, outside))
+                })
+            })
+            .await
+            .unwrap();
+
+        sqlx::query(&format!("DROP TABLE {table}"))
+            .execute(&pool)
+            .await
+            .unwrap();
+        assert_eq!(inside, [1, 2]);
+        assert!(outside.is_empty());
+    }
+
+    #[tokio::test]
+    #[ignore = "requires a PostgreSQL database (TEST_DATABASE_URL)"]
+    async fn fetch_in_chunks_delivers_every_row_exactly_once() {
+        let executor = TransactionExecutor::new(testing::pool().await);
+        let mut chunks: Vec<Vec<i32>> = Vec::new();
+
+        let delivered = executor
+            .fetch_in_chunks(
+                "SELECT n FROM generate_series(1, 25) AS n ORDER BY n",
+                PgArguments::default(),
+                10,
+                |rows: Vec<(i32,)>| {
+                    chunks.push(rows.into_iter().map(|(n,)| n).collect());
+                    async { Ok(()) }
+                },
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(delivered, 25);
+        assert_eq!(chunks.iter().map(Vec::len).collect::<Vec<_>>(), [10, 10, 5]);
+        assert_eq!(chunks.concat(), (1..=25).collect::<Vec<_>>());
+    }
+
+    #[tokio::test]
+    #[ignore = "requires a PostgreSQL database (TEST_DATABASE_URL)"]
+    async fn fetch_in_chunks_rolls_back_when_a_chunk_fails() {
+        let observer = Arc::new(RecordingObserver::default());
+        let executor = TransactionExecutor::new(testing::pool().await)
+            .with_observer(Arc::clone(&observer) as Arc<dyn TransactionObserver>);
+        let mut calls = 0;
+
+        let error = executor
+            .fetch_in_chunks(
+                "SELECT n FROM generate_series(1, 25) AS n",
+                PgArguments::default(),
+                10,
+                |_: Vec<(i32,)>| {
+                    calls += 1;
+                    let fail = calls == 2;
+                    async move {
+                        ensure!(!fail, "chunk rejected");
+                        Ok(())
+                    }
+                },
+            )
+            .await
+            .unwrap_err();
+
+        assert_eq!(calls, 2);
+        assert!(
+            format!("{error:#}").contains("Failed to process chunk 1: chunk rejected"),
+            "{error:#}"
+        );
+        assert!(observer.commits.lock().unwrap().is_empty());
+        assert_eq!(observer.rollbacks.lock().unwrap().len(), 1);
+    }
+
+    /// `polls` 回目にポーリングされた時点で完了する取り消し要求を返します。
+    fn cancel_on_poll(polls: usize, counter: Arc<AtomicUsize>) -> impl Future<Output = ()> {
+        std::future::poll_fn(move |_| {
+            if counter.fetch_add(1, Ordering::SeqCst) + 1 >= polls {
+                std::task::Poll::Ready(())
+            } else {
+                std::task::Poll::Pending
+            }
+        })
+    }
+
+    #[tokio::test]
+    #[ignore = "requires a PostgreSQL database (TEST_DATABASE_URL)"]
+    async fn execute_queries_with_cancel_between_statements_commits_nothing() {
+        let pool = testing::pool().await;
+        let table = testing::unique_table("cancel_between");
+        sqlx::query(&format!("CREATE TABLE {table} (id int)"))
+            .execute(&pool)
+            .await
+            .unwrap();
+        let inserts: Vec<String> = (1..=3)
+            .map(|id| format!("INSERT INTO {table} VALUES ({id})"))
+            .collect();
+        let polls = Arc::new(AtomicUsize::new(0));
+
+        // 1 番目のステートメントの前は未完了、2 番目の前で完了します。
+        let error = TransactionExecutor::new(pool.clone())
+            .execute_queries_with_cancel(
+                cancel_on_poll(2, Arc::clone(&polls)),
+                inserts.iter().map(|sql| sqlx::query(sql)),
+            )
+            .await
+            .unwrap_err();
+
+        let count: i64 = sqlx::query_scalar(&format!("SELECT count(*) FROM {table}"))
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        sqlx::query(&format!("DROP TABLE {table}"))
+            .execute(&pool)
+            .await
+            .unwrap();
+        assert!(matches!(
+            error.root_cause().downcast_ref::<DbError>(),
+            Some(DbError::Cancelled)
+        ));
+        assert!(
+            format!("{error:#}").contains("Transaction cancelled before query at index 1"),
+            "{error:#}"
+        );
+        assert_eq!(polls.load(Ordering::SeqCst), 2);
+        assert_eq!(count, 0);
+    }
+
+    #[tokio::test]
+    #[ignore = "requires a PostgreSQL database (TEST_DATABASE_URL)"]
+    async fn execute_queries_with_cancel_ignores_a_cancel_after_the_last_statement() {
+        let pool = testing::pool().await;
+        let table = testing::unique_table("cancel_after");
+        sqlx::query(&format!("CREATE TABLE {table} (id int)"))
+            .execute(&pool)
+            .await
+            .unwrap();
+        let inserts: Vec<String> = (1..=3)
+            .map(|id| format!("INSERT INTO {table} VALUES ({id})"))
+            .collect();
+        let polls = Arc::new(AtomicUsize::new(0));
+
+        // すべてのステートメントの実行後に初めて完了する取り消し要求はコミットを妨げません。
+        let rows_affected = TransactionExecutor::new(pool.clone())
+            .execute_queries_with_cancel(
+                cancel_on_poll(4, Arc::clone(&polls)),
+                inserts.iter().map(|sql| sqlx::query(sql)),
+            )
+            .await
+            .unwrap();
+
+        let count: i64 = sqlx::query_scalar(&format!("SELECT count(*) FROM {table}"))
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        sqlx::query(&format!("DROP TABLE {table}"))
+            .execute(&pool)
+            .await
+            .unwrap();
+        assert_eq!(rows_affected, vec![1, 1, 1]);
+        assert_eq!(polls.load(Ordering::SeqCst), 3);
+        assert_eq!(count, 3);
+    }
+
+    #[tokio::test]
+    #[ignore = "requires a PostgreSQL database (TEST_DATABASE_URL)"]
+    async fn with_transaction_cancellable_rolls_back_a_closure_cancelled_mid_way() {
+        let pool = testing::pool().await;
+        let table = testing::unique_table("cancel_closure");
+        sqlx::query(&format!("CREATE TABLE {table} (id int)"))
+            .execute(&pool)
+            .await
+            .unwrap();
+        let (cancel_sender, cancel_receiver) = tokio::sync::oneshot::channel::<()>();
+
+        let error = TransactionExecutor::new(pool.clone())
+            .with_transaction_cancellable(
+                async {
+                    cancel_receiver.await.ok();
+                },
+                &TransactionOptions::default(),
+                |tx| {
+                    let table = table.clone();
+                    Box::pin(async move {
+                        sqlx::query(&format!("INSERT INTO {table} VALUES (1)"))
+                            .execute(&mut **tx)
+                            .await?;
+                        cancel_sender.send(()).ok();
+                        // 取り消しが次のポーリングで必ず観測されるよう、一度制御を返します。
+                        tokio::task::yield_now().await;
+                        sqlx::query(&format!("INSERT INTO {table} VALUES (2)"))
+                            .execute(&mut **tx)
+                            .await?;
+                        Ok(())
+                    })
+                },
+            )
+            .await
+            .unwrap_err();
+
+        let count: i64 = sqlx::query_scalar(&format!("SELECT count(*) FROM {table}"))
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        sqlx::query(&format!("DROP TABLE {table}"))
+            .execute(&pool)
+            .await
+            .unwrap();
+        assert!(matches!(
+            error.root_cause().downcast_ref::<DbError>(),
+            Some(DbError::Cancelled)
+        ));
+        assert_eq!(count, 0);
+    }
+
+    #[tokio::test]
+    #[ignore = "requires a PostgreSQL database (TEST_DATABASE_URL)"]
+    async fn execute_in_chunks_commits_each_chunk_and_reports_progress() {
+        let pool = testing::pool().await;
+        let table = testing::unique_table("in_chunks");
+        sqlx::query(&format!("CREATE TABLE {table} (id int)"))
+            .execute(&pool)
+            .await
+            .unwrap();
+        let observer = Arc::new(RecordingObserver::default());
+        let executor = TransactionExecutor::new(pool.clone())
+            .with_observer(Arc::clone(&observer) as Arc<dyn TransactionObserver>);
+        let inserts: Vec<String> = (1..=25)
+            .map(|id| format!("INSERT INTO {table} VALUES ({id})"))
+            .collect();
+        let mut reported = Vec::new();
+
+        let committed_chunks = executor
+            .execute_in_chunks(inserts.iter().map(|sql| sqlx::query(sql)), 10, |progress| {
+                reported.push(progress);
+                async { Ok(()) }
+            })
+            .await
+            .unwrap();
+
+        let count: i64 = sqlx::query_scalar(&format!("SELECT count(*) FROM {table}"))
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        sqlx::query(&format!("DROP TABLE {table}"))
+            .execute(&pool)
+            .await
+            .unwrap();
+        assert_eq!(committed_chunks, 3);
+        assert_eq!(*observer.commits.lock().unwrap(), vec![10, 10, 5]);
+        let progress = |chunk_index, statements_done| ChunkProgress {
+            chunk_index,
+            statements_done,
+            total: Some(25),
+        };
+        assert_eq!(
+            reported,
+            vec![progress(0, 10), progress(1, 20), progress(2, 25)]
+        );
+        assert_eq!(count, 25);
+    }
+
+    #[tokio::test]
+    #[ignore = "requires a PostgreSQL database (TEST_DATABASE_URL)"]
+    async fn execute_in_chunks_keeps_earlier_chunks_when_a_later_chunk_fails() {
+        let pool = testing::pool().await;
+        let table = testing::unique_table("in_chunks_failure");
+        sqlx::query(&format!("CREATE TABLE {table} (id int CHECK (id <> 14))"))
+            .execute(&pool)
+            .await
+            .unwrap();
+        let inserts: Vec<String> = (1..=25)
+            .map(|id| format!("INSERT INTO {table} VALUES ({id})"))
+            .collect();
+        let mut reported = Vec::new();
+
+        // 14 番目の挿入（インデックス 13）は 2 番目のチャンクで制約違反になります。
+        let error = TransactionExecutor::new(pool.clone())
+            .execute_in_chunks(inserts.iter().map(|sql| sqlx::query(sql)), 10, |progress| {
+                reported.push(progress.chunk_index);
+                async { Ok(()) }
+            })
+            .await
+            .unwrap_err();
+
+        let ids: Vec<i32> = sqlx::query_scalar(&format!("SELECT id FROM {table} ORDER BY id"))
+            .fetch_all(&pool)
+            .await
+            .unwrap();
+        sqlx::query(&format!("DROP TABLE {table}"))
+            .execute(&pool)
+            .await
+            .unwrap();
+        assert!(
+            format!("{error:#}").contains(
+                "Failed at statement 13 in chunk 1 (queries 10..20); 1 earlier chunks remain committed"
+            ),
+            "{error:#}"
+        );
+        assert_eq!(reported, vec![0]);
+        assert_eq!(ids, (1..=10).collect::<Vec<_>>());
+    }
+
+    #[tokio::test]
+    #[ignore = "requires a PostgreSQL database (TEST_DATABASE_URL)"]
+    async fn on_commit_callbacks_run_in_order_after_the_commit() {
+        let pool = testing::pool().await;
+        let table = testing::unique_table("on_commit");
+        sqlx::query(&format!("CREATE TABLE {table} (id int)"))
+            .execute(&pool)
+            .await
+            .unwrap();
+        let events = Arc::new(std::sync::Mutex::new(Vec::new()));
+
+        let outcome = TransactionExecutor::new(pool.clone())
+            .with_transaction_context(|context| {
+                let (pool, table, events) = (pool.clone(), table.clone(), Arc::clone(&events));
+                Box::pin(async move {
+                    context
+                        .execute(sqlx::query(&format!("INSERT INTO {table} VALUES (1)")))
+                        .await?;
+                    // 別の接続から挿入した行が見えることで、コミット後に実行されたことを確かめます。
+                    let first_events = Arc::clone(&events);
+                    context.on_commit(async move {
+                        let count: i64 =
+                            sqlx::query_scalar(&format!("SELECT count(*) FROM {table}"))
+                                .fetch_one(&pool)
+                                .await?;
+                        first_events
+                            .lock()
+                            .unwrap()
+                            .push(format!("first sees {count}"));
+                        Ok(())
+                    });
+                    context.on_commit(async { bail!("publish failed") });
+                    let third_events = Arc::clone(&events);
+                    context.on_commit(async move {
+                        third_events.lock().unwrap().push("third".to_string());
+                        Ok(())
+                    });
+                    let rollback_events = Arc::clone(&events);
+                    context.on_rollback(async move {
+                        rollback_events.lock().unwrap().push("rollback".to_string());
+                    });
+                    events.lock().unwrap().push("closure done".to_string());
+                    Ok(7)
+                })
+            })
+            .await
+            .unwrap();
+
+        sqlx::query(&format!("DROP TABLE {table}"))
+            .execute(&pool)
+            .await
+            .unwrap();
+        assert_eq!(
+            *events.lock().unwrap(),
+            ["closure done", "first sees 1", "third"]
+        );
+        assert_eq!(outcome.hook_errors().len(), 1);
+        assert_eq!(
+            format!("{:#}", outcome.hook_errors()[0]),
+            "on_commit callback at index 1 failed: publish failed"
+        );
+        assert_eq!(outcome.into_value(), 7);
+    }
+
+    #[tokio::test]
+    #[ignore = "requires a PostgreSQL database (TEST_DATABASE_URL)"]
+    async fn on_rollback_callbacks_run_in_order_and_on_commit_callbacks_are_dropped() {
+        let pool = testing::pool().await;
+        let table = testing::unique_table("on_rollback");
+        sqlx::query(&format!("CREATE TABLE {table} (id int)"))
+            .execute(&pool)
+            .await
+            .unwrap();
+        let events = Arc::new(std::sync::Mutex::new(Vec::new()));
+
+        let error = TransactionExecutor::new(pool.clone())
+            .with_transaction_context::<(), _>(|context| {
+                let (table, events) = (table.clone(), Arc::clone(&events));
+                Box::pin(async move {
+                    context
+                        .execute(sqlx::query(&format!("INSERT INTO {table} VALUES (1)")))
+                        .await?;
+                    for label in ["commit", "rollback 1", "rollback 2"] {
+                        let events = Arc::clone(&events);
+                        let record = async move { events.lock().unwrap().push(label) };
+                        if label == "commit" {
+                            context.on_commit(record.map(Ok));
+                        } else {
+                            context.on_rollback(record);
+                        }
+                    }
+                    bail!("closure failed")
+                })
+            })
+            .await
+            .unwrap_err();
+
+        let count: i64 = sqlx::query_scalar(&format!("SELECT count(*) FROM {table}"))
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        sqlx::query(&format!("DROP TABLE {table}"))
+            .execute(&pool)
+            .await
+            .unwrap();
+        assert_eq!(error.to_string(), "closure failed");
+        assert_eq!(*events.lock().unwrap(), ["rollback 1", "rollback 2"]);
+        assert_eq!(count, 0);
+    }
+
+    #[tokio::test]
+    #[ignore = "requires a PostgreSQL database (TEST_DATABASE_URL)"]
+    async fn dry_run_reports_affected_rows_and_leaves_the_table_empty() {
+        let pool = testing::pool().await;
+        let table = testing::unique_table("dry_run");
+        sqlx::query(&format!("CREATE TABLE {table} (id int PRIMARY KEY)"))
+            .execute(&pool)
+            .await
+            .unwrap();
+        let executor = TransactionExecutor::new(pool.clone());
+        let insert = format!("INSERT INTO {table} SELECT generate_series(1, 3)");
+        let update = format!("UPDATE {table} SET id = id + 10 WHERE id > 1");
+
+        let report = executor
+            .execute_queries_dry_run([sqlx::query(&insert), sqlx::query(&update)])
+            .await
+            .unwrap();
+        let failed = executor
+            .execute_script_dry_run(&format!(
+                "{insert};\n\nINSERT INTO {table} VALUES (1);\n{update};"
+            ))
+            .await
+            .unwrap();
+
+        let count: i64 = sqlx::query_scalar(&format!("SELECT count(*) FROM {table}"))
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        sqlx::query(&format!("DROP TABLE {table}"))
+            .execute(&pool)
+            .await
+            .unwrap();
+        assert!(report.is_success());
+        let rows_affected: Vec<u64> = report
+            .statements
+            .iter()
+            .map(|statement| statement.rows_affected)
+            .collect();
+        assert_eq!(rows_affected, vec![3, 2]);
+        assert_eq!(report.total_rows_affected(), 5);
+        let failure = failed.failure.as_ref().unwrap();
+        assert_eq!(failure.index, 1);
+        assert_eq!(testing::sqlstate(&failure.error).as_deref(), Some("23505"));
+        assert!(
+            format!("{:#}", failure.error).contains("statement 1 at line 3"),
+            "{:#}",
+            failure.error
+        );
+        assert_eq!(failed.total_rows_affected(), 3);
+        assert_eq!(count, 0);
+    }
+
+    /// 他のテストプロセスと衝突しないアドバイザリロックのキーを返します。
+    fn test_lock_key(test: i32) -> AdvisoryLockKey {
+        AdvisoryLockKey::Pair(std::process::id() as i32, test)
+    }
+
+    /// ロックを取得した後 `hold` の間保持してから `label` を返すクロージャで、ロックの取得を試みます。
+    async fn try_hold_lock(
+        executor: &TransactionExecutor,
+        key: AdvisoryLockKey,
+        hold: Duration,
+        label: &'static str,
+    ) -> LockAttempt<&'static str> {
+        executor
+            .try_with_advisory_lock(key, move |tx| {
+                Box::pin(async move {
+                    sqlx::query("SELECT pg_sleep($1)")
+                        .bind(hold.as_secs_f64())
+                        .execute(&mut **tx)
+                        .await?;
+                    Ok(label)
+                })
+            })
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    #[ignore = "requires a PostgreSQL database (TEST_DATABASE_URL)"]
+    async fn try_with_advisory_lock_lets_exactly_one_of_two_concurrent_calls_win() {
+        let executor = TransactionExecutor::new(testing::pool().await);
+        let key = test_lock_key(1);
+        let hold = Duration::from_millis(300);
+
+        let (first, second) = tokio::join!(
+            try_hold_lock(&executor, key, hold, "first"),
+            try_hold_lock(&executor, key, hold, "second"),
+        );
+        // 勝った側がコミットした時点でロックは解放されています。
+        let after_commit = try_hold_lock(&executor, key, Duration::ZERO, "after").await;
+
+        let winners: Vec<_> = [first, second]
+            .into_iter()
+            .filter_map(LockAttempt::acquired)
+            .collect();
+        assert_eq!(winners.len(), 1, "{winners:?}");
+        assert_eq!(after_commit, LockAttempt::Acquired("after"));
+    }
+
+    #[tokio::test]
+    #[ignore = "requires a PostgreSQL database (TEST_DATABASE_URL)"]
+    async fn advisory_lock_is_released_when_the_transaction_rolls_back() {
+        let executor = TransactionExecutor::new(testing::pool().await);
+        let key = test_lock_key(2);
+        let (locked_sender, locked_receiver) = tokio::sync::oneshot::channel::<()>();
+        let (fail_sender, fail_receiver) = tokio::sync::oneshot::channel::<()>();
+
+        let holder = executor.with_advisory_lock::<(), _>(key, |_| {
+            Box::pin(async move {
+                locked_sender.send(()).ok();
+                fail_receiver.await.ok();
+                bail!("holder failed")
+            })
+        });
+        let contender = async {
+            locked_receiver.await.unwrap();
+            let while_held = try_hold_lock(&executor, key, Duration::ZERO, "while held").await;
+            fail_sender.send(()).ok();
+            while_held
+        };
+        let (holder, while_held) = tokio::join!(holder, contender);
+        let after_rollback = try_hold_lock(&executor, key, Duration::ZERO, "after").await;
+
+        assert_eq!(holder.unwrap_err().to_string(), "holder failed");
+        assert_eq!(while_held, LockAttempt::LockNotAcquired);
+        assert_eq!(after_rollback, LockAttempt::Acquired("after"));
+    }
+
+    /// COPY の text 形式でエスケープが必要な文字を含む、`id` ごとに異なる本文です。
+    fn copy_body(id: i32) -> String {
+        match id % 5 {
+            0 => format!("plain {id}"),
+            1 => format!("comma, \"quoted\" {id}"),
+            2 => format!("line\nbreak\r\n{id}"),
+            3 => format!("tab\tand back\\slash {id} \\N"),
+            _ => format!("日本語 '{id}' {}", "x".repeat(80)),
+        }
+    }
+
+    #[tokio::test]
+    #[ignore = "requires a PostgreSQL database (TEST_DATABASE_URL)"]
+    async fn copy_in_round_trips_rows_that_need_escaping() {
+        const ROWS: i32 = 10_000;
+        let pool = testing::pool().await;
+        let table = testing::unique_table("copy_round_trip");
+        sqlx::query(&format!(
+            "CREATE TABLE {table} (id int PRIMARY KEY, body text NOT NULL, note text)"
+        ))
+        .execute(&pool)
+        .await
+        .unwrap();
+        let rows = futures_util::stream::iter(1..=ROWS).map(|id| {
+            let note = (id % 3 != 0).then(|| format!("note,{id}"));
+            vec![
+                CopyValue::Int4(Some(id)),
+                CopyValue::Text(Some(copy_body(id))),
+                CopyValue::Text(note),
+            ]
+        });
+
+        let written = TransactionExecutor::new(pool.clone())
+            .copy_in(&table, &["id", "body", "note"], rows)
+            .await
+            .unwrap();
+
+        let stored: Vec<(i32, String, Option<String>)> =
+            sqlx::query_as(&format!("SELECT id, body, note FROM {table} ORDER BY id"))
+                .fetch_all(&pool)
+                .await
+                .unwrap();
+        sqlx::query(&format!("DROP TABLE {table}"))
+            .execute(&pool)
+            .await
+            .unwrap();
+        assert_eq!(written, ROWS as u64);
+        assert_eq!(stored.len(), ROWS as usize);
+        for (id, body, note) in stored {
+            assert_eq!(body, copy_body(id));
+            assert_eq!(note, (id % 3 != 0).then(|| format!("note,{id}")));
+        }
+    }
+
+    #[tokio::test]
+    #[ignore = "requires a PostgreSQL database (TEST_DATABASE_URL)"]
+    async fn copy_in_leaves_the_table_empty_when_a_row_fails_mid_stream() {
+        let pool = testing::pool().await;
+        let table = testing::unique_table("copy_failure");
+        sqlx::query(&format!(
+            "CREATE TABLE {table} (id int PRIMARY KEY, body text NOT NULL)"
+        ))
+        .execute(&pool)
+        .await
+        .unwrap();
+        let executor = TransactionExecutor::new(pool.clone());
+        let row = |id: i32| {
+            vec![
+                CopyValue::Int4(Some(id)),
+                CopyValue::Text(Some(copy_body(id))),
+            ]
+        };
+
+        // 先頭の数千行を送信した後で、列数の誤りとサーバー側の制約違反でそれぞれ失敗させます。
+        let short_row = executor
+            .copy_in(
+                &table,
+                &["id", "body"],
+                futures_util::stream::iter(1..=10_000).map(|id| {
+                    if id == 5_000 {
+                        vec![CopyValue::Int4(Some(id))]
+                    } else {
+                        row(id)
+                    }
+                }),
+            )
+            .await
+            .unwrap_err();
+        let null_body = executor
+            .copy_in(
+                &table,
+                &["id", "body"],
+                futures_util::stream::iter(1..=10_000).map(|id| {
+                    if id == 9_000 {
+                        vec![CopyValue::Int4(Some(id)), CopyValue::Text(None)]
+                    } else {
+                        row(id)
+                    }
+                }),
+            )
+            .await
+            .unwrap_err();
+
+        let count: i64 = sqlx::query_scalar(&format!("SELECT count(*) FROM {table}"))
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        sqlx::query(&format!("DROP TABLE {table}"))
+            .execute(&pool)
+            .await
+            .unwrap();
+        assert!(
+            format!("{short_row:#}").contains("Row 4999 has 1 values, expected 2"),
+            "{short_row:#}"
+        );
+        assert_eq!(testing::sqlstate(&null_body).as_deref(), Some("23502"));
+        assert_eq!(count, 0);
+    }
+
+    /// 子テーブルから親テーブルへの遅延可能な外部キーを持つ親子テーブルを作成し、テーブル名を返します。
+    async fn create_parent_child(pool: &PgPool, prefix: &str) -> (String, String) {
+        let parent = testing::unique_table(&format!("{prefix}_parent"));
+        let child = testing::unique_table(&format!("{prefix}_child"));
+        sqlx::query(&format!("CREATE TABLE {parent} (id int PRIMARY KEY)"))
+            .execute(pool)
+            .await
+            .unwrap();
+        sqlx::query(&format!(
+            "CREATE TABLE {child} (id int PRIMARY KEY, parent_id int NOT NULL, \
+             CONSTRAINT child_parent_fk FOREIGN KEY (parent_id) REFERENCES {parent} (id) \
+             DEFERRABLE INITIALLY IMMEDIATE)"
+        ))
+        .execute(pool)
+        .await
+        .unwrap();
+        (parent, child)
+    }
+
+    /// 親子テーブルの行数を返してから両テーブルを削除します。
+    async fn drop_parent_child(pool: &PgPool, parent: &str, child: &str) -> (i64, i64) {
+        let count = |table: String| async move {
+            sqlx::query_scalar::<_, i64>(&format!("SELECT count(*) FROM {table}"))
+                .fetch_one(pool)
+                .await
+                .unwrap()
+        };
+        let counts = (
+            count(parent.to_string()).await,
+            count(child.to_string()).await,
+        );
+        sqlx::query(&format!("DROP TABLE {child}, {parent}"))
+            .execute(pool)
+            .await
+            .unwrap();
+        counts
+    }
+
+    /// 子を親より先に挿入するバッチが、制約を遅延させた場合だけコミットできることを確認します。
+    #[tokio::test]
+    #[ignore = "requires a PostgreSQL database (TEST_DATABASE_URL)"]
+    async fn deferred_constraints_allow_out_of_order_inserts() {
+        let pool = testing::pool().await;
+        let (parent, child) = create_parent_child(&pool, "deferred_order").await;
+        let executor = TransactionExecutor::new(pool.clone());
+        let insert_child = format!("INSERT INTO {child} VALUES ($1, $1)");
+        let insert_parent = format!("INSERT INTO {parent} VALUES ($1)");
+        let batch = |id: i32| {
+            [
+                sqlx::query(&insert_child).bind(id),
+                sqlx::query(&insert_parent).bind(id),
+            ]
+        };
+
+        let immediate = executor
+            .execute_queries_with_options(&TransactionOptions::default(), batch(1))
+            .await
+            .unwrap_err();
+        let all = executor
+            .execute_queries_with_options(
+                &TransactionOptions::with_deferred_constraints(DeferredConstraints::All),
+                batch(2),
+            )
+            .await
+            .unwrap();
+        let named = executor
+            .execute_queries_with_options(
+                &TransactionOptions::with_deferred_constraints(DeferredConstraints::named([
+                    "child_parent_fk",
+                ])),
+                batch(3),
+            )
+            .await
+            .unwrap();
+
+        let counts = drop_parent_child(&pool, &parent, &child).await;
+        assert_eq!(testing::sqlstate(&immediate).as_deref(), Some("23503"));
+        assert!(
+            format!("{immediate:#}").contains("index 0"),
+            "{immediate:#}"
+        );
+        assert_eq!(all, vec![1, 1]);
+        assert_eq!(named, vec![1, 1]);
+        assert_eq!(counts, (2, 2));
+    }
+
+    /// 遅延させた制約に最後まで違反したままのバッチが、`COMMIT` で遅延した制約の違反として失敗することを確認します。
+    #[tokio::test]
+    #[ignore = "requires a PostgreSQL database (TEST_DATABASE_URL)"]
+    async fn inconsistent_deferred_batch_fails_at_commit_with_the_constraint_name() {
+        let pool = testing::pool().await;
+        let (parent, child) = create_parent_child(&pool, "deferred_commit").await;
+
+        let error = TransactionExecutor::new(pool.clone())
+            .execute_queries_with_options(
+                &TransactionOptions::with_deferred_constraints(DeferredConstraints::named([
+                    "child_parent_fk",
+                ])),
+                [
+                    sqlx::query(&format!("INSERT INTO {parent} VALUES (1)")),
+                    sqlx::query(&format!("INSERT INTO {child} VALUES (1, 1)")),
+                    sqlx::query(&format!("INSERT INTO {child} VALUES (2, 99)")),
+                ],
+            )
+            .await
+            .unwrap_err();
+
+        let counts = drop_parent_child(&pool, &parent, &child).await;
+        assert_eq!(testing::sqlstate(&error).as_deref(), Some("23503"));
+        assert!(
+            format!("{error:#}").contains(
+                "Failed to commit transaction: deferred constraint child_parent_fk was violated at COMMIT"
+            ),
+            "{error:#}"
+        );
+        assert!(matches!(
+            DbError::find(&error),
+            Some(DbError::ForeignKeyViolation { .. })
+        ));
+        assert_eq!(counts, (0, 0));
+    }
+
+    /// 親子テーブルに親と子を挿入するクロージャ API の処理です。子の挿入には親の生成された ID を使います。
+    async fn insert_parent_and_child(
+        executor: &TransactionExecutor,
+        parent: &str,
+        child: &str,
+        label: Option<&'static str>,
+    ) -> Result<(i32, i32)> {
+        let insert_parent = format!("INSERT INTO {parent} (name) VALUES ('order') RETURNING id");
+        let insert_child =
+            format!("INSERT INTO {child} (parent_id, label) VALUES ($1, $2) RETURNING id");
+        executor
+            .with_transaction(move |tx| {
+                Box::pin(async move {
+                    let parent_ids = execute_returning(
+                        tx,
+                        sqlx::query(&insert_parent)
+                            .try_map(|row: PgRow| sqlx::Row::try_get::<i32, _>(&row, 0)),
+                    )
+                    .await?;
+                    let child_ids = execute_returning(
+                        tx,
+                        sqlx::query(&insert_child)
+                            .bind(parent_ids[0])
+                            .bind(label)
+                            .try_map(|row: PgRow| sqlx::Row::try_get::<i32, _>(&row, 0)),
+                    )
+                    .await?;
+                    Ok((parent_ids[0], child_ids[0]))
+                })
+            })
+            .await
+    }
+
+    /// 返された親の ID で子を挿入してコミットでき、子の挿入に失敗した場合は親の挿入も取り消されることを確認します。
+    #[tokio::test]
+    #[ignore = "requires a PostgreSQL database (TEST_DATABASE_URL)"]
+    async fn returned_ids_feed_later_statements_and_roll_back_together() {
+        let pool = testing::pool().await;
+        let parent = testing::unique_table("returning_parent");
+        let child = testing::unique_table("returning_child");
+        sqlx::query(&format!(
+            "CREATE TABLE {parent} (id int GENERATED ALWAYS AS IDENTITY PRIMARY KEY, name text NOT NULL)"
+        ))
+        .execute(&pool)
+        .await
+        .unwrap();
+        sqlx::query(&format!(
+            "CREATE TABLE {child} (id int GENERATED ALWAYS AS IDENTITY PRIMARY KEY, \
+             parent_id int NOT NULL REFERENCES {parent} (id), label text NOT NULL)"
+        ))
+        .execute(&pool)
+        .await
+        .unwrap();
+        let executor = TransactionExecutor::new(pool.clone());
+
+        let (parent_id, child_id) =
+            insert_parent_and_child(&executor, &parent, &child, Some("first"))
+                .await
+                .unwrap();
+        let failed = insert_parent_and_child(&executor, &parent, &child, None)
+            .await
+            .unwrap_err();
+        let none: Vec<i32> = executor
+            .execute_query_returning(
+                sqlx::query(&format!("DELETE FROM {child} WHERE false RETURNING id"))
+                    .try_map(|row: PgRow| sqlx::Row::try_get::<i32, _>(&row, 0)),
+            )
+            .await
+            .unwrap();
+        let renamed: Vec<String> = executor
+            .execute_query_returning(
+                sqlx::query(&format!(
+                    "UPDATE {parent} SET name = 'renamed' WHERE id = $1 RETURNING name"
+                ))
+                .bind(parent_id)
+                .try_map(|row: PgRow| sqlx::Row::try_get::<String, _>(&row, 0)),
+            )
+            .await
+            .unwrap();
+
+        let rows: Vec<(i32, i32, String)> = sqlx::query_as(&format!(
+            "SELECT p.id, c.id, c.label FROM {parent} p JOIN {child} c ON c.parent_id = p.id"
+        ))
+        .fetch_all(&pool)
+        .await
+        .unwrap();
+        let parents: i64 = sqlx::query_scalar(&format!("SELECT count(*) FROM {parent}"))
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        sqlx::query(&format!("DROP TABLE {child}, {parent}"))
+            .execute(&pool)
+            .await
+            .unwrap();
+        assert_eq!(rows, [(parent_id, child_id, "first".to_string())]);
+        assert_eq!(testing::sqlstate(&failed).as_deref(), Some("23502"));
+        assert_eq!(parents, 1);
+        assert!(none.is_empty());
+        assert_eq!(renamed, ["renamed"]);
+    }
+
+    type AuditRow = (
+        Option<String>,
+        String,
+        i64,
+        i64,
+        Option<String>,
+        Option<String>,
+        bool,
+    );
+
+    /// 監査ログのテーブルの記録を、追加した順に返します。
+    async fn audit_rows(pool: &PgPool, audit_table: &str) -> Vec<AuditRow> {
+        sqlx::query_as(&format!(
+            "SELECT label, outcome, statement_count, rows_affected, application_user, error, \
+             started_at <= finished_at FROM {audit_table} ORDER BY finished_at"
+        ))
+        .fetch_all(pool)
+        .await
+        .unwrap()
+    }
+
+    /// コミットしたトランザクションは件数付きで 1 行だけ記録され、ロールバックしたトランザクションは
+    /// `with_rollbacks(true)` の場合だけ記録されることを確認します。
+    #[tokio::test]
+    #[ignore = "requires a PostgreSQL database (TEST_DATABASE_URL)"]
+    async fn audit_log_records_commits_and_optionally_rollbacks() {
+        let pool = testing::pool().await;
+        let table = testing::unique_table("audited");
+        let audit_table = testing::unique_table("audit_log");
+        sqlx::query(&format!("CREATE TABLE {table} (id int PRIMARY KEY)"))
+            .execute(&pool)
+            .await
+            .unwrap();
+        let executor = |record_rollbacks: bool| {
+            TransactionExecutor::new(pool.clone()).with_audit_log(
+                AuditLog::for_table(&audit_table)
+                    .unwrap()
+                    .with_application_user("nightly-batch")
+                    .with_rollbacks(record_rollbacks),
+            )
+        };
+        executor(false).ensure_audit_table().await.unwrap();
+        // 既にある場合は何もしません。
+        executor(false).ensure_audit_table().await.unwrap();
+        let insert_sql = format!("INSERT INTO {table} SELECT unnest($1::int[])");
+        let insert = |ids: &[i32]| sqlx::query(&insert_sql).bind(ids.to_vec());
+
+        executor(false)
+            .execute_queries_labeled("load", [insert(&[1, 2]), insert(&[3]), insert(&[])])
+            .await
+            .unwrap();
+        let after_commit = audit_rows(&pool, &audit_table).await;
+
+        executor(false)
+            .execute_queries_labeled("silent", [insert(&[5]), insert(&[1])])
+            .await
+            .unwrap_err();
+        let after_silent_rollback = audit_rows(&pool, &audit_table).await;
+
+        executor(true)
+            .execute_queries_labeled("recorded", [insert(&[6]), insert(&[1])])
+            .await
+            .unwrap_err();
+        let after_recorded_rollback = audit_rows(&pool, &audit_table).await;
+
+        let ids: Vec<i32> = sqlx::query_scalar(&format!("SELECT id FROM {table} ORDER BY id"))
+            .fetch_all(&pool)
+            .await
+            .unwrap();
+        sqlx::query(&format!("DROP TABLE {table}, {audit_table}"))
+            .execute(&pool)
+            .await
+            .unwrap();
+        let committed = (
+            Some("load".to_string()),
+            "committed".to_string(),
+            3,
+            3,
+            Some("nightly-batch".to_string()),
+            None,
+            true,
+        );
+        assert_eq!(after_commit.len(), 1);
+        assert_eq!(after_commit[0], committed);
+        assert_eq!(after_silent_rollback, after_commit);
+        assert_eq!(after_recorded_rollback.len(), 2);
+        assert_eq!(after_recorded_rollback[0], committed);
+        let (label, outcome, statement_count, rows_affected, user, error, ordered) =
+            &after_recorded_rollback[1];
+        assert_eq!(label.as_deref(), Some("recorded"));
+        assert_eq!(outcome, "rolled_back");
+        // 失敗したステートメントは件数に含めません。
+        assert_eq!((*statement_count, *rows_affected), (1, 1));
+        assert_eq!(user.as_deref(), Some("nightly-batch"));
+        assert!(
+            error
+                .as_deref()
+                .is_some_and(|error| error.contains("index 1")),
+            "{error:?}"
+        );
+        assert!(ordered);
+        assert_eq!(ids, [1, 2, 3]);
+    }
+
+    /// `ids` の各値を挿入する `QuerySpec` を 1 件ずつ生成するストリームです。`fail_at` の位置では `Err` を返します。
+    ///
+    /// 生成した件数を `produced` に数えるため、ストリームをどこまで読んだかを確認できます。
+    fn insert_stream(
+        table: &str,
+        ids: std::ops::RangeInclusive<i32>,
+        fail_at: Option<i32>,
+        produced: Arc<AtomicUsize>,
+    ) -> impl Stream<Item = Result<QuerySpec>> + use<> {
+        let sql = format!("INSERT INTO {table} VALUES ($1)");
+        futures_util::stream::iter(ids).map(move |id| {
+            produced.fetch_add(1, Ordering::Relaxed);
+            if Some(id) == fail_at {
+                bail!("source read failed");
+            }
+            Ok(QuerySpec::new(sql.as_str()).bind(id))
+        })
+    }
+
+    /// 数千件のステートメントを生成するストリームを、ベクタに集めずに単一トランザクションでコミットできることを確認します。
+    #[tokio::test]
+    #[ignore = "requires a PostgreSQL database (TEST_DATABASE_URL)"]
+    async fn execute_query_stream_commits_every_generated_statement() {
+        let pool = testing::pool().await;
+        let table = testing::unique_table("query_stream");
+        sqlx::query(&format!("CREATE TABLE {table} (id int PRIMARY KEY)"))
+            .execute(&pool)
+            .await
+            .unwrap();
+        let produced = Arc::new(AtomicUsize::new(0));
+
+        let report = TransactionExecutor::new(pool.clone())
+            .execute_query_stream_with(
+                &QueryStreamOptions {
+                    max_statements: Some(5_000),
+                    progress_interval: Some(1_000),
+                },
+                insert_stream(&table, 1..=3_000, None, produced.clone()),
+            )
+            .await
+            .unwrap();
+
+        let (count, sum): (i64, i64) =
+            sqlx::query_as(&format!("SELECT count(*), sum(id) FROM {table}"))
+                .fetch_one(&pool)
+                .await
+                .unwrap();
+        sqlx::query(&format!("DROP TABLE {table}"))
+            .execute(&pool)
+            .await
+            .unwrap();
+        assert_eq!(
+            report,
+            QueryStreamReport {
+                statements: 3_000,
+                rows_affected: 3_000,
+            }
+        );
+        assert_eq!(produced.load(Ordering::Relaxed), 3_000);
+        assert_eq!((count, sum), (3_000, 3_000 * 3_001 / 2));
+    }
+
+    /// ストリームが途中で `Err` を返した場合は、それまでのステートメントも含めて全体がロールバックされ、
+    /// それ以降のストリームは読まれないことを確認します。
+    #[tokio::test]
+    #[ignore = "requires a PostgreSQL database (TEST_DATABASE_URL)"]
+    async fn execute_query_stream_rolls_back_everything_on_an_error_item() {
+        let pool = testing::pool().await;
+        let table = testing::unique_table("query_stream_error");
+        sqlx::query(&format!("CREATE TABLE {table} (id int PRIMARY KEY)"))
+            .execute(&pool)
+            .await
+            .unwrap();
+        let produced = Arc::new(AtomicUsize::new(0));
+        let observer = Arc::new(RecordingObserver::default());
+
+        // id が 0 から始まるため、インデックス 500 の要素が `Err` です。
+        let error = TransactionExecutor::new(pool.clone())
+            .with_observer(Arc::clone(&observer) as Arc<dyn TransactionObserver>)
+            .execute_query_stream(insert_stream(
+                &table,
+                0..=2_999,
+                Some(500),
+                produced.clone(),
+            ))
+            .await
+            .unwrap_err();
+
+        let count: i64 = sqlx::query_scalar(&format!("SELECT count(*) FROM {table}"))
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        sqlx::query(&format!("DROP TABLE {table}"))
+            .execute(&pool)
+            .await
+            .unwrap();
+        let message = format!("{error:#}");
+        assert!(
+            message.contains(
+                "Query stream failed to produce statement at index 500: source read failed"
+            ),
+            "{message}"
+        );
+        assert_eq!(count, 0);
+        assert_eq!(produced.load(Ordering::Relaxed), 501);
+        assert_eq!(*observer.rollbacks.lock().unwrap(), [Some(500)]);
+    }
+
+    #[test]
+    fn savepoint_name_quotes_keywords_and_mixed_case_prefixes() {
+        for prefix in ["Select", "order", "_Batch1"] {
+            let name = savepoint_name(prefix).unwrap();
+
+            let sequence = name
+                .strip_prefix(&format!("\"{prefix}_"))
+                .and_then(|rest| rest.strip_suffix('"'))
+                .unwrap_or_else(|| panic!("unexpected savepoint name {name}"));
+            assert!(sequence.parse::<u64>().is_ok(), "{name}");
+        }
+    }
+
+    #[test]
+    fn savepoint_name_is_distinct_across_calls() {
+        let names: std::collections::HashSet<String> =
+            (0..100).map(|_| savepoint_name("sp").unwrap()).collect();
+
+        assert_eq!(names.len(), 100);
+    }
+
+    #[test]
+    fn cursor_name_is_distinct_across_calls() {
+        let names: std::collections::HashSet<String> = (0..100).map(|_| cursor_name()).collect();
+
+        assert_eq!(names.len(), 100);
+    }
+
+    #[test]
+    fn savepoint_name_rejects_prefixes_outside_the_identifier_pattern() {
+        let too_long = "a".repeat(MAX_SAVEPOINT_PREFIX_LEN + 1);
+        for prefix in [
+            "",
+            "1sp",
+            "sp; ROLLBACK",
+            "sp\"x",
+            "sp-1",
+            "セーブ",
+            too_long.as_str(),
+        ] {
+            assert!(savepoint_name(prefix).is_err(), "{prefix:?}");
+        }
+        assert!(savepoint_name(&"a".repeat(MAX_SAVEPOINT_PREFIX_LEN)).is_ok());
+    }
+
+    #[tokio::test]
+    #[ignore = "requires a PostgreSQL database (TEST_DATABASE_URL)"]
+    async fn with_savepoint_accepts_keyword_prefixes_and_nesting() {
+        let mut tx = testing::pool().await.begin().await.unwrap();
+
+        let error = with_savepoint::<(), _>(&mut tx, "Select", |tx| {
+            Box::pin(async move {
+                with_savepoint(tx, "Select", |tx| {
+                    Box::pin(async move {
+                        sqlx::query("SELECT 1").execute(&mut **tx).await?;
+                        Ok(())
+                    })
+                })
+                .await?;
+                bail!("roll back the outer savepoint")
+            })
+        })
+        .await
+        .unwrap_err();
+
+        assert_eq!(error.to_string(), "roll back the outer savepoint");
+        sqlx::query("SELECT 1").execute(&mut *tx).await.unwrap();
+        tx.commit().await.unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore = "requires a PostgreSQL database (TEST_DATABASE_URL)"]
+    async fn a_failed_rollback_keeps_the_original_error() {
+        let pool = testing::pool().await;
+        let terminator = pool.clone();
+        let hooks = TransactionHooks::new().before_commit(move |tx| {
+            let terminator = terminator.clone();
+            Box::pin(async move {
+                let backend_pid: i32 = sqlx::query_scalar("SELECT pg_backend_pid()")
+                    .fetch_one(&mut **tx)
+                    .await?;
+                sqlx::query("SELECT pg_terminate_backend($1)")
+                    .bind(backend_pid)
+                    .execute(&terminator)
+                    .await?;
+                bail!("original failure")
+            })
+        });
+
+        let error = TransactionExecutor::new(pool)
+            .with_hooks(hooks)
+            .execute_query(sqlx::query("SELECT 1"))
+            .await
+            .unwrap_err();
+
+        assert_eq!(error.root_cause().to_string(), "original failure");
+        assert!(
+            format!("{error:#}").contains("Failed to rollback transaction"),
+            "{error:#}"
+        );
+    }
+
+    /// 通知された結果を記録するオブザーバーです。
+    #[derive(Default)]
+    struct RecordingObserver {
+        commits: std::sync::Mutex<Vec<usize>>,
+        rollbacks: std::sync::Mutex<Vec<Option<usize>>>,
+    }
+
+    impl TransactionObserver for RecordingObserver {
+        fn on_commit(&self, _duration: Duration, query_count: usize) {
+            self.commits.lock().unwrap().push(query_count);
+        }
+
+        fn on_rollback(&self, _duration: Duration, error_index: Option<usize>) {
+            self.rollbacks.lock().unwrap().push(error_index);
+        }
+    }
+
+    #[tokio::test]
+    #[ignore = "requires a PostgreSQL database (TEST_DATABASE_URL)"]
+    async fn observer_receives_the_query_count_and_the_failing_index() {
+        let observer = Arc::new(RecordingObserver::default());
+        let executor = TransactionExecutor::new(testing::pool().await)
+            .with_observer(Arc::clone(&observer) as Arc<dyn TransactionObserver>);
+
+        executor
+            .execute_queries([
+                sqlx::query("SELECT 1"),
+                sqlx::query("SELECT 2"),
+                sqlx::query("SELECT 3"),
+            ])
+            .await
+            .unwrap();
+        executor
+            .execute_queries([
+                sqlx::query("SELECT 1"),
+                sqlx::query("SELECT 1 / 0"),
+                sqlx::query("SELECT 3"),
+            ])
+            .await
+            .unwrap_err();
+        executor
+            .with_hooks(
+                TransactionHooks::new()
+                    .before_commit(|_| Box::pin(async { bail!("reject the commit") })),
+            )
+            .execute_query(sqlx::query("SELECT 1"))
+            .await
+            .unwrap_err();
+
+        assert_eq!(*observer.commits.lock().unwrap(), [3]);
+        assert_eq!(*observer.rollbacks.lock().unwrap(), [Some(1), None]);
+    }
+
+    #[tokio::test]
+    #[ignore = "requires a PostgreSQL database (TEST_DATABASE_URL)"]
+    async fn execute_queries_idempotent_treats_a_duplicate_insert_as_a_no_op() {
+        let pool = testing::pool().await;
+        let table = testing::unique_table("idempotent");
+        sqlx::query(&format!("CREATE TABLE {table} (id int PRIMARY KEY)"))
+            .execute(&pool)
+            .await
+            .unwrap();
+        let executor = TransactionExecutor::new(pool.clone());
+        let insert = format!("INSERT INTO {table} VALUES (1)");
+
+        executor
+            .execute_queries_idempotent([sqlx::query(&insert)])
+            .await
+            .unwrap();
+        executor
+            .execute_queries_idempotent([sqlx::query(&insert)])
+            .await
+            .unwrap();
+        let other_error = executor
+            .execute_queries_idempotent([sqlx::query(&format!(
+                "INSERT INTO {table} VALUES (NULL)"
+            ))])
+            .await;
+
+        let count: i64 = sqlx::query_scalar(&format!("SELECT count(*) FROM {table}"))
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        sqlx::query(&format!("DROP TABLE {table}"))
+            .execute(&pool)
+            .await
+            .unwrap();
+        assert_eq!(count, 1);
+        assert!(other_error.is_err());
+    }
+
+    #[tokio::test]
+    #[ignore = "requires a PostgreSQL database (TEST_DATABASE_URL)"]
+    async fn with_transaction_isolation_sets_the_level_inside_the_transaction() {
+        let executor = TransactionExecutor::new(testing::pool().await);
+
+        for level in [
+            IsolationLevel::ReadCommitted,
+            IsolationLevel::RepeatableRead,
+            IsolationLevel::Serializable,
+        ] {
+            let isolation: String = executor
+                .with_transaction_isolation(level, |tx| {
+                    Box::pin(async move {
+                        let isolation = sqlx::query_scalar("SHOW transaction_isolation")
+                            .fetch_one(&mut **tx)
+                            .await?;
+                        Ok(isolation)
+                    })
+                })
+                .await
+                .unwrap();
+
+            assert_eq!(isolation, level.as_sql().to_lowercase());
+        }
+    }
+
+    #[tokio::test]
+    #[ignore = "requires a PostgreSQL database (TEST_DATABASE_URL)"]
+    async fn read_only_transactions_reject_writes() {
+        let pool = testing::pool().await;
+        let table = testing::unique_table("read_only");
+        sqlx::query(&format!("CREATE TABLE {table} (id int)"))
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        let error = TransactionExecutor::new(pool.clone())
+            .execute_queries_with_options(
+                &TransactionOptions::read_only(),
+                [sqlx::query(&format!("INSERT INTO {table} VALUES (1)"))],
+            )
+            .await
+            .unwrap_err();
+        let read_error = QueryExecutor::new(pool.clone())
+            .fetch_all_read_only(
+                sqlx::query(&format!("INSERT INTO {table} VALUES (2) RETURNING id"))
+                    .try_map(|row: PgRow| sqlx::Row::try_get::<i32, _>(&row, 0)),
+            )
+            .await
+            .unwrap_err();
+
+        let count: i64 = sqlx::query_scalar(&format!("SELECT count(*) FROM {table}"))
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        sqlx::query(&format!("DROP TABLE {table}"))
+            .execute(&pool)
+            .await
+            .unwrap();
+        assert_eq!(testing::sqlstate(&error).as_deref(), Some("25006"));
+        assert_eq!(testing::sqlstate(&read_error).as_deref(), Some("25006"));
+        assert_eq!(count, 0);
+    }
+
+    #[tokio::test]
+    #[ignore = "requires a PostgreSQL database (TEST_DATABASE_URL)"]
+    async fn with_savepoint_contains_an_inner_failure_and_keeps_the_outer_writes() {
+        let pool = testing::pool().await;
+        let table = testing::unique_table("savepoint");
+        sqlx::query(&format!("CREATE TABLE {table} (id int PRIMARY KEY)"))
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        let mut tx = pool.begin().await.unwrap();
+        sqlx::query(&format!("INSERT INTO {table} VALUES (1)"))
+            .execute(&mut *tx)
+            .await
+            .unwrap();
+        let insert_duplicate = format!("INSERT INTO {table} VALUES (2), (1)");
+        let error = with_savepoint::<(), _>(&mut tx, "inner", |tx| {
+            Box::pin(async move {
+                sqlx::query(&insert_duplicate).execute(&mut **tx).await?;
+                Ok(())
+            })
+        })
+        .await
+        .unwrap_err();
+        sqlx::query(&format!("INSERT INTO {table} VALUES (3)"))
+            .execute(&mut *tx)
+            .await
+            .unwrap();
+        let named_savepoints: i64 = sqlx::query_scalar(
+            "SELECT count(*) FROM pg_prepared_statements \
+             WHERE statement LIKE 'SAVEPOINT%' OR statement LIKE 'RELEASE%' \
+             OR statement LIKE 'ROLLBACK TO%'",
+        )
+        .fetch_one(&mut *tx)
+        .await
+        .unwrap();
+        tx.commit().await.unwrap();
+
+        let ids: Vec<i32> = sqlx::query_scalar(&format!("SELECT id FROM {table} ORDER BY id"))
+            .fetch_all(&pool)
+            .await
+            .unwrap();
+        sqlx::query(&format!("DROP TABLE {table}"))
+            .execute(&pool)
+            .await
+            .unwrap();
+        assert!(error.downcast_ref::<sqlx::Error>().is_some(), "{error:#}");
+        assert_eq!(ids, [1, 3]);
+        assert_eq!(named_savepoints, 0);
+    }
+
+    #[tokio::test]
+    #[ignore = "requires a PostgreSQL database (TEST_DATABASE_URL)"]
+    async fn execute_queries_best_effort_commits_everything_but_the_failing_statement() {
+        let pool = testing::pool().await;
+        let table = testing::unique_table("best_effort");
+        sqlx::query(&format!("CREATE TABLE {table} (id int CHECK (id > 0))"))
+            .execute(&pool)
+            .await
+            .unwrap();
+        let statements: Vec<String> = [1, 2, -3, 4, 5]
+            .iter()
+            .map(|id| format!("INSERT INTO {table} VALUES ({id})"))
+            .collect();
+
+        let outcome = TransactionExecutor::new(pool.clone())
+            .execute_queries_best_effort(statements.iter().map(|sql| sqlx::query(sql)))
+            .await
+            .unwrap();
+
+        let ids: Vec<i32> = sqlx::query_scalar(&format!("SELECT id FROM {table} ORDER BY id"))
+            .fetch_all(&pool)
+            .await
+            .unwrap();
+        sqlx::query(&format!("DROP TABLE {table}"))
+            .execute(&pool)
+            .await
+            .unwrap();
+        assert_eq!(ids, [1, 2, 4, 5]);
+        assert_eq!(outcome.succeeded(), 4);
+        let failures: Vec<_> = outcome.failures().collect();
+        assert_eq!(failures.len(), 1);
+        let (index, error) = failures[0];
+        assert_eq!(index, 2);
+        assert!(
+            matches!(DbError::find(error), Some(DbError::CheckViolation { .. })),
+            "{error:#}"
+        );
+    }
+
+    /// 接続しない実行器用のプールです。最初のクエリまで接続しません。
+    fn unconnected_pool() -> PgPool {
+        PgPool::connect_lazy("postgres://localhost/unused").unwrap()
+    }
+
+    #[tokio::test]
+    async fn with_limit_rejects_zero_in_flight() {
+        let Err(error) = TransactionExecutor::with_limit(unconnected_pool(), 0) else {
+            panic!("a limit of 0 must be rejected");
+        };
+
+        assert_eq!(error.to_string(), "max_in_flight must be greater than 0");
+    }
+
+    #[tokio::test]
+    async fn concurrency_limit_reports_saturation_after_the_queue_timeout() {
+        let executor = TransactionExecutor::with_limit(unconnected_pool(), 1)
+            .unwrap()
+            .with_queue_timeout(Some(Duration::from_millis(10)));
+        let limit = executor.limit.as_ref().unwrap();
+
+        let permit = limit.acquire().await.unwrap();
+        assert_eq!(executor.in_flight(), Some(1));
+        assert_eq!(executor.available_permits(), Some(0));
+        let error = limit.acquire().await.unwrap_err();
+        assert!(matches!(
+            DbError::find(&error),
+            Some(DbError::ExecutorSaturated { queue_timeout }) if *queue_timeout == Duration::from_millis(10)
+        ));
+
+        drop(permit);
+        assert_eq!(executor.in_flight(), Some(0));
+        assert!(limit.acquire().await.is_ok());
+    }
+
+    #[tokio::test]
+    #[ignore = "requires a PostgreSQL database (TEST_DATABASE_URL)"]
+    async fn with_limit_returns_executor_saturated_while_transactions_hold_every_slot() {
+        let executor = TransactionExecutor::with_limit(testing::pool().await, 1)
+            .unwrap()
+            .with_queue_timeout(Some(Duration::from_millis(50)));
+
+        let tx = executor.begin().await.unwrap();
+        let error = executor
+            .clone()
+            .execute_query(sqlx::query("SELECT 1"))
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            DbError::find(&error),
+            Some(DbError::ExecutorSaturated { .. })
+        ));
+
+        tx.rollback().await.unwrap();
+        executor
+            .execute_query(sqlx::query("SELECT 1"))
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore = "requires a PostgreSQL database (TEST_DATABASE_URL)"]
+    async fn scope_commits_or_rolls_back_repository_calls_together() {
+        let pool = testing::pool().await;
+        let executor = TransactionExecutor::new(pool.clone());
+        let table = testing::unique_table("scope");
+        sqlx::query(&format!("CREATE TABLE {table} (id int)"))
+            .execute(&pool)
+            .await
+            .unwrap();
+        let insert = |id: i32| {
+            let sql = format!("INSERT INTO {table} VALUES ($1)");
+            let executor = executor.clone();
+            async move { executor.execute_query(sqlx::query(&sql).bind(id)).await }
+        };
+
+        executor
+            .scope(|| async {
+                insert(1).await?;
+                insert(2).await?;
+                Ok(())
+            })
+            .await
+            .unwrap();
+        let error = executor
+            .scope::<(), _, _>(|| async {
+                insert(3).await?;
+                insert(4).await?;
+                bail!("abandon the scope")
+            })
+            .await
+            .unwrap_err();
+
+        let ids: Vec<i32> = sqlx::query_scalar(&format!("SELECT id FROM {table} ORDER BY id"))
+            .fetch_all(&pool)
+            .await
+            .unwrap();
+        sqlx::query(&format!("DROP TABLE {table}"))
+            .execute(&pool)
+            .await
+            .unwrap();
+        assert_eq!(error.to_string(), "abandon the scope");
+        assert_eq!(ids, [1, 2]);
+    }
+
+    #[tokio::test]
+    #[ignore = "requires a PostgreSQL database (TEST_DATABASE_URL)"]
+    async fn execute_script_reports_the_failing_statement_and_rolls_back() {
+        let connection_pool = testing::connect().await;
+        let executor = TransactionExecutor::from_shared_pool(&connection_pool);
+        let table = testing::unique_table("script_rollback");
+        let script = format!(
+            "CREATE TABLE {table} (id int);\nINSERT INTO {table} VALUES (1);\n\nSELECT * FROM {table}_missing;\nSELECT 1;"
+        );
+
+        let error = executor.execute_script(&script).await.unwrap_err();
+
+        assert!(
+            format!("{error:#}").contains("Failed to execute script statement 2 at line 4"),
+            "{error:#}"
+        );
+        let created: Option<String> = sqlx::query_scalar("SELECT to_regclass($1)::text")
+            .bind(&table)
+            .fetch_one(connection_pool.for_workload(Workload::Interactive))
+            .await
+            .unwrap();
+        assert_eq!(created, None);
+    }
+
+    #[tokio::test]
+    #[ignore = "requires a PostgreSQL database (TEST_DATABASE_URL)"]
+    async fn statement_errors_never_include_bound_values_or_literals() {
+        const SENTINEL: &str = "bound-sentinel-3f9a";
+        let connection_pool = testing::connect().await;
+        let sql = "SELECT $1::text, 'literal-secret', $$dollar-secret$$, 1 / 0";
+
+        for error_detail in [ErrorDetail::Minimal, ErrorDetail::include_sql()] {
+            let executor = TransactionExecutor::from_shared_pool(&connection_pool)
+                .with_error_detail(error_detail);
+            let error = executor
+                .execute_queries([sqlx::query(sql).bind(SENTINEL)])
+                .await
+                .unwrap_err();
+
+            let formatted = format!("{error:#}");
+            let debugged = format!("{error:?}");
+            for output in [&formatted, &debugged] {
+                assert!(!output.contains(SENTINEL), "{output}");
+                assert!(!output.contains("literal-secret"), "{output}");
+                assert!(!output.contains("dollar-secret"), "{output}");
+            }
+            assert!(formatted.contains("SQLSTATE 22012"), "{formatted}");
+            assert_eq!(
+                formatted.contains("SQL: SELECT $1::text, '…', '…', 1 / 0"),
+                error_detail != ErrorDetail::Minimal,
+                "{formatted}"
+            );
+        }
+    }
+}
@@ -0,0 +1,201 @@
+use crate::database::{
+    connection_pool::begin_transaction, error::DbError, identifier::quote_identifier,
+    retry::AcquireRetry,
+};
+use anyhow::{Context, Result, ensure};
+use sqlx::{Executor, PgPool, Postgres, Transaction};
+use std::{fmt, time::Duration};
+
+/// トランザクションの分離レベルです。
+///
+/// 未指定の場合はデータベースの既定値（通常は `READ COMMITTED`）が使われます。
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum IsolationLevel {
+    ReadCommitted,
+    RepeatableRead,
+    Serializable,
+}
+
+impl IsolationLevel {
+    /// `SET TRANSACTION ISOLATION LEVEL` に続けて指定する SQL キーワードを返します。
+    pub fn as_sql(self) -> &'static str {
+        match self {
+            Self::ReadCommitted => "READ COMMITTED",
+            Self::RepeatableRead => "REPEATABLE READ",
+            Self::Serializable => "SERIALIZABLE",
+        }
+    }
+}
+
+impl fmt::Display for IsolationLevel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_sql())
+    }
+}
+
+/// トランザクションの開始直後にコミットまで遅延させる制約です。
+///
+/// 遅延できるのは `DEFERRABLE` として定義した制約のみです。遅延した制約の違反は `COMMIT` の時点で
+/// 検出され、コミットのエラーとして返ります。
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DeferredConstraints {
+    /// 遅延可能なすべての制約を遅延させます（`SET CONSTRAINTS ALL DEFERRED`）。
+    All,
+    /// 指定した名前の制約だけを遅延させます。名前は `schema.name` の形式でも指定できます。
+    Named(Vec<String>),
+}
+
+impl DeferredConstraints {
+    /// 指定した名前の制約を遅延させる設定を返します。
+    pub fn named<I, S>(names: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        Self::Named(names.into_iter().map(Into::into).collect())
+    }
+
+    /// `SET CONSTRAINTS` 文を返します。制約名は識別子として引用符で囲みます。
+    fn statement(&self) -> Result<String> {
+        let targets = match self {
+            Self::All => "ALL".to_string(),
+            Self::Named(names) => {
+                ensure!(
+                    !names.is_empty(),
+                    "At least one constraint name is required to defer constraints"
+                );
+                names
+                    .iter()
+                    .map(|name| quote_constraint_name(name))
+                    .collect::<Result<Vec<_>>>()?
+                    .join(", ")
+            }
+        };
+        Ok(format!("SET CONSTRAINTS {targets} DEFERRED"))
+    }
+}
+
+/// `schema.name` 形式を許容して制約名を引用符で囲みます。
+fn quote_constraint_name(name: &str) -> Result<String> {
+    let quoted = name
+        .split('.')
+        .map(quote_identifier)
+        .collect::<Result<Vec<_>>>()
+        .with_context(|| format!("Invalid constraint name: {name:?}"))?;
+    Ok(quoted.join("."))
+}
+
+/// トランザクション開始時に設定する特性です。
+///
+/// 既定値はデータベースの既定の分離レベルで、読み書き可能な、ステートメントの実行時間に上限のないトランザクションです。
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TransactionOptions {
+    /// 分離レベルです。`None` の場合はデータベースの既定値を使います。
+    pub isolation: Option<IsolationLevel>,
+    /// `true` の場合は `READ ONLY` トランザクションとして開始し、書き込みをデータベース側で拒否します。
+    pub read_only: bool,
+    /// トランザクション内の各ステートメントの実行時間の上限です。
+    ///
+    /// `SET LOCAL statement_timeout` で設定するため、トランザクションの終了とともに元に戻ります。
+    /// 上限を超えたステートメントはサーバー側で取り消され、原因に `DbError::QueryCanceled` を含む
+    /// エラーとなってトランザクションはロールバックされます。1 ミリ秒未満の端数は切り上げます。
+    pub statement_timeout: Option<Duration>,
+    /// コミットまで遅延させる制約です。`None` の場合は制約の定義どおりに検査します。
+    ///
+    /// 親子の行を外部キーの順序に関係なく挿入する場合などに使います。遅延した制約の違反は
+    /// `COMMIT` で検出され、遅延した制約の違反によるコミットの失敗であることを示すエラーになります。
+    pub deferred_constraints: Option<DeferredConstraints>,
+}
+
+impl TransactionOptions {
+    /// 読み取り専用トランザクションの設定を返します。
+    pub fn read_only() -> Self {
+        Self {
+            read_only: true,
+            ..Self::default()
+        }
+    }
+
+    /// 指定した分離レベルの設定を返します。
+    pub fn with_isolation(level: IsolationLevel) -> Self {
+        Self {
+            isolation: Some(level),
+            ..Self::default()
+        }
+    }
+
+    /// ステートメントの実行時間の上限を指定した設定を返します。
+    pub fn with_statement_timeout(timeout: Duration) -> Self {
+        Self {
+            statement_timeout: Some(timeout),
+            ..Self::default()
+        }
+    }
+
+    /// 制約をコミットまで遅延させる設定を返します。
+    pub fn with_deferred_constraints(deferred_constraints: DeferredConstraints) -> Self {
+        Self {
+            deferred_constraints: Some(deferred_constraints),
+            ..Self::default()
+        }
+    }
+
+    /// 設定を反映するために `begin()` 直後に実行する SQL 文を返します。
+    fn setup_statements(&self) -> Result<Vec<String>> {
+        let mut statements = Vec::new();
+
+        let mut modes = Vec::new();
+        if let Some(level) = self.isolation {
+            modes.push(format!("ISOLATION LEVEL {}", level.as_sql()));
+        }
+        if self.read_only {
+            modes.push("READ ONLY".to_string());
+        }
+        if !modes.is_empty() {
+            statements.push(format!("SET TRANSACTION {}", modes.join(", ")));
+        }
+
+        if let Some(timeout) = self.statement_timeout {
+            // ミリ秒は整数のみを埋め込むため、文字列連結でも安全です。
+            let millis = timeout.as_nanos().div_ceil(1_000_000);
+            statements.push(format!("SET LOCAL statement_timeout = '{millis}ms'"));
+        }
+
+        if let Some(deferred_constraints) = &self.deferred_constraints {
+            statements.push(deferred_constraints.statement()?);
+        }
+        Ok(statements)
+    }
+}
+
+/// トランザクションを開始し、`options` の特性をいずれのクエリよりも先に設定します。
+///
+/// 接続の取得がタイムアウトした場合は `acquire_retry` に従って再試行します。
+/// 設定に失敗した場合はトランザクションをロールバックしてエラーを返します。
+pub(super) async fn begin_with_options(
+    pool: &PgPool,
+    options: &TransactionOptions,
+    acquire_retry: &AcquireRetry,
+) -> Result<Transaction<'static, Postgres>> {
+    ensure!(
+        options
+            .statement_timeout
+            .is_none_or(|timeout| !timeout.is_zero()),
+        "statement_timeout must be greater than 0"
+    );
+    let setup_statements = options.setup_statements()?;
+
+    let mut tx = begin_transaction(pool, acquire_retry).await?;
+    for sql in setup_statements {
+        // 設定のための固定の文は名前付きのステートメントとして残さないよう、単純クエリで実行します。
+        if let Err(error) = tx.execute(sqlx::raw_sql(&sql)).await {
+            tx.rollback()
+                .await
+                .map_err(DbError::from)
+                .context("Failed to rollback transaction")?;
+            return Err(DbError::from(error))
+                .with_context(|| format!("Failed to set transaction characteristics: {sql}"));
+        }
+    }
+    Ok(tx)
+}
@@ -0,0 +1,116 @@
+use std::{
+    fmt,
+    time::{Duration, Instant},
+};
+
+/// `TransactionExecutor::execute_queries_reporting` が返す、トランザクションの所要時間の内訳です。
+///
+/// 失敗した場合も、それまでに完了したステートメントの内訳をエラーのコンテキストとして添付します。
+/// 呼び出し側は `error.downcast_ref::<TransactionReport>()` で取り出せます。
+#[derive(Debug, Clone)]
+pub struct TransactionReport {
+    /// トランザクションを開始した時刻です。
+    pub started_at: Instant,
+    /// コミットが完了した時刻です。コミットに至らなかった場合は `None` です。
+    pub committed_at: Option<Instant>,
+    /// 成功したステートメントごとの所要時間と影響を受けた行数です。
+    pub statements: Vec<StatementStat>,
+    /// コミットにかかった時間です。コミットに至らなかった場合は `None` です。
+    pub commit_duration: Option<Duration>,
+}
+
+/// トランザクション内の 1 ステートメントの実行結果です。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StatementStat {
+    /// クエリ列の中でのインデックスです。
+    pub index: usize,
+    /// 実行にかかった時間です。
+    pub duration: Duration,
+    /// 影響を受けた行数です。
+    pub rows_affected: u64,
+}
+
+impl TransactionReport {
+    /// 開始からコミット完了まで（コミットしていない場合は現在まで）の経過時間を返します。
+    pub fn total_duration(&self) -> Duration {
+        match self.committed_at {
+            Some(committed_at) => committed_at.duration_since(self.started_at),
+            None => self.started_at.elapsed(),
+        }
+    }
+}
+
+impl fmt::Display for TransactionReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.committed_at {
+            Some(_) => write!(
+                f,
+                "transaction committed {} statements in {:?}",
+                self.statements.len(),
+                self.total_duration()
+            ),
+            None => write!(
+                f,
+                "transaction rolled back after {} successful statements",
+                self.statements.len()
+            ),
+        }
+    }
+}
+
+/// `TransactionExecutor::execute_queries_dry_run` が返す、ロールバック済みのトランザクションの実行結果です。
+///
+/// ドライランでは成否にかかわらず必ずロールバックするため、何もコミットされていません。
+#[derive(Debug)]
+pub struct DryRunReport {
+    /// 成功したステートメントごとの所要時間と、コミットしていれば影響を受けたはずの行数です。
+    pub statements: Vec<StatementStat>,
+    /// 失敗したステートメントです。失敗した時点で以降のステートメントは実行していません。
+    pub failure: Option<DryRunFailure>,
+    /// 開始からロールバック完了までの時間です。
+    pub duration: Duration,
+}
+
+/// ドライランで失敗したステートメントのインデックスとエラーです。
+#[derive(Debug)]
+pub struct DryRunFailure {
+    /// 失敗したステートメントのインデックスです。
+    pub index: usize,
+    /// PostgreSQL が返したエラーです。
+    pub error: anyhow::Error,
+}
+
+impl DryRunReport {
+    /// すべてのステートメントが成功したかどうかを返します。
+    pub fn is_success(&self) -> bool {
+        self.failure.is_none()
+    }
+
+    /// 成功したステートメントで影響を受けたはずの行数の合計を返します。
+    pub fn total_rows_affected(&self) -> u64 {
+        self.statements
+            .iter()
+            .map(|statement| statement.rows_affected)
+            .sum()
+    }
+}
+
+impl fmt::Display for DryRunReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "dry run executed {} statements affecting {} rows in {:?}",
+            self.statements.len(),
+            self.total_rows_affected(),
+            self.duration
+        )?;
+        if let Some(failure) = &self.failure {
+            write!(
+                f,
+                "; statement {} failed: {:#}",
+                failure.index, failure.error
+            )?;
+        }
+        f.write_str("; rolled back, nothing was committed")
+    }
+}
@@ -0,0 +1,43 @@
+use crate::database::transaction_executor::TransactionGuard;
+use std::{future::Future, sync::Arc};
+use tokio::sync::Mutex;
+
+/// `TransactionExecutor::scope` で開始し、スコープ内の実行器の呼び出しが共有するトランザクションです。
+pub(super) type AmbientTransaction = Arc<Mutex<TransactionGuard>>;
+
+tokio::task_local! {
+    static AMBIENT_TRANSACTION: AmbientTransaction;
+}
+
+/// `TransactionExecutor::scope_with` の中でさらにスコープを開始した場合の扱いです。
+///
+/// 外側にスコープがない場合は、いずれも新しいトランザクションを開始します。
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum Propagation {
+    /// 外側のトランザクションにそのまま参加します。内側で失敗した場合は外側のトランザクション全体が
+    /// ロールバックされます（内側のエラーを外側のクロージャが返した場合）。
+    #[default]
+    Join,
+    /// 外側のトランザクション内にセーブポイントを作成し、内側の失敗ではそのセーブポイントまでを取り消します。
+    Nested,
+    /// 外側とは独立した新しいトランザクションを開始し、内側の終了時にコミットまたはロールバックします。
+    /// 外側のトランザクションと同じ行を更新するとロックを待ち合うため注意してください。
+    RequiresNew,
+}
+
+/// 現在のタスクで有効なアンビエントトランザクションを返します。
+pub(super) fn current() -> Option<AmbientTransaction> {
+    AMBIENT_TRANSACTION.try_with(Arc::clone).ok()
+}
+
+/// `transaction` をアンビエントトランザクションとして `future` を実行します。
+pub(super) async fn run_in<F: Future>(transaction: AmbientTransaction, future: F) -> F::Output {
+    AMBIENT_TRANSACTION.scope(transaction, future).await
+}
+
+/// 現在のタスクが `TransactionExecutor::scope` の中で実行されているかどうかを返します。
+///
+/// `tokio::spawn` で起動したタスクはスコープを引き継がないため、常に `false` です。
+pub fn in_scope() -> bool {
+    AMBIENT_TRANSACTION.try_with(|_| ()).is_ok()
+}
@@ -0,0 +1,304 @@
+use crate::database::{error::DbError, transaction_executor::TransactionExecutor};
+use anyhow::{Context, Result, ensure};
+use sqlx::{
+    PgPool, Postgres, Row,
+    postgres::{PgArguments, PgRow},
+    query::Query,
+};
+use std::{collections::HashMap, time::Duration};
+use uuid::Uuid;
+
+/// この実行器が作成するプリペアドトランザクションの GID の接頭辞です。
+const GID_PREFIX: &str = "tm2pc_";
+/// プライマリ側の GID の接尾辞です。両方のプールが同じサーバーを指していても GID が衝突しないよう分けます。
+const PRIMARY_SUFFIX: &str = "_p";
+/// セカンダリ側の GID の接尾辞です。
+const SECONDARY_SUFFIX: &str = "_s";
+
+/// 2 つの接続プールにまたがる書き込みを、2 相コミット（`PREPARE TRANSACTION`）で原子的に実行します。
+///
+/// 両方のサーバーで `max_prepared_transactions` を 1 以上に設定しておく必要があります。既定値の 0 の
+/// ままの場合、`execute` はクエリを実行する前にエラーを返します。
+///
+/// コミットは必ずプライマリ、セカンダリの順に、ロールバックは必ずセカンダリ、プライマリの順に行います。
+/// この順序により、プロセスが途中で停止しても `recover_prepared` が残ったプリペアドトランザクションの
+/// 状態だけから、コミットとロールバックのどちらで解決すべきかを判断できます。
+///
+/// 各トランザクションに使った接続は、終了時にプールへ返さずに閉じます。future が途中で破棄されても
+/// 開いたままのトランザクションがプールへ戻らないようにするためです。フックとオブザーバーは適用されません。
+#[derive(Clone)]
+pub struct TwoPhaseExecutor {
+    primary: TransactionExecutor,
+    secondary: TransactionExecutor,
+}
+
+/// `recover_prepared` で解決したプリペアドトランザクションの GID です。
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RecoveryReport {
+    /// コミットした GID です。
+    pub committed: Vec<String>,
+    /// ロールバックした GID です。
+    pub rolled_back: Vec<String>,
+}
+
+impl TwoPhaseExecutor {
+    /// プライマリとセカンダリのトランザクション実行器から 2 相コミットの実行器を作成します。
+    ///
+    /// 実行器に設定した同時実行数の制限は、それぞれのトランザクションにも適用されます。
+    pub fn new(primary: TransactionExecutor, secondary: TransactionExecutor) -> Self {
+        Self { primary, secondary }
+    }
+
+    /// プライマリとセカンダリのクエリをそれぞれのトランザクションで実行し、両方をコミットします。
+    ///
+    /// 両方のトランザクションを `PREPARE TRANSACTION` するまでに失敗した場合は、どちらもロールバックします。
+    /// プライマリのコミット後にセカンダリのコミットが失敗した場合はエラーを返しますが、セカンダリの
+    /// トランザクションはプリペアド状態で残るため、`recover_prepared` でコミットされます。
+    pub async fn execute<'a, P, S>(&self, primary_queries: P, secondary_queries: S) -> Result<()>
+    where
+        P: IntoIterator<Item = Query<'a, Postgres, PgArguments>>,
+        S: IntoIterator<Item = Query<'a, Postgres, PgArguments>>,
+    {
+        ensure_prepared_transactions_enabled(self.primary.pool())
+            .await
+            .context("Primary database cannot run two-phase commit")?;
+        ensure_prepared_transactions_enabled(self.secondary.pool())
+            .await
+            .context("Secondary database cannot run two-phase commit")?;
+
+        let base = format!("{GID_PREFIX}{}", Uuid::new_v4().simple());
+        let primary_gid = format!("{base}{PRIMARY_SUFFIX}");
+        let secondary_gid = format!("{base}{SECONDARY_SUFFIX}");
+
+        let _primary_slot = self.primary.acquire_slot().await?;
+        prepare(self.primary.pool(), &primary_gid, primary_queries)
+            .await
+            .context("Failed to prepare primary transaction")?;
+
+        let _secondary_slot = match self.secondary.acquire_slot().await {
+            Ok(slot) => slot,
+            Err(error) => {
+                return Err(self.abort(None, &primary_gid, error).await);
+            }
+        };
+        if let Err(error) = prepare(self.secondary.pool(), &secondary_gid, secondary_queries)
+            .await
+            .context("Failed to prepare secondary transaction")
+        {
+            return Err(self.abort(Some(&secondary_gid), &primary_gid, error).await);
+        }
+
+        if let Err(error) = finish_prepared(self.primary.pool(), "COMMIT", &primary_gid)
+            .await
+            .context("Failed to commit primary prepared transaction")
+        {
+            // 応答だけが失われた場合はコミット済みのため、プリペアド状態で残っているかで判断します。
+            match is_prepared(self.primary.pool(), &primary_gid).await {
+                Ok(true) => {
+                    return Err(self.abort(Some(&secondary_gid), &primary_gid, error).await);
+                }
+                Ok(false) => {}
+                Err(_) => {
+                    return Err(error.context(
+                        "Outcome of the primary commit is unknown; recover_prepared will resolve both transactions",
+                    ));
+                }
+            }
+        }
+        finish_prepared(self.secondary.pool(), "COMMIT", &secondary_gid)
+            .await
+            .with_context(|| {
+                format!(
+                    "Primary transaction committed but secondary prepared transaction {secondary_gid} \
+                     failed to commit; recover_prepared will commit it"
+                )
+            })
+    }
+
+    /// `older_than` 以上前に作成されたまま残っているプリペアドトランザクションを解決します。
+    ///
+    /// クラッシュ後の起動時などに呼び出します。セカンダリだけが残っている場合はプライマリがコミット
+    /// 済みのためコミットし、それ以外はロールバックします。実行中の `execute` を妨げないよう、
+    /// `older_than` には `execute` の所要時間より十分長い時間を指定してください。
+    pub async fn recover_prepared(&self, older_than: Duration) -> Result<RecoveryReport> {
+        let primary = prepared_transactions(self.primary.pool(), PRIMARY_SUFFIX, older_than)
+            .await
+            .context("Failed to list primary prepared transactions")?;
+        let secondary = prepared_transactions(self.secondary.pool(), SECONDARY_SUFFIX, older_than)
+            .await
+            .context("Failed to list secondary prepared transactions")?;
+
+        let mut report = RecoveryReport::default();
+        for (base, expired) in &secondary {
+            if !expired {
+                continue;
+            }
+            let gid = format!("{base}{SECONDARY_SUFFIX}");
+            if primary.contains_key(base) {
+                finish_prepared(self.secondary.pool(), "ROLLBACK", &gid).await?;
+                report.rolled_back.push(gid);
+            } else {
+                finish_prepared(self.secondary.pool(), "COMMIT", &gid).await?;
+                report.committed.push(gid);
+            }
+        }
+        for (base, expired) in &primary {
+            let secondary_pending = secondary.get(base).is_some_and(|expired| !expired);
+            if !expired || secondary_pending {
+                continue;
+            }
+            let gid = format!("{base}{PRIMARY_SUFFIX}");
+            finish_prepared(self.primary.pool(), "ROLLBACK", &gid).await?;
+            report.rolled_back.push(gid);
+        }
+        Ok(report)
+    }
+
+    /// セカンダリ、プライマリの順にプリペアドトランザクションをロールバックし、元のエラーを返します。
+    ///
+    /// セカンダリのロールバックに失敗した場合は、プライマリを残したまま返します。残ったトランザクションは
+    /// `recover_prepared` でロールバックされます。
+    async fn abort(
+        &self,
+        secondary_gid: Option<&str>,
+        primary_gid: &str,
+        error: anyhow::Error,
+    ) -> anyhow::Error {
+        if let Some(gid) = secondary_gid
+            && let Err(rollback_error) = rollback_if_prepared(self.secondary.pool(), gid).await
+        {
+            tracing::warn!(
+                gid,
+                error = %format!("{rollback_error:#}"),
+                "Failed to roll back prepared transaction; leaving it for recover_prepared"
+            );
+            return error;
+        }
+        if let Err(rollback_error) = rollback_if_prepared(self.primary.pool(), primary_gid).await {
+            tracing::warn!(
+                gid = primary_gid,
+                error = %format!("{rollback_error:#}"),
+                "Failed to roll back prepared transaction; leaving it for recover_prepared"
+            );
+        }
+        error
+    }
+}
+
+/// `max_prepared_transactions` が 0 の場合にエラーを返します。
+async fn ensure_prepared_transactions_enabled(pool: &PgPool) -> Result<()> {
+    let setting: String = sqlx::query_scalar("SELECT current_setting('max_prepared_transactions')")
+        .fetch_one(pool)
+        .await
+        .map_err(DbError::from)
+        .context("Failed to read max_prepared_transactions")?;
+    ensure!(
+        setting.trim() != "0",
+        "max_prepared_transactions is 0; set it to at least 1 on the server to use two-phase commit"
+    );
+    Ok(())
+}
+
+/// 新しい接続でクエリを実行し、`gid` で `PREPARE TRANSACTION` します。
+async fn prepare<'a, I>(pool: &PgPool, gid: &str, queries: I) -> Result<()>
+where
+    I: IntoIterator<Item = Query<'a, Postgres, PgArguments>>,
+{
+    let mut connection = pool
+        .acquire()
+        .await
+        .map_err(DbError::from)
+        .context("Failed to acquire connection")?;
+    // 失敗時や future の破棄時にトランザクションが開いたまま残らないよう、接続は常に閉じます。
+    connection.close_on_drop();
+
+    sqlx::raw_sql("BEGIN")
+        .execute(&mut *connection)
+        .await
+        .map_err(DbError::from)
+        .context("Failed to begin transaction")?;
+    for (index, query) in queries.into_iter().enumerate() {
+        query
+            .execute(&mut *connection)
+            .await
+            .map_err(DbError::from)
+            .with_context(|| format!("Failed to execute query at index {index}"))?;
+    }
+    sqlx::raw_sql(&format!("PREPARE TRANSACTION {}", quote_literal(gid)))
+        .execute(&mut *connection)
+        .await
+        .map_err(DbError::from)
+        .with_context(|| format!("Failed to prepare transaction {gid}"))?;
+    Ok(())
+}
+
+/// `gid` のトランザクションがプリペアド状態で残っているかどうかを返します。
+async fn is_prepared(pool: &PgPool, gid: &str) -> Result<bool> {
+    sqlx::query_scalar(
+        "SELECT EXISTS (SELECT 1 FROM pg_prepared_xacts WHERE gid = $1 AND database = current_database())",
+    )
+    .bind(gid)
+    .fetch_one(pool)
+    .await
+    .map_err(DbError::from)
+    .with_context(|| format!("Failed to look up prepared transaction {gid}"))
+}
+
+/// `gid` のトランザクションがプリペアド状態で残っている場合はロールバックします。
+///
+/// `PREPARE TRANSACTION` が失敗した場合はプリペアドトランザクションが作られていないため、何もしません。
+async fn rollback_if_prepared(pool: &PgPool, gid: &str) -> Result<()> {
+    if is_prepared(pool, gid).await? {
+        finish_prepared(pool, "ROLLBACK", gid).await?;
+    }
+    Ok(())
+}
+
+/// プリペアドトランザクションを `COMMIT PREPARED` または `ROLLBACK PREPARED` で終了します。
+async fn finish_prepared(pool: &PgPool, command: &str, gid: &str) -> Result<()> {
+    sqlx::raw_sql(&format!("{command} PREPARED {}", quote_literal(gid)))
+        .execute(pool)
+        .await
+        .map_err(DbError::from)
+        .with_context(|| format!("Failed to {command} PREPARED {gid}"))?;
+    Ok(())
+}
+
+/// 接続先データベースに残っている、`suffix` で終わるこの実行器のプリペアドトランザクションを返します。
+///
+/// キーは接尾辞を除いた GID、値は `older_than` 以上前に作成されたかどうかです。
+async fn prepared_transactions(
+    pool: &PgPool,
+    suffix: &str,
+    older_than: Duration,
+) -> Result<HashMap<String, bool>> {
+    let older_than_ms = i64::try_from(older_than.as_millis()).unwrap_or(i64::MAX);
+    let rows = sqlx::query(
+        "SELECT gid, prepared < now() - $1 * interval '1 millisecond' AS expired \
+         FROM pg_prepared_xacts \
+         WHERE database = current_database() AND starts_with(gid, $2)",
+    )
+    .bind(older_than_ms)
+    .bind(GID_PREFIX)
+    .try_map(|row: PgRow| {
+        Ok((
+            row.try_get::<String, _>("gid")?,
+            row.try_get::<bool, _>("expired")?,
+        ))
+    })
+    .fetch_all(pool)
+    .await
+    .map_err(DbError::from)?;
+    Ok(rows
+        .into_iter()
+        .filter_map(|(gid, expired)| {
+            gid.strip_suffix(suffix)
+                .map(|base| (base.to_string(), expired))
+        })
+        .collect())
+}
+
+/// 文字列を単一引用符で囲み、SQL の文字列リテラルとして埋め込める形に変換します。
+fn quote_literal(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "''"))
+}
@@ -1,63 +1,161 @@
-mod database;
-
 use anyhow::Result;
-use database::connection_pool::ConnectionPool;
-use database::query_executor::QueryExecutor;
-use sqlx::{Row, postgres::PgRow};
+use database_manager_rs::database::connection_pool::{ConnectionPool, SharedConnectionPool};
+use database_manager_rs::database::health::HealthMonitorConfig;
+use database_manager_rs::database::migration::auto_migrate_enabled;
+use database_manager_rs::database::pool_config::Workload;
+use database_manager_rs::database::query_executor::QueryExecutor;
+use database_manager_rs::database::scheduler::{Job, JobSchedule, JobScheduler};
+use database_manager_rs::database::transaction_executor::TransactionExecutor;
+use std::{sync::Arc, time::Duration};
+use tokio::sync::watch;
+
+const HEALTH_CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+const HEALTH_CHECK_DEGRADED_LATENCY: Duration = Duration::from_millis(500);
+const FEATURE_INTERVAL: Duration = Duration::from_secs(5);
+const BATCH_MAX_RETRIES: u32 = 2;
+const SHUTDOWN_DRAIN_TIMEOUT: Duration = Duration::from_secs(30);
+const MIGRATIONS_DIR: &str = "./migrations";
 
 #[tokio::main]
 async fn main() -> Result<()> {
     let connection_pool = ConnectionPool::shared().await?;
+    if auto_migrate_enabled()? {
+        let report = connection_pool.run_migrations_from(MIGRATIONS_DIR).await?;
+        println!("Applied {} migrations", report.applied.len());
+    }
     let query_executor = QueryExecutor::from_shared_pool(&connection_pool);
 
-    let worker_executor = query_executor.clone();
-    let batch_executor = query_executor.clone();
+    let (shutdown_sender, shutdown) = watch::channel(false);
+    let signal_sender = shutdown_sender.clone();
+    tokio::spawn(async move {
+        if let Err(error) = wait_for_shutdown_signal().await {
+            eprintln!("Failed to listen for shutdown signal: {error:#}");
+        }
+        let _ = signal_sender.send(true);
+    });
+
+    // バッチが接続を使い切っても画面の処理が待たされないよう、接続数を分割している場合はバッチ用のプールを使います。
+    let batch_executor = TransactionExecutor::for_workload(&connection_pool, Workload::Batch);
     let ui_executor = query_executor.clone();
 
-    let worker = tokio::spawn(async move { resident_feature(worker_executor).await });
-    let batch = tokio::spawn(async move { scheduled_batch_feature(batch_executor).await });
-    let ui = tokio::spawn(async move { screen_feature(ui_executor).await });
+    let worker_pool = Arc::clone(&connection_pool);
+    let worker_shutdown = shutdown.clone();
+    let batch_shutdown = shutdown.clone();
+    let ui_shutdown = shutdown;
+    let worker = tokio::spawn(async move { resident_feature(worker_pool, worker_shutdown).await });
+    let batch =
+        tokio::spawn(async move { scheduled_batch_feature(batch_executor, batch_shutdown).await });
+    let ui = tokio::spawn(async move { screen_feature(ui_executor, ui_shutdown).await });
 
-    let (worker_result, batch_result, ui_result) = tokio::try_join!(worker, batch, ui)?;
-    worker_result?;
-    batch_result?;
-    ui_result?;
+    // いずれかのタスクが失敗または panic した場合は、残りのタスクにも終了を要求してからすべての終了を待ちます。
+    let results = futures_util::future::join_all([worker, batch, ui].map(|task| {
+        let shutdown_sender = shutdown_sender.clone();
+        async move {
+            let result = task.await;
+            if !matches!(result, Ok(Ok(()))) {
+                let _ = shutdown_sender.send(true);
+            }
+            result
+        }
+    }))
+    .await;
+    connection_pool.close(SHUTDOWN_DRAIN_TIMEOUT).await?;
+    for result in results {
+        result??;
+    }
 
     println!("Hello, world!");
 
     Ok(())
 }
 
-async fn resident_feature(query_executor: QueryExecutor) -> Result<()> {
-    health_check(&query_executor).await?;
-    let _health_checks: Vec<i64> = query_executor
-        .fetch_all(
-            sqlx::query("SELECT 1::bigint as value UNION ALL SELECT 1::bigint as value")
-                .try_map(|row: PgRow| row.try_get("value")),
-        )
-        .await?;
+async fn resident_feature(
+    connection_pool: SharedConnectionPool,
+    mut shutdown: watch::Receiver<bool>,
+) -> Result<()> {
+    let monitor = connection_pool.spawn_health_monitor(
+        HealthMonitorConfig::new()
+            .with_interval(FEATURE_INTERVAL)
+            .with_timeout(HEALTH_CHECK_TIMEOUT)
+            .with_degraded_latency(HEALTH_CHECK_DEGRADED_LATENCY),
+    )?;
+    let mut status = monitor.subscribe();
+    loop {
+        tokio::select! {
+            _ = shutdown.wait_for(|requested| *requested) => break,
+            changed = status.changed() => {
+                if changed.is_err() {
+                    break;
+                }
+                println!("Database health: {:?}", *status.borrow_and_update());
+            }
+        }
+    }
+    monitor.stop();
     Ok(())
 }
 
-async fn scheduled_batch_feature(query_executor: QueryExecutor) -> Result<()> {
-    query_executor
-        .execute_queries(vec![sqlx::query("SELECT 1"), sqlx::query("SELECT 1")])
-        .await?;
-    Ok(())
+async fn scheduled_batch_feature(
+    transaction_executor: TransactionExecutor,
+    mut shutdown: watch::Receiver<bool>,
+) -> Result<()> {
+    let scheduler = JobScheduler::new(transaction_executor).with_job(
+        Job::new(
+            "scheduled_batch",
+            JobSchedule::Every(FEATURE_INTERVAL),
+            |transaction_executor| {
+                Box::pin(async move {
+                    transaction_executor
+                        .execute_queries(vec![sqlx::query("SELECT 1"), sqlx::query("SELECT 1")])
+                        .await?;
+                    Ok(())
+                })
+            },
+        )
+        .with_retries(BATCH_MAX_RETRIES, FEATURE_INTERVAL),
+    )?;
+    scheduler
+        .run(async move {
+            let _ = shutdown.wait_for(|requested| *requested).await;
+        })
+        .await
 }
 
-async fn screen_feature(query_executor: QueryExecutor) -> Result<()> {
-    query_executor
-        .execute_query(sqlx::query("SELECT 1"))
-        .await?;
-    Ok(())
+async fn screen_feature(
+    query_executor: QueryExecutor,
+    mut shutdown: watch::Receiver<bool>,
+) -> Result<()> {
+    loop {
+        // 一時的な障害で画面の処理全体が止まらないよう、失敗はログに出力して次の反復で再試行します。
+        if let Err(error) = query_executor.execute_query(sqlx::query("SELECT 1")).await {
+            eprintln!("Screen query failed: {error:#}");
+        }
+        if wait_for_next_iteration(&mut shutdown).await {
+            return Ok(());
+        }
+    }
 }
 
-async fn health_check(query_executor: &QueryExecutor) -> Result<()> {
-    let _health_check: Option<i64> = query_executor
-        .fetch_one(
-            sqlx::query("SELECT 1::bigint as value").try_map(|row: PgRow| row.try_get("value")),
-        )
-        .await?;
+/// 次の反復まで待機し、その間に終了が要求された場合は `true` を返します。
+async fn wait_for_next_iteration(shutdown: &mut watch::Receiver<bool>) -> bool {
+    tokio::select! {
+        _ = shutdown.wait_for(|requested| *requested) => true,
+        () = tokio::time::sleep(FEATURE_INTERVAL) => false,
+    }
+}
+
+/// Ctrl+C（Unix では SIGTERM も）を受信するまで待機します。
+async fn wait_for_shutdown_signal() -> Result<()> {
+    #[cfg(unix)]
+    {
+        let mut terminate =
+            tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())?;
+        tokio::select! {
+            result = tokio::signal::ctrl_c() => result?,
+            _ = terminate.recv() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c().await?;
     Ok(())
 }